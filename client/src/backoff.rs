@@ -0,0 +1,130 @@
+//! Retry scheduling shared by everything that talks to the network. Each retryable operation
+//! (an announce to one tracker, a dial to one peer) owns a [`Backoff`], reports failures to it and
+//! waits for the delay it returns before trying again.
+
+use std::time::Duration;
+
+use rand::Rng;
+
+/// Tracker announces: keep trying for a long time, but never more often than once a minute after
+/// the first few failures.
+pub const TRACKER_ANNOUNCE: Policy = Policy {
+    initial: Duration::from_secs(15),
+    max: Duration::from_secs(30 * 60),
+    multiplier: 2,
+    jitter: 0.25,
+    max_attempts: None,
+};
+
+/// Outgoing peer dials: peers come and go, so give up after a handful of attempts.
+pub const PEER_DIAL: Policy = Policy {
+    initial: Duration::from_secs(5),
+    max: Duration::from_secs(5 * 60),
+    multiplier: 3,
+    jitter: 0.25,
+    max_attempts: Some(5),
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Policy {
+    /// The delay after the first failure.
+    pub initial: Duration,
+
+    /// The upper bound on the delay, before jitter is applied.
+    pub max: Duration,
+
+    /// The factor by which the delay grows after each consecutive failure.
+    pub multiplier: u32,
+
+    /// The fraction (0.0 to 1.0) by which each delay is randomly lengthened or shortened, so that
+    /// many operations failing at once don't all retry at once.
+    pub jitter: f64,
+
+    /// The number of failures after which to give up entirely, if any.
+    pub max_attempts: Option<u32>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    policy: Policy,
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn new(policy: Policy) -> Self {
+        Self {
+            policy,
+            failures: 0,
+        }
+    }
+
+    /// Record a failure, returning how long to wait before the next attempt, or `None` if the
+    /// policy says to stop trying.
+    pub fn failed(&mut self) -> Option<Duration> {
+        self.failures = self.failures.saturating_add(1);
+
+        if self
+            .policy
+            .max_attempts
+            .is_some_and(|max_attempts| self.failures >= max_attempts)
+        {
+            return None;
+        }
+
+        let delay = self.base_delay();
+
+        if self.policy.jitter > 0.0 {
+            let factor = rand::thread_rng().gen_range(-self.policy.jitter..=self.policy.jitter);
+            Some(delay.mul_f64(1.0 + factor))
+        } else {
+            Some(delay)
+        }
+    }
+
+    /// Record a success, resetting the delay to its initial value.
+    pub fn succeeded(&mut self) {
+        self.failures = 0;
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    fn base_delay(&self) -> Duration {
+        let exponent = self.failures.saturating_sub(1);
+
+        self.policy
+            .multiplier
+            .checked_pow(exponent)
+            .and_then(|factor| self.policy.initial.checked_mul(factor))
+            .unwrap_or(self.policy.max)
+            .min(self.policy.max)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backoff_test() {
+        let mut backoff = Backoff::new(Policy {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(10),
+            multiplier: 2,
+            jitter: 0.0,
+            max_attempts: Some(6),
+        });
+
+        assert_eq!(Some(Duration::from_secs(1)), backoff.failed());
+        assert_eq!(Some(Duration::from_secs(2)), backoff.failed());
+        assert_eq!(Some(Duration::from_secs(4)), backoff.failed());
+        assert_eq!(Some(Duration::from_secs(8)), backoff.failed());
+        assert_eq!(Some(Duration::from_secs(10)), backoff.failed());
+        assert_eq!(None, backoff.failed());
+        assert_eq!(6, backoff.failures());
+
+        backoff.succeeded();
+        assert_eq!(Some(Duration::from_secs(1)), backoff.failed());
+    }
+}
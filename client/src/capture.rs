@@ -0,0 +1,288 @@
+//! A record of the messages exchanged with peers, for debugging the wire protocol. With
+//! `--capture`, every message sent or received is appended to a file along with when and with
+//! which peer, and `toytorrent decode-capture` prints the file back.
+//!
+//! The file starts with [`MAGIC`], followed by a record for each message:
+//!
+//! * the time in microseconds since the Unix epoch, as a big-endian u64;
+//! * 0 if the message was received, or 1 if it was sent;
+//! * the peer's address as text, preceded by its length as a u8;
+//! * the message as it went over the wire, with its length prefix.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+use toytorrent_common as common;
+
+use common::peer::PeerMessage;
+
+const MAGIC: &[u8] = b"toytorrent capture 1\n";
+
+static CAPTURE: OnceLock<Mutex<BufWriter<File>>> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Flow {
+    Received,
+    Sent,
+}
+
+/// One message read back from a capture.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Record {
+    time: SystemTime,
+    flow: Flow,
+    addr: SocketAddr,
+
+    /// The length of the message on the wire, with its length prefix.
+    len: usize,
+    message: PeerMessage,
+}
+
+/// How many messages of one kind went each way, and how long they were in total.
+#[derive(Clone, Copy, Debug, Default)]
+struct Tally {
+    received: (u64, u64),
+    sent: (u64, u64),
+}
+
+/// Start capturing to `path`, replacing it if it exists. Only the first call has any effect.
+pub fn start(path: &Path) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(MAGIC)?;
+    file.flush()?;
+
+    CAPTURE.set(Mutex::new(file)).ok();
+    Ok(())
+}
+
+/// Add a message to the capture, if there is one.
+pub async fn record(addr: SocketAddr, flow: Flow, message: &PeerMessage) {
+    let Some(capture) = CAPTURE.get() else {
+        return;
+    };
+
+    let bytes = encode(SystemTime::now(), flow, addr, message).await;
+    let mut file = capture.lock().unwrap();
+
+    if let Err(e) = file.write_all(&bytes).and_then(|()| file.flush()) {
+        say!("Unable to write to the capture: {}", e);
+    }
+}
+
+/// Print the messages in the capture at `path`, timed from the first, followed with `summary` by
+/// how many of each kind there were.
+pub fn decode(path: &Path, summary: bool) -> Result<(), common::Error> {
+    let bytes = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    let mut input = bytes
+        .strip_prefix(MAGIC)
+        .ok_or_else(|| format!("{} isn't a capture", path.display()))?;
+
+    let mut first = None;
+    let mut tallies: BTreeMap<&'static str, Tally> = BTreeMap::new();
+
+    while !input.is_empty() {
+        let (record, rest) = Record::decode(input)?;
+        input = rest;
+
+        let first = *first.get_or_insert(record.time);
+        let elapsed = record.time.duration_since(first).unwrap_or(Duration::ZERO);
+        let arrow = match record.flow {
+            Flow::Received => "<-",
+            Flow::Sent => "->",
+        };
+
+        say!(
+            "{:>12.6} {:21} {} {}",
+            elapsed.as_secs_f64(),
+            record.addr,
+            arrow,
+            describe(&record.message),
+        );
+
+        let tally = tallies.entry(kind(&record.message)).or_default();
+        let (count, len) = match record.flow {
+            Flow::Received => &mut tally.received,
+            Flow::Sent => &mut tally.sent,
+        };
+        *count += 1;
+        *len += record.len as u64;
+    }
+
+    if summary {
+        say!("");
+
+        for (kind, tally) in tallies {
+            say!(
+                "{:14} {:>8} received ({:>10}) {:>8} sent ({:>10})",
+                kind,
+                tally.received.0,
+                common::Bytes::from(tally.received.1).to_string(),
+                tally.sent.0,
+                common::Bytes::from(tally.sent.1).to_string(),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+async fn encode(time: SystemTime, flow: Flow, addr: SocketAddr, message: &PeerMessage) -> Vec<u8> {
+    let micros = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_micros() as u64);
+    let addr = addr.to_string();
+
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&micros.to_be_bytes());
+    bytes.push(match flow {
+        Flow::Received => 0,
+        Flow::Sent => 1,
+    });
+    bytes.push(addr.len() as u8);
+    bytes.extend_from_slice(addr.as_bytes());
+
+    // Writing to memory can't fail.
+    message.clone().write_to(&mut bytes).await.ok();
+
+    bytes
+}
+
+impl Record {
+    /// Read the record at the start of `input`, returning it and the rest of the input.
+    fn decode(input: &[u8]) -> Result<(Self, &[u8]), common::Error> {
+        let truncated = || common::Error::from("The capture ends partway through a message");
+
+        let (header, input) = split(input, 10).ok_or_else(truncated)?;
+        let (addr, input) = split(input, usize::from(header[9])).ok_or_else(truncated)?;
+        let (len, _) = split(input, 4).ok_or_else(truncated)?;
+        let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+        let (wire, input) = split(input, 4 + len).ok_or_else(truncated)?;
+
+        let micros = u64::from_be_bytes(header[..8].try_into().unwrap());
+        let flow = match header[8] {
+            0 => Flow::Received,
+            1 => Flow::Sent,
+            flow => return Err(format!("Unknown direction {}", flow).into()),
+        };
+
+        let addr = std::str::from_utf8(addr)
+            .ok()
+            .and_then(|addr| addr.parse().ok())
+            .ok_or("Invalid peer address")?;
+
+        let message = PeerMessage::try_from(&wire[4..])
+            .map_err(|e| format!("Invalid message from {}: {:?}", addr, e))?;
+
+        let record = Self {
+            time: SystemTime::UNIX_EPOCH + Duration::from_micros(micros),
+            flow,
+            addr,
+            len: wire.len(),
+            message,
+        };
+
+        Ok((record, input))
+    }
+}
+
+fn split(input: &[u8], at: usize) -> Option<(&[u8], &[u8])> {
+    (at <= input.len()).then(|| input.split_at(at))
+}
+
+fn kind(message: &PeerMessage) -> &'static str {
+    match message {
+        PeerMessage::KeepAlive => "keep-alive",
+        PeerMessage::Choke => "choke",
+        PeerMessage::Unchoke => "unchoke",
+        PeerMessage::Interested => "interested",
+        PeerMessage::NotInterested => "not interested",
+        PeerMessage::Have { .. } => "have",
+        PeerMessage::Bitfield { .. } => "bitfield",
+        PeerMessage::Request { .. } => "request",
+        PeerMessage::Piece { .. } => "piece",
+        PeerMessage::Cancel { .. } => "cancel",
+        PeerMessage::Port { .. } => "port",
+        PeerMessage::Extended { .. } => "extended",
+        PeerMessage::HashRequest { .. } => "hash request",
+        PeerMessage::Hashes { .. } => "hashes",
+        PeerMessage::HashReject { .. } => "hash reject",
+    }
+}
+
+/// The message on one line, leaving out block data and hashes.
+fn describe(message: &PeerMessage) -> String {
+    let block = |block: &common::BlockRef| {
+        format!("{}:{}+{}", block.index(), block.begin(), block.length())
+    };
+
+    let hashes = |request: &common::peer::HashRequest| {
+        format!(
+            "{} layer {} {}+{}",
+            request.pieces_root, request.base_layer, request.index, request.length,
+        )
+    };
+
+    let detail = match message {
+        PeerMessage::Have { index } => index.to_string(),
+        PeerMessage::Bitfield { bitfield } => format!(
+            "with {} pieces",
+            bitfield.iter().map(|byte| byte.count_ones()).sum::<u32>(),
+        ),
+        PeerMessage::Request { block: b }
+        | PeerMessage::Piece { block: b, .. }
+        | PeerMessage::Cancel { block: b } => block(b),
+        PeerMessage::Port { port } => port.to_string(),
+        PeerMessage::Extended { id, payload } => format!("{} with {} bytes", id, payload.len()),
+        PeerMessage::HashRequest { request }
+        | PeerMessage::Hashes { request, .. }
+        | PeerMessage::HashReject { request } => hashes(request),
+        _ => return kind(message).to_string(),
+    };
+
+    format!("{} {}", kind(message), detail)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn record_test() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        let addr: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        let message = PeerMessage::Piece {
+            block: common::BlockRef::new(3, 16384, 4),
+            data: b"data".to_vec(),
+        };
+
+        let mut bytes = encode(time, Flow::Sent, addr, &message).await;
+        bytes.extend(encode(time, Flow::Received, addr, &PeerMessage::KeepAlive).await);
+
+        let (record, rest) = Record::decode(&bytes).unwrap();
+        assert_eq!(
+            Record {
+                time,
+                flow: Flow::Sent,
+                addr,
+                len: 17,
+                message,
+            },
+            record,
+        );
+
+        let (record, rest) = Record::decode(rest).unwrap();
+        assert_eq!(
+            (Flow::Received, PeerMessage::KeepAlive),
+            (record.flow, record.message)
+        );
+        assert!(rest.is_empty());
+
+        assert!(Record::decode(&bytes[..30]).is_err());
+    }
+}
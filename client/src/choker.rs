@@ -0,0 +1,268 @@
+//! Strategies for choosing which peers to upload to. Like [`PiecePicker`](super::PiecePicker),
+//! the session is generic over [`Choker`], so alternative algorithms can be tried out without
+//! touching the session loop.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use rand::seq::SliceRandom;
+
+/// Peers connected this recently are likelier to get the optimistic unchoke, since they have no
+/// pieces to trade yet and would otherwise struggle to get a start.
+const NEW_PEER_AGE: Duration = Duration::from_secs(60);
+
+/// How many times likelier a new peer is to get the optimistic unchoke, as in the original
+/// BitTorrent client.
+const NEW_PEER_WEIGHT: u32 = 3;
+
+/// What a choker gets to know about each interested peer.
+#[derive(Clone, Copy, Debug)]
+pub struct Candidate {
+    pub addr: SocketAddr,
+
+    /// The rate at which the peer is sending us data, in bytes per second.
+    pub download_rate: f64,
+
+    /// The rate at which we are sending the peer data, in bytes per second.
+    pub upload_rate: f64,
+
+    pub am_choking: bool,
+
+    /// When the connection to the peer was established.
+    pub connected_at: Instant,
+}
+
+pub trait Choker: fmt::Debug {
+    /// Called every rechoke interval with all interested peers, returning those to unchoke. The
+    /// rest are choked. `seeding` is set once we have the complete torrent.
+    fn rechoke(&mut self, candidates: &[Candidate], seeding: bool, now: Instant)
+        -> Vec<SocketAddr>;
+}
+
+/// The choking algorithm from the original BitTorrent client: unchoke the peers that upload to us
+/// fastest, plus one at random (the "optimistic unchoke"), rotated every few rounds, to discover
+/// better peers and give new ones a start. Choked peers that connected recently are the likeliest
+/// to get the optimistic unchoke.
+///
+/// Once seeding there is nothing left to reciprocate, so peers are ranked like [`SeedMode`].
+#[derive(Clone, Debug)]
+pub struct TitForTat {
+    /// The number of peers to unchoke, including the optimistic unchoke.
+    pub slots: usize,
+
+    /// How long to keep the optimistic unchoke before choosing another.
+    pub optimistic_interval: Duration,
+
+    optimistic: Option<(SocketAddr, Instant)>,
+}
+
+/// Unchoke the peers that we can upload to fastest, plus an optimistic unchoke. Suited to seeds,
+/// which have nothing to gain from reciprocation.
+#[derive(Clone, Debug, Default)]
+pub struct SeedMode(TitForTat);
+
+impl TitForTat {
+    pub fn new(slots: usize, optimistic_interval: Duration) -> Self {
+        Self {
+            slots,
+            optimistic_interval,
+            optimistic: None,
+        }
+    }
+
+    fn rechoke_by(
+        &mut self,
+        candidates: &[Candidate],
+        now: Instant,
+        rate: impl Fn(&Candidate) -> f64,
+    ) -> Vec<SocketAddr> {
+        let mut ranked: Vec<&Candidate> = candidates.iter().collect();
+        ranked.sort_by(|a, b| rate(b).total_cmp(&rate(a)));
+
+        let mut unchoked: Vec<SocketAddr> = ranked
+            .iter()
+            .take(self.slots.saturating_sub(1))
+            .map(|candidate| candidate.addr)
+            .collect();
+
+        // Keep the optimistic unchoke for a while, unless it left or earned a regular slot.
+        let optimistic_is_valid = self.optimistic.is_some_and(|(addr, since)| {
+            now.duration_since(since) < self.optimistic_interval
+                && !unchoked.contains(&addr)
+                && candidates.iter().any(|c| c.addr == addr)
+        });
+
+        // Give the slot to a peer that is choked now if there is one, rather than the one that
+        // just had it.
+        if !optimistic_is_valid {
+            let eligible: Vec<&Candidate> = candidates
+                .iter()
+                .filter(|candidate| !unchoked.contains(&candidate.addr))
+                .collect();
+
+            let choked: Vec<&Candidate> = eligible
+                .iter()
+                .copied()
+                .filter(|candidate| candidate.am_choking)
+                .collect();
+
+            let pool = if choked.is_empty() { eligible } else { choked };
+
+            self.optimistic = pool
+                .choose_weighted(&mut rand::thread_rng(), |candidate| {
+                    optimistic_weight(candidate, now)
+                })
+                .ok()
+                .map(|candidate| (candidate.addr, now));
+        }
+
+        if self.slots > 0 {
+            unchoked.extend(self.optimistic.map(|(addr, _)| addr));
+        }
+
+        unchoked
+    }
+}
+
+/// How likely a peer is to get the optimistic unchoke, relative to the others.
+fn optimistic_weight(candidate: &Candidate, now: Instant) -> u32 {
+    if now.duration_since(candidate.connected_at) < NEW_PEER_AGE {
+        NEW_PEER_WEIGHT
+    } else {
+        1
+    }
+}
+
+impl Default for TitForTat {
+    fn default() -> Self {
+        Self::new(4, Duration::from_secs(30))
+    }
+}
+
+impl Choker for TitForTat {
+    fn rechoke(
+        &mut self,
+        candidates: &[Candidate],
+        seeding: bool,
+        now: Instant,
+    ) -> Vec<SocketAddr> {
+        if seeding {
+            self.rechoke_by(candidates, now, |candidate| candidate.upload_rate)
+        } else {
+            self.rechoke_by(candidates, now, |candidate| candidate.download_rate)
+        }
+    }
+}
+
+impl SeedMode {
+    pub fn new(slots: usize, optimistic_interval: Duration) -> Self {
+        Self(TitForTat::new(slots, optimistic_interval))
+    }
+}
+
+impl Choker for SeedMode {
+    fn rechoke(
+        &mut self,
+        candidates: &[Candidate],
+        _seeding: bool,
+        now: Instant,
+    ) -> Vec<SocketAddr> {
+        self.0
+            .rechoke_by(candidates, now, |candidate| candidate.upload_rate)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use toytorrent_common::{Clock, ManualClock};
+
+    fn candidate(port: u16, download_rate: f64, upload_rate: f64) -> Candidate {
+        Candidate {
+            addr: SocketAddr::from(([127, 0, 0, 1], port)),
+            download_rate,
+            upload_rate,
+            am_choking: true,
+            connected_at: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn rechoke_test() {
+        let candidates = [
+            candidate(1, 10.0, 0.0),
+            candidate(2, 30.0, 5.0),
+            candidate(3, 20.0, 1.0),
+            candidate(4, 0.0, 50.0),
+        ];
+
+        let clock = ManualClock::new();
+        let interval = Duration::from_secs(30);
+
+        let unchoked = TitForTat::new(3, interval).rechoke(&candidates, false, clock.now());
+        assert_eq!(3, unchoked.len());
+        assert_eq!(&[candidates[1].addr, candidates[2].addr], &unchoked[..2]);
+        assert!([candidates[0].addr, candidates[3].addr].contains(&unchoked[2]));
+
+        let unchoked = SeedMode::new(2, interval).rechoke(&candidates, false, clock.now());
+        assert_eq!(2, unchoked.len());
+        assert_eq!(candidates[3].addr, unchoked[0]);
+
+        assert!(TitForTat::new(0, interval)
+            .rechoke(&candidates, false, clock.now())
+            .is_empty());
+    }
+
+    #[test]
+    fn optimistic_unchoke_test() {
+        let candidates: Vec<Candidate> = (1..=10).map(|port| candidate(port, 0.0, 0.0)).collect();
+        let clock = ManualClock::new();
+        let mut choker = TitForTat::new(1, Duration::from_secs(30));
+
+        let optimistic = choker.rechoke(&candidates, false, clock.now());
+        assert_eq!(1, optimistic.len());
+
+        clock.advance(Duration::from_secs(29));
+        assert_eq!(optimistic, choker.rechoke(&candidates, false, clock.now()));
+
+        // The old optimistic unchoke may be chosen again, but the choice is made anew.
+        clock.advance(Duration::from_secs(1));
+        choker.rechoke(&candidates, false, clock.now());
+        assert_eq!(Some(clock.now()), choker.optimistic.map(|(_, since)| since));
+    }
+
+    #[test]
+    fn optimistic_rotation_test() {
+        let clock = ManualClock::new();
+        let mut choker = TitForTat::new(1, Duration::from_secs(30));
+
+        let mut candidates = [candidate(1, 0.0, 0.0), candidate(2, 0.0, 0.0)];
+        candidates[0].am_choking = false;
+        choker.optimistic = Some((candidates[0].addr, clock.now()));
+
+        // The slot goes to the choked peer, not the one that had it.
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(
+            vec![candidates[1].addr],
+            choker.rechoke(&candidates, false, clock.now())
+        );
+
+        // Unless no one else is waiting for it.
+        clock.advance(Duration::from_secs(30));
+        let unchoked = choker.rechoke(&candidates[..1], false, clock.now());
+        assert_eq!(vec![candidates[0].addr], unchoked);
+    }
+
+    #[test]
+    fn optimistic_weight_test() {
+        let clock = ManualClock::new();
+        let mut peer = candidate(1, 0.0, 0.0);
+        peer.connected_at = clock.now();
+
+        assert_eq!(NEW_PEER_WEIGHT, optimistic_weight(&peer, clock.now()));
+
+        clock.advance(NEW_PEER_AGE);
+        assert_eq!(1, optimistic_weight(&peer, clock.now()));
+    }
+}
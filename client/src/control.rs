@@ -0,0 +1,88 @@
+//! Commands that control the client while it runs. For now they are read line by line from
+//! standard input.
+
+use std::str::FromStr;
+
+use tokio::io::{self, AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc;
+
+use toytorrent_common as common;
+
+use super::status;
+
+const USAGE: &str = "Unknown command; expected `standby`, `resume`, `pause <info hash>`, \
+                     `unpause <info hash>`, `retry <info hash>`, `remove <info hash>` or \
+                     `remove-with-data <info hash>`";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Command {
+    /// Stop all network activity without forgetting anything about the torrents.
+    Standby,
+
+    /// Undo `Standby`.
+    Resume,
+
+    /// Pause or unpause a torrent, or resume it after a storage error once that's been dealt with.
+    Apply {
+        info_hash: common::InfoHash,
+        action: status::Action,
+    },
+
+    /// Take a torrent out of the session, deleting its files too with `delete_files`.
+    Remove {
+        info_hash: common::InfoHash,
+        delete_files: bool,
+    },
+}
+
+impl FromStr for Command {
+    type Err = &'static str;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let mut words = input.split_whitespace();
+
+        match (words.next(), words.next(), words.next()) {
+            (Some("standby"), None, _) => Ok(Self::Standby),
+            (Some("resume"), None, _) => Ok(Self::Resume),
+            (Some(command @ ("pause" | "unpause" | "retry")), Some(info_hash), None) => {
+                let info_hash =
+                    common::InfoHash::from_hex(info_hash).ok_or("Expected an info hash in hex")?;
+
+                let action = match command {
+                    "pause" => status::Action::Pause,
+                    "unpause" => status::Action::Unpause,
+                    _ => status::Action::Retry,
+                };
+
+                Ok(Self::Apply { info_hash, action })
+            }
+            (Some(command @ ("remove" | "remove-with-data")), Some(info_hash), None) => {
+                Ok(Self::Remove {
+                    info_hash: common::InfoHash::from_hex(info_hash)
+                        .ok_or("Expected an info hash in hex")?,
+                    delete_files: command == "remove-with-data",
+                })
+            }
+            _ => Err(USAGE),
+        }
+    }
+}
+
+pub async fn read_stdin(sender: mpsc::Sender<super::Incoming>) {
+    let mut lines = BufReader::new(io::stdin()).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match line.parse::<Command>() {
+            Ok(command) => {
+                if sender.send(command.into()).await.is_err() {
+                    return;
+                }
+            }
+            Err(e) => say!("{}", e),
+        }
+    }
+}
@@ -0,0 +1,44 @@
+//! Free space on the filesystems that torrents are saved to.
+
+use std::io;
+use std::path::Path;
+
+/// Downloads are paused when free space drops below this, and resumed once there's twice as much.
+pub const RESERVE: u64 = 64 * 1024 * 1024;
+
+/// The space available to us on the filesystem that holds `path`, which must exist.
+#[cfg(unix)]
+pub fn available(path: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    // SAFETY: `statvfs` is plain old data, for which all zeroes is a valid value.
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    // SAFETY: `path` is a valid C string and `stat` is valid for writes for the whole call.
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn available(_path: &Path) -> io::Result<u64> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// The space available for a download into `dir`, which may not exist yet, in which case the
+/// closest directory above it that does is checked.
+pub fn available_for(dir: &Path) -> io::Result<u64> {
+    let existing = dir
+        .ancestors()
+        .find(|dir| dir.is_dir())
+        .unwrap_or(Path::new("."));
+
+    available(existing)
+}
@@ -0,0 +1,334 @@
+//! Watching RSS and Atom feeds for torrents to add. Each feed is polled on an interval, and the
+//! `.torrent` files of items whose titles pass the feed's filters are downloaded and added to the
+//! session.
+//!
+//! Feeds are listed in a plain text file, one `key value` pair per line. `url` starts a new feed,
+//! and the keys after it apply to that feed:
+//!
+//! ```text
+//! # Comments start with `#`.
+//! url https://example.com/releases.rss
+//! include (?i)debian-\d+.*amd64
+//! exclude (?i)\bnetinst\b
+//! download-dir /srv/debian
+//! ```
+//!
+//! `include` and `exclude` are regular expressions and may be given more than once. An item is
+//! added if its title matches any `include` (or there are none) and no `exclude`.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use regex::Regex;
+use tokio::sync::{mpsc, watch};
+use tokio::time;
+
+use toytorrent_common as common;
+
+/// The largest feed or `.torrent` file to download.
+const MAX_RESPONSE: u64 = 8 * 1024 * 1024;
+
+const TORRENT_MIME_TYPE: &str = "application/x-bittorrent";
+
+/// A torrent found in a feed, to be added to the session.
+pub struct Incoming {
+    pub metainfo: Box<common::metainfo::MetainfoFile>,
+    pub download_dir: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Feed {
+    url: String,
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+
+    /// Where to save the torrents added from this feed, if not the default.
+    download_dir: Option<PathBuf>,
+}
+
+/// An item of a feed, with the link to its torrent.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Item {
+    title: String,
+    link: String,
+}
+
+impl Feed {
+    /// Read the list of feeds at `path`.
+    pub fn load(path: &Path) -> Result<Vec<Self>, common::Error> {
+        let config = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::parse_config(&config)
+    }
+
+    fn parse_config(config: &str) -> Result<Vec<Self>, common::Error> {
+        let mut feeds: Vec<Self> = Vec::new();
+
+        for (i, line) in config.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once(char::is_whitespace)
+                .map(|(key, value)| (key, value.trim()))
+                .ok_or_else(|| format!("Line {}: expected a key and a value", i + 1))?;
+
+            if key == "url" {
+                feeds.push(Self {
+                    url: value.to_string(),
+                    include: Vec::new(),
+                    exclude: Vec::new(),
+                    download_dir: None,
+                });
+                continue;
+            }
+
+            let feed = feeds
+                .last_mut()
+                .ok_or_else(|| format!("Line {}: `{}` must follow a `url`", i + 1, key))?;
+
+            let regex = || Regex::new(value).map_err(|e| format!("Line {}: {}", i + 1, e));
+
+            match key {
+                "include" => feed.include.push(regex()?),
+                "exclude" => feed.exclude.push(regex()?),
+                "download-dir" => feed.download_dir = Some(value.into()),
+                _ => return Err(format!("Line {}: unknown key `{}`", i + 1, key).into()),
+            }
+        }
+
+        Ok(feeds)
+    }
+
+    fn matches(&self, title: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|regex| regex.is_match(title)))
+            && !self.exclude.iter().any(|regex| regex.is_match(title))
+    }
+}
+
+/// Poll `feeds` every `interval`, sending each new matching torrent to the session. The feeds are
+/// read again from `path` on every `reload`, without forgetting the items already seen.
+pub async fn watch(
+    path: PathBuf,
+    mut feeds: Vec<Feed>,
+    mut reload: watch::Receiver<()>,
+    client: reqwest::Client,
+    interval: Duration,
+    sender: mpsc::Sender<super::Incoming>,
+) {
+    // Links that have been dealt with, so that an item isn't added again on every poll. Failed
+    // downloads are left out to be tried again.
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut tick = time::interval(interval);
+
+    loop {
+        tokio::select! {
+            _ = tick.tick() => {}
+            Ok(()) = reload.changed() => {
+                // A broken file leaves the feeds as they were rather than stop watching them.
+                match Feed::load(&path) {
+                    Ok(reloaded) => {
+                        say!("Watching {} feeds from {}", reloaded.len(), path.display());
+                        feeds = reloaded;
+                        tick.reset_immediately();
+                    }
+                    Err(e) => say!("Not reloading feeds: {}", e),
+                }
+                continue;
+            }
+        }
+
+        for feed in feeds.iter() {
+            let items = fetch(&client, &feed.url)
+                .await
+                .and_then(|body| parse_items(&String::from_utf8_lossy(&body)));
+
+            let items = match items {
+                Ok(items) => items,
+                Err(e) => {
+                    say!("Unable to read feed {}: {}", feed.url, e);
+                    continue;
+                }
+            };
+
+            for item in items {
+                if seen.contains(&item.link) || !feed.matches(&item.title) {
+                    continue;
+                }
+
+                if item.link.starts_with("magnet:") {
+                    say!(
+                        "Skipping {}: magnet links are not supported yet",
+                        item.title
+                    );
+                    seen.insert(item.link);
+                    continue;
+                }
+
+                let metainfo = fetch(&client, &item.link)
+                    .await
+                    .and_then(|body| common::metainfo::MetainfoFile::try_from(&body[..]));
+
+                let metainfo = match metainfo {
+                    Ok(metainfo) => metainfo,
+                    Err(e) => {
+                        say!("Unable to fetch {} from {}: {}", item.title, item.link, e);
+                        continue;
+                    }
+                };
+
+                say!("Found {} in {}", item.title, feed.url);
+                seen.insert(item.link);
+
+                let incoming = Incoming {
+                    metainfo: Box::new(metainfo),
+                    download_dir: feed.download_dir.clone(),
+                };
+
+                if sender.send(incoming.into()).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+pub async fn fetch(client: &reqwest::Client, url: &str) -> Result<Vec<u8>, common::Error> {
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|e| e.to_string())?;
+
+    super::tracker::read_body(response, MAX_RESPONSE.into())
+        .await
+        .map_err(|e| e.to_string().into())
+}
+
+/// The items of an RSS or Atom feed that link to a torrent.
+fn parse_items(xml: &str) -> Result<Vec<Item>, common::Error> {
+    let document = roxmltree::Document::parse(xml).map_err(|e| e.to_string())?;
+
+    Ok(document
+        .descendants()
+        .filter(|node| matches!(node.tag_name().name(), "item" | "entry"))
+        .filter_map(|node| {
+            Some(Item {
+                title: children(node, "title").next()?.text()?.trim().to_string(),
+                link: item_link(node)?.trim().to_string(),
+            })
+        })
+        .collect())
+}
+
+/// The link to an item's torrent: an RSS enclosure, an Atom enclosure or torrent link, or failing
+/// those the item's own link.
+fn item_link<'a>(item: roxmltree::Node<'a, '_>) -> Option<&'a str> {
+    children(item, "enclosure")
+        .find_map(|enclosure| enclosure.attribute("url"))
+        .or_else(|| {
+            children(item, "link")
+                .filter(|link| {
+                    link.attribute("rel") == Some("enclosure")
+                        || link.attribute("type") == Some(TORRENT_MIME_TYPE)
+                })
+                .find_map(|link| link.attribute("href"))
+        })
+        .or_else(|| children(item, "link").find_map(|link| link.attribute("href").or(link.text())))
+}
+
+fn children<'a, 'input>(
+    node: roxmltree::Node<'a, 'input>,
+    name: &'static str,
+) -> impl Iterator<Item = roxmltree::Node<'a, 'input>> {
+    node.children()
+        .filter(move |child| child.is_element() && child.tag_name().name() == name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_config_test() {
+        let feeds = Feed::parse_config(
+            "# Linux\n\
+             url https://example.com/a.rss\n\
+             include (?i)debian\n\
+             exclude netinst\n\
+             download-dir /srv/debian\n\
+             \n\
+             url https://example.com/b.xml\n",
+        )
+        .unwrap();
+
+        assert_eq!(2, feeds.len());
+        assert_eq!(Some(PathBuf::from("/srv/debian")), feeds[0].download_dir);
+        assert!(feeds[0].matches("Debian 12.5 DVD"));
+        assert!(!feeds[0].matches("debian 12.5 netinst"));
+        assert!(!feeds[0].matches("Ubuntu 24.04"));
+        assert!(feeds[1].matches("Ubuntu 24.04"));
+
+        assert!(Feed::parse_config("include debian\n").is_err());
+        assert!(Feed::parse_config("url https://example.com\ninclude (\n").is_err());
+        assert!(Feed::parse_config("url https://example.com\ncolour blue\n").is_err());
+    }
+
+    #[test]
+    fn parse_items_test() {
+        let rss = r#"<?xml version="1.0"?>
+            <rss version="2.0"><channel>
+                <title>Releases</title>
+                <item>
+                    <title><![CDATA[ Debian 12.5 ]]></title>
+                    <link>https://example.com/debian</link>
+                    <enclosure url="https://example.com/debian.torrent"/>
+                </item>
+                <item>
+                    <title>Ubuntu 24.04</title>
+                    <link>magnet:?xt=urn:btih:0000000000000000000000000000000000000000</link>
+                </item>
+                <item><link>https://example.com/untitled.torrent</link></item>
+            </channel></rss>"#;
+
+        assert_eq!(
+            vec![
+                Item {
+                    title: "Debian 12.5".to_string(),
+                    link: "https://example.com/debian.torrent".to_string(),
+                },
+                Item {
+                    title: "Ubuntu 24.04".to_string(),
+                    link: "magnet:?xt=urn:btih:0000000000000000000000000000000000000000"
+                        .to_string(),
+                },
+            ],
+            parse_items(rss).unwrap(),
+        );
+
+        let atom = r#"<?xml version="1.0"?>
+            <feed xmlns="http://www.w3.org/2005/Atom">
+                <title>Releases</title>
+                <entry>
+                    <title>Fedora 40</title>
+                    <link href="https://example.com/fedora"/>
+                    <link rel="enclosure" href="https://example.com/fedora.torrent"/>
+                </entry>
+            </feed>"#;
+
+        assert_eq!(
+            vec![Item {
+                title: "Fedora 40".to_string(),
+                link: "https://example.com/fedora.torrent".to_string(),
+            }],
+            parse_items(atom).unwrap(),
+        );
+
+        assert!(parse_items("<rss>").is_err());
+    }
+}
@@ -0,0 +1,158 @@
+//! A torrent's files on disk, under its download directory.
+
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use toytorrent_common as common;
+
+/// Where the torrent's files go under `download_dir`. Fails if the metainfo names a path that
+/// would end up anywhere else, for instance with a `..` component.
+pub fn paths(
+    download_dir: &Path,
+    info: &common::metainfo::Info,
+) -> Result<Vec<PathBuf>, common::Error> {
+    info.file_spans()
+        .iter()
+        .map(|span| {
+            let mut path = download_dir.to_path_buf();
+
+            for part in span.path.iter() {
+                let mut components = Path::new(part).components();
+
+                match (components.next(), components.next()) {
+                    (Some(Component::Normal(_)), None) => path.push(part),
+                    _ => return Err(format!("Unsafe path component `{}`", part).into()),
+                }
+            }
+
+            Ok(path)
+        })
+        .collect()
+}
+
+/// Delete the torrent's files from `download_dir`, along with the directories of a multi-file
+/// torrent that are left empty. Symlinks are deleted rather than followed, and nothing is deleted
+/// from a directory that a symlink leads outside `download_dir`. Returns the number of files
+/// deleted.
+pub fn delete(download_dir: &Path, info: &common::metainfo::Info) -> Result<usize, common::Error> {
+    let root = download_dir
+        .canonicalize()
+        .map_err(|e| format!("{}: {}", download_dir.display(), e))?;
+    let mut deleted = 0;
+
+    for path in paths(&root, info)? {
+        // The file can't be there if its directory isn't.
+        let Some(Ok(dir)) = path.parent().map(Path::canonicalize) else {
+            continue;
+        };
+
+        if !dir.starts_with(&root) {
+            return Err(format!("{} is outside {}", dir.display(), root.display()).into());
+        }
+
+        match fs::remove_file(&path) {
+            Ok(()) => deleted += 1,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("{}: {}", path.display(), e).into()),
+        }
+    }
+
+    if let common::metainfo::Info::MultiFile { name, .. } = info {
+        remove_empty_dirs(&root.join(name));
+    }
+
+    Ok(deleted)
+}
+
+/// Remove `dir` and the directories under it, deepest first, as long as they're empty. Symlinks
+/// aren't followed.
+fn remove_empty_dirs(dir: &Path) {
+    if !fs::symlink_metadata(dir).is_ok_and(|metadata| metadata.is_dir()) {
+        return;
+    }
+
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+                remove_empty_dirs(&entry.path());
+            }
+        }
+    }
+
+    fs::remove_dir(dir).ok();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn info(files: &[&[&str]]) -> common::metainfo::Info {
+        common::metainfo::Info::MultiFile {
+            piece_length: 16384,
+            pieces: Vec::new(),
+            name: "torrent".to_string(),
+            files: files
+                .iter()
+                .map(|path| common::metainfo::File {
+                    length: 1,
+                    md5sum: None,
+                    path: path.iter().map(|part| part.to_string()).collect(),
+                    attr: None,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn paths_test() {
+        assert_eq!(
+            Ok(vec![
+                PathBuf::from("/srv/torrent/a"),
+                PathBuf::from("/srv/torrent/b/c"),
+            ]),
+            paths(Path::new("/srv"), &info(&[&["a"], &["b", "c"]])),
+        );
+        assert!(paths(Path::new("/srv"), &info(&[&["..", "etc", "passwd"]])).is_err());
+        assert!(paths(Path::new("/srv"), &info(&[&["/etc/passwd"]])).is_err());
+        assert!(paths(Path::new("/srv"), &info(&[&["a/../../b"]])).is_err());
+        assert!(paths(Path::new("/srv"), &info(&[&[""]])).is_err());
+    }
+
+    #[test]
+    fn delete_test() {
+        let dir = std::env::temp_dir().join(format!("toytorrent-delete-{}", std::process::id()));
+        let downloads = dir.join("downloads");
+        let info = info(&[&["a"], &["b", "c"], &["d"]]);
+
+        // `d` was never downloaded, and the directories left empty go with the files.
+        fs::create_dir_all(downloads.join("torrent").join("b")).unwrap();
+        fs::write(downloads.join("torrent").join("a"), b"a").unwrap();
+        fs::write(downloads.join("torrent").join("b").join("c"), b"c").unwrap();
+
+        let deleted = delete(&downloads, &info);
+        let emptied = !downloads.join("torrent").exists();
+
+        // A directory that a symlink leads out of the download directory is left alone.
+        #[cfg(unix)]
+        let escaped = {
+            fs::create_dir_all(dir.join("outside")).unwrap();
+            fs::write(dir.join("outside").join("c"), b"c").unwrap();
+            fs::create_dir_all(downloads.join("torrent")).unwrap();
+            std::os::unix::fs::symlink(dir.join("outside"), downloads.join("torrent").join("b"))
+                .unwrap();
+
+            (
+                delete(&downloads, &info).is_err(),
+                dir.join("outside").join("c").exists(),
+            )
+        };
+
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(Ok(2), deleted);
+        assert!(emptied);
+        #[cfg(unix)]
+        assert_eq!((true, true), escaped);
+    }
+}
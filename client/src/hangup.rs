@@ -0,0 +1,31 @@
+//! Reloading configuration files on SIGHUP, as daemons conventionally do, without restarting the
+//! session and dropping its connections.
+
+use tokio::sync::watch;
+
+/// Listen for SIGHUP, which would otherwise terminate the client. The receiver is marked changed
+/// after each one; on platforms without SIGHUP, its sender is dropped at once.
+pub fn listen() -> watch::Receiver<()> {
+    let (sender, receiver) = watch::channel(());
+
+    #[cfg(unix)]
+    match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(mut hangups) => {
+            tokio::spawn(async move {
+                while hangups.recv().await.is_some() {
+                    say!("Reloading configuration");
+
+                    if sender.send(()).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+        Err(e) => say!("Unable to listen for SIGHUP: {}", e),
+    }
+
+    #[cfg(not(unix))]
+    drop(sender);
+
+    receiver
+}
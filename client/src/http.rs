@@ -0,0 +1,328 @@
+//! Just enough HTTP/1.1 for the client's local servers: one request per connection, with a body
+//! only if it has a `Content-Length`.
+
+use std::io;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// The longest request head to accept.
+const MAX_HEAD_LEN: usize = 8 * 1024;
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+
+    /// Everything after the `?` in the request target, if anything.
+    pub query: String,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Response {
+    status: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    /// The value of the first header called `name`, which is case-insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The parameters of the query string.
+    pub fn query_params(&self) -> Vec<(String, Vec<u8>)> {
+        url_decode_pairs(&self.query)
+    }
+
+    /// The fields of a form in the body, sent as `application/x-www-form-urlencoded` or
+    /// `multipart/form-data`. The value of a file field is the file's contents.
+    pub fn form(&self) -> Vec<(String, Vec<u8>)> {
+        let content_type = self.header("content-type").unwrap_or_default();
+
+        let boundary = content_type
+            .split(';')
+            .map(str::trim)
+            .find_map(|param| param.strip_prefix("boundary="))
+            .map(|boundary| boundary.trim_matches('"'));
+
+        match boundary {
+            Some(boundary) if content_type.starts_with("multipart/form-data") => {
+                multipart_fields(&self.body, boundary)
+            }
+            _ => url_decode_pairs(&String::from_utf8_lossy(&self.body)),
+        }
+    }
+
+    pub fn parse_head(head: &str) -> io::Result<Self> {
+        let mut lines = head.lines();
+        let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+
+        let (Some(method), Some(target)) = (request_line.next(), request_line.next()) else {
+            return Err(invalid_data("Invalid request line"));
+        };
+
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+        Ok(Self {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: query.to_string(),
+            headers: lines
+                .filter_map(|line| line.split_once(':'))
+                .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+                .collect(),
+            body: Vec::new(),
+        })
+    }
+}
+
+impl Response {
+    pub fn new(status: &'static str, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            headers: vec![("Content-Type", content_type.to_string())],
+            body: body.into(),
+        }
+    }
+
+    pub fn empty(status: &'static str) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    pub async fn write_to(&self, stream: &mut TcpStream) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {}\r\n", self.status);
+
+        for (name, value) in self.headers.iter() {
+            head += &format!("{}: {}\r\n", name, value);
+        }
+
+        head += &format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.body.len()
+        );
+
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(&self.body).await
+    }
+}
+
+/// Read a request, with a body of up to `max_body` bytes.
+pub async fn read_request(stream: &mut TcpStream, max_body: usize) -> io::Result<Request> {
+    let mut data = Vec::new();
+    let mut buf = [0; 1024];
+
+    let head_len = loop {
+        if let Some(end) = data.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end + 4;
+        }
+
+        if data.len() > MAX_HEAD_LEN {
+            return Err(invalid_data("Request head too long"));
+        }
+
+        let len = stream.read(&mut buf).await?;
+
+        if len == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        data.extend_from_slice(&buf[..len]);
+    };
+
+    let mut request = Request::parse_head(&String::from_utf8_lossy(&data[..head_len]))?;
+
+    let content_length = request
+        .header("content-length")
+        .map(|len| {
+            len.parse::<usize>()
+                .map_err(|_| invalid_data("Invalid Content-Length"))
+        })
+        .transpose()?
+        .unwrap_or(0);
+
+    if content_length > max_body {
+        return Err(invalid_data("Request body too long"));
+    }
+
+    let mut body = data.split_off(head_len);
+
+    while body.len() < content_length {
+        let len = stream.read(&mut buf).await?;
+
+        if len == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        body.extend_from_slice(&buf[..len]);
+    }
+
+    body.truncate(content_length);
+    request.body = body;
+
+    Ok(request)
+}
+
+/// Split `a=1&b=2` into decoded names and values.
+fn url_decode_pairs(input: &str) -> Vec<(String, Vec<u8>)> {
+    input
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let name = String::from_utf8_lossy(&url_decode(name)).into_owned();
+            (name, url_decode(value))
+        })
+        .collect()
+}
+
+fn url_decode(input: &str) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut bytes = input.bytes();
+
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'+' => output.push(b' '),
+            b'%' => {
+                let hex = [bytes.next(), bytes.next()];
+
+                match hex.map(|digit| digit.and_then(|digit| (digit as char).to_digit(16))) {
+                    [Some(a), Some(b)] => output.push((a * 16 + b) as u8),
+                    _ => {
+                        output.push(b'%');
+                        output.extend(hex.into_iter().flatten());
+                    }
+                }
+            }
+            byte => output.push(byte),
+        }
+    }
+
+    output
+}
+
+/// The fields of a `multipart/form-data` body. Parts without a name are skipped.
+fn multipart_fields(body: &[u8], boundary: &str) -> Vec<(String, Vec<u8>)> {
+    // With a line break in front, the first delimiter looks like all the others.
+    let body = [&b"\r\n"[..], body].concat();
+    let delimiter = format!("\r\n--{}", boundary);
+    let mut fields = Vec::new();
+    let mut rest = &body[..];
+
+    while let Some(start) = find(rest, delimiter.as_bytes()) {
+        rest = &rest[start + delimiter.len()..];
+
+        // The last delimiter is followed by `--`.
+        if rest.starts_with(b"--") {
+            break;
+        }
+
+        let part = &rest[..find(rest, delimiter.as_bytes()).unwrap_or(rest.len())];
+        let Some(head_end) = find(part, b"\r\n\r\n") else {
+            continue;
+        };
+
+        let head = String::from_utf8_lossy(&part[..head_end]);
+        let name = head
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .filter(|(header, _)| header.trim().eq_ignore_ascii_case("content-disposition"))
+            .flat_map(|(_, value)| value.split(';'))
+            .find_map(|param| param.trim().strip_prefix("name="))
+            .map(|name| name.trim_matches('"').to_string());
+
+        if let Some(name) = name {
+            fields.push((name, part[head_end + 4..].to_vec()));
+        }
+    }
+
+    fields
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn invalid_data(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_head_test() {
+        let request = Request::parse_head(
+            "GET /api/torrents?sort=name HTTP/1.1\r\nHost: localhost\r\nRange: bytes=0-\r\n\r\n",
+        )
+        .unwrap();
+
+        assert_eq!("GET", request.method);
+        assert_eq!("/api/torrents", request.path);
+        assert_eq!("sort=name", request.query);
+        assert_eq!(Some("bytes=0-"), request.header("range"));
+        assert_eq!(None, request.header("authorization"));
+
+        assert!(Request::parse_head("\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn form_test() {
+        let mut request = Request::parse_head(
+            "POST /api/v2/torrents/delete HTTP/1.1\r\n\
+             Content-Type: application/x-www-form-urlencoded\r\n\r\n",
+        )
+        .unwrap();
+        request.body = b"hashes=a%7Cb&deleteFiles=false&name=a+b%2".to_vec();
+
+        assert_eq!(
+            vec![
+                ("hashes".to_string(), b"a|b".to_vec()),
+                ("deleteFiles".to_string(), b"false".to_vec()),
+                ("name".to_string(), b"a b%2".to_vec()),
+            ],
+            request.form(),
+        );
+
+        let mut request = Request::parse_head(
+            "POST /api/v2/torrents/add HTTP/1.1\r\n\
+             Content-Type: multipart/form-data; boundary=\"xyz\"\r\n\r\n",
+        )
+        .unwrap();
+        request.body = b"--xyz\r\n\
+            Content-Disposition: form-data; name=\"torrents\"; filename=\"a.torrent\"\r\n\
+            Content-Type: application/x-bittorrent\r\n\r\n\
+            d4:infoe\r\n\
+            --xyz\r\n\
+            Content-Disposition: form-data; name=\"savepath\"\r\n\r\n\
+            /srv\r\n\
+            --xyz--\r\n"
+            .to_vec();
+
+        assert_eq!(
+            vec![
+                ("torrents".to_string(), b"d4:infoe".to_vec()),
+                ("savepath".to_string(), b"/srv".to_vec()),
+            ],
+            request.form(),
+        );
+    }
+}
@@ -1,51 +1,620 @@
+/// Print a line of text for humans, which goes to standard error instead if `--output json` is
+/// keeping standard output for events.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        $crate::output::text(format_args!($($arg)*))
+    };
+}
+
+mod backoff;
+mod capture;
+mod choker;
+mod control;
+mod disk;
+mod feed;
+mod files;
+mod hangup;
+mod http;
+mod json;
+mod metadata;
+#[cfg(feature = "fuse")]
+mod mount;
+mod notify;
+mod output;
 mod peer;
-mod session;
+mod picker;
+mod qbittorrent;
+mod quirks;
+mod resolver;
+mod resume;
+mod rpc;
+mod selftest;
+mod socket;
+mod status;
+mod storage;
+mod stream;
 mod tracker;
+mod ui;
+mod upload;
+mod verify;
+mod watchdog;
+mod webseed;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs;
-use std::io;
-use std::net::{IpAddr, SocketAddr};
-use std::path::PathBuf;
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use tokio::net::TcpListener;
 use tokio::sync::mpsc;
+use tokio::time;
 
 use toytorrent_common as common;
 
-const PEER_ID_CLIENT: &'static str = "tt";
-const PEER_ID_VERSION: &'static str = "0000";
-const USER_AGENT: &'static str = "ToyTorrent/0.0";
+use common::metainfo::{merkle, MerkleHash};
+use common::peer::HashRequest;
+use common::tracker::Event;
+use status::Status;
+
+pub use choker::{Candidate, Choker, SeedMode, TitForTat};
+pub use picker::{PiecePicker, RandomFirst, RarestFirst, Sequential};
+pub use storage::{ByteRange, Export, ExportStream, Files, Memory, Pipe, Storage};
+
+const PEER_ID_CLIENT: &str = "tt";
+const PEER_ID_VERSION: &str = "0000";
+const USER_AGENT: &str = "ToyTorrent/0.0";
+
+/// Blocks are requested in this size, the largest that all clients accept.
+const BLOCK_LENGTH: u64 = 16 * 1024;
+
+/// How long the choker keeps an optimistic unchoke before choosing another.
+const CHOKER_OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The most hashes to ask a peer for at once, so that the reply fits in one message buffer.
+const HASH_REQUEST_MAX_LENGTH: u32 = 256;
+
+/// The most hashes to send a peer at once. Other clients ask for up to 512.
+const HASHES_MAX_LENGTH: u32 = 512;
+
+/// The number of pieces from a stream's position on to download ahead of everything else.
+const STREAM_READAHEAD_PIECES: u32 = 8;
+
+/// How many pieces that fail verification a peer can send us blocks of before it's banned. In the
+/// endgame a piece can be put together from several peers' blocks, and every one of them is
+/// charged with the failure, as there's no telling which sent the bad block.
+const MAX_HASH_FAILURES: u32 = 3;
+
+/// Once fewer blocks than this are missing and every missing piece is being downloaded, the rest
+/// are requested from every peer that has them, so that the last one or two slow peers don't hold
+/// up the whole download.
+const ENDGAME_BLOCKS: usize = 20;
 
 /// A barebones BitTorrent client
-#[derive(Debug, Parser)]
+#[derive(Clone, Debug, Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Args {
-    /// The path to the metainfo (.torrent) file
-    file: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Metainfo (.torrent) files and magnet links to add, or `-` to read a metainfo file from
+    /// standard input, which then isn't read for commands. Without any, the torrents of the last
+    /// run are picked up again from `<info hash>.torrent` and `<info hash>.resume` in --state-dir,
+    /// which keep every torrent added along with its download directory and whether it was
+    /// paused, and the data they already have is checked against their hashes. Options for a
+    /// single torrent, such as --import, --pipe, --stream-port and --min-seeders, apply to the
+    /// first.
+    #[arg(value_name = "TORRENT")]
+    torrents: Vec<String>,
 
-    /// The port to listen on
+    /// Save the torrents given after this in this directory, up to the next --download-dir
+    #[arg(long)]
+    download_dir: Vec<PathBuf>,
+
+    /// The torrents, each with the options given for it, filled in by `Args::from_command_line`.
+    #[arg(skip)]
+    items: Vec<Item>,
+
+    /// The port to listen on, unless systemd passes in a socket to listen on
     #[arg(short, long, default_value_t = 6881)]
     port: u16,
 
-    /// The IP address to bind
+    /// The IP address to bind, unless systemd passes in a socket to listen on
     #[arg(short, long, default_value = "0.0.0.0")]
     bind: IpAddr,
+
+    /// Send small messages to peers straight away rather than coalescing them (TCP_NODELAY)
+    #[arg(long)]
+    tcp_nodelay: bool,
+
+    /// The send buffer size of peer connections, instead of the system's default
+    #[arg(long)]
+    tcp_send_buffer: Option<common::Bytes>,
+
+    /// The receive buffer size of peer connections, instead of the system's default
+    #[arg(long)]
+    tcp_recv_buffer: Option<common::Bytes>,
+
+    /// Probe idle peer connections, so that peers that vanish without closing them are noticed
+    #[arg(long)]
+    tcp_keepalive: bool,
+
+    /// Let other sockets listen on --port too (SO_REUSEPORT), such as another instance of the
+    /// client. Unix only.
+    #[arg(long)]
+    reuse_port: bool,
+
+    /// Don't start downloading unless a tracker reports at least this many seeders
+    #[arg(long, requires = "torrents")]
+    min_seeders: Option<u64>,
+
+    /// Fall back to web seeds after this many minutes without receiving data from peers
+    #[arg(long, default_value_t = 5)]
+    webseed_fallback_minutes: u64,
+
+    /// Send Have messages to every peer, even those that already have the piece. Some clients
+    /// rely on them to estimate our download rate.
+    #[arg(long)]
+    send_redundant_haves: bool,
+
+    /// Leave some pieces out of the bitfield sent to new peers and announce them with Have
+    /// messages instead, to get past ISPs that throttle connections to seeds
+    #[arg(long)]
+    lazy_bitfield: bool,
+
+    /// Stop all network activity while the bind address is unavailable (for instance when a VPN
+    /// drops), resuming once it returns. Requires --bind.
+    #[arg(long)]
+    kill_switch: bool,
+
+    /// The UDP port for the DHT, advertised to peers that support it. The DHT is disabled if this
+    /// isn't given.
+    #[arg(long)]
+    dht_port: Option<u16>,
+
+    /// Start our peer ID with this instead of the usual client and version, for interop testing.
+    /// The rest of the ID is filled with random characters.
+    #[arg(long, value_parser = common::PeerId::with_prefix)]
+    peer_id_prefix: Option<common::PeerId>,
+
+    /// The two-character client code in our peer ID, for interop testing against clients and
+    /// trackers that filter by client
+    #[arg(long, default_value = PEER_ID_CLIENT, value_parser = parse_client_id)]
+    client_id: String,
+
+    /// The four-character client version in our peer ID
+    #[arg(long, default_value = PEER_ID_VERSION, value_parser = parse_client_version)]
+    client_version: String,
+
+    /// The User-Agent to send trackers, web seeds and feeds, and the client name to send peers
+    #[arg(long, default_value = USER_AGENT, value_parser = parse_user_agent)]
+    user_agent: String,
+
+    /// The directory to keep state that should survive a restart in
+    #[arg(long, default_value = ".toytorrent")]
+    state_dir: PathBuf,
+
+    /// The order in which to download pieces
+    #[arg(long, value_enum, default_value_t = PickerKind::RandomFirst)]
+    picker: PickerKind,
+
+    /// How to choose the peers to upload to
+    #[arg(long, value_enum, default_value_t = ChokerKind::TitForTat)]
+    choker: ChokerKind,
+
+    /// The number of peers to upload to at once, including the optimistic unchoke
+    #[arg(long, default_value_t = 4)]
+    upload_slots: usize,
+
+    /// Choke a peer once we've uploaded this much to it without getting anything back, whatever
+    /// the choker says. Doesn't apply while seeding.
+    #[arg(long)]
+    leech_quota: Option<common::Bytes>,
+
+    /// Give peers a fresh leech quota after this many minutes
+    #[arg(long, default_value_t = 10, requires = "leech_quota")]
+    leech_window_minutes: u64,
+
+    /// Which peer addresses from trackers not to dial
+    #[arg(long, value_enum, default_value_t = peer::AddrFilter::Bogon)]
+    peer_filter: peer::AddrFilter,
+
+    /// The largest tracker response to accept
+    #[arg(long, default_value = "1MiB")]
+    max_tracker_response: common::Bytes,
+
+    /// Adjust the announces sent to the trackers listed in this file, for trackers that expect
+    /// something other than the spec
+    #[arg(long)]
+    tracker_quirks: Option<PathBuf>,
+
+    /// The most memory to hold in pieces that are partway downloaded and blocks that peers are
+    /// waiting on, across all torrents. Once it's used up, no more pieces are requested until
+    /// those in flight have been written out and the queued blocks sent
+    #[arg(long, default_value = "256MiB")]
+    memory_budget: common::Bytes,
+
+    /// Serve the torrent's files over HTTP on this localhost port, so that a media player can
+    /// play them while they download. Seeking is supported.
+    #[arg(long, requires = "torrents")]
+    stream_port: Option<u16>,
+
+    /// Mount the torrent's files read-only at this directory. Reading a file downloads what's read
+    /// first and waits for it.
+    #[cfg(feature = "fuse")]
+    #[arg(long, requires = "torrents")]
+    mount: Option<PathBuf>,
+
+    /// Watch the RSS and Atom feeds listed in this file, adding the torrents whose titles pass
+    /// each feed's filters. Reread on SIGHUP
+    #[arg(long)]
+    feeds: Option<PathBuf>,
+
+    /// How often to check the feeds for new torrents
+    #[arg(long, default_value_t = 15)]
+    feed_interval_minutes: u64,
+
+    /// Show a desktop notification for these events
+    #[cfg(feature = "desktop-notifications")]
+    #[arg(long, value_enum, value_delimiter = ',')]
+    desktop_notify: Vec<notify::Event>,
+
+    /// POST a JSON object describing each of --webhook-events to this URL
+    #[arg(long)]
+    webhook: Option<String>,
+
+    /// The events to POST to --webhook
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "finished,error,all-finished"
+    )]
+    webhook_events: Vec<notify::Event>,
+
+    /// Serve a JSON API and a web UI for managing the client on this port
+    #[arg(long)]
+    rpc_port: Option<u16>,
+
+    /// The IP address to bind the API to. Addresses other than localhost require --rpc-token or
+    /// --rpc-user.
+    #[arg(long, default_value = "127.0.0.1")]
+    rpc_bind: IpAddr,
+
+    /// Require requests to the API to carry this bearer token
+    #[arg(long, env = "TOYTORRENT_RPC_TOKEN", hide_env_values = true)]
+    rpc_token: Option<String>,
+
+    /// Require requests to the API to carry basic auth credentials for this user
+    #[arg(long, requires = "rpc_password")]
+    rpc_user: Option<String>,
+
+    /// The password of --rpc-user
+    #[arg(
+        long,
+        env = "TOYTORRENT_RPC_PASSWORD",
+        hide_env_values = true,
+        requires = "rpc_user"
+    )]
+    rpc_password: Option<String>,
+
+    /// Let web pages from this origin use the API, for instance a web UI hosted elsewhere. May be
+    /// given more than once, and `*` allows any origin.
+    #[arg(long)]
+    rpc_cors_origin: Vec<String>,
+
+    /// How to print progress and other events
+    #[arg(long, value_enum, default_value_t = output::Format::Text)]
+    output: output::Format,
+
+    /// Record every message exchanged with peers, piece data included, to this file for
+    /// `decode-capture` to print
+    #[arg(long)]
+    capture: Option<PathBuf>,
+
+    /// Dump the bytes exchanged with peers to standard error: the `lengths` of the reads and
+    /// writes, their `head` or their `full` contents
+    #[arg(long)]
+    debug_wire: Option<common::DebugVerbosity>,
+
+    /// Only dump the connections with peers at this IP address, or whose peer IDs start with this,
+    /// such as `-qB`. May be given more than once.
+    #[arg(long, requires = "debug_wire")]
+    debug_wire_peer: Vec<peer::PeerFilter>,
+
+    /// Refuse to add torrents that don't fit in the free space where they're saved, rather than
+    /// only warning
+    #[arg(long)]
+    require_space: bool,
+
+    /// Seed files that are already on disk: the torrent's files are read from this directory, laid
+    /// out as in the torrent, and the pieces that pass verification are seeded right away. Missing
+    /// pieces are downloaded into the same files.
+    #[arg(long, requires = "torrents", conflicts_with = "pipe")]
+    import: Option<PathBuf>,
+
+    /// Write the torrent's data to standard output in order, for piping into another program.
+    /// Other output goes to standard error. Best with --picker sequential.
+    #[arg(long, requires = "torrents", conflicts_with_all = ["output", "stream_port"])]
+    pipe: bool,
+}
+
+/// A torrent given on the command line, with the options that apply to it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Item {
+    source: Source,
+    download_dir: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Source {
+    File(PathBuf),
+    Stdin,
+    Magnet(String),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Self::File(path) => write!(f, "{}", path.display()),
+            Self::Stdin => write!(f, "standard input"),
+            Self::Magnet(uri) => write!(f, "{}", uri),
+        }
+    }
+}
+
+impl Args {
+    /// Parse the command line, pairing each torrent with the --download-dir given before it.
+    pub fn from_command_line() -> Self {
+        let matches = Self::command().get_matches();
+        let mut args = Self::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+        args.items = items(&matches);
+        args
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum PickerKind {
+    /// Rarest pieces first
+    RarestFirst,
+
+    /// Pieces in order, for streaming
+    Sequential,
+
+    /// A few random pieces, then rarest first
+    RandomFirst,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum ChokerKind {
+    /// Peers that upload to us fastest, or that we upload to fastest once seeding
+    TitForTat,
+
+    /// Peers that we upload to fastest
+    SeedMode,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum Command {
+    /// Print information about a metainfo file and the health of its swarm without downloading
+    Show {
+        /// The path to the metainfo (.torrent) file
+        file: PathBuf,
+    },
+
+    /// Transfer a generated payload between an internal seeder and leecher over localhost and
+    /// report whether each stage of the download works
+    SelfTest,
+
+    /// Print the peer messages recorded with --capture
+    DecodeCapture {
+        /// The capture file
+        file: PathBuf,
+
+        /// Follow the messages with how many of each kind were sent and received
+        #[arg(long)]
+        summary: bool,
+    },
+
+    /// Check downloaded data against a metainfo file's hashes without joining the swarm. Exits
+    /// with an error unless the torrent is complete
+    Verify {
+        /// The path to the metainfo (.torrent) file
+        file: PathBuf,
+
+        /// The directory the torrent was downloaded into, which holds its file or directory
+        data: PathBuf,
+
+        /// Print whether each piece is valid, not just each file
+        #[arg(long)]
+        pieces: bool,
+    },
+}
+
+/// Whether the client may use the network at all, independent of any one torrent.
+#[derive(Debug)]
+struct Network {
+    interface_available: bool,
+    standby: bool,
 }
 
-#[derive(Debug, Default)]
-struct Torrents(HashMap<common::InfoHash, Torrent>);
+impl Network {
+    fn is_enabled(&self) -> bool {
+        self.interface_available && !self.standby
+    }
+}
+
+#[derive(Debug)]
+struct Torrents<P, C>(HashMap<common::InfoHash, Torrent<P, C>>);
 
 #[derive(Debug)]
-struct Torrent {
+struct Torrent<P, C> {
     metainfo: common::metainfo::MetainfoFile,
-    peers: HashMap<common::PeerId, peer::Peer>,
     peer_connections: HashMap<SocketAddr, common::PeerId>,
+    have: common::Bitfield,
+    webseed_fallback: webseed::Fallback,
+    key: common::PeerKey,
+    picker: P,
+    choker: C,
+
+    /// Applied on top of the choker, if set.
+    upload_quota: Option<peer::UploadQuota>,
+
+    /// Peer addresses that have been dialled.
+    candidates: peer::Candidates,
+
+    /// Pieces being downloaded from peers.
+    downloading: HashMap<u32, PieceBuffer>,
+
+    /// Piece layers of v2 files being fetched from peers, by `pieces root`.
+    partial_layers: HashMap<MerkleHash, Vec<Option<MerkleHash>>>,
+
+    /// Where the data of the pieces we have goes: the torrent's files under its download
+    /// directory, unless something else was given to run_session. It's only missing while it's
+    /// in use on the blocking thread pool, or if the files couldn't be laid out.
+    storage: Option<Box<dyn Storage>>,
+
+    /// Pieces that the stream server is waiting on, to download before any others.
+    streaming: Vec<u32>,
+
+    /// Where to save the torrent's files, if not the default.
+    download_dir: Option<PathBuf>,
+
+    /// Whether downloading is paused for want of disk space.
+    disk_full: bool,
+
+    /// The storage error that paused the torrent, until it's retried.
+    error: Option<String>,
+
+    /// Whether the user paused the torrent.
+    paused: bool,
+
+    /// Whether the torrent's data on disk is being hashed.
+    checking: bool,
+
+    /// When to announce to each of the torrent's trackers.
+    trackers: tracker::Schedule,
+
+    /// The bytes uploaded to and downloaded from peers and web seeds this session, as reported to
+    /// trackers.
+    uploaded: u64,
+    downloaded: u64,
+}
+
+/// A piece being assembled from blocks, to be verified once complete.
+#[derive(Debug)]
+struct PieceBuffer {
+    /// The peer that sent each of the piece's blocks that have arrived. In the endgame, a block
+    /// can arrive more than once, and the first to arrive is kept.
+    received: Vec<Option<SocketAddr>>,
+    data: Vec<u8>,
+}
+
+impl PieceBuffer {
+    fn new(length: u64) -> Self {
+        Self {
+            received: vec![None; length.div_ceil(BLOCK_LENGTH) as usize],
+            data: vec![0; length as usize],
+        }
+    }
+
+    /// Copy a block from the peer at `from` into the piece. Returns false if it's already arrived,
+    /// or if it isn't one of the blocks that we request.
+    fn receive(&mut self, block: &common::BlockRef, data: &[u8], from: SocketAddr) -> bool {
+        let begin = u64::from(block.begin());
+        let length = self.data.len() as u64;
+        let n = (begin / BLOCK_LENGTH) as usize;
+
+        if begin % BLOCK_LENGTH != 0
+            || begin >= length
+            || data.len() as u64 != BLOCK_LENGTH.min(length - begin)
+            || self.received[n].is_some()
+        {
+            return false;
+        }
+
+        self.data[begin as usize..begin as usize + data.len()].copy_from_slice(data);
+        self.received[n] = Some(from);
+        true
+    }
+
+    /// The peers that sent the blocks that have arrived.
+    fn senders(&self) -> HashSet<SocketAddr> {
+        self.received.iter().flatten().copied().collect()
+    }
+
+    /// The blocks of the piece with `index` that haven't arrived yet.
+    fn missing(&self, index: u32) -> impl Iterator<Item = common::BlockRef> + '_ {
+        let length = self.data.len() as u64;
+
+        (0..)
+            .zip(&self.received)
+            .filter(|(_, received)| received.is_none())
+            .map(move |(n, _)| {
+                let begin = n * BLOCK_LENGTH;
+                common::BlockRef::new(index, begin as u32, BLOCK_LENGTH.min(length - begin) as u32)
+            })
+    }
+}
+
+/// The memory held by pieces being downloaded and blocks queued to upload, across all torrents
+/// and peers, and how much may be.
+#[derive(Clone, Copy, Debug)]
+struct MemoryBudget {
+    limit: u64,
+    used: u64,
+}
+
+impl MemoryBudget {
+    fn new<P, C>(
+        limit: common::Bytes,
+        torrents: &Torrents<P, C>,
+        connections: &HashMap<SocketAddr, peer::Peer>,
+    ) -> Self {
+        let downloading: u64 = torrents
+            .values()
+            .flat_map(|torrent| torrent.downloading.values())
+            .map(|buffer| buffer.data.len() as u64)
+            .sum();
+
+        // Each block a peer has asked for is read into memory to be sent when its turn comes.
+        let uploading: u64 = connections
+            .values()
+            .flat_map(|peer| peer.peer_requesting.iter())
+            .map(|block| u64::from(block.length()))
+            .sum();
+
+        Self {
+            limit: limit.into(),
+            used: downloading + uploading,
+        }
+    }
+
+    /// Take `len` bytes of the budget if there's room. A piece can always be taken while none are
+    /// held, so that a budget smaller than a piece slows downloads rather than stopping them.
+    fn take(&mut self, len: u64) -> bool {
+        if self.used > 0 && self.used + len > self.limit {
+            return false;
+        }
+
+        self.used += len;
+        true
+    }
 }
 
 enum Incoming {
     Tracker(tracker::Incoming),
     Peer(peer::Incoming),
+    WebSeed(webseed::Incoming),
+    Stream(stream::Incoming),
+    Feed(feed::Incoming),
+    Rpc(rpc::Incoming),
+    Interface(watchdog::Incoming),
+    Control(control::Command),
     IoError(io::Error),
 }
 
@@ -61,6 +630,42 @@ impl From<peer::Incoming> for Incoming {
     }
 }
 
+impl From<webseed::Incoming> for Incoming {
+    fn from(input: webseed::Incoming) -> Self {
+        Self::WebSeed(input)
+    }
+}
+
+impl From<watchdog::Incoming> for Incoming {
+    fn from(input: watchdog::Incoming) -> Self {
+        Self::Interface(input)
+    }
+}
+
+impl From<stream::Incoming> for Incoming {
+    fn from(input: stream::Incoming) -> Self {
+        Self::Stream(input)
+    }
+}
+
+impl From<feed::Incoming> for Incoming {
+    fn from(input: feed::Incoming) -> Self {
+        Self::Feed(input)
+    }
+}
+
+impl From<rpc::Incoming> for Incoming {
+    fn from(input: rpc::Incoming) -> Self {
+        Self::Rpc(input)
+    }
+}
+
+impl From<control::Command> for Incoming {
+    fn from(input: control::Command) -> Self {
+        Self::Control(input)
+    }
+}
+
 impl From<io::Error> for Incoming {
     fn from(input: io::Error) -> Self {
         Self::IoError(input)
@@ -68,57 +673,2865 @@ impl From<io::Error> for Incoming {
 }
 
 pub async fn run(args: Args) {
-    let metainfo: common::metainfo::MetainfoFile =
-        fs::read(&args.file).unwrap().as_slice().try_into().unwrap();
+    output::init(args.output);
 
-    let mut torrents: Torrents = Torrents::default();
-    torrents.0.insert(
-        *metainfo.info_hash(),
-        Torrent {
-            metainfo,
-            peers: HashMap::new(),
-            peer_connections: HashMap::new(),
-        },
-    );
+    if args.pipe {
+        output::take_stdout();
+    }
+
+    let resolver = resolver::Resolver::default();
+    let http_client = tracker::http_client(resolver.clone(), &args.user_agent);
+
+    if let Some(Command::Show { file }) = &args.command {
+        if let Err(e) = show(file, &http_client, args.max_tracker_response).await {
+            say!("{}: {}", file.display(), e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::SelfTest) = &args.command {
+        if !selftest::run().await {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::DecodeCapture { file, summary }) = &args.command {
+        if let Err(e) = capture::decode(file, *summary) {
+            say!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Verify { file, data, pieces }) = &args.command {
+        match verify::run(file, data, *pieces) {
+            Ok(true) => return,
+            Ok(false) => {}
+            Err(e) => say!("{}", e),
+        }
+        std::process::exit(1);
+    }
+
+    if let Some(path) = &args.capture {
+        if let Err(e) = capture::start(path) {
+            say!("Unable to capture to {}: {}", path.display(), e);
+            return;
+        }
+    }
+
+    if let Some(verbosity) = args.debug_wire {
+        peer::init_debug(verbosity, args.debug_wire_peer.clone());
+    }
+
+    // Buffers larger than the system allows are capped by it anyway.
+    let buffer_size = |size: common::Bytes| u32::try_from(u64::from(size)).unwrap_or(u32::MAX);
+
+    socket::init(socket::Options {
+        nodelay: args.tcp_nodelay,
+        send_buffer_size: args.tcp_send_buffer.map(buffer_size),
+        recv_buffer_size: args.tcp_recv_buffer.map(buffer_size),
+        keepalive: args.tcp_keepalive,
+        reuse_port: args.reuse_port,
+    });
+
+    match args.picker {
+        PickerKind::RarestFirst => run_with_picker(args, RarestFirst, http_client).await,
+        PickerKind::Sequential => run_with_picker(args, Sequential, http_client).await,
+        PickerKind::RandomFirst => run_with_picker(args, RandomFirst::default(), http_client).await,
+    }
+}
+
+async fn run_with_picker<P: PiecePicker + Clone>(
+    args: Args,
+    picker: P,
+    http_client: reqwest::Client,
+) {
+    let slots = args.upload_slots;
+    let clock = common::SystemClock;
+    let storage = args
+        .pipe
+        .then(|| Box::new(Pipe::new(io::stdout())) as Box<dyn Storage>);
+
+    match args.choker {
+        ChokerKind::TitForTat => {
+            let choker = TitForTat::new(slots, CHOKER_OPTIMISTIC_INTERVAL);
+            run_session(args, picker, choker, storage, http_client, &clock).await
+        }
+        ChokerKind::SeedMode => {
+            let choker = SeedMode::new(slots, CHOKER_OPTIMISTIC_INTERVAL);
+            run_session(args, picker, choker, storage, http_client, &clock).await
+        }
+    }
+}
+
+/// Download a torrent, choosing pieces with `picker` and peers to upload to with `choker`, and
+/// taking the time from `clock`. The torrent's pieces go to `storage` if given. Trackers and web
+/// seeds are reached with `http_client`, which caches DNS lookups for the whole run.
+pub async fn run_session<P: PiecePicker + Clone, C: Choker + Clone>(
+    args: Args,
+    picker: P,
+    choker: C,
+    storage: Option<Box<dyn Storage>>,
+    http_client: reqwest::Client,
+    clock: &dyn common::Clock,
+) {
+    let peer_id = args
+        .peer_id_prefix
+        .unwrap_or_else(|| common::PeerId::create(&args.client_id, &args.client_version).unwrap());
+
+    let mut metainfos = Vec::new();
+
+    for item in &args.items {
+        if let Source::Magnet(uri) = &item.source {
+            let metainfo = match uri.parse() {
+                Ok(magnet) => load_magnet(&magnet, &args, peer_id, &http_client).await,
+                Err(e) => Err(e),
+            };
+
+            match metainfo {
+                Ok(metainfo) => metainfos.push((metainfo, item.download_dir.clone())),
+                Err(e) => say!("Not adding {}: {}", item.source, e),
+            }
+            continue;
+        }
+
+        match load_metainfo(&item.source) {
+            Ok(metainfo) => metainfos.push((metainfo, item.download_dir.clone())),
+            Err(e) => say!("Not adding {}: {}", item.source, e),
+        }
+    }
+
+    let metainfo = metainfos.first().map(|(metainfo, _)| metainfo);
+
+    if let (Some(metainfo), Some(min_seeders)) = (metainfo, args.min_seeders) {
+        let seeders = scrape_all(metainfo, &http_client, args.max_tracker_response)
+            .await
+            .into_iter()
+            .filter_map(|(_, result)| result.ok())
+            .map(|file| file.complete)
+            .max();
+
+        match seeders {
+            Some(seeders) if seeders >= min_seeders => {}
+            Some(seeders) => {
+                say!("Not starting: {seeders} seeders available, {min_seeders} required");
+                return;
+            }
+            None => {
+                say!("Not starting: no tracker reported the number of seeders");
+                return;
+            }
+        }
+    }
+
+    // The stream server, the mount and the storage serve the first torrent on the command line.
+    let info_hash = metainfo.map(|metainfo| *metainfo.info_hash());
+    let stream_files = metainfo.map(stream::StreamFile::all).unwrap_or_default();
+
+    let mut torrents = Torrents::new();
+    let mut storage = storage;
+
+    for (index, (metainfo, download_dir)) in metainfos.into_iter().enumerate() {
+        let mut torrent =
+            Torrent::new(metainfo, picker.clone(), choker.clone(), &args, clock.now());
+        torrent.download_dir = download_dir;
+
+        if index > 0 {
+            torrents.add(torrent, &args).await;
+            continue;
+        }
+
+        if let Some(dir) = &args.import {
+            match torrent.import(dir, clock) {
+                Ok(have) => say!(
+                    "Imported {} of {} pieces from {}",
+                    have,
+                    torrent.have.piece_count(),
+                    dir.display(),
+                ),
+                Err(e) => say!("Unable to import from {}: {}", dir.display(), e),
+            }
+        }
+
+        if storage.is_some() {
+            torrent.storage = storage.take();
+        }
+
+        torrents.add(torrent, &args).await;
+    }
+
+    if args.items.is_empty() {
+        for torrent in restore(&args, &picker, &choker, clock) {
+            torrents.add(torrent, &args).await;
+        }
+    }
 
     let mut connections: HashMap<SocketAddr, peer::Peer> = HashMap::new();
 
-    let peer_id = common::PeerId::create("tt", "0000");
+    // Peers that broke the protocol, which we won't talk to again this session.
+    let mut banned: HashSet<IpAddr> = HashSet::new();
+
     let (incoming_sender, mut incoming_receiver) = mpsc::channel::<Incoming>(100);
 
-    let listener = TcpListener::bind(SocketAddr::new(args.bind, args.port))
-        .await
-        .expect("Unable to bind to IP and port");
+    let listener = match socket::inherited().expect("Unable to use the socket from systemd") {
+        Some(listener) => listener,
+        None => socket::listen(SocketAddr::new(args.bind, args.port))
+            .expect("Unable to bind to IP and port"),
+    };
 
     let mut processes = tokio::task::JoinSet::new();
 
-    processes.spawn(peer::listen(peer_id, listener, incoming_sender));
+    let reserved = common::peer::reserved_bytes(args.dht_port.is_some());
 
-    while let Some(message) = incoming_receiver.recv().await {
-        match message {
-            Incoming::Peer(peer::Incoming {
-                from_socket_addr,
-                event,
-            }) => match event {
-                peer::IncomingEvent::HandshakeInfoHash {
+    processes.spawn(peer::listen(
+        peer_id,
+        reserved,
+        listener,
+        incoming_sender.clone(),
+    ));
+
+    if args.kill_switch {
+        if args.bind.is_unspecified() {
+            say!("--kill-switch has no effect without a specific --bind address");
+        } else {
+            processes.spawn(watchdog::watch(
+                args.bind,
+                Duration::from_secs(1),
+                incoming_sender.clone(),
+            ));
+        }
+    }
+
+    processes.spawn(control::read_stdin(incoming_sender.clone()));
+
+    let quirks = match &args.tracker_quirks {
+        Some(path) => quirks::Quirks::load(path).unwrap_or_else(|e| {
+            say!("Not applying tracker quirks: {}", e);
+            quirks::Quirks::default()
+        }),
+        None => quirks::Quirks::default(),
+    };
+
+    let (announcer, announce_receiver) = mpsc::unbounded_channel();
+
+    processes.spawn(tracker::announce(
+        incoming_sender.clone(),
+        announce_receiver,
+        peer_id,
+        args.port,
+        http_client.clone(),
+        args.max_tracker_response,
+        quirks,
+    ));
+
+    let reload = hangup::listen();
+
+    if let Some(feeds_path) = &args.feeds {
+        match feed::Feed::load(feeds_path) {
+            Ok(feeds) => {
+                processes.spawn(feed::watch(
+                    feeds_path.clone(),
+                    feeds,
+                    reload.clone(),
+                    http_client.clone(),
+                    Duration::from_secs(args.feed_interval_minutes * 60),
+                    incoming_sender.clone(),
+                ));
+            }
+            Err(e) => say!("Not watching feeds: {}", e),
+        }
+    }
+
+    if let (Some(stream_port), Some(info_hash)) = (args.stream_port, info_hash) {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), stream_port);
+
+        match TcpListener::bind(addr).await {
+            Ok(stream_listener) => {
+                stream::print_urls(addr, &stream_files);
+                processes.spawn(stream::listen(
+                    stream_listener,
                     info_hash,
-                    is_valid_sender,
-                } => {
-                    is_valid_sender
-                        .send(torrents.0.contains_key(&info_hash))
-                        .ok();
+                    stream_files,
+                    incoming_sender.clone(),
+                ));
+            }
+            Err(e) => say!("Unable to start the stream server on {}: {}", addr, e),
+        }
+    }
+
+    // Unmounted when dropped at the end of the session.
+    #[cfg(feature = "fuse")]
+    let _mount = match (
+        &args.mount,
+        info_hash.and_then(|info_hash| torrents.get(&info_hash)),
+    ) {
+        (Some(mountpoint), Some(torrent)) => {
+            match mount::mount(mountpoint, &torrent.metainfo, incoming_sender.clone()) {
+                Ok(session) => {
+                    say!("Mounted at {}", mountpoint.display());
+                    Some(session)
                 }
-                peer::IncomingEvent::Connected { peer } => {
-                    torrents.0.entry(peer.info_hash).and_modify(|torrent| {
-                        torrent.peer_connections.insert(from_socket_addr, peer_id);
-                    });
-                    connections.insert(peer.connection.addr, peer);
+                Err(e) => {
+                    say!("Unable to mount at {}: {}", mountpoint.display(), e);
+                    None
                 }
-                peer::IncomingEvent::Message { message } => todo!(),
-                peer::IncomingEvent::Closed => todo!(),
-            },
-            Incoming::Tracker(tracker::Incoming { info_hash, event }) => (),
-            Incoming::IoError(e) => println!("{:?}", e),
+            }
+        }
+        _ => None,
+    };
+
+    if let Some(rpc_port) = args.rpc_port {
+        let addr = SocketAddr::new(args.rpc_bind, rpc_port);
+        let access = rpc::Access::new(
+            args.rpc_token.as_deref(),
+            args.rpc_user.as_deref().zip(args.rpc_password.as_deref()),
+            args.rpc_cors_origin.clone(),
+        );
+
+        if !access.requires_auth() && !addr.ip().is_loopback() {
+            say!(
+                "Not starting the API on {}: --rpc-token or --rpc-user is required",
+                addr
+            );
+        } else {
+            match TcpListener::bind(addr).await {
+                Ok(rpc_listener) => {
+                    say!("Serving the web UI and API at http://{}/", addr);
+                    processes.spawn(rpc::listen(
+                        rpc_listener,
+                        access,
+                        http_client.clone(),
+                        incoming_sender.clone(),
+                    ));
+                }
+                Err(e) => say!("Unable to start the API on {}: {}", addr, e),
+            }
         }
     }
+
+    #[cfg(feature = "desktop-notifications")]
+    let desktop_events = args.desktop_notify.clone();
+    #[cfg(not(feature = "desktop-notifications"))]
+    let desktop_events = Vec::new();
+
+    let notifier = notify::Notifier::new(
+        desktop_events,
+        args.webhook.clone(),
+        args.webhook_events.clone(),
+        http_client.clone(),
+    );
+
+    let mut network = Network {
+        interface_available: true,
+        standby: false,
+    };
+
+    let mut uploads = upload::Scheduler::default();
+    let mut tick = time::interval(Duration::from_secs(10));
+
+    loop {
+        let message = tokio::select! {
+            message = incoming_receiver.recv() => match message {
+                Some(message) => message,
+                None => break,
+            },
+            _ = tick.tick() => {
+                let now = clock.now();
+
+                if network.is_enabled() {
+                    for torrent in torrents.values_mut() {
+                        start_webseed_fallback(torrent, now, &incoming_sender, &http_client);
+
+                        if !torrent.is_paused() {
+                            for (url, event) in torrent.trackers.due(now) {
+                                send_announce(torrent, url, event, &announcer);
+                            }
+                        }
+                    }
+                }
+
+                connections.values_mut().for_each(|peer| peer.stats.update(now));
+                torrents.values_mut().for_each(Torrent::update_disk_full);
+
+                for torrent in torrents.values_mut() {
+                    torrent.check_files().await;
+                }
+
+                for torrent in torrents.values_mut() {
+                    rechoke(torrent, &mut connections, now).await;
+                }
+
+                // Peers left idle while the memory budget was used up or the torrent was paused.
+                let idle: Vec<SocketAddr> = connections
+                    .values()
+                    .filter(|peer| !peer.peer_choking && peer.am_requesting.is_empty())
+                    .map(|peer| peer.connection.addr)
+                    .collect();
+                let mut budget = MemoryBudget::new(args.memory_budget, &torrents, &connections);
+
+                for addr in idle {
+                    request_from(&mut torrents, &mut connections, &addr, &mut budget, now).await;
+                }
+
+                // Requests that are still queued, such as from peers that have just been unchoked.
+                let queued: usize = connections
+                    .values()
+                    .map(|peer| peer.peer_requesting.len())
+                    .sum();
+
+                for _ in 0..queued {
+                    if !serve_block(&mut torrents, &mut connections, &mut uploads).await {
+                        break;
+                    }
+                }
+
+                print_status(&torrents, &connections, now);
+
+                continue;
+            }
+        };
+
+        match message {
+            Incoming::Peer(peer::Incoming {
+                from_socket_addr,
+                event,
+            }) => match event {
+                peer::IncomingEvent::HandshakeInfoHash {
+                    info_hash,
+                    is_valid_sender,
+                } => {
+                    let is_valid = network.is_enabled()
+                        && torrents.contains(&info_hash)
+                        && !banned.contains(&from_socket_addr.ip());
+                    is_valid_sender.send(is_valid).ok();
+                }
+                peer::IncomingEvent::Connected { mut peer } => {
+                    if !network.is_enabled() {
+                        peer.close(peer::CloseReason::Local);
+                        continue;
+                    }
+
+                    if banned.contains(&from_socket_addr.ip()) {
+                        peer.close(peer::CloseReason::Banned);
+                        continue;
+                    }
+
+                    let duplicate = connections
+                        .values()
+                        .find(|other| {
+                            other.info_hash == peer.info_hash && other.peer_id == peer.peer_id
+                        })
+                        .map(|other| (other.connection.addr, peer.supersedes(other, &peer_id)));
+
+                    match duplicate {
+                        Some((_, false)) => {
+                            say!("{:21} Closing duplicate connection", from_socket_addr);
+                            peer.close(peer::CloseReason::Duplicate);
+                            continue;
+                        }
+                        Some((other_addr, true)) => {
+                            say!("{:21} Closing duplicate connection", other_addr);
+                            let reason = peer::CloseReason::Duplicate;
+                            disconnect(&mut torrents, &mut connections, &other_addr, reason);
+                        }
+                        None => {}
+                    }
+
+                    // The torrent may have been removed since the handshake.
+                    let Some(torrent) = torrents.get_mut(&peer.info_hash) else {
+                        peer.close(peer::CloseReason::Local);
+                        continue;
+                    };
+
+                    peer.stats = peer::Stats::new(clock.now());
+                    peer.bitfield = common::Bitfield::new(torrent.have.piece_count());
+
+                    let sent = peer.send_bitfield(&torrent.have, args.lazy_bitfield).await;
+
+                    let mut peer = match sent {
+                        Ok(peer) => peer,
+                        Err(e) => {
+                            say!("{:21} Error sending bitfield: {:?}", from_socket_addr, e);
+                            continue;
+                        }
+                    };
+
+                    output::event(output::Event::PeerConnected {
+                        info_hash: peer.info_hash,
+                        addr: from_socket_addr,
+                        peer_id: peer.peer_id,
+                    });
+
+                    torrent
+                        .peer_connections
+                        .insert(from_socket_addr, peer.peer_id);
+
+                    if let Err(e) = peer.send_extended_handshake(&args.user_agent, &[]).await {
+                        say!(
+                            "{:21} Error sending extended handshake: {:?}",
+                            from_socket_addr,
+                            e
+                        );
+                    }
+
+                    if let Err(e) = request_piece_layers(torrent, &mut peer).await {
+                        say!("{:21} Error sending HashRequest: {:?}", from_socket_addr, e);
+                    }
+
+                    // The Port message carries the DHT's UDP port, never the TCP listen port, so
+                    // there is nothing to send if the DHT is disabled.
+                    if let Some(dht_port) = args.dht_port.filter(|_| peer.supports_dht()) {
+                        let message = common::peer::PeerMessage::Port { port: dht_port };
+
+                        if let Err(e) = peer.send_message(message).await {
+                            say!("{:21} Error sending DHT port: {:?}", from_socket_addr, e);
+                        }
+                    }
+
+                    connections.insert(peer.connection.addr, peer);
+                }
+                peer::IncomingEvent::Message { message } => {
+                    let Some(peer) = connections.get_mut(&from_socket_addr) else {
+                        continue;
+                    };
+
+                    if let Err(e) = peer.receive(&message, clock.now()) {
+                        say!("{:21} Banning: {}", from_socket_addr, e);
+
+                        banned.insert(from_socket_addr.ip());
+                        let reason = peer::CloseReason::Banned;
+                        disconnect(&mut torrents, &mut connections, &from_socket_addr, reason);
+                        continue;
+                    }
+
+                    let info_hash = peer.info_hash;
+                    let Some(torrent) = torrents.get_mut(&info_hash) else {
+                        continue;
+                    };
+
+                    let mut completed = None;
+                    let mut arrived = None;
+                    let mut failed = HashSet::new();
+
+                    match &message {
+                        common::peer::PeerMessage::Bitfield { .. }
+                        | common::peer::PeerMessage::Have { .. } => {
+                            if let Err(e) = update_interest(torrent, peer).await {
+                                say!("{:21} Error sending Interested: {:?}", from_socket_addr, e);
+                            }
+                        }
+                        common::peer::PeerMessage::Piece { block, data } => {
+                            torrent.downloaded += data.len() as u64;
+                            torrent.webseed_fallback.record_progress(clock.now());
+                            peer.am_requesting.retain(|requested| requested != block);
+
+                            let written = torrent.write_block(block, data, from_socket_addr).await;
+                            completed = match written {
+                                Ok(completed) => completed,
+                                Err((index, senders)) => {
+                                    say!(
+                                        "{:21} Piece {} failed verification, sent by {} peers",
+                                        from_socket_addr,
+                                        index,
+                                        senders.len(),
+                                    );
+                                    failed = senders;
+                                    None
+                                }
+                            };
+                            arrived = Some(block.clone());
+                        }
+                        common::peer::PeerMessage::HashRequest { request } => {
+                            if let Err(e) = serve_hashes(torrent, peer, request).await {
+                                say!("{:21} Error sending Hashes: {:?}", from_socket_addr, e);
+                            }
+                        }
+                        common::peer::PeerMessage::Hashes { request, hashes } => {
+                            match torrent.receive_hashes(request, hashes) {
+                                Ok(true) => say!(
+                                    "{:21} Received the piece layer of {}",
+                                    from_socket_addr,
+                                    request.pieces_root,
+                                ),
+                                Ok(false) => {}
+                                Err(e) => say!("{:21} Invalid Hashes: {}", from_socket_addr, e),
+                            }
+                        }
+                        common::peer::PeerMessage::HashReject { request } => {
+                            say!(
+                                "{:21} Hash request for {} rejected",
+                                from_socket_addr,
+                                request.pieces_root,
+                            );
+                        }
+                        _ => {}
+                    }
+
+                    let finished = completed.is_some() && torrent.have.is_full();
+
+                    for addr in failed {
+                        let Some(peer) = connections.get_mut(&addr) else {
+                            continue;
+                        };
+
+                        peer.stats.hash_failures += 1;
+
+                        if peer.stats.hash_failures >= MAX_HASH_FAILURES {
+                            say!(
+                                "{:21} Banning: {} pieces failed verification",
+                                addr,
+                                peer.stats.hash_failures,
+                            );
+
+                            banned.insert(addr.ip());
+                            let reason = peer::CloseReason::Banned;
+                            disconnect(&mut torrents, &mut connections, &addr, reason);
+                        }
+                    }
+
+                    if !connections.contains_key(&from_socket_addr) {
+                        continue;
+                    }
+
+                    if let Some(block) = arrived {
+                        cancel_block(&mut connections, &info_hash, &from_socket_addr, &block).await;
+                    }
+
+                    if let Some(index) = completed {
+                        send_have(
+                            &mut connections,
+                            &info_hash,
+                            index,
+                            !args.send_redundant_haves,
+                        )
+                        .await;
+                    }
+
+                    if let common::peer::PeerMessage::Unchoke
+                    | common::peer::PeerMessage::Piece { .. } = message
+                    {
+                        let mut budget =
+                            MemoryBudget::new(args.memory_budget, &torrents, &connections);
+
+                        request_from(
+                            &mut torrents,
+                            &mut connections,
+                            &from_socket_addr,
+                            &mut budget,
+                            clock.now(),
+                        )
+                        .await;
+                    }
+
+                    // Each request lets one block go out, to whichever peer's turn it is.
+                    if let common::peer::PeerMessage::Request { .. } = message {
+                        serve_block(&mut torrents, &mut connections, &mut uploads).await;
+                    }
+
+                    if finished {
+                        announce_event(&torrents, &info_hash, Event::Completed, &announcer);
+                        notify_finished(&torrents, &info_hash, &notifier);
+                    }
+                }
+                peer::IncomingEvent::Closed {
+                    reason: reason @ peer::CloseReason::Io(_),
+                } => {
+                    if let Some(peer) = connections.remove(&from_socket_addr) {
+                        say!("{:21} Connection closed: {}", from_socket_addr, reason);
+                        forget_peer(&mut torrents, &connections, &peer);
+                    }
+                }
+                // Connections that closed during the handshake never joined the session, and it
+                // has forgotten the ones it closed itself already.
+                peer::IncomingEvent::Closed { .. } => {}
+            },
+            Incoming::Tracker(tracker::Incoming { info_hash, event }) => match event {
+                tracker::IncomingEvent::AnnounceResponse { url, response } => {
+                    if let Some(torrent) = torrents.get_mut(&info_hash) {
+                        let interval = Duration::from_secs(response.interval);
+                        torrent.trackers.succeeded(&url, interval, clock.now());
+                    }
+
+                    output::event(output::Event::AnnounceSucceeded {
+                        info_hash,
+                        peers: response.peers.len(),
+                        seeders: response.complete,
+                        leechers: response.incomplete,
+                        completed: response.downloaded,
+                        interval: response.interval,
+                    });
+
+                    if !network.is_enabled() {
+                        continue;
+                    }
+
+                    let Some(torrent) = torrents.get_mut(&info_hash) else {
+                        continue;
+                    };
+
+                    for addrs in torrent
+                        .candidates
+                        .add_peers(&response.peers, args.peer_filter)
+                    {
+                        if addrs.iter().any(|addr| connections.contains_key(addr)) {
+                            continue;
+                        }
+
+                        processes.spawn(peer::connect(
+                            addrs,
+                            peer_id,
+                            reserved,
+                            info_hash,
+                            incoming_sender.clone(),
+                        ));
+                    }
+                }
+                tracker::IncomingEvent::AnnounceError {
+                    url,
+                    error,
+                    retry_in,
+                } => {
+                    if let Some(torrent) = torrents.get_mut(&info_hash) {
+                        torrent.trackers.failed(&url, retry_in, clock.now());
+                    }
+
+                    output::event(output::Event::AnnounceFailed {
+                        info_hash,
+                        url: Some(url.clone()),
+                        message: error.to_string(),
+                        retry_in,
+                    });
+
+                    if !error.is_permanent() {
+                        continue;
+                    }
+
+                    let Some(torrent) = torrents.get(&info_hash) else {
+                        continue;
+                    };
+
+                    let message = format!("{} won't accept announces: {}", url, error);
+                    say!("{}: {}", torrent.metainfo.info.name(), message);
+
+                    notifier.send(notify::Notification::Error {
+                        name: torrent.metainfo.info.name().to_string(),
+                        info_hash,
+                        message,
+                    });
+                }
+            },
+            Incoming::WebSeed(webseed::Incoming {
+                info_hash,
+                index,
+                result,
+            }) => match result {
+                Ok(data) => {
+                    let written = match torrents.get_mut(&info_hash) {
+                        Some(torrent) => {
+                            torrent.downloaded += data.len() as u64;
+                            torrent.write_piece(index, data).await
+                        }
+                        None => false,
+                    };
+
+                    if written {
+                        send_have(
+                            &mut connections,
+                            &info_hash,
+                            index,
+                            !args.send_redundant_haves,
+                        )
+                        .await;
+
+                        if torrents.get(&info_hash).is_some_and(|t| t.have.is_full()) {
+                            announce_event(&torrents, &info_hash, Event::Completed, &announcer);
+                            notify_finished(&torrents, &info_hash, &notifier);
+                        }
+                    }
+                }
+                Err(e) => say!("Unable to fetch piece {} from web seeds: {}", index, e),
+            },
+            Incoming::Stream(stream::Incoming {
+                info_hash,
+                offset,
+                length,
+                reply,
+            }) => {
+                if let Some(torrent) = torrents.get_mut(&info_hash) {
+                    reply.send(torrent.stream_read(offset, length).await).ok();
+                }
+            }
+            Incoming::Feed(feed::Incoming {
+                metainfo,
+                download_dir,
+            }) => {
+                let mut torrent = Torrent::new(
+                    *metainfo,
+                    picker.clone(),
+                    choker.clone(),
+                    &args,
+                    clock.now(),
+                );
+                torrent.download_dir = download_dir;
+                torrents.add(torrent, &args).await;
+            }
+            Incoming::Rpc(rpc::Incoming::Add {
+                metainfo,
+                download_dir,
+                reply,
+            }) => {
+                let mut torrent = Torrent::new(
+                    *metainfo,
+                    picker.clone(),
+                    choker.clone(),
+                    &args,
+                    clock.now(),
+                );
+                torrent.download_dir = download_dir;
+                reply.send(torrents.add(torrent, &args).await).ok();
+            }
+            Incoming::Rpc(rpc::Incoming::AddMagnet {
+                magnet,
+                download_dir,
+            }) => {
+                let args = args.clone();
+                let http_client = http_client.clone();
+                let sender = incoming_sender.clone();
+
+                // Fetching the metadata from peers can take a while.
+                processes.spawn(async move {
+                    match load_magnet(&magnet, &args, peer_id, &http_client).await {
+                        Ok(metainfo) => {
+                            rpc::add_torrent(&sender, metainfo, download_dir).await;
+                        }
+                        Err(e) => say!("Not adding {}: {}", magnet, e),
+                    }
+                });
+            }
+            Incoming::Rpc(rpc::Incoming::Remove {
+                info_hashes,
+                delete_files,
+            }) => remove_torrents(
+                &mut torrents,
+                &mut connections,
+                &info_hashes,
+                delete_files,
+                &args.state_dir,
+                &announcer,
+            ),
+            Incoming::Rpc(rpc::Incoming::Torrents { reply }) => {
+                reply.send(torrents.statuses()).ok();
+            }
+            Incoming::Interface(watchdog::Incoming { ip, available }) => {
+                network.interface_available = available;
+
+                if available {
+                    say!("{} is available again", ip);
+
+                    if network.is_enabled() {
+                        say!("Resuming network activity");
+                    }
+                } else {
+                    say!(
+                        "{} is no longer available, closing {} connections and pausing network \
+                        activity",
+                        ip,
+                        connections.len(),
+                    );
+                    close_all(&mut torrents, &mut connections);
+                }
+            }
+            Incoming::Control(control::Command::Standby) => {
+                if !network.standby {
+                    say!(
+                        "Entering standby, closing {} connections",
+                        connections.len()
+                    );
+                    network.standby = true;
+                    standby(&mut torrents, &mut connections, &announcer, clock.now());
+                }
+            }
+            Incoming::Control(control::Command::Resume) => {
+                if network.standby {
+                    say!("Leaving standby");
+                    network.standby = false;
+
+                    if network.is_enabled() {
+                        resume(&mut torrents, &announcer, clock.now());
+                    }
+                }
+            }
+            Incoming::Control(control::Command::Remove {
+                info_hash,
+                delete_files,
+            }) => remove_torrents(
+                &mut torrents,
+                &mut connections,
+                &[info_hash],
+                delete_files,
+                &args.state_dir,
+                &announcer,
+            ),
+            Incoming::Control(control::Command::Apply { info_hash, action }) => {
+                torrents.apply(&info_hash, action, &args.state_dir);
+            }
+            Incoming::IoError(e) => say!("{:?}", e),
+        }
+    }
+}
+
+impl<P, C> Torrents<P, C> {
+    fn new() -> Self {
+        Self(HashMap::new())
+    }
+
+    fn get(&self, info_hash: &common::InfoHash) -> Option<&Torrent<P, C>> {
+        self.0.get(info_hash)
+    }
+
+    fn get_mut(&mut self, info_hash: &common::InfoHash) -> Option<&mut Torrent<P, C>> {
+        self.0.get_mut(info_hash)
+    }
+
+    fn contains(&self, info_hash: &common::InfoHash) -> bool {
+        self.0.contains_key(info_hash)
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&common::InfoHash, &Torrent<P, C>)> {
+        self.0.iter()
+    }
+
+    fn values(&self) -> impl Iterator<Item = &Torrent<P, C>> {
+        self.0.values()
+    }
+
+    fn values_mut(&mut self) -> impl Iterator<Item = &mut Torrent<P, C>> {
+        self.0.values_mut()
+    }
+
+    /// Take a torrent out of the session. Its saved state is left for the caller to deal with.
+    fn remove(&mut self, info_hash: &common::InfoHash) -> Option<Torrent<P, C>> {
+        self.0.remove(info_hash)
+    }
+
+    /// The status of every torrent, for the web UI and the APIs.
+    fn statuses(&self) -> Vec<rpc::TorrentStatus> {
+        self.iter()
+            .map(|(info_hash, torrent)| rpc::TorrentStatus {
+                name: torrent.metainfo.info.name().to_string(),
+                info_hash: *info_hash,
+                status: torrent.status(),
+                length: torrent.metainfo.info.length(),
+                downloaded: torrent.have_bytes().into(),
+                left: torrent.left().into(),
+                pieces: torrent.have.piece_count(),
+                pieces_have: torrent.have.count(),
+                peers: torrent.peer_connections.len(),
+                download_dir: torrent.download_dir.clone(),
+                error: torrent.error.clone(),
+            })
+            .collect()
+    }
+
+    /// Pause, unpause or retry the torrent with `info_hash`, reporting why not if that can't be
+    /// done. Whether it's paused is saved in its resume data in `state_dir`.
+    fn apply(&mut self, info_hash: &common::InfoHash, action: status::Action, state_dir: &Path) {
+        let result = match self.get_mut(info_hash) {
+            Some(torrent) => torrent.apply(action).map(|()| torrent),
+            None => Err("No such torrent".into()),
+        };
+
+        let torrent = match result {
+            Ok(torrent) => torrent,
+            Err(e) => {
+                say!("Unable to {} {}: {}", action, info_hash, e);
+                return;
+            }
+        };
+
+        let path = resume::ResumeData::path(state_dir, info_hash);
+
+        if let Err(e) = torrent.resume_data().save(&path) {
+            say!("Unable to save resume data to {}: {}", path.display(), e);
+        }
+    }
+
+    /// Add a torrent to the session, unless it's there already or there's no room for it with
+    /// --require-space, saving it to be restored on the next run. Returns whether it was added.
+    async fn add(&mut self, mut torrent: Torrent<P, C>, args: &Args) -> bool {
+        let info_hash = *torrent.metainfo.info_hash();
+
+        if self.0.contains_key(&info_hash) {
+            return false;
+        }
+
+        if torrent.storage.is_none() {
+            match Files::new(torrent.save_dir(), &torrent.metainfo.info) {
+                Ok(files) => torrent.storage = Some(Box::new(files)),
+                Err(e) => torrent.fail(format!("Unable to lay out the files: {}", e)),
+            }
+        }
+
+        if let Err(e) = torrent.check_space() {
+            if args.require_space {
+                say!("Not adding {}: {}", torrent.metainfo.info.name(), e);
+                return false;
+            }
+
+            say!("{}: {}", torrent.metainfo.info.name(), e);
+        }
+
+        let resume_data = torrent.resume_data();
+
+        if let Err(e) = resume::save_torrent(&args.state_dir, &torrent.metainfo, &resume_data) {
+            say!(
+                "Unable to save {} for the next run: {}",
+                torrent.metainfo.info.name(),
+                e
+            );
+        }
+
+        match &torrent.download_dir {
+            Some(dir) => say!(
+                "Added {} to {}",
+                torrent.metainfo.info.name(),
+                dir.display()
+            ),
+            None => say!("Added {}", torrent.metainfo.info.name()),
+        }
+
+        self.0.insert(info_hash, torrent);
+
+        let reused = self.reuse_pieces(&info_hash).await;
+
+        if reused > 0 {
+            say!("Took {} pieces from other torrents in the session", reused);
+        }
+
+        true
+    }
+
+    /// Give a torrent the pieces it shares with the other torrents in the session, for
+    /// cross-seeding. A piece with the same hash and length as one another torrent has is the same
+    /// data, wherever it falls in the files, as long as that torrent's storage can read it back.
+    /// Each torrent's pieces are read and verified in one go on the blocking thread pool. Returns
+    /// the number of pieces taken.
+    async fn reuse_pieces(&mut self, info_hash: &common::InfoHash) -> u32 {
+        let Some(torrent) = self.0.get(info_hash) else {
+            return 0;
+        };
+
+        let mut wanted: HashMap<(common::metainfo::Piece, u64), Vec<u32>> = HashMap::new();
+
+        for index in 0..torrent.have.piece_count() {
+            if torrent.have.contains(index) {
+                continue;
+            }
+
+            if let Some(key) = piece_key(&torrent.metainfo.info, index) {
+                wanted.entry(key).or_default().push(index);
+            }
+        }
+
+        if wanted.is_empty() {
+            return 0;
+        }
+
+        let metainfo = Arc::new(torrent.metainfo.clone());
+        let mut found: Vec<(Vec<u32>, Vec<u8>)> = Vec::new();
+
+        for (other_info_hash, other) in self.0.iter_mut() {
+            if other_info_hash == info_hash || wanted.is_empty() {
+                continue;
+            }
+
+            // The pieces of ours that each of the other torrent's pieces would fill.
+            let matching: Vec<(u32, Vec<u32>)> = other
+                .have
+                .iter()
+                .filter_map(|other_index| {
+                    let key = piece_key(&other.metainfo.info, other_index)?;
+                    Some((other_index, wanted.remove(&key)?))
+                })
+                .collect();
+
+            if matching.is_empty() {
+                continue;
+            }
+
+            let metainfo = Arc::clone(&metainfo);
+
+            let read = storage::unblocked(&mut other.storage, move |storage| {
+                let mut verified = Vec::new();
+
+                for (other_index, indices) in matching {
+                    let Ok(Some(data)) = storage.read_piece(other_index) else {
+                        continue;
+                    };
+
+                    let indices: Vec<u32> = indices
+                        .into_iter()
+                        .filter(|&index| metainfo.verify_piece(index, &data))
+                        .collect();
+
+                    if !indices.is_empty() {
+                        verified.push((indices, data.into_owned()));
+                    }
+                }
+
+                Ok(verified)
+            })
+            .await;
+
+            if let Some(Ok(verified)) = read {
+                found.extend(verified);
+            }
+        }
+
+        let Some(torrent) = self.0.get_mut(info_hash) else {
+            return 0;
+        };
+
+        let mut reused = 0;
+
+        // A piece read once fills every piece of ours with the same hash, and is only copied for
+        // the second and later of those.
+        for (indices, data) in found {
+            let Some((&last, rest)) = indices.split_last() else {
+                continue;
+            };
+
+            for &index in rest {
+                reused += u32::from(torrent.write_piece(index, data.clone()).await);
+            }
+
+            reused += u32::from(torrent.write_piece(last, data).await);
+        }
+
+        reused
+    }
+}
+
+impl<P, C> Torrent<P, C> {
+    /// Set up a torrent to download, with the key and download directory from its resume data.
+    fn new(
+        metainfo: common::metainfo::MetainfoFile,
+        picker: P,
+        choker: C,
+        args: &Args,
+        now: Instant,
+    ) -> Self {
+        let resume_path = resume::ResumeData::path(&args.state_dir, metainfo.info_hash());
+        let mut resume_data = resume::ResumeData::load(&resume_path).unwrap_or_else(|e| {
+            say!("Ignoring unreadable resume data: {}", e);
+            resume::ResumeData::default()
+        });
+
+        if resume_data.key.is_none() {
+            resume_data.key = Some(common::PeerKey::generate());
+
+            if let Err(e) = resume_data.save(&resume_path) {
+                say!(
+                    "Unable to save resume data to {}: {}",
+                    resume_path.display(),
+                    e
+                );
+            }
+        }
+
+        Self {
+            key: resume_data.key.unwrap(),
+            picker,
+            choker,
+            upload_quota: args.leech_quota.map(|bytes| peer::UploadQuota {
+                bytes: bytes.into(),
+                window: Duration::from_secs(args.leech_window_minutes * 60),
+            }),
+            candidates: peer::Candidates::default(),
+            downloading: HashMap::new(),
+            partial_layers: HashMap::new(),
+            storage: None,
+            streaming: Vec::new(),
+            download_dir: resume_data.download_dir,
+            disk_full: false,
+            error: None,
+            paused: resume_data.paused,
+            checking: false,
+            have: common::Bitfield::new(metainfo.info.pieces().len() as u32),
+            trackers: tracker::Schedule::new(&metainfo.announce_urls(), now),
+            uploaded: 0,
+            downloaded: 0,
+            metainfo,
+            peer_connections: HashMap::new(),
+            webseed_fallback: webseed::Fallback::new(
+                Duration::from_secs(args.webseed_fallback_minutes * 60),
+                now,
+            ),
+        }
+    }
+
+    /// What to save about the torrent for the next run.
+    fn resume_data(&self) -> resume::ResumeData {
+        resume::ResumeData {
+            key: Some(self.key.clone()),
+            download_dir: self.download_dir.clone(),
+            paused: self.paused,
+        }
+    }
+
+    /// The total length of the pieces we have.
+    fn have_bytes(&self) -> common::Bytes {
+        self.have
+            .iter()
+            .filter_map(|index| self.metainfo.info.piece_range(index))
+            .map(|range| common::Bytes::from(range.end - range.start))
+            .sum()
+    }
+
+    /// The bytes we still want that haven't been verified yet, as reported to trackers and the
+    /// API. Every piece is wanted, as there's no way to skip files yet, but BEP 47 padding files
+    /// are never counted.
+    fn left(&self) -> common::Bytes {
+        let info = &self.metainfo.info;
+
+        (0..self.have.piece_count())
+            .filter(|index| !self.have.contains(*index))
+            .filter_map(|index| info.piece_range(index))
+            .map(|range| common::Bytes::from(range.end - range.start - info.padding_in(range)))
+            .sum()
+    }
+
+    /// Buffer a block arriving from the peer at `from`, writing out its piece once that completes
+    /// it and it passes verification, and returning the piece's index if it was written. Fails
+    /// with the index and the peers that sent its blocks if it completed a piece that didn't,
+    /// which is then thrown away to be downloaded again.
+    async fn write_block(
+        &mut self,
+        block: &common::BlockRef,
+        data: &[u8],
+        from: SocketAddr,
+    ) -> Result<Option<u32>, (u32, HashSet<SocketAddr>)> {
+        let index = block.index();
+        let Some(buffer) = self.downloading.get_mut(&index) else {
+            return Ok(None);
+        };
+
+        if !buffer.receive(block, data, from) || buffer.received.contains(&None) {
+            return Ok(None);
+        }
+
+        let Some(buffer) = self.downloading.remove(&index) else {
+            return Ok(None);
+        };
+
+        let valid = self.metainfo.verify_piece(index, &buffer.data);
+
+        output::event(output::Event::PieceVerified {
+            info_hash: *self.metainfo.info_hash(),
+            name: self.metainfo.info.name().to_string(),
+            index,
+            valid,
+        });
+
+        if !valid {
+            return Err((index, buffer.senders()));
+        }
+
+        Ok(self.write_piece(index, buffer.data).await.then_some(index))
+    }
+
+    /// Write a verified piece to the storage on the blocking thread pool, recording it as had once
+    /// it's stored. Returns whether we didn't already have it and it was stored. If the storage
+    /// fails, the torrent is paused.
+    async fn write_piece(&mut self, index: u32, data: Vec<u8>) -> bool {
+        if self.has_piece(index) {
+            return false;
+        }
+
+        let written = storage::unblocked(&mut self.storage, move |storage| {
+            storage.write_piece(index, data)
+        })
+        .await;
+
+        self.record_piece(index, written.unwrap_or(Ok(())))
+    }
+
+    /// Count a piece as had once its data has been `written`, returning whether it was.
+    fn record_piece(&mut self, index: u32, written: io::Result<()>) -> bool {
+        if let Err(e) = written {
+            self.fail(format!("Unable to store piece {}: {}", index, e));
+            return false;
+        }
+
+        self.have.insert(index);
+        self.streaming.retain(|&streaming| streaming != index);
+        true
+    }
+
+    /// Whether we have the piece at `index`, verified and stored.
+    fn has_piece(&self, index: u32) -> bool {
+        self.have.contains(index)
+    }
+
+    /// Whether the download is down to its last few blocks, every one of them claimed.
+    fn in_endgame(&self) -> bool {
+        let missing = self.have.piece_count() - self.have.count();
+
+        if missing as usize != self.downloading.len() {
+            return false;
+        }
+
+        let blocks: usize = self
+            .downloading
+            .values()
+            .map(|buffer| {
+                buffer
+                    .received
+                    .iter()
+                    .filter(|received| received.is_none())
+                    .count()
+            })
+            .sum();
+
+        blocks < ENDGAME_BLOCKS
+    }
+
+    /// The data of a block of a piece we have, if the storage can read it back. It's read on the
+    /// blocking thread pool.
+    async fn read_block(&mut self, block: &common::BlockRef) -> Option<Vec<u8>> {
+        if !self.has_piece(block.index()) {
+            return None;
+        }
+
+        let block = block.clone();
+
+        storage::unblocked(&mut self.storage, move |storage| storage.read_block(&block))
+            .await?
+            .ok()?
+    }
+
+    fn status(&self) -> Status {
+        if self.error.is_some() {
+            Status::Errored
+        } else if self.checking {
+            Status::Checking
+        } else if self.paused {
+            Status::Paused
+        } else if self.disk_full {
+            Status::Queued
+        } else if self.have.is_full() {
+            Status::Seeding
+        } else {
+            Status::Downloading
+        }
+    }
+
+    /// Whether the torrent isn't downloading for now.
+    fn is_paused(&self) -> bool {
+        !matches!(self.status(), Status::Downloading | Status::Seeding)
+    }
+
+    /// Pause, unpause or retry the torrent, if its status allows.
+    fn apply(&mut self, action: status::Action) -> Result<(), common::Error> {
+        self.status().allows(action)?;
+
+        let name = self.metainfo.info.name();
+
+        match action {
+            status::Action::Pause => {
+                say!("{}: paused", name);
+                self.paused = true;
+            }
+            status::Action::Unpause => {
+                say!("{}: unpaused", name);
+                self.paused = false;
+            }
+            status::Action::Retry => {
+                say!("{}: retrying", name);
+                self.error = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pause the torrent after a storage error, until it's retried.
+    fn fail(&mut self, message: String) {
+        say!(
+            "{}: pausing after an error: {}",
+            self.metainfo.info.name(),
+            message
+        );
+        self.error = Some(message);
+    }
+
+    /// Where the torrent's files are saved.
+    fn save_dir(&self) -> &Path {
+        self.download_dir.as_deref().unwrap_or(Path::new("."))
+    }
+
+    /// Check that what's left to download fits in the free space where it's saved.
+    fn check_space(&self) -> Result<(), common::Error> {
+        let left = self.left();
+        let available = disk::available_for(self.save_dir())
+            .map_err(|e| format!("Unable to check free space: {}", e))?;
+
+        if u64::from(left) > available {
+            return Err(format!(
+                "{} left to download, but only {} free in {}",
+                left,
+                common::Bytes::from(available),
+                self.save_dir().display(),
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Pause downloading while the disk is nearly full, and resume once there's room again.
+    fn update_disk_full(&mut self) {
+        if self.have.is_full() {
+            return;
+        }
+
+        let Ok(available) = disk::available_for(self.save_dir()) else {
+            return;
+        };
+
+        let name = self.metainfo.info.name();
+
+        if !self.disk_full && available < disk::RESERVE {
+            say!(
+                "{}: pausing, only {} free",
+                name,
+                common::Bytes::from(available)
+            );
+            self.disk_full = true;
+        } else if self.disk_full && available >= 2 * disk::RESERVE {
+            say!(
+                "{}: resuming, {} free",
+                name,
+                common::Bytes::from(available)
+            );
+            self.disk_full = false;
+        }
+    }
+
+    /// Verify the pieces whose files were changed by something else, so that the ones that no
+    /// longer match are downloaded again rather than served to peers. The files are looked at and
+    /// the pieces read back and hashed on the blocking thread pool.
+    async fn check_files(&mut self) {
+        let modified = storage::unblocked(&mut self.storage, |storage| Ok(storage.modified()));
+
+        let Some(Ok(modified)) = modified.await else {
+            return;
+        };
+
+        let modified: Vec<u32> = modified
+            .into_iter()
+            .filter(|&index| self.have.contains(index))
+            .collect();
+
+        if modified.is_empty() {
+            return;
+        }
+
+        let metainfo = self.metainfo.clone();
+
+        let invalid = storage::unblocked(&mut self.storage, move |storage| {
+            Ok(modified
+                .into_iter()
+                .filter(|&index| match storage.read_piece(index) {
+                    Ok(Some(data)) => !metainfo.verify_piece(index, &data),
+                    Ok(None) | Err(_) => true,
+                })
+                .collect::<Vec<u32>>())
+        });
+
+        let Some(Ok(invalid)) = invalid.await else {
+            return;
+        };
+
+        let name = self.metainfo.info.name();
+
+        for &index in &invalid {
+            self.have.remove(index);
+
+            output::event(output::Event::PieceVerified {
+                info_hash: *self.metainfo.info_hash(),
+                name: name.to_string(),
+                index,
+                valid: false,
+            });
+        }
+
+        if !invalid.is_empty() {
+            say!(
+                "{}: {} pieces changed on disk and will be downloaded again",
+                name,
+                invalid.len()
+            );
+        }
+    }
+
+    /// Take the torrent's data from files already under `dir`, which then become its storage and
+    /// where it's saved, reporting progress with the time from `clock`. Returns the number of
+    /// pieces that passed verification.
+    fn import(&mut self, dir: &Path, clock: &dyn common::Clock) -> Result<u32, common::Error> {
+        let files = Files::new(dir, &self.metainfo.info)?;
+        let have = self.check(files, clock)?;
+
+        self.download_dir = Some(dir.to_path_buf());
+        Ok(have)
+    }
+
+    /// Check the data already in the torrent's download directory, as for a torrent restored from
+    /// the last run. Returns the number of pieces that passed verification.
+    fn recheck(&mut self, clock: &dyn common::Clock) -> Result<u32, common::Error> {
+        let files = Files::new(self.save_dir(), &self.metainfo.info)?;
+        self.check(files, clock)
+    }
+
+    /// Hash the pieces in `files`, which then become the torrent's storage, and count those that
+    /// pass verification as had. Returns how many did.
+    fn check(&mut self, mut files: Files, clock: &dyn common::Clock) -> Result<u32, common::Error> {
+        let mut progress = verify::Progress::new(&self.metainfo, clock.now());
+        self.checking = true;
+
+        for index in 0..self.have.piece_count() {
+            let piece = files.read_piece(index).map_err(|e| {
+                self.checking = false;
+                e.to_string()
+            })?;
+
+            if piece.is_some_and(|data| self.metainfo.verify_piece(index, &data)) {
+                self.have.insert(index);
+            }
+
+            progress.piece(clock.now());
+        }
+
+        progress.finish(clock.now());
+        self.checking = false;
+
+        self.storage = Some(Box::new(files));
+        Ok(self.have.count())
+    }
+
+    /// Read up to `length` bytes at `offset` for the stream server, stopping at the end of the
+    /// piece. Only that block is read, on the blocking thread pool. If we don't have the piece
+    /// yet, it and the few after it are downloaded next.
+    async fn stream_read(&mut self, offset: u64, length: u64) -> Option<Vec<u8>> {
+        let piece_length = self.metainfo.info.piece_length();
+        let index = (offset / piece_length) as u32;
+
+        if self.has_piece(index) {
+            let range = self.metainfo.info.piece_range(index)?;
+            let length = length.min(range.end.saturating_sub(offset));
+            let block = common::BlockRef::new(index, (offset - range.start) as u32, length as u32);
+
+            let read =
+                storage::unblocked(&mut self.storage, move |storage| storage.read_block(&block))
+                    .await;
+
+            match read {
+                Some(Ok(Some(data))) => return Some(data),
+                Some(Ok(None)) | None => {}
+                Some(Err(e)) => {
+                    self.fail(format!("Unable to read piece {}: {}", index, e));
+                    return None;
+                }
+            }
+        }
+
+        self.streaming = (index..index.saturating_add(STREAM_READAHEAD_PIECES))
+            .filter(|&index| index < self.have.piece_count() && !self.have.contains(index))
+            .collect();
+
+        None
+    }
+
+    /// Check hashes a peer sent for one of our missing piece layers, returning whether that
+    /// completed the layer.
+    fn receive_hashes(
+        &mut self,
+        request: &HashRequest,
+        hashes: &[MerkleHash],
+    ) -> Result<bool, common::Error> {
+        let piece_length = self.metainfo.info.piece_length();
+
+        let Some(v2) = self.metainfo.v2.as_mut() else {
+            return Err("Not a v2 torrent".into());
+        };
+
+        if v2.piece_layers.contains_key(&request.pieces_root) {
+            return Ok(false);
+        }
+
+        let Some(file) = v2.file(&request.pieces_root) else {
+            return Err(format!("Unknown pieces root {}", request.pieces_root).into());
+        };
+
+        let length = request.length as usize;
+
+        if request.base_layer != merkle::piece_height(piece_length)
+            || hashes.len() != length + request.proof_layers as usize
+        {
+            return Err("Hashes don't match the request".into());
+        }
+
+        let (layer, uncles) = hashes.split_at(length);
+
+        if !merkle::verify_proof(layer, request.index as usize, uncles, &request.pieces_root) {
+            return Err(format!("Hashes don't match pieces root {}", request.pieces_root).into());
+        }
+
+        let partial = self
+            .partial_layers
+            .entry(request.pieces_root)
+            .or_insert_with(|| vec![None; file.length.div_ceil(piece_length) as usize]);
+
+        for (slot, hash) in partial.iter_mut().skip(request.index as usize).zip(layer) {
+            *slot = Some(*hash);
+        }
+
+        if partial.iter().any(Option::is_none) {
+            return Ok(false);
+        }
+
+        let layer = self
+            .partial_layers
+            .remove(&request.pieces_root)
+            .unwrap_or_default()
+            .into_iter()
+            .flatten()
+            .collect();
+
+        v2.insert_piece_layer(request.pieces_root, layer, piece_length)?;
+        Ok(true)
+    }
+}
+
+/// Tell the peer we're interested if it has a piece we don't.
+async fn update_interest<P, C>(torrent: &Torrent<P, C>, peer: &mut peer::Peer) -> io::Result<()> {
+    if peer.am_interested {
+        return Ok(());
+    }
+
+    let wanted = peer
+        .bitfield
+        .iter()
+        .any(|index| !torrent.have.contains(index));
+
+    if wanted {
+        peer.am_interested = true;
+        peer.send_message(common::peer::PeerMessage::Interested)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// For each piece, the number of connected peers that have it.
+fn availability<P, C>(
+    connections: &HashMap<SocketAddr, peer::Peer>,
+    torrent: &Torrent<P, C>,
+) -> Vec<usize> {
+    let mut availability = vec![0; torrent.have.piece_count() as usize];
+
+    for peer in connections
+        .values()
+        .filter(|peer| &peer.info_hash == torrent.metainfo.info_hash())
+    {
+        for index in peer.bitfield.iter() {
+            if let Some(count) = availability.get_mut(index as usize) {
+                *count += 1;
+            }
+        }
+    }
+
+    availability
+}
+
+/// Send the peer at `addr` whatever requests it has room for, reporting any failure.
+async fn request_from<P: PiecePicker, C>(
+    torrents: &mut Torrents<P, C>,
+    connections: &mut HashMap<SocketAddr, peer::Peer>,
+    addr: &SocketAddr,
+    budget: &mut MemoryBudget,
+    now: Instant,
+) {
+    let Some(info_hash) = connections.get(addr).map(|peer| peer.info_hash) else {
+        return;
+    };
+
+    let Some(torrent) = torrents.get_mut(&info_hash) else {
+        return;
+    };
+
+    let availability = availability(connections, torrent);
+
+    if let Some(peer) = connections.get_mut(addr) {
+        if let Err(e) = request_blocks(torrent, peer, &availability, budget, now).await {
+            say!("{:21} Error sending Request: {:?}", addr, e);
+        }
+    }
+}
+
+/// If the peer is letting us download, keep as many requests queued with it as its pipeline depth
+/// allows, claiming more pieces to request blocks of as needed while the memory budget lasts.
+async fn request_blocks<P: PiecePicker, C>(
+    torrent: &mut Torrent<P, C>,
+    peer: &mut peer::Peer,
+    availability: &[usize],
+    budget: &mut MemoryBudget,
+    now: Instant,
+) -> io::Result<()> {
+    if torrent.is_paused() || peer.peer_choking {
+        return Ok(());
+    }
+
+    let depth = peer
+        .stats
+        .pipeline_depth(BLOCK_LENGTH)
+        .min(peer.request_limit().unwrap_or(usize::MAX));
+
+    while peer.am_requesting.len() < depth {
+        let Some(block) = peer.unrequested.pop_front() else {
+            if claim_piece(torrent, peer, availability, budget)
+                || claim_endgame_block(torrent, peer)
+            {
+                continue;
+            }
+
+            break;
+        };
+
+        peer.stats.record_request(now);
+        peer.am_requesting.push(block.clone());
+        peer.send_message(common::peer::PeerMessage::Request { block })
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Pick a piece to download from the peer and queue all of its blocks to be requested. Returns
+/// false if the peer has nothing we want that no other peer is sending us already, or if there's
+/// no room in the memory budget for another piece.
+fn claim_piece<P: PiecePicker, C>(
+    torrent: &mut Torrent<P, C>,
+    peer: &mut peer::Peer,
+    availability: &[usize],
+    budget: &mut MemoryBudget,
+) -> bool {
+    let candidates: Vec<u32> = peer
+        .bitfield
+        .iter()
+        .filter(|&index| !torrent.have.contains(index) && !torrent.downloading.contains_key(&index))
+        .collect();
+
+    if candidates.is_empty() {
+        return false;
+    }
+
+    let have = torrent.have.count() as usize;
+
+    // Pieces that a stream is waiting on come first, in order, then any that storage needs next.
+    let streaming = torrent
+        .streaming
+        .iter()
+        .copied()
+        .chain(
+            torrent
+                .storage
+                .as_ref()
+                .and_then(|storage| storage.next_wanted()),
+        )
+        .find(|index| candidates.contains(index));
+
+    let Some(index) = streaming.or_else(|| torrent.picker.pick(&candidates, availability, have))
+    else {
+        return false;
+    };
+
+    let Some(range) = torrent.metainfo.info.piece_range(index) else {
+        return false;
+    };
+
+    let length = range.end - range.start;
+
+    if !budget.take(length) {
+        return false;
+    }
+
+    let buffer = PieceBuffer::new(length);
+    peer.unrequested.extend(buffer.missing(index));
+    torrent.downloading.insert(index, buffer);
+
+    true
+}
+
+/// In the endgame, queue a missing block to be requested from the peer too, whichever peer it was
+/// requested from first. Returns false outside the endgame, or if the peer has none of the missing
+/// blocks that we haven't asked it for already.
+fn claim_endgame_block<P, C>(torrent: &Torrent<P, C>, peer: &mut peer::Peer) -> bool {
+    if !torrent.in_endgame() {
+        return false;
+    }
+
+    let block = torrent
+        .downloading
+        .iter()
+        .filter(|(&index, _)| peer.has_piece(index))
+        .flat_map(|(&index, buffer)| buffer.missing(index))
+        .find(|block| !peer.am_requesting.contains(block));
+
+    match block {
+        Some(block) => {
+            peer.unrequested.push_back(block);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Cancel the requests that other peers have for a block that's just arrived from `from`, which
+/// only happens in the endgame.
+async fn cancel_block(
+    connections: &mut HashMap<SocketAddr, peer::Peer>,
+    info_hash: &common::InfoHash,
+    from: &SocketAddr,
+    block: &common::BlockRef,
+) {
+    for peer in connections.values_mut() {
+        if peer.info_hash != *info_hash || peer.connection.addr == *from {
+            continue;
+        }
+
+        peer.unrequested.retain(|queued| queued != block);
+
+        if !peer.am_requesting.contains(block) {
+            continue;
+        }
+
+        peer.am_requesting.retain(|requested| requested != block);
+        peer.stats.record_cancel();
+
+        let message = common::peer::PeerMessage::Cancel {
+            block: block.clone(),
+        };
+
+        if let Err(e) = peer.send_message(message).await {
+            say!("{:21} Error sending Cancel: {:?}", peer.connection.addr, e);
+        }
+    }
+}
+
+/// Send a block that a peer requested, to the peer whose turn it is. Returns whether there was a
+/// request to serve, even if its block couldn't be read and it was dropped.
+async fn serve_block<P, C>(
+    torrents: &mut Torrents<P, C>,
+    connections: &mut HashMap<SocketAddr, peer::Peer>,
+    uploads: &mut upload::Scheduler,
+) -> bool {
+    let waiting: HashMap<SocketAddr, u32> = connections
+        .values()
+        .filter(|peer| !peer.am_choking && !peer.peer_requesting.is_empty())
+        .map(|peer| {
+            let weight = upload::weight(
+                peer.stats.download.per_second(),
+                peer.stats.upload.per_second(),
+                BLOCK_LENGTH as f64,
+            );
+
+            (peer.connection.addr, weight)
+        })
+        .collect();
+
+    let Some(addr) = uploads.next(&waiting) else {
+        return false;
+    };
+
+    let Some(peer) = connections.get_mut(&addr) else {
+        return false;
+    };
+
+    let block = peer.peer_requesting.remove(0);
+
+    let Some(torrent) = torrents.get_mut(&peer.info_hash) else {
+        return true;
+    };
+
+    let Some(data) = torrent.read_block(&block).await else {
+        return true;
+    };
+
+    torrent.uploaded += data.len() as u64;
+
+    if let Err(e) = peer
+        .send_message(common::peer::PeerMessage::Piece { block, data })
+        .await
+    {
+        say!("{:21} Error sending Piece: {:?}", addr, e);
+    }
+
+    true
+}
+
+/// Ask a peer that supports BitTorrent v2 for the piece layers we're missing, as torrents may leave
+/// them out.
+async fn request_piece_layers<P, C>(
+    torrent: &Torrent<P, C>,
+    peer: &mut peer::Peer,
+) -> io::Result<()> {
+    let Some(v2) = torrent.metainfo.v2.as_ref().filter(|_| peer.supports_v2()) else {
+        return Ok(());
+    };
+
+    let piece_length = torrent.metainfo.info.piece_length();
+
+    for file in v2.missing_piece_layers(piece_length) {
+        let Some(pieces_root) = file.pieces_root else {
+            continue;
+        };
+
+        // Each request carries the uncle hashes up to the root, so that it can be checked alone.
+        let piece_count = file.length.div_ceil(piece_length) as u32;
+        let width = piece_count.next_power_of_two();
+        let length = width.min(HASH_REQUEST_MAX_LENGTH);
+
+        for index in (0..piece_count).step_by(length as usize) {
+            let request = HashRequest {
+                pieces_root,
+                base_layer: merkle::piece_height(piece_length),
+                index,
+                length,
+                proof_layers: (width / length).ilog2(),
+            };
+
+            peer.send_message(common::peer::PeerMessage::HashRequest { request })
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Answer a peer's request for hashes from one of our piece layers.
+async fn serve_hashes<P, C>(
+    torrent: &Torrent<P, C>,
+    peer: &mut peer::Peer,
+    request: &HashRequest,
+) -> io::Result<()> {
+    let piece_height = merkle::piece_height(torrent.metainfo.info.piece_length());
+
+    let hashes = torrent
+        .metainfo
+        .v2
+        .as_ref()
+        .and_then(|v2| v2.piece_layers.get(&request.pieces_root))
+        .filter(|_| request.base_layer == piece_height && request.length <= HASHES_MAX_LENGTH)
+        .and_then(|layer| {
+            merkle::proof(
+                layer,
+                MerkleHash::padding(piece_height),
+                request.index as usize,
+                request.length as usize,
+                request.proof_layers,
+            )
+        });
+
+    let message = match hashes {
+        Some(hashes) => common::peer::PeerMessage::Hashes {
+            request: *request,
+            hashes,
+        },
+        None => common::peer::PeerMessage::HashReject { request: *request },
+    };
+
+    peer.send_message(message).await
+}
+
+/// Let the choker decide which of the torrent's interested peers to upload to, and tell every
+/// peer whose status changed. Unless we're seeding, peers over the upload quota aren't candidates.
+async fn rechoke<P, C: Choker>(
+    torrent: &mut Torrent<P, C>,
+    connections: &mut HashMap<SocketAddr, peer::Peer>,
+    now: Instant,
+) {
+    let info_hash = *torrent.metainfo.info_hash();
+    let seeding = torrent.have.is_full();
+
+    let candidates: Vec<Candidate> = connections
+        .values_mut()
+        .filter(|peer| peer.info_hash == info_hash && peer.peer_interested)
+        .filter_map(|peer| match torrent.upload_quota {
+            Some(quota) if !seeding && peer.stats.exceeds_quota(&quota, now) => None,
+            _ => Some(peer),
+        })
+        .map(|peer| Candidate {
+            addr: peer.connection.addr,
+            download_rate: peer.stats.download.per_second(),
+            upload_rate: peer.stats.upload.per_second(),
+            am_choking: peer.am_choking,
+            connected_at: peer.stats.connected_at,
+        })
+        .collect();
+
+    let unchoked = torrent.choker.rechoke(&candidates, seeding, now);
+
+    for peer in connections
+        .values_mut()
+        .filter(|peer| peer.info_hash == info_hash)
+    {
+        let choke = !unchoked.contains(&peer.connection.addr);
+
+        if choke == peer.am_choking {
+            continue;
+        }
+
+        let (message, name) = if choke {
+            (common::peer::PeerMessage::Choke, "Choke")
+        } else {
+            (common::peer::PeerMessage::Unchoke, "Unchoke")
+        };
+
+        peer.am_choking = choke;
+
+        if let Err(e) = peer.send_message(message).await {
+            say!(
+                "{:21} Error sending {}: {:?}",
+                peer.connection.addr,
+                name,
+                e
+            );
+        }
+    }
+}
+
+/// What identifies the data of a piece across torrents: its hash and its length.
+fn piece_key(info: &common::metainfo::Info, index: u32) -> Option<(common::metainfo::Piece, u64)> {
+    let piece = info.pieces().get(index as usize)?.clone();
+    let range = info.piece_range(index)?;
+
+    Some((piece, range.end - range.start))
+}
+
+/// Take torrents out of the session, and delete their files too with `delete_files`.
+fn remove_torrents<P, C>(
+    torrents: &mut Torrents<P, C>,
+    connections: &mut HashMap<SocketAddr, peer::Peer>,
+    info_hashes: &[common::InfoHash],
+    delete_files: bool,
+    state_dir: &Path,
+    announcer: &mpsc::UnboundedSender<tracker::Outgoing>,
+) {
+    for info_hash in info_hashes {
+        announce_event(torrents, info_hash, Event::Stopped, announcer);
+
+        let Some(torrent) = torrents.remove(info_hash) else {
+            continue;
+        };
+
+        for addr in torrent.peer_connections.keys() {
+            if let Some(peer) = connections.remove(addr) {
+                peer.close(peer::CloseReason::Local);
+            }
+        }
+
+        if let Err(e) = resume::forget_torrent(state_dir, info_hash) {
+            say!(
+                "Unable to remove {} from the saved session: {}",
+                info_hash,
+                e
+            );
+        }
+
+        let name = torrent.metainfo.info.name().to_string();
+
+        let files_deleted = if delete_files {
+            match files::delete(torrent.save_dir(), &torrent.metainfo.info) {
+                Ok(count) => Some(count),
+                Err(e) => {
+                    say!("Unable to delete the files of {}: {}", name, e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        output::event(output::Event::TorrentRemoved {
+            info_hash: *info_hash,
+            name,
+            files_deleted,
+        });
+    }
+}
+
+/// Ask the announce task to announce `torrent` to `url`.
+fn send_announce<P, C>(
+    torrent: &Torrent<P, C>,
+    url: String,
+    event: Option<Event>,
+    announcer: &mpsc::UnboundedSender<tracker::Outgoing>,
+) {
+    let outgoing = tracker::Outgoing {
+        announce_url: url,
+        info_hash: *torrent.metainfo.info_hash(),
+        uploaded: torrent.uploaded,
+        downloaded: torrent.downloaded,
+        left: torrent.left().into(),
+        event,
+        numwant: None,
+        key: Some(torrent.key.clone()),
+    };
+
+    // The announce task only stops with the session.
+    announcer.send(outgoing).ok();
+}
+
+/// Tell every tracker that has heard from the torrent with `info_hash` about `event` straight
+/// away, rather than at its next announce.
+fn announce_event<P, C>(
+    torrents: &Torrents<P, C>,
+    info_hash: &common::InfoHash,
+    event: Event,
+    announcer: &mpsc::UnboundedSender<tracker::Outgoing>,
+) {
+    let Some(torrent) = torrents.get(info_hash) else {
+        return;
+    };
+
+    for url in torrent.trackers.started() {
+        send_announce(torrent, url.to_string(), Some(event), announcer);
+    }
+}
+
+/// Close the connection to a peer and forget it.
+fn disconnect<P, C>(
+    torrents: &mut Torrents<P, C>,
+    connections: &mut HashMap<SocketAddr, peer::Peer>,
+    addr: &SocketAddr,
+    reason: peer::CloseReason,
+) {
+    if let Some(peer) = connections.remove(addr) {
+        forget_peer(torrents, connections, &peer);
+        peer.close(reason);
+    }
+}
+
+/// Take a peer that's been disconnected out of its torrent, letting go of the pieces it was
+/// sending us so that other peers can be asked for them. Pieces that the remaining `connections`
+/// are sending us too, as in the endgame, are kept.
+fn forget_peer<P, C>(
+    torrents: &mut Torrents<P, C>,
+    connections: &HashMap<SocketAddr, peer::Peer>,
+    peer: &peer::Peer,
+) {
+    let Some(torrent) = torrents.get_mut(&peer.info_hash) else {
+        return;
+    };
+
+    torrent.peer_connections.remove(&peer.connection.addr);
+
+    let shared = |index: u32| {
+        connections
+            .values()
+            .filter(|other| other.info_hash == peer.info_hash)
+            .flat_map(|other| other.am_requesting.iter().chain(&other.unrequested))
+            .any(|block| block.index() == index)
+    };
+
+    for block in peer.am_requesting.iter().chain(&peer.unrequested) {
+        if !shared(block.index()) {
+            torrent.downloading.remove(&block.index());
+        }
+    }
+}
+
+/// Go into standby, telling every tracker that has heard from a torrent that it's stopped and
+/// closing every connection.
+fn standby<P, C>(
+    torrents: &mut Torrents<P, C>,
+    connections: &mut HashMap<SocketAddr, peer::Peer>,
+    announcer: &mpsc::UnboundedSender<tracker::Outgoing>,
+    now: Instant,
+) {
+    for torrent in torrents.values_mut() {
+        for url in torrent.trackers.stop(now) {
+            send_announce(torrent, url, Some(Event::Stopped), announcer);
+        }
+    }
+
+    close_all(torrents, connections);
+}
+
+/// Come out of standby, announcing each torrent that isn't paused to its trackers as started.
+fn resume<P, C>(
+    torrents: &mut Torrents<P, C>,
+    announcer: &mpsc::UnboundedSender<tracker::Outgoing>,
+    now: Instant,
+) {
+    for torrent in torrents.values_mut().filter(|torrent| !torrent.is_paused()) {
+        for (url, event) in torrent.trackers.due(now) {
+            send_announce(torrent, url, event, announcer);
+        }
+    }
+}
+
+fn close_all<P, C>(
+    torrents: &mut Torrents<P, C>,
+    connections: &mut HashMap<SocketAddr, peer::Peer>,
+) {
+    for (_, peer) in connections.drain() {
+        peer.close(peer::CloseReason::Local);
+    }
+
+    for torrent in torrents.values_mut() {
+        torrent.peer_connections.clear();
+    }
+}
+
+fn print_status<P, C>(
+    torrents: &Torrents<P, C>,
+    connections: &HashMap<SocketAddr, peer::Peer>,
+    now: Instant,
+) {
+    for (info_hash, torrent) in torrents.iter() {
+        output::event(output::Event::Progress {
+            info_hash: *info_hash,
+            name: torrent.metainfo.info.name().to_string(),
+            downloaded: torrent.have_bytes().into(),
+            length: torrent.metainfo.info.length(),
+            pieces_have: torrent.have.count(),
+            pieces: torrent.have.piece_count(),
+            peers: connections
+                .values()
+                .filter(|peer| &peer.info_hash == info_hash)
+                .count(),
+        });
+
+        for peer in connections
+            .values()
+            .filter(|peer| &peer.info_hash == info_hash)
+        {
+            say!("  {:>5.1}% {}", peer.progress() * 100.0, peer.summary(now));
+        }
+    }
+}
+
+/// Report that the torrent with `info_hash` has finished downloading, and whether it was the last.
+fn notify_finished<P, C>(
+    torrents: &Torrents<P, C>,
+    info_hash: &common::InfoHash,
+    notifier: &notify::Notifier,
+) {
+    let Some(torrent) = torrents.get(info_hash) else {
+        return;
+    };
+
+    say!("Finished {}", torrent.metainfo.info.name());
+
+    notifier.send(notify::Notification::Finished {
+        name: torrent.metainfo.info.name().to_string(),
+        info_hash: *info_hash,
+    });
+
+    if torrents.values().all(|torrent| torrent.have.is_full()) {
+        notifier.send(notify::Notification::AllFinished {
+            torrents: torrents.len(),
+        });
+    }
+}
+
+/// Announce a newly completed piece to every peer connected for the torrent.
+async fn send_have(
+    connections: &mut HashMap<SocketAddr, peer::Peer>,
+    info_hash: &common::InfoHash,
+    index: u32,
+    suppress_redundant: bool,
+) {
+    for peer in connections
+        .values_mut()
+        .filter(|peer| &peer.info_hash == info_hash)
+    {
+        if let Err(e) = peer.send_have(index, suppress_redundant).await {
+            say!("{:21} Error sending Have: {:?}", peer.connection.addr, e);
+        }
+    }
+}
+
+/// If the swarm for this torrent has stalled, hand all missing pieces to the web seeds.
+fn start_webseed_fallback<P, C>(
+    torrent: &mut Torrent<P, C>,
+    now: Instant,
+    sender: &mpsc::Sender<Incoming>,
+    http_client: &reqwest::Client,
+) {
+    let seeds = webseed::WebSeed::all(&torrent.metainfo);
+
+    if seeds.is_empty() || torrent.is_paused() || !torrent.webseed_fallback.should_activate(now) {
+        return;
+    }
+
+    let missing: Vec<u32> = (0..torrent.have.piece_count())
+        .filter(|&index| !torrent.have.contains(index))
+        .collect();
+
+    if missing.is_empty() {
+        return;
+    }
+
+    say!(
+        "{}: no progress from peers, fetching {} pieces from {} web seeds",
+        torrent.metainfo.info_hash(),
+        missing.len(),
+        seeds.len(),
+    );
+
+    tokio::spawn(webseed::fetch_pieces(
+        sender.clone(),
+        http_client.clone(),
+        torrent.metainfo.clone(),
+        seeds,
+        missing,
+    ));
+}
+
+async fn show(
+    path: &Path,
+    http_client: &reqwest::Client,
+    max_response: common::Bytes,
+) -> Result<(), common::Error> {
+    let metainfo = load_metainfo(&Source::File(path.to_path_buf()))?;
+
+    say!("Name:      {}", metainfo.info.name());
+    say!("Info hash: {}", metainfo.info_hash());
+    say!("Size:      {}", common::Bytes::from(metainfo.info.length()));
+    say!("Pieces:    {}", metainfo.info.pieces().len());
+
+    for (url, result) in scrape_all(&metainfo, http_client, max_response).await {
+        match result {
+            Ok(file) => say!(
+                "{url}: {} seeders, {} leechers, {} downloads",
+                file.complete,
+                file.incomplete,
+                file.downloaded,
+            ),
+            Err(e) => say!("{url}: {e}"),
+        }
+    }
+
+    Ok(())
+}
+
+async fn scrape_all<'a>(
+    metainfo: &'a common::metainfo::MetainfoFile,
+    http_client: &reqwest::Client,
+    max_response: common::Bytes,
+) -> Vec<(
+    &'a str,
+    Result<common::tracker::ScrapeFile, tracker::TrackerError>,
+)> {
+    let mut results = Vec::new();
+
+    for url in metainfo.announce_urls() {
+        results.push((
+            url,
+            tracker::scrape(http_client, url, *metainfo.info_hash(), max_response).await,
+        ));
+    }
+
+    results
+}
+
+/// The torrents on the command line, each with the last --download-dir given before it.
+fn items(matches: &clap::ArgMatches) -> Vec<Item> {
+    let download_dirs: Vec<(usize, &PathBuf)> = matches
+        .indices_of("download_dir")
+        .into_iter()
+        .flatten()
+        .zip(
+            matches
+                .get_many::<PathBuf>("download_dir")
+                .into_iter()
+                .flatten(),
+        )
+        .collect();
+
+    matches
+        .indices_of("torrents")
+        .into_iter()
+        .flatten()
+        .zip(matches.get_many::<String>("torrents").into_iter().flatten())
+        .map(|(index, torrent)| Item {
+            source: if torrent == "-" {
+                Source::Stdin
+            } else if torrent.starts_with("magnet:") {
+                Source::Magnet(torrent.clone())
+            } else {
+                Source::File(PathBuf::from(torrent))
+            },
+            download_dir: download_dirs
+                .iter()
+                .rev()
+                .find(|(dir_index, _)| *dir_index < index)
+                .map(|(_, dir)| dir.to_path_buf()),
+        })
+        .collect()
+}
+
+/// Read a metainfo file from a file or standard input.
+fn load_metainfo(source: &Source) -> Result<common::metainfo::MetainfoFile, common::Error> {
+    let bytes = match source {
+        Source::File(path) => fs::read(path),
+        Source::Stdin => {
+            let mut bytes = Vec::new();
+            io::stdin().lock().read_to_end(&mut bytes).map(|_| bytes)
+        }
+        Source::Magnet(_) => return Err("Magnet links are not metainfo files".into()),
+    };
+
+    bytes.map_err(|e| e.to_string())?.as_slice().try_into()
+}
+
+/// The torrents saved in the state directory by the last run, with the data they already have
+/// checked against their hashes so that it isn't downloaded again.
+fn restore<P: Clone, C: Clone>(
+    args: &Args,
+    picker: &P,
+    choker: &C,
+    clock: &dyn common::Clock,
+) -> Vec<Torrent<P, C>> {
+    resume::saved_torrents(&args.state_dir)
+        .into_iter()
+        .map(|metainfo| {
+            let mut torrent =
+                Torrent::new(metainfo, picker.clone(), choker.clone(), args, clock.now());
+            let name = torrent.metainfo.info.name().to_string();
+
+            match torrent.recheck(clock) {
+                Ok(have) => say!(
+                    "{}: {} of {} pieces already downloaded",
+                    name,
+                    have,
+                    torrent.have.piece_count(),
+                ),
+                Err(e) => say!("{}: unable to check the downloaded data: {}", name, e),
+            }
+
+            torrent
+        })
+        .collect()
+}
+
+/// Find the metainfo for a magnet link among the copies kept for the torrents of earlier sessions.
+async fn load_magnet(
+    magnet: &common::MagnetUri,
+    args: &Args,
+    peer_id: common::PeerId,
+    http_client: &reqwest::Client,
+) -> Result<common::metainfo::MetainfoFile, common::Error> {
+    let path = resume::ResumeData::metainfo_path(&args.state_dir, &magnet.info_hash);
+
+    match fs::read(path) {
+        Ok(bytes) => bytes.as_slice().try_into(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            say!("Fetching the metadata of {} from peers", magnet.name());
+
+            metadata::fetch(
+                magnet,
+                peer_id,
+                args.port,
+                http_client,
+                args.max_tracker_response,
+                &args.user_agent,
+            )
+            .await
+        }
+        Err(e) => Err(e.to_string().into()),
+    }
+}
+
+fn parse_client_id(input: &str) -> Result<String, &'static str> {
+    common::PeerId::check_client_id(input).map(|()| input.to_string())
+}
+
+fn parse_client_version(input: &str) -> Result<String, &'static str> {
+    common::PeerId::check_client_version(input).map(|()| input.to_string())
+}
+
+/// Accept a User-Agent of printable ASCII, which any HTTP server will take as a header value.
+fn parse_user_agent(input: &str) -> Result<String, &'static str> {
+    if input.trim().is_empty() {
+        return Err("User-Agent must not be empty");
+    }
+
+    if !input.bytes().all(|b| b == b' ' || b.is_ascii_graphic()) {
+        return Err("User-Agent must consist of printable ASCII characters");
+    }
+
+    Ok(input.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::process;
+
+    use sha1::{Digest, Sha1};
+
+    #[test]
+    fn items_test() {
+        let matches = Args::command().get_matches_from([
+            "toytorrent",
+            "a.torrent",
+            "--download-dir",
+            "/x",
+            "-",
+            "magnet:?xt=urn:btih:0000000000000000000000000000000000000000",
+            "b.torrent",
+            "--download-dir",
+            "/y",
+            "c.torrent",
+        ]);
+
+        let item = |source, download_dir: Option<&str>| Item {
+            source,
+            download_dir: download_dir.map(PathBuf::from),
+        };
+
+        assert_eq!(
+            vec![
+                item(Source::File("a.torrent".into()), None),
+                item(Source::Stdin, Some("/x")),
+                item(
+                    Source::Magnet(
+                        "magnet:?xt=urn:btih:0000000000000000000000000000000000000000".into(),
+                    ),
+                    Some("/x"),
+                ),
+                item(Source::File("b.torrent".into()), Some("/x")),
+                item(Source::File("c.torrent".into()), Some("/y")),
+            ],
+            items(&matches),
+        );
+    }
+
+    #[test]
+    fn piece_buffer_test() {
+        let mut buffer = PieceBuffer::new(BLOCK_LENGTH + 100);
+        let first = common::BlockRef::new(7, 0, BLOCK_LENGTH as u32);
+        let last = common::BlockRef::new(7, BLOCK_LENGTH as u32, 100);
+
+        assert_eq!(
+            vec![first.clone(), last.clone()],
+            buffer.missing(7).collect::<Vec<_>>()
+        );
+
+        let a = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let b = SocketAddr::from(([10, 0, 0, 2], 6881));
+
+        assert!(!buffer.receive(&common::BlockRef::new(7, 1, 100), &[1; 100], a));
+        assert!(!buffer.receive(&last, &[1; 99], a));
+        assert!(buffer.receive(&last, &[1; 100], a));
+        assert!(!buffer.receive(&last, &[2; 100], b));
+
+        assert_eq!(vec![first.clone()], buffer.missing(7).collect::<Vec<_>>());
+        assert_eq!(&[1; 100], &buffer.data[BLOCK_LENGTH as usize..]);
+        assert_eq!(HashSet::from([a]), buffer.senders());
+
+        assert!(buffer.receive(&first, &[2; BLOCK_LENGTH as usize], b));
+        assert_eq!(HashSet::from([a, b]), buffer.senders());
+    }
+
+    #[test]
+    fn left_test() {
+        let file = |length, path: &str, attr: Option<&str>| common::metainfo::File {
+            length,
+            md5sum: None,
+            path: vec![path.to_string()],
+            attr: attr.map(str::to_string),
+        };
+
+        // Two pieces: the first is 10 bytes of `a` and 6 of padding, the second 5 bytes of `b`.
+        let info = common::metainfo::Info::MultiFile {
+            piece_length: 16,
+            pieces: vec![[0; 20].into(); 2],
+            name: "padded".to_string(),
+            files: vec![
+                file(10, "a", None),
+                file(6, ".pad/6", Some("p")),
+                file(5, "b", Some("x")),
+            ],
+        };
+
+        let mut torrent = torrent(&info, "http://localhost/announce");
+        assert!(matches!(
+            &torrent.metainfo.info,
+            common::metainfo::Info::MultiFile { files, .. } if files[1].is_padding(),
+        ));
+
+        assert_eq!(common::Bytes::from(15), torrent.left());
+        torrent.have.insert(1);
+        assert_eq!(common::Bytes::from(10), torrent.left());
+        torrent.have.insert(0);
+        assert_eq!(common::Bytes::from(0), torrent.left());
+    }
+
+    #[tokio::test]
+    async fn standby_test() {
+        let body = b"d8:intervali900e5:peers0:e".to_vec();
+        let (url, mut requests) =
+            tracker::test::fake_tracker(vec![(200, body.clone()), (200, body)]).await;
+        let (announcer, _receiver) =
+            tracker::test::spawn_announce(1024.into(), quirks::Quirks::default());
+
+        let info = common::metainfo::Info::SingleFile {
+            piece_length: 16,
+            pieces: vec![[0; 20].into()],
+            name: "standby".to_string(),
+            length: 16,
+            md5sum: None,
+        };
+        let now = Instant::now();
+
+        // The tracker has already acknowledged that the torrent started.
+        let mut torrent = torrent(&info, &url);
+        torrent.trackers.due(now + Duration::from_secs(10));
+        torrent
+            .trackers
+            .succeeded(&url, Duration::from_secs(900), now);
+        let mut torrents = Torrents(HashMap::from([(*torrent.metainfo.info_hash(), torrent)]));
+
+        standby(&mut torrents, &mut HashMap::new(), &announcer, now);
+        let request = requests.recv().await.unwrap();
+        assert!(request.contains("event=stopped"), "{request}");
+
+        resume(&mut torrents, &announcer, now);
+        let request = requests.recv().await.unwrap();
+        assert!(request.contains("event=started"), "{request}");
+    }
+
+    #[tokio::test]
+    async fn download_test() {
+        let dir = std::env::temp_dir().join(format!("toytorrent-download-{}", process::id()));
+        let args = args(&dir.join("state"));
+
+        // `a` is padded out to the second piece, which `b` starts.
+        let data: Vec<u8> = (1..=20).chain([0; 12]).chain(21..=30).collect();
+        let file = |length, path: &str, attr: Option<&str>| common::metainfo::File {
+            length,
+            md5sum: None,
+            path: path.split('/').map(str::to_string).collect(),
+            attr: attr.map(str::to_string),
+        };
+        let info = common::metainfo::Info::MultiFile {
+            piece_length: 16,
+            pieces: data
+                .chunks(16)
+                .map(|chunk| <[u8; 20]>::from(Sha1::digest(chunk)).into())
+                .collect(),
+            name: "download".to_string(),
+            files: vec![
+                file(20, "a", None),
+                file(12, ".pad/12", Some("p")),
+                file(10, "b", None),
+            ],
+        };
+
+        let mut torrent = Torrent::new(
+            metainfo(&info, "http://localhost/announce"),
+            (),
+            (),
+            &args,
+            Instant::now(),
+        );
+        torrent.download_dir = Some(dir.join("downloads"));
+        let info_hash = *torrent.metainfo.info_hash();
+
+        let mut torrents = Torrents::new();
+        assert!(torrents.add(torrent, &args).await);
+        let torrent = torrents.get_mut(&info_hash).unwrap();
+        let peer = SocketAddr::from(([10, 0, 0, 1], 6881));
+
+        for (index, piece) in (0..).zip(data.chunks(16)) {
+            let block = common::BlockRef::new(index, 0, piece.len() as u32);
+            torrent
+                .downloading
+                .insert(index, PieceBuffer::new(piece.len() as u64));
+
+            assert!(!torrent.has_piece(index));
+            assert_eq!(
+                Ok(Some(index)),
+                torrent.write_block(&block, piece, peer).await
+            );
+            assert!(torrent.has_piece(index));
+        }
+
+        let block = common::BlockRef::new(1, 2, 6);
+        assert_eq!(
+            Some(data[18..24].to_vec()),
+            torrent.read_block(&block).await
+        );
+
+        // Streams are read up to the end of the piece.
+        assert_eq!(
+            Some(data[18..24].to_vec()),
+            torrent.stream_read(18, 6).await
+        );
+        assert_eq!(
+            Some(data[28..32].to_vec()),
+            torrent.stream_read(28, 100).await
+        );
+
+        let files = dir.join("downloads").join("download");
+        let (a, b) = (fs::read(files.join("a")), fs::read(files.join("b")));
+
+        // Changing `b` behind our back loses the piece it's in, and only that one.
+        fs::write(files.join("b"), b"changed").unwrap();
+        torrent.check_files().await;
+        assert!(!torrent.has_piece(2));
+        assert!(torrent.has_piece(0) && torrent.has_piece(1));
+
+        let padded = files.join(".pad").exists();
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(&data[..20], &a.unwrap()[..]);
+        assert_eq!(&data[32..], &b.unwrap()[..]);
+        assert!(!padded);
+    }
+
+    #[tokio::test]
+    async fn reuse_test() {
+        let dir = std::env::temp_dir().join(format!("toytorrent-reuse-{}", process::id()));
+        let args = args(&dir.join("state"));
+
+        let piece = |byte: u8| vec![byte; 16];
+        let info = |name: &str, pieces: &[&Vec<u8>]| common::metainfo::Info::SingleFile {
+            piece_length: 16,
+            pieces: pieces
+                .iter()
+                .map(|data| <[u8; 20]>::from(Sha1::digest(data)).into())
+                .collect(),
+            name: name.to_string(),
+            length: 16 * pieces.len() as u64,
+            md5sum: None,
+        };
+        let (a, b) = (piece(1), piece(2));
+
+        // The second torrent has `a` twice, and a piece that the first doesn't.
+        let mut torrents = Torrents::new();
+        let mut info_hashes = Vec::new();
+
+        for info in [
+            info("first", &[&a, &b]),
+            info("second", &[&a, &a, &piece(3)]),
+        ] {
+            let metainfo = metainfo(&info, "http://localhost/announce");
+            let mut torrent = Torrent::new(metainfo, (), (), &args, Instant::now());
+            torrent.storage = Some(Box::new(Memory::default()));
+            info_hashes.push(*torrent.metainfo.info_hash());
+
+            if info_hashes.len() == 1 {
+                assert!(torrent.write_piece(0, a.clone()).await);
+                assert!(torrent.write_piece(1, b.clone()).await);
+            }
+
+            assert!(torrents.add(torrent, &args).await);
+        }
+
+        let second = torrents.get_mut(&info_hashes[1]).unwrap();
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(vec![0, 1], second.have.iter().collect::<Vec<_>>());
+        let block = common::BlockRef::new(1, 0, 16);
+        assert_eq!(Some(a), second.read_block(&block).await);
+    }
+
+    #[tokio::test]
+    async fn restore_test() {
+        use common::Clock;
+
+        let dir = std::env::temp_dir().join(format!("toytorrent-restore-{}", process::id()));
+        let args = args(&dir.join("state"));
+        let clock = common::ManualClock::new();
+
+        let data: Vec<u8> = (1..=20).collect();
+        let info = common::metainfo::Info::SingleFile {
+            piece_length: 16,
+            pieces: data
+                .chunks(16)
+                .map(|chunk| <[u8; 20]>::from(Sha1::digest(chunk)).into())
+                .collect(),
+            name: "restore".to_string(),
+            length: data.len() as u64,
+            md5sum: None,
+        };
+
+        // A completed download, left paused when the client stopped.
+        fs::create_dir_all(dir.join("downloads")).unwrap();
+        fs::write(dir.join("downloads").join("restore"), &data).unwrap();
+
+        let metainfo = metainfo(&info, "http://localhost/announce");
+        let info_hash = *metainfo.info_hash();
+        let mut torrent = Torrent::new(metainfo, (), (), &args, clock.now());
+        torrent.download_dir = Some(dir.join("downloads"));
+
+        let mut torrents = Torrents::new();
+        assert!(torrents.add(torrent, &args).await);
+        torrents.apply(&info_hash, status::Action::Pause, &args.state_dir);
+        drop(torrents);
+
+        let restored = restore(&args, &(), &(), &clock);
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(1, restored.len());
+        assert_eq!(&info_hash, restored[0].metainfo.info_hash());
+        assert_eq!(common::Bytes::from(0), restored[0].left());
+        assert!(restored[0].have.is_full());
+        assert!(restored[0].paused);
+        assert_eq!(Some(dir.join("downloads")), restored[0].download_dir);
+    }
+
+    /// The arguments to run with, keeping state in `state_dir`.
+    fn args(state_dir: &Path) -> Args {
+        Args::parse_from([
+            "toytorrent",
+            "--state-dir",
+            state_dir.to_str().unwrap(),
+            "test.torrent",
+        ])
+    }
+
+    fn metainfo(info: &common::metainfo::Info, announce: &str) -> common::metainfo::MetainfoFile {
+        let fields: [(&str, common::BencodeValue); 2] =
+            [("announce", announce.into()), ("info", info.into())];
+
+        fields
+            .into_iter()
+            .collect::<common::BencodeValue>()
+            .try_into()
+            .unwrap()
+    }
+
+    /// A torrent of `info` announced to `announce`, with its state kept in a temporary directory.
+    fn torrent(info: &common::metainfo::Info, announce: &str) -> Torrent<(), ()> {
+        let state_dir =
+            std::env::temp_dir().join(format!("toytorrent-{}-{}", info.name(), process::id(),));
+        let metainfo = metainfo(info, announce);
+        let torrent = Torrent::new(metainfo, (), (), &args(&state_dir), Instant::now());
+        fs::remove_dir_all(&state_dir).ok();
+        torrent
+    }
 }
@@ -1,10 +1,8 @@
-use clap::Parser;
-
 use toytorrent_client as client;
 
-#[async_std::main]
+#[tokio::main]
 async fn main() {
-    let args = client::Args::parse();
+    let args = client::Args::from_command_line();
 
     client::run(args).await;
 }
@@ -0,0 +1,575 @@
+//! Fetching the metadata of a magnet link from peers, with the metadata extension (BEP 9). The
+//! link's trackers and `x.pe` peers are asked for peers to connect to, and each peer that supports
+//! `ut_metadata` is asked for the pieces of the info dictionary that are still missing, until they
+//! add up to a dictionary with the link's info hash.
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use sha1::{Digest, Sha1};
+use tokio::net;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio::time;
+
+use toytorrent_common as common;
+
+use super::{peer, tracker, Incoming};
+use common::peer::{MetadataMessage, PeerMessage, METADATA_PIECE_LEN, UT_METADATA};
+
+/// How long to look for the metadata before giving up.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The message ID for peers to send us `ut_metadata` messages with.
+const UT_METADATA_ID: u8 = 1;
+
+/// The largest info dictionary to accept. Real ones are far smaller, and this keeps a peer from
+/// having us set aside whatever it claims.
+const MAX_METADATA_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The pieces of the info dictionary fetched so far, each with the peer that sent it.
+#[derive(Debug)]
+struct Metadata {
+    size: usize,
+    pieces: Vec<Option<(Vec<u8>, SocketAddr)>>,
+}
+
+impl Metadata {
+    fn new(size: u64) -> Option<Self> {
+        if size == 0 || size > MAX_METADATA_SIZE {
+            return None;
+        }
+
+        let size = size as usize;
+
+        Some(Self {
+            size,
+            pieces: vec![None; size.div_ceil(METADATA_PIECE_LEN)],
+        })
+    }
+
+    fn missing(&self) -> Vec<u32> {
+        (0..self.pieces.len() as u32)
+            .filter(|&piece| self.pieces[piece as usize].is_none())
+            .collect()
+    }
+
+    /// Keep a piece that `from` sent, if it's one of ours and the right length.
+    fn insert(&mut self, piece: u32, total_size: u64, data: Vec<u8>, from: SocketAddr) {
+        let index = piece as usize;
+
+        if total_size != self.size as u64 || index >= self.pieces.len() {
+            return;
+        }
+
+        if data.len() == (self.size - index * METADATA_PIECE_LEN).min(METADATA_PIECE_LEN) {
+            self.pieces[index] = Some((data, from));
+        }
+    }
+
+    /// The whole info dictionary, once every piece is in.
+    fn assemble(&self) -> Option<Vec<u8>> {
+        self.pieces
+            .iter()
+            .map(|piece| piece.as_ref().map(|(data, _)| &data[..]))
+            .collect::<Option<Vec<_>>>()
+            .map(|pieces| pieces.concat())
+    }
+
+    /// Throw away every piece, returning the peers that sent them.
+    fn clear(&mut self) -> HashSet<SocketAddr> {
+        self.pieces
+            .iter_mut()
+            .filter_map(|piece| piece.take().map(|(_, from)| from))
+            .collect()
+    }
+}
+
+/// Fetch the metadata of `magnet` from peers, naming ourselves `client` to them, and return it as
+/// a metainfo file listing the link's trackers.
+pub async fn fetch(
+    magnet: &common::MagnetUri,
+    peer_id: common::PeerId,
+    port: u16,
+    http_client: &reqwest::Client,
+    max_response: common::Bytes,
+    client: &str,
+) -> Result<common::metainfo::MetainfoFile, common::Error> {
+    let fetch_info = fetch_info(magnet, peer_id, port, http_client, max_response, client);
+
+    let info = time::timeout(FETCH_TIMEOUT, fetch_info)
+        .await
+        .map_err(|_| format!("No peer sent the metadata within {:?}", FETCH_TIMEOUT))??;
+
+    metainfo(magnet, &info)
+}
+
+async fn fetch_info(
+    magnet: &common::MagnetUri,
+    peer_id: common::PeerId,
+    port: u16,
+    http_client: &reqwest::Client,
+    max_response: common::Bytes,
+    client: &str,
+) -> Result<Vec<u8>, common::Error> {
+    let info_hash = magnet.info_hash;
+    let reserved = common::peer::reserved_bytes(false);
+    let (sender, mut receiver) = mpsc::channel::<Incoming>(100);
+
+    // Each lookup comes up with addresses to dial, from either a tracker or an `x.pe` parameter.
+    let mut lookups: JoinSet<Result<Vec<SocketAddr>, String>> = JoinSet::new();
+
+    for url in &magnet.trackers {
+        let url = url.clone();
+        let http_client = http_client.clone();
+
+        lookups.spawn(async move {
+            tracker::find_peers(&http_client, &url, info_hash, peer_id, port, max_response)
+                .await
+                .map_err(|e| format!("{}: {}", url, e))
+        });
+    }
+
+    for addr in &magnet.peers {
+        let addr = addr.clone();
+
+        lookups.spawn(async move {
+            net::lookup_host(&addr)
+                .await
+                .map(Iterator::collect)
+                .map_err(|e| format!("{}: {}", addr, e))
+        });
+    }
+
+    let mut dials = JoinSet::new();
+    let mut dialled: HashSet<SocketAddr> = HashSet::new();
+    let mut peers: HashMap<SocketAddr, peer::Peer> = HashMap::new();
+
+    // The metadata so far for each size that peers claim it to be, so that a peer that lies about
+    // the size only holds up the pieces that it's asked for.
+    let mut metadata: HashMap<u64, Metadata> = HashMap::new();
+
+    loop {
+        if lookups.is_empty() && dials.is_empty() && peers.is_empty() {
+            return Err("No peer could send the metadata".into());
+        }
+
+        // Events come first, so that a dial is only seen to have finished once its peer is in.
+        tokio::select! {
+            biased;
+
+            Some(incoming) = receiver.recv() => {
+                let Incoming::Peer(peer::Incoming { from_socket_addr, event }) = incoming else {
+                    continue;
+                };
+
+                match event {
+                    peer::IncomingEvent::HandshakeInfoHash { is_valid_sender, .. } => {
+                        is_valid_sender.send(false).ok();
+                    }
+                    peer::IncomingEvent::Connected { peer } => {
+                        let Ok(mut peer) = peer.send_bitfield(&Default::default(), false).await
+                        else {
+                            continue;
+                        };
+
+                        if !peer.supports_extensions() {
+                            peer.close(peer::CloseReason::Local);
+                            continue;
+                        }
+
+                        let extensions = [(UT_METADATA, UT_METADATA_ID)];
+
+                        match peer.send_extended_handshake(client, &extensions).await {
+                            Ok(()) => {
+                                peers.insert(from_socket_addr, peer);
+                            }
+                            Err(e) => peer.close(e.into()),
+                        }
+                    }
+                    peer::IncomingEvent::Message { message } => {
+                        let Some(peer) = peers.get_mut(&from_socket_addr) else {
+                            continue;
+                        };
+
+                        if peer.receive(&message, Instant::now()).is_err() {
+                            close(&mut peers, from_socket_addr);
+                            continue;
+                        }
+
+                        match message {
+                            PeerMessage::Extended {
+                                id: common::peer::EXTENDED_HANDSHAKE_ID,
+                                ..
+                            } => {
+                                let size = metadata_size(peer);
+
+                                if let Some(new) = size.and_then(Metadata::new) {
+                                    metadata.entry(new.size as u64).or_insert(new);
+                                }
+
+                                let sent = match size.and_then(|size| metadata.get(&size)) {
+                                    Some(metadata) => request(peer, &metadata.missing()).await,
+                                    None => false,
+                                };
+
+                                if !sent {
+                                    close(&mut peers, from_socket_addr);
+                                }
+                            }
+                            PeerMessage::Extended {
+                                id: UT_METADATA_ID,
+                                payload,
+                            } => match MetadataMessage::try_from(&payload[..]) {
+                                Ok(MetadataMessage::Data {
+                                    piece,
+                                    total_size,
+                                    data,
+                                }) => {
+                                    let Some(size) = metadata_size(peer) else {
+                                        continue;
+                                    };
+                                    let Some(metadata) = metadata.get_mut(&size) else {
+                                        continue;
+                                    };
+
+                                    metadata.insert(piece, total_size, data, from_socket_addr);
+
+                                    let Some(info) = metadata.assemble() else {
+                                        continue;
+                                    };
+
+                                    if Sha1::digest(&info)[..] == *info_hash.as_slice() {
+                                        for (_, peer) in peers.drain() {
+                                            peer.close(peer::CloseReason::Local);
+                                        }
+
+                                        return Ok(info);
+                                    }
+
+                                    // Some peer sent a bad piece. Without knowing which, start
+                                    // over with the peers of this size that didn't send any.
+                                    for addr in metadata.clear() {
+                                        close(&mut peers, addr);
+                                    }
+
+                                    let missing = metadata.missing();
+
+                                    for peer in peers.values_mut() {
+                                        if metadata_size(peer) == Some(size) {
+                                            request(peer, &missing).await;
+                                        }
+                                    }
+                                }
+                                Ok(MetadataMessage::Request { piece }) => {
+                                    // We don't have the metadata to share.
+                                    reply(peer, MetadataMessage::Reject { piece }).await;
+                                }
+                                Ok(MetadataMessage::Reject { .. }) => {}
+                                Err(_) => close(&mut peers, from_socket_addr),
+                            },
+                            _ => {}
+                        }
+                    }
+                    peer::IncomingEvent::Closed { .. } => {
+                        peers.remove(&from_socket_addr);
+                    }
+                }
+            }
+            Some(result) = lookups.join_next() => {
+                match result.map_err(|e| e.to_string()).and_then(|addrs| addrs) {
+                    Ok(addrs) => {
+                        for addr in addrs {
+                            if dialled.insert(addr) {
+                                dials.spawn(peer::connect(
+                                    vec![addr],
+                                    peer_id,
+                                    reserved,
+                                    info_hash,
+                                    sender.clone(),
+                                ));
+                            }
+                        }
+                    }
+                    Err(e) => say!("Not finding peers for the metadata with {}", e),
+                }
+            }
+            Some(_) = dials.join_next() => {}
+        }
+    }
+}
+
+/// Ask `peer` for `pieces` of the metadata, returning whether it supports `ut_metadata` and the
+/// requests were sent.
+async fn request(peer: &mut peer::Peer, pieces: &[u32]) -> bool {
+    for &piece in pieces {
+        if !reply(peer, MetadataMessage::Request { piece }).await {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Send `message` to `peer` with the ID it asked for `ut_metadata` messages to be sent with,
+/// returning whether it was sent.
+async fn reply(peer: &mut peer::Peer, message: MetadataMessage) -> bool {
+    let Some(id) = peer
+        .extended
+        .as_ref()
+        .and_then(|extended| extended.extension_id(UT_METADATA))
+    else {
+        return false;
+    };
+
+    let payload = message.encode();
+    peer.send_message(PeerMessage::Extended { id, payload })
+        .await
+        .is_ok()
+}
+
+/// The size of the metadata that `peer` said it has in its extended handshake.
+fn metadata_size(peer: &peer::Peer) -> Option<u64> {
+    peer.extended
+        .as_ref()
+        .and_then(|extended| extended.metadata_size)
+}
+
+fn close(peers: &mut HashMap<SocketAddr, peer::Peer>, addr: SocketAddr) {
+    if let Some(peer) = peers.remove(&addr) {
+        peer.close(peer::CloseReason::Local);
+    }
+}
+
+/// A metainfo file made up of the fetched info dictionary and the link's trackers. The dictionary
+/// is kept byte for byte, since its hash is the torrent's identity.
+fn metainfo(
+    magnet: &common::MagnetUri,
+    info: &[u8],
+) -> Result<common::metainfo::MetainfoFile, common::Error> {
+    let announce = magnet.trackers.first().map_or("", String::as_str);
+    let announce_list: Vec<common::BencodeValue> = magnet
+        .trackers
+        .iter()
+        .map(|tracker| vec![common::BencodeValue::from(tracker.as_str())].into())
+        .collect();
+
+    let mut bytes = b"d".to_vec();
+    bytes.extend(common::BencodeValue::from("announce").encode());
+    bytes.extend(common::BencodeValue::from(announce).encode());
+
+    if announce_list.len() > 1 {
+        bytes.extend(common::BencodeValue::from("announce-list").encode());
+        bytes.extend(common::BencodeValue::from(announce_list).encode());
+    }
+
+    bytes.extend(common::BencodeValue::from("info").encode());
+    bytes.extend(info);
+    bytes.push(b'e');
+
+    let metainfo = common::metainfo::MetainfoFile::try_from(&bytes[..])?;
+
+    if metainfo.info_hash() != &magnet.info_hash {
+        return Err("The metadata isn't bencoded canonically, so can't be used".into());
+    }
+
+    Ok(metainfo)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    use tokio::net::TcpListener;
+
+    fn info() -> Vec<u8> {
+        let info = common::metainfo::Info::SingleFile {
+            piece_length: 16 * 1024,
+            // Enough pieces for the dictionary to take more than one metadata piece.
+            pieces: (0..1000u32)
+                .map(|i| <[u8; 20]>::from(Sha1::digest(i.to_be_bytes())).into())
+                .collect(),
+            name: "metadata-test".to_string(),
+            length: 1000 * 16 * 1024,
+            md5sum: None,
+        };
+
+        common::BencodeValue::from(&info).encode()
+    }
+
+    fn peer_id() -> common::PeerId {
+        common::PeerId::create(super::super::PEER_ID_CLIENT, super::super::PEER_ID_VERSION).unwrap()
+    }
+
+    /// Listen for peers, returning the address to reach them at, and serve `info` to anyone who
+    /// asks for it over `ut_metadata`, claiming it to be `size` bytes. Each peer hears about the
+    /// metadata after `delay`.
+    async fn spawn_seed(
+        processes: &mut JoinSet<()>,
+        info: &[u8],
+        size: usize,
+        delay: Duration,
+    ) -> SocketAddr {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, receiver) = mpsc::channel(100);
+        let reserved = common::peer::reserved_bytes(false);
+
+        processes.spawn(peer::listen(peer_id(), reserved, listener, sender));
+        processes.spawn(seed(info.to_vec(), size, delay, receiver));
+
+        addr
+    }
+
+    async fn seed(
+        info: Vec<u8>,
+        size: usize,
+        delay: Duration,
+        mut receiver: mpsc::Receiver<Incoming>,
+    ) {
+        let info_hash = common::InfoHash::from(<[u8; 20]>::from(Sha1::digest(&info)));
+        let mut peers: HashMap<SocketAddr, peer::Peer> = HashMap::new();
+
+        while let Some(incoming) = receiver.recv().await {
+            let Incoming::Peer(peer::Incoming {
+                from_socket_addr,
+                event,
+            }) = incoming
+            else {
+                continue;
+            };
+
+            match event {
+                peer::IncomingEvent::HandshakeInfoHash {
+                    info_hash: their_info_hash,
+                    is_valid_sender,
+                } => {
+                    is_valid_sender.send(their_info_hash == info_hash).ok();
+                }
+                peer::IncomingEvent::Connected { peer } => {
+                    let mut peer = peer
+                        .send_bitfield(&Default::default(), false)
+                        .await
+                        .unwrap();
+                    time::sleep(delay).await;
+
+                    let handshake = common::peer::ExtendedHandshake {
+                        extensions: [(UT_METADATA.to_string(), 3)].into(),
+                        metadata_size: Some(size as u64),
+                        ..Default::default()
+                    };
+
+                    peer.send_message(PeerMessage::Extended {
+                        id: common::peer::EXTENDED_HANDSHAKE_ID,
+                        payload: handshake.encode(),
+                    })
+                    .await
+                    .unwrap();
+
+                    peers.insert(from_socket_addr, peer);
+                }
+                peer::IncomingEvent::Message { message } => {
+                    let peer = peers.get_mut(&from_socket_addr).unwrap();
+                    peer.receive(&message, Instant::now()).unwrap();
+
+                    let PeerMessage::Extended { id: 3, payload } = message else {
+                        continue;
+                    };
+
+                    let Ok(MetadataMessage::Request { piece }) =
+                        MetadataMessage::try_from(&payload[..])
+                    else {
+                        panic!("Expected a request");
+                    };
+
+                    let start = (piece as usize * METADATA_PIECE_LEN).min(info.len());
+                    let end = (start + METADATA_PIECE_LEN).min(info.len());
+                    let data = MetadataMessage::Data {
+                        piece,
+                        total_size: size as u64,
+                        data: info[start..end].to_vec(),
+                    };
+
+                    assert!(reply(peer, data).await);
+                }
+                peer::IncomingEvent::Closed { .. } => {
+                    peers.remove(&from_socket_addr);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn metadata_test() {
+        let from: SocketAddr = (Ipv4Addr::LOCALHOST, 6881).into();
+        let mut metadata = Metadata::new(METADATA_PIECE_LEN as u64 + 10).unwrap();
+        assert_eq!(vec![0, 1], metadata.missing());
+
+        metadata.insert(1, 100, vec![0; 10], from);
+        metadata.insert(1, METADATA_PIECE_LEN as u64 + 10, vec![0; 11], from);
+        metadata.insert(2, METADATA_PIECE_LEN as u64 + 10, vec![0; 10], from);
+        assert_eq!(vec![0, 1], metadata.missing());
+
+        metadata.insert(1, METADATA_PIECE_LEN as u64 + 10, vec![1; 10], from);
+        assert_eq!(vec![0], metadata.missing());
+        assert_eq!(None, metadata.assemble());
+
+        metadata.insert(
+            0,
+            METADATA_PIECE_LEN as u64 + 10,
+            vec![0; METADATA_PIECE_LEN],
+            from,
+        );
+        assert_eq!(
+            Some(METADATA_PIECE_LEN + 10),
+            metadata.assemble().map(|info| info.len())
+        );
+
+        assert_eq!(HashSet::from([from]), metadata.clear());
+        assert_eq!(vec![0, 1], metadata.missing());
+
+        assert!(Metadata::new(0).is_none());
+        assert!(Metadata::new(MAX_METADATA_SIZE + 1).is_none());
+    }
+
+    #[tokio::test]
+    async fn fetch_test() {
+        let info = info();
+        assert!(info.len() > METADATA_PIECE_LEN);
+
+        let info_hash = common::InfoHash::from(<[u8; 20]>::from(Sha1::digest(&info)));
+        let mut processes = JoinSet::new();
+
+        // The first peer to answer lies about the size, which mustn't hold up the honest one.
+        let liar = spawn_seed(&mut processes, &info, info.len() + 1, Duration::ZERO).await;
+        let honest = spawn_seed(
+            &mut processes,
+            &info,
+            info.len(),
+            Duration::from_millis(200),
+        )
+        .await;
+
+        let magnet: common::MagnetUri = format!(
+            "magnet:?xt=urn:btih:{}&tr=http%3A%2F%2Ftracker.invalid%2Fannounce&x.pe={}&x.pe={}",
+            info_hash, liar, honest,
+        )
+        .parse()
+        .unwrap();
+
+        let http_client = tracker::http_client(Default::default(), super::super::USER_AGENT);
+        let metainfo = fetch(&magnet, peer_id(), 6881, &http_client, 1024.into(), "test")
+            .await
+            .unwrap();
+
+        assert_eq!(&info_hash, metainfo.info_hash());
+        assert_eq!(
+            vec!["http://tracker.invalid/announce"],
+            metainfo.announce_urls()
+        );
+        assert_eq!(1000, metainfo.info.pieces().len());
+    }
+}
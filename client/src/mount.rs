@@ -0,0 +1,292 @@
+//! A read-only FUSE filesystem of a torrent's files. Reading a file downloads the pieces it needs
+//! first and blocks until they're verified, so only what's read of a large torrent is fetched.
+
+use std::ffi::OsStr;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use fuser::{
+    BackgroundSession, FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData,
+    ReplyDirectory, ReplyEntry, Request,
+};
+use tokio::sync::{mpsc, oneshot};
+
+use toytorrent_common as common;
+
+use super::stream;
+
+/// How long the kernel may cache attributes. Nothing ever changes.
+const TTL: Duration = Duration::from_secs(3600);
+
+/// How long to wait before asking again for a piece we don't have yet.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+const ROOT_INO: u64 = 1;
+
+#[derive(Debug)]
+struct Node {
+    name: String,
+    parent: u64,
+    kind: NodeKind,
+}
+
+#[derive(Debug)]
+enum NodeKind {
+    Directory { children: Vec<u64> },
+    File { offset: u64, length: u64 },
+}
+
+#[derive(Debug)]
+struct TorrentFs {
+    info_hash: common::InfoHash,
+
+    /// Indexed by inode number less one.
+    nodes: Vec<Node>,
+    sender: mpsc::Sender<super::Incoming>,
+}
+
+/// Mount the torrent's files at `mountpoint` until the returned session is dropped.
+pub fn mount(
+    mountpoint: &Path,
+    metainfo: &common::metainfo::MetainfoFile,
+    sender: mpsc::Sender<super::Incoming>,
+) -> std::io::Result<BackgroundSession> {
+    let fs = TorrentFs::new(metainfo, sender);
+    let options = [
+        MountOption::RO,
+        MountOption::FSName("toytorrent".to_string()),
+    ];
+
+    fuser::spawn_mount2(fs, mountpoint, &options)
+}
+
+impl TorrentFs {
+    fn new(
+        metainfo: &common::metainfo::MetainfoFile,
+        sender: mpsc::Sender<super::Incoming>,
+    ) -> Self {
+        let mut fs = Self {
+            info_hash: *metainfo.info_hash(),
+            nodes: vec![Node {
+                name: String::new(),
+                parent: ROOT_INO,
+                kind: NodeKind::Directory {
+                    children: Vec::new(),
+                },
+            }],
+            sender,
+        };
+
+        for span in metainfo.info.file_spans() {
+            let Some((file_name, directories)) = span.path.split_last() else {
+                continue;
+            };
+
+            let parent = directories
+                .iter()
+                .fold(ROOT_INO, |parent, name| fs.directory(parent, name));
+
+            fs.add(
+                parent,
+                file_name,
+                NodeKind::File {
+                    offset: span.offset,
+                    length: span.length,
+                },
+            );
+        }
+
+        fs
+    }
+
+    fn node(&self, ino: u64) -> Option<&Node> {
+        self.nodes.get(usize::try_from(ino).ok()?.checked_sub(1)?)
+    }
+
+    fn child(&self, parent: u64, name: &OsStr) -> Option<u64> {
+        let NodeKind::Directory { children } = &self.node(parent)?.kind else {
+            return None;
+        };
+
+        children.iter().copied().find(|&ino| {
+            self.node(ino)
+                .is_some_and(|node| name == node.name.as_str())
+        })
+    }
+
+    /// The directory `name` in `parent`, created if need be.
+    fn directory(&mut self, parent: u64, name: &str) -> u64 {
+        self.child(parent, OsStr::new(name)).unwrap_or_else(|| {
+            self.add(
+                parent,
+                name,
+                NodeKind::Directory {
+                    children: Vec::new(),
+                },
+            )
+        })
+    }
+
+    fn add(&mut self, parent: u64, name: &str, kind: NodeKind) -> u64 {
+        self.nodes.push(Node {
+            name: name.to_string(),
+            parent,
+            kind,
+        });
+
+        let ino = self.nodes.len() as u64;
+
+        if let Some(NodeKind::Directory { children }) = self
+            .nodes
+            .get_mut(parent as usize - 1)
+            .map(|node| &mut node.kind)
+        {
+            children.push(ino);
+        }
+
+        ino
+    }
+
+    fn attr(&self, ino: u64) -> Option<FileAttr> {
+        let (kind, size, perm, nlink) = match self.node(ino)?.kind {
+            NodeKind::Directory { .. } => (FileType::Directory, 0, 0o555, 2),
+            NodeKind::File { length, .. } => (FileType::RegularFile, length, 0o444, 1),
+        };
+
+        Some(FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: SystemTime::UNIX_EPOCH,
+            mtime: SystemTime::UNIX_EPOCH,
+            ctime: SystemTime::UNIX_EPOCH,
+            crtime: SystemTime::UNIX_EPOCH,
+            kind,
+            perm,
+            nlink,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: 16 * 1024,
+            flags: 0,
+        })
+    }
+
+    /// Read `length` bytes at `offset` in the torrent, waiting for pieces that we don't have yet.
+    /// Returns `None` if the session has ended.
+    fn read_torrent(&self, mut offset: u64, length: u64) -> Option<Vec<u8>> {
+        let end = offset + length;
+        let mut data = Vec::with_capacity(length as usize);
+
+        while offset < end {
+            let (reply, receiver) = oneshot::channel();
+
+            self.sender
+                .blocking_send(
+                    stream::Incoming {
+                        info_hash: self.info_hash,
+                        offset,
+                        length: end - offset,
+                        reply,
+                    }
+                    .into(),
+                )
+                .ok()?;
+
+            match receiver.blocking_recv().ok()? {
+                Some(chunk) if !chunk.is_empty() => {
+                    offset += chunk.len() as u64;
+                    data.extend_from_slice(&chunk);
+                }
+                _ => thread::sleep(POLL_INTERVAL),
+            }
+        }
+
+        Some(data)
+    }
+}
+
+impl Filesystem for TorrentFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self.child(parent, name).and_then(|ino| self.attr(ino)) {
+            Some(attr) => reply.entry(&TTL, &attr, 0),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.attr(ino) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let Some(NodeKind::File {
+            offset: file_offset,
+            length,
+        }) = self.node(ino).map(|node| &node.kind)
+        else {
+            return reply.error(libc::ENOENT);
+        };
+
+        let start = (offset.max(0) as u64).min(*length);
+        let end = start.saturating_add(size.into()).min(*length);
+
+        match self.read_torrent(file_offset + start, end - start) {
+            Some(data) => reply.data(&data),
+            None => reply.error(libc::EIO),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let Some(node) = self.node(ino) else {
+            return reply.error(libc::ENOENT);
+        };
+
+        let NodeKind::Directory { children } = &node.kind else {
+            return reply.error(libc::ENOTDIR);
+        };
+
+        let entries = [
+            (ino, FileType::Directory, "."),
+            (node.parent, FileType::Directory, ".."),
+        ]
+        .into_iter()
+        .chain(children.iter().filter_map(|&child| {
+            let node = self.node(child)?;
+            let kind = match node.kind {
+                NodeKind::Directory { .. } => FileType::Directory,
+                NodeKind::File { .. } => FileType::RegularFile,
+            };
+            Some((child, kind, node.name.as_str()))
+        }));
+
+        // Each entry's offset is where to carry on from after it.
+        for (i, (ino, kind, name)) in entries.enumerate().skip(offset.max(0) as usize) {
+            if reply.add(ino, i as i64 + 1, kind, name) {
+                break;
+            }
+        }
+
+        reply.ok();
+    }
+}
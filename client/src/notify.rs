@@ -0,0 +1,205 @@
+//! Telling the user about torrents that finish or fail, with desktop notifications and by POSTing
+//! JSON to a webhook. Each can be limited to some kinds of event.
+
+use clap::ValueEnum;
+use reqwest::header;
+
+use toytorrent_common as common;
+
+use super::json;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum Event {
+    /// A torrent finished downloading
+    Finished,
+
+    /// A torrent ran into an error that won't go away by itself
+    Error,
+
+    /// Every torrent in the session finished downloading
+    AllFinished,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Notification {
+    Finished {
+        name: String,
+        info_hash: common::InfoHash,
+    },
+    Error {
+        name: String,
+        info_hash: common::InfoHash,
+        message: String,
+    },
+    AllFinished {
+        torrents: usize,
+    },
+}
+
+#[derive(Debug)]
+pub struct Notifier {
+    desktop_events: Vec<Event>,
+    webhook: Option<String>,
+    webhook_events: Vec<Event>,
+    client: reqwest::Client,
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Finished => "finished",
+            Self::Error => "error",
+            Self::AllFinished => "all-finished",
+        }
+    }
+}
+
+impl Notification {
+    pub fn event(&self) -> Event {
+        match self {
+            Self::Finished { .. } => Event::Finished,
+            Self::Error { .. } => Event::Error,
+            Self::AllFinished { .. } => Event::AllFinished,
+        }
+    }
+
+    #[cfg(feature = "desktop-notifications")]
+    fn summary(&self) -> String {
+        match self {
+            Self::Finished { name, .. } => format!("Finished {}", name),
+            Self::Error { name, .. } => format!("Error in {}", name),
+            Self::AllFinished { .. } => "All torrents finished".to_string(),
+        }
+    }
+
+    #[cfg(feature = "desktop-notifications")]
+    fn body(&self) -> String {
+        match self {
+            Self::Finished { info_hash, .. } => info_hash.to_string(),
+            Self::Error { message, .. } => message.clone(),
+            Self::AllFinished { torrents: 1 } => "1 torrent downloaded".to_string(),
+            Self::AllFinished { torrents } => format!("{} torrents downloaded", torrents),
+        }
+    }
+
+    /// The body POSTed to the webhook, for example
+    /// `{"event":"finished","name":"debian.iso","info_hash":"..."}`.
+    fn to_json(&self) -> String {
+        let event = format!("\"event\":{}", json::string(self.event().name()));
+
+        match self {
+            Self::Finished { name, info_hash } => format!(
+                "{{{},\"name\":{},\"info_hash\":{}}}",
+                event,
+                json::string(name),
+                json::string(&info_hash.to_string()),
+            ),
+            Self::Error {
+                name,
+                info_hash,
+                message,
+            } => format!(
+                "{{{},\"name\":{},\"info_hash\":{},\"message\":{}}}",
+                event,
+                json::string(name),
+                json::string(&info_hash.to_string()),
+                json::string(message),
+            ),
+            Self::AllFinished { torrents } => format!("{{{},\"torrents\":{}}}", event, torrents),
+        }
+    }
+}
+
+impl Notifier {
+    pub fn new(
+        desktop_events: Vec<Event>,
+        webhook: Option<String>,
+        webhook_events: Vec<Event>,
+        client: reqwest::Client,
+    ) -> Self {
+        Self {
+            desktop_events,
+            webhook,
+            webhook_events,
+            client,
+        }
+    }
+
+    /// Send `notification` wherever its kind of event is wanted. Nothing waits for it to arrive.
+    pub fn send(&self, notification: Notification) {
+        if self.desktop_events.contains(&notification.event()) {
+            show_desktop(&notification);
+        }
+
+        if let Some(url) = self
+            .webhook
+            .as_ref()
+            .filter(|_| self.webhook_events.contains(&notification.event()))
+        {
+            let request = self
+                .client
+                .post(url)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(notification.to_json());
+
+            tokio::task::spawn(async move {
+                if let Err(e) = request
+                    .send()
+                    .await
+                    .and_then(reqwest::Response::error_for_status)
+                {
+                    say!("Unable to send webhook: {}", e);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(feature = "desktop-notifications")]
+fn show_desktop(notification: &Notification) {
+    let (summary, body) = (notification.summary(), notification.body());
+
+    // Showing a notification talks to the notification daemon synchronously.
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = notify_rust::Notification::new()
+            .appname("ToyTorrent")
+            .summary(&summary)
+            .body(&body)
+            .show()
+        {
+            say!("Unable to show notification: {}", e);
+        }
+    });
+}
+
+#[cfg(not(feature = "desktop-notifications"))]
+fn show_desktop(_notification: &Notification) {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn to_json_test() {
+        let info_hash = common::InfoHash::from([0xab; 20]);
+
+        assert_eq!(
+            format!(
+                "{{\"event\":\"error\",\"name\":\"a \\\"b\\\"\",\"info_hash\":\"{}\",\
+                 \"message\":\"line\\nbreak\\u0007\"}}",
+                info_hash,
+            ),
+            Notification::Error {
+                name: "a \"b\"".to_string(),
+                info_hash,
+                message: "line\nbreak\u{7}".to_string(),
+            }
+            .to_json(),
+        );
+
+        assert_eq!(
+            "{\"event\":\"all-finished\",\"torrents\":3}",
+            Notification::AllFinished { torrents: 3 }.to_json(),
+        );
+    }
+}
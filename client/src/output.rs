@@ -0,0 +1,331 @@
+//! What the client prints. By default that's text for humans. With `--output json`, events are
+//! printed as one JSON object per line for scripts instead, and the text goes to standard error so
+//! that standard output holds nothing else.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use clap::ValueEnum;
+
+use toytorrent_common as common;
+
+use super::json;
+
+static FORMAT: OnceLock<Format> = OnceLock::new();
+
+/// Whether standard output carries the torrent's data, so that text has to go elsewhere.
+static STDOUT_TAKEN: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum Format {
+    /// Text for humans
+    #[default]
+    Text,
+
+    /// Newline-delimited JSON events
+    Json,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// A torrent's progress, reported every few seconds.
+    Progress {
+        info_hash: common::InfoHash,
+        name: String,
+        downloaded: u64,
+        length: u64,
+        pieces_have: u32,
+        pieces: u32,
+        peers: usize,
+    },
+    /// Progress hashing a torrent's data that's already on disk, reported every second or so and
+    /// once more at the end.
+    Checking {
+        info_hash: common::InfoHash,
+        name: String,
+        checked: u32,
+        pieces: u32,
+        pieces_per_second: f64,
+
+        /// How much longer the check should take, once there's a rate to go by.
+        eta: Option<Duration>,
+    },
+    PeerConnected {
+        info_hash: common::InfoHash,
+        addr: SocketAddr,
+        peer_id: common::PeerId,
+    },
+    PieceVerified {
+        info_hash: common::InfoHash,
+        name: String,
+        index: u32,
+        valid: bool,
+    },
+    AnnounceSucceeded {
+        info_hash: common::InfoHash,
+        peers: usize,
+        seeders: Option<u64>,
+        leechers: Option<u64>,
+
+        /// How many times the torrent has been downloaded, if the tracker says.
+        completed: Option<u64>,
+
+        interval: u64,
+    },
+    AnnounceFailed {
+        info_hash: common::InfoHash,
+        url: Option<String>,
+        message: String,
+        retry_in: Option<Duration>,
+    },
+    /// A torrent was taken out of the session. `files_deleted` is set if its files were deleted.
+    TorrentRemoved {
+        info_hash: common::InfoHash,
+        name: String,
+        files_deleted: Option<usize>,
+    },
+}
+
+/// Set the output format for the rest of the run. Only the first call has any effect.
+pub fn init(format: Format) {
+    FORMAT.set(format).ok();
+}
+
+/// Send all text to standard error from now on, leaving standard output to the torrent's data.
+pub fn take_stdout() {
+    STDOUT_TAKEN.store(true, Ordering::Relaxed);
+}
+
+/// Print a line of text for humans. Use the `say!` macro rather than calling this directly.
+pub fn text(args: fmt::Arguments) {
+    match FORMAT.get().copied().unwrap_or_default() {
+        Format::Text if !STDOUT_TAKEN.load(Ordering::Relaxed) => println!("{}", args),
+        _ => eprintln!("{}", args),
+    }
+}
+
+/// Report an event, as JSON or as text if there is any for it.
+pub fn event(event: Event) {
+    match FORMAT.get().copied().unwrap_or_default() {
+        Format::Text => {
+            if let Some(text) = event.text() {
+                self::text(format_args!("{}", text));
+            }
+        }
+        Format::Json => println!("{}", event.to_json()),
+    }
+}
+
+impl Event {
+    /// The text to print for humans, if the event is worth mentioning.
+    fn text(&self) -> Option<String> {
+        match self {
+            Self::Progress {
+                name,
+                downloaded,
+                length,
+                pieces_have,
+                pieces,
+                peers,
+                ..
+            } => Some(format!(
+                "{} -- {} of {} ({}/{} pieces), {} peers",
+                name,
+                common::Bytes::from(*downloaded),
+                common::Bytes::from(*length),
+                pieces_have,
+                pieces,
+                peers,
+            )),
+            Self::Checking {
+                name,
+                checked,
+                pieces,
+                pieces_per_second,
+                eta,
+                ..
+            } => Some(format!(
+                "{} -- checked {}/{} pieces, {:.0} pieces/s{}",
+                name,
+                checked,
+                pieces,
+                pieces_per_second,
+                eta.map_or(String::new(), |eta| format!(", {}s left", eta.as_secs())),
+            )),
+            Self::PeerConnected { .. } => None,
+            Self::PieceVerified { valid: true, .. } => None,
+            Self::PieceVerified {
+                name,
+                index,
+                valid: false,
+                ..
+            } => Some(format!("Piece {} of {} failed verification", index, name)),
+            // Announces are only reported as events; a tracker that gives up for good is already
+            // mentioned in the text.
+            Self::AnnounceSucceeded { .. } | Self::AnnounceFailed { .. } => None,
+            Self::TorrentRemoved {
+                name,
+                files_deleted: None,
+                ..
+            } => Some(format!("Removed {}", name)),
+            Self::TorrentRemoved {
+                name,
+                files_deleted: Some(files_deleted),
+                ..
+            } => Some(format!(
+                "Removed {} and deleted {} files",
+                name, files_deleted
+            )),
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let fields = match self {
+            Self::Progress {
+                info_hash,
+                name,
+                downloaded,
+                length,
+                pieces_have,
+                pieces,
+                peers,
+            } => format!(
+                "\"event\":\"progress\",\"info_hash\":\"{}\",\"name\":{},\"downloaded\":{},\
+                 \"length\":{},\"pieces_have\":{},\"pieces\":{},\"peers\":{}",
+                info_hash,
+                json::string(name),
+                downloaded,
+                length,
+                pieces_have,
+                pieces,
+                peers,
+            ),
+            Self::Checking {
+                info_hash,
+                name,
+                checked,
+                pieces,
+                pieces_per_second,
+                eta,
+            } => format!(
+                "\"event\":\"checking\",\"info_hash\":\"{}\",\"name\":{},\"checked\":{},\
+                 \"pieces\":{},\"pieces_per_second\":{:.1},\"eta\":{}",
+                info_hash,
+                json::string(name),
+                checked,
+                pieces,
+                pieces_per_second,
+                json_option(&eta.map(|eta| eta.as_secs())),
+            ),
+            Self::PeerConnected {
+                info_hash,
+                addr,
+                peer_id,
+            } => format!(
+                "\"event\":\"peer_connected\",\"info_hash\":\"{}\",\"addr\":\"{}\",\
+                 \"peer_id\":{}",
+                info_hash,
+                addr,
+                json::string(&peer_id.to_string()),
+            ),
+            Self::PieceVerified {
+                info_hash,
+                index,
+                valid,
+                ..
+            } => format!(
+                "\"event\":\"piece_verified\",\"info_hash\":\"{}\",\"index\":{},\"valid\":{}",
+                info_hash, index, valid,
+            ),
+            Self::AnnounceSucceeded {
+                info_hash,
+                peers,
+                seeders,
+                leechers,
+                completed,
+                interval,
+            } => format!(
+                "\"event\":\"announce\",\"info_hash\":\"{}\",\"ok\":true,\"peers\":{},\
+                 \"seeders\":{},\"leechers\":{},\"completed\":{},\"interval\":{}",
+                info_hash,
+                peers,
+                json_option(seeders),
+                json_option(leechers),
+                json_option(completed),
+                interval,
+            ),
+            Self::AnnounceFailed {
+                info_hash,
+                url,
+                message,
+                retry_in,
+            } => format!(
+                "\"event\":\"announce\",\"info_hash\":\"{}\",\"ok\":false,\"url\":{},\
+                 \"message\":{},\"retry_in\":{}",
+                info_hash,
+                url.as_deref().map_or("null".to_string(), json::string),
+                json::string(message),
+                json_option(&retry_in.map(|retry_in| retry_in.as_secs())),
+            ),
+            Self::TorrentRemoved {
+                info_hash,
+                name,
+                files_deleted,
+            } => format!(
+                "\"event\":\"torrent_removed\",\"info_hash\":\"{}\",\"name\":{},\
+                 \"files_deleted\":{}",
+                info_hash,
+                json::string(name),
+                json_option(&files_deleted.map(|files_deleted| files_deleted as u64)),
+            ),
+        };
+
+        format!("{{{}}}", fields)
+    }
+}
+
+fn json_option(value: &Option<u64>) -> String {
+    value.map_or("null".to_string(), |value| value.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn to_json_test() {
+        let info_hash = common::InfoHash::from([1; 20]);
+
+        assert_eq!(
+            format!(
+                "{{\"event\":\"piece_verified\",\"info_hash\":\"{}\",\"index\":7,\"valid\":false}}",
+                info_hash,
+            ),
+            Event::PieceVerified {
+                info_hash,
+                name: "a".to_string(),
+                index: 7,
+                valid: false,
+            }
+            .to_json(),
+        );
+
+        assert_eq!(
+            format!(
+                "{{\"event\":\"announce\",\"info_hash\":\"{}\",\"ok\":false,\"url\":null,\
+                 \"message\":\"\\\"no\\\"\",\"retry_in\":60}}",
+                info_hash,
+            ),
+            Event::AnnounceFailed {
+                info_hash,
+                url: None,
+                message: "\"no\"".to_string(),
+                retry_in: Some(Duration::from_secs(60)),
+            }
+            .to_json(),
+        );
+    }
+}
@@ -1,91 +1,100 @@
 use std::io;
-use std::marker::PhantomData;
+use std::net::SocketAddr;
 
-use tokio::io::{AsyncReadExt};
-use tokio::net::tcp;
+use tokio::io::AsyncRead;
+use tokio::sync::mpsc;
 
+use super::{BitfieldExchange, CloseReason, Connection, Incoming, IncomingEvent};
+use crate::{capture, output};
 use toytorrent_common as common;
-use super::{PendingIncoming, PendingOutgoing, Connection, Incoming, IncomingEvent};
+use toytorrent_net as net;
 
 #[derive(Debug)]
 pub struct Active;
 
 impl Connection<Active> {
-    pub fn from_pending_incoming(connection: Connection<PendingIncoming>) -> Self {
-        let (read_stream, write_stream) = connection.stream.unwrap().into_split();
-
-        Self {
-            sender: connection.sender,
-            stream: None,
-            read_stream: Some(read_stream),
-            write_stream: Some(write_stream),
-            addr: connection.addr,
-            my_peer_id: connection.my_peer_id,
-            status: PhantomData,
-        }
+    pub(super) fn from_bitfield_exchange(connection: Connection<BitfieldExchange>) -> Self {
+        let mut connection = connection.into_status::<Active>();
+        connection.spawn_listener();
+        connection
     }
 
-    pub fn from_pending_outgoing(connection: Connection<PendingOutgoing>) -> Self {
-        let (read_stream, write_stream) = connection.stream.unwrap().into_split();
-
-        Self {
-            sender: connection.sender,
-            stream: None,
-            read_stream: Some(read_stream),
-            write_stream: Some(write_stream),
-            addr: connection.addr,
-            my_peer_id: connection.my_peer_id,
-            status: PhantomData,
-        }
-    }
+    /// Spawn a task that reads messages from the peer and forwards them to the event loop,
+    /// followed by a `Closed` event once the peer drops the connection.
+    fn spawn_listener(&mut self) {
+        let read_stream = self.read_stream.take().unwrap();
+        let addr = self.addr;
+        let sender = self.sender.clone();
+        let debug = self.debug;
 
-    fn read_stream(&mut self) -> &mut tcp::OwnedReadHalf {
-        self.read_stream.as_mut().unwrap()
-    }
+        let listener = tokio::spawn(async move {
+            let result = match debug {
+                Some(verbosity) => {
+                    let reader =
+                        common::AsyncDebugReader::new(addr.to_string(), verbosity, read_stream);
+                    listen(reader, addr, sender.clone()).await
+                }
+                None => listen(read_stream, addr, sender.clone()).await,
+            };
 
-    fn write_stream(&mut self) -> &mut tcp::OwnedWriteHalf {
-        self.write_stream.as_mut().unwrap()
-    }
+            let reason = match result {
+                Ok(()) => CloseReason::Io(io::ErrorKind::UnexpectedEof.into()),
+                Err(e) => CloseReason::Io(e),
+            };
 
-    async fn listen(&mut self) -> io::Result<()> {
-        let mut len_buf = [0u8; 4];
-        let mut buf = [0u8; common::peer::PEERMESSAGE_PIECE_MAX_LEN];
+            sender
+                .send(
+                    Incoming {
+                        from_socket_addr: addr,
+                        event: IncomingEvent::Closed { reason },
+                    }
+                    .into(),
+                )
+                .await
+                .ok();
+        });
 
-        loop {
-            self.read_stream().read_exact(&mut len_buf).await?;
-            let len = u32::from_be_bytes(len_buf) as usize;
+        self.listener = Some(listener.abort_handle());
+    }
 
-            if len > common::peer::PEERMESSAGE_PIECE_MAX_LEN {
-                return Err(io::Error::new(
-                    io::ErrorKind::Unsupported,
-                    format!(
-                        "Received message too long: max length was {} bytes, got {} bytes",
-                        common::peer::PEERMESSAGE_PIECE_MAX_LEN,
-                        len,
-                    ),
-                ));
-            }
+    pub async fn send(&mut self, message: common::peer::PeerMessage) -> io::Result<usize> {
+        let Some(write_stream) = self.write_stream.as_mut() else {
+            return Err(io::ErrorKind::NotConnected.into());
+        };
 
-            self.read_stream().read_exact(&mut buf[..len]).await?;
+        capture::record(self.addr, capture::Flow::Sent, &message).await;
 
-            match common::peer::PeerMessage::try_from(&buf[..len]) {
-                Ok(message) => self
-                    .sender
-                    .send(
-                        Incoming {
-                            from_socket_addr: self.addr,
-                            event: IncomingEvent::Message { message },
-                        }
-                        .into(),
-                    )
-                    .await
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?,
-                Err(e) => eprintln!("{:?}", e),
+        match self.debug {
+            Some(verbosity) => {
+                let mut writer =
+                    common::AsyncDebugWriter::new(self.addr.to_string(), verbosity, write_stream);
+                message.write_to(&mut writer).await
             }
+            None => message.write_to(write_stream).await,
         }
     }
+}
+
+async fn listen(
+    read_stream: impl AsyncRead + Unpin,
+    addr: SocketAddr,
+    sender: mpsc::Sender<crate::Incoming>,
+) -> io::Result<()> {
+    let mut reader = net::MessageReader::new(read_stream, addr, output::text);
+
+    loop {
+        let message = reader.next_message().await?;
+        capture::record(addr, capture::Flow::Received, &message).await;
 
-    async fn send(&mut self, message: common::peer::PeerMessage) -> io::Result<usize> {
-        message.write_to(&mut self.write_stream()).await
+        sender
+            .send(
+                Incoming {
+                    from_socket_addr: addr,
+                    event: IncomingEvent::Message { message },
+                }
+                .into(),
+            )
+            .await
+            .map_err(io::Error::other)?;
     }
 }
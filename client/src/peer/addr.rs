@@ -0,0 +1,206 @@
+//! Peer addresses as they come from trackers and other peers, which may list the same peer more
+//! than once, in different forms, or give addresses that can't be dialled at all.
+
+use std::collections::hash_map::{Entry, HashMap};
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use clap::ValueEnum;
+
+use toytorrent_common as common;
+
+/// Which addresses not to dial.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum AddrFilter {
+    /// Dial any address with a port
+    None,
+
+    /// Skip addresses that can't belong to a peer, such as multicast and documentation ranges
+    #[default]
+    Bogon,
+
+    /// Skip private, loopback and link-local addresses as well, for swarms on the internet
+    Private,
+}
+
+/// The addresses that have been dialled so far, so that each peer is dialled once however many
+/// times and from however many sources it's listed.
+#[derive(Debug, Default)]
+pub struct Candidates(HashSet<SocketAddr>);
+
+impl AddrFilter {
+    pub fn allows(&self, addr: &SocketAddr) -> bool {
+        match self {
+            Self::None => true,
+            Self::Bogon => !is_bogon(&addr.ip()),
+            Self::Private => !is_bogon(&addr.ip()) && !is_private(&addr.ip()),
+        }
+    }
+}
+
+impl Candidates {
+    /// Take an address to dial, returning it normalized if it's new and passes the filter.
+    pub fn add(&mut self, addr: SocketAddr, filter: AddrFilter) -> Option<SocketAddr> {
+        let addr = normalize(addr)?;
+
+        if filter.allows(&addr) && self.0.insert(addr) {
+            Some(addr)
+        } else {
+            None
+        }
+    }
+
+    /// Take the addresses to dial out of a tracker's peer list, each group one peer's. Addresses
+    /// listed under the same peer ID, such as a dual-stack peer's IPv4 and IPv6 addresses, are
+    /// grouped to be raced against each other rather than dialled as separate peers.
+    pub fn add_peers(
+        &mut self,
+        peers: &[common::tracker::Peer],
+        filter: AddrFilter,
+    ) -> Vec<Vec<SocketAddr>> {
+        let mut groups: Vec<Vec<SocketAddr>> = Vec::new();
+        let mut by_peer_id: HashMap<common::PeerId, usize> = HashMap::new();
+
+        for peer in peers {
+            let Some(addr) = self.add(peer.addr, filter) else {
+                continue;
+            };
+
+            match peer.peer_id.map(|peer_id| by_peer_id.entry(peer_id)) {
+                Some(Entry::Occupied(entry)) => groups[*entry.get()].push(addr),
+                Some(Entry::Vacant(entry)) => {
+                    entry.insert(groups.len());
+                    groups.push(vec![addr]);
+                }
+                None => groups.push(vec![addr]),
+            }
+        }
+
+        groups
+    }
+}
+
+/// Unwrap IPv4 addresses mapped into IPv6, so that both forms of an address compare equal. Port 0
+/// can't be dialled, so it gives `None`.
+pub fn normalize(addr: SocketAddr) -> Option<SocketAddr> {
+    if addr.port() == 0 {
+        return None;
+    }
+
+    match addr.ip() {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => Some(SocketAddr::new(ip.into(), addr.port())),
+            None => Some(addr),
+        },
+        IpAddr::V4(_) => Some(addr),
+    }
+}
+
+fn is_bogon(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.octets()[0] == 0
+                || ip.octets()[0] >= 240
+                || ip.is_multicast()
+                || ip.is_documentation()
+        }
+        IpAddr::V6(ip) => {
+            ip.is_unspecified() || ip.is_multicast() || ip.segments()[..2] == [0x2001, 0xdb8]
+        }
+    }
+}
+
+fn is_private(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_private() || ip.is_loopback() || ip.is_link_local() || is_shared(ip)
+        }
+        IpAddr::V6(ip) => {
+            ip.is_loopback()
+                || ip.segments()[0] & 0xfe00 == 0xfc00
+                || ip.segments()[0] & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+/// Carrier-grade NAT, 100.64.0.0/10.
+fn is_shared(ip: &Ipv4Addr) -> bool {
+    ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    #[test]
+    fn candidates_test() {
+        let mut candidates = Candidates::default();
+        let addr: SocketAddr = "203.0.113.1:6881".parse().unwrap();
+        let public: SocketAddr = "8.8.8.8:6881".parse().unwrap();
+        let mapped = SocketAddr::new(Ipv6Addr::from(0xffff_0808_0808).into(), 6881);
+
+        assert_eq!(None, candidates.add(addr, AddrFilter::Bogon));
+        assert_eq!(Some(addr), candidates.add(addr, AddrFilter::None));
+        assert_eq!(Some(public), candidates.add(mapped, AddrFilter::Private));
+        assert_eq!(None, candidates.add(public, AddrFilter::Private));
+        assert_eq!(
+            None,
+            candidates.add("1.1.1.1:0".parse().unwrap(), AddrFilter::None)
+        );
+    }
+
+    #[test]
+    fn add_peers_test() {
+        let peer = |peer_id: Option<u8>, addr: &str| common::tracker::Peer {
+            last_seen: std::time::Instant::now(),
+            peer_id: peer_id.map(|byte| common::PeerId::from([byte; 20])),
+            addr: addr.parse().unwrap(),
+            uploaded: None,
+            downloaded: None,
+            left: None,
+            key: None,
+            supportcrypto: None,
+            requirecrypto: None,
+            connectable: None,
+        };
+
+        let mut candidates = Candidates::default();
+        let peers = [
+            peer(Some(1), "8.8.8.8:1"),
+            peer(None, "8.8.4.4:2"),
+            peer(Some(2), "1.1.1.1:3"),
+            peer(Some(1), "[2001:4860::8888]:1"),
+            peer(None, "[2001:4860::8844]:2"),
+            peer(Some(2), "1.1.1.1:3"),
+        ];
+
+        let addr = |addr: &str| addr.parse::<SocketAddr>().unwrap();
+
+        assert_eq!(
+            vec![
+                vec![addr("8.8.8.8:1"), addr("[2001:4860::8888]:1")],
+                vec![addr("8.8.4.4:2")],
+                vec![addr("1.1.1.1:3")],
+                vec![addr("[2001:4860::8844]:2")],
+            ],
+            candidates.add_peers(&peers, AddrFilter::Bogon),
+        );
+        assert!(candidates.add_peers(&peers, AddrFilter::Bogon).is_empty());
+    }
+
+    #[test]
+    fn filter_test() {
+        let allows = |filter: AddrFilter, addr: &str| filter.allows(&addr.parse().unwrap());
+
+        assert!(allows(AddrFilter::Bogon, "192.168.1.2:1"));
+        assert!(!allows(AddrFilter::Private, "192.168.1.2:1"));
+        assert!(!allows(AddrFilter::Private, "100.100.0.1:1"));
+        assert!(!allows(AddrFilter::Private, "[fd00::1]:1"));
+        assert!(!allows(AddrFilter::Bogon, "[ff02::1]:1"));
+        assert!(!allows(AddrFilter::Bogon, "255.255.255.255:1"));
+        assert!(allows(AddrFilter::Private, "[2a00::1]:1"));
+        assert!(allows(AddrFilter::None, "0.0.0.0:1"));
+    }
+}
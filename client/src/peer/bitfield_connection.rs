@@ -0,0 +1,21 @@
+use super::{Active, Connection, Handshaking};
+
+#[derive(Debug)]
+pub struct BitfieldExchange;
+
+impl Connection<BitfieldExchange> {
+    pub(super) fn from_handshaking(connection: Connection<Handshaking>) -> Self {
+        let mut connection = connection.into_status::<BitfieldExchange>();
+        let (read_stream, write_stream) = connection.stream.take().unwrap().into_split();
+
+        connection.read_stream = Some(read_stream);
+        connection.write_stream = Some(write_stream);
+        connection
+    }
+
+    /// Start exchanging messages, forwarding the peer's to the session. Our bitfield has to be the
+    /// first message sent.
+    pub(super) fn activate(self) -> Connection<Active> {
+        Connection::from_bitfield_exchange(self)
+    }
+}
@@ -0,0 +1,33 @@
+use super::{CloseReason, Connection, Incoming, IncomingEvent};
+
+#[derive(Debug)]
+pub struct Closing;
+
+impl Connection<Closing> {
+    /// Stop listening to the peer and let go of the stream, whatever state the connection was in.
+    pub(super) fn from_open<Status>(connection: Connection<Status>) -> Self {
+        let mut connection = connection.into_status::<Closing>();
+
+        if let Some(listener) = connection.listener.take() {
+            listener.abort();
+        }
+
+        connection.stream = None;
+        connection.read_stream = None;
+        connection.write_stream = None;
+        connection
+    }
+
+    pub(super) async fn notify(self, reason: CloseReason) {
+        self.sender
+            .send(
+                Incoming {
+                    from_socket_addr: self.addr,
+                    event: IncomingEvent::Closed { reason },
+                }
+                .into(),
+            )
+            .await
+            .ok();
+    }
+}
@@ -0,0 +1,86 @@
+//! Hex dumps of the bytes exchanged with peers, with `--debug-wire`. Connections are dumped from
+//! the end of the handshake, once the peer's ID is known to filter on.
+
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use toytorrent_common as common;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Debug)]
+struct Config {
+    verbosity: common::DebugVerbosity,
+    peers: Vec<PeerFilter>,
+}
+
+/// A peer whose connections to dump.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PeerFilter {
+    Ip(IpAddr),
+
+    /// The start of the peer's ID, which usually names its client, such as `-qB`.
+    PeerId(String),
+}
+
+impl PeerFilter {
+    fn matches(&self, addr: &SocketAddr, peer_id: &common::PeerId) -> bool {
+        match self {
+            Self::Ip(ip) => super::addr::normalize(*addr).is_some_and(|addr| addr.ip() == *ip),
+            Self::PeerId(prefix) => peer_id.as_slice().starts_with(prefix.as_bytes()),
+        }
+    }
+}
+
+impl FromStr for PeerFilter {
+    type Err = &'static str;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        if let Ok(ip) = input.parse() {
+            Ok(Self::Ip(ip))
+        } else if !input.is_empty() && input.len() <= 20 {
+            Ok(Self::PeerId(input.to_string()))
+        } else {
+            Err("Expected an IP address or the start of a peer ID")
+        }
+    }
+}
+
+/// Dump the connections with the peers that pass any of `peers`, or with every peer if there are
+/// none. Only the first call has any effect.
+pub fn init(verbosity: common::DebugVerbosity, peers: Vec<PeerFilter>) {
+    CONFIG.set(Config { verbosity, peers }).ok();
+}
+
+/// How much to dump of a connection with the peer, if it's to be dumped at all.
+pub fn verbosity(addr: &SocketAddr, peer_id: &common::PeerId) -> Option<common::DebugVerbosity> {
+    let config = CONFIG.get()?;
+
+    (config.peers.is_empty() || config.peers.iter().any(|peer| peer.matches(addr, peer_id)))
+        .then_some(config.verbosity)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn peer_filter_test() {
+        let peer_id = common::PeerId::create("qB", "4500").unwrap();
+        let addr: SocketAddr = "[::ffff:192.0.2.1]:6881".parse().unwrap();
+
+        let matches = |filter: &str| {
+            filter
+                .parse::<PeerFilter>()
+                .unwrap()
+                .matches(&addr, &peer_id)
+        };
+
+        assert!(matches("192.0.2.1"));
+        assert!(!matches("192.0.2.2"));
+        assert!(matches("-qB"));
+        assert!(!matches("-TR"));
+        assert!("".parse::<PeerFilter>().is_err());
+    }
+}
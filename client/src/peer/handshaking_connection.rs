@@ -0,0 +1,34 @@
+use std::io;
+
+use super::{BitfieldExchange, CloseReason, Connection, PendingIncoming, PendingOutgoing};
+
+#[derive(Debug)]
+pub struct Handshaking;
+
+impl Connection<Handshaking> {
+    pub(super) fn from_pending_incoming(connection: Connection<PendingIncoming>) -> Self {
+        connection.into_status()
+    }
+
+    pub(super) fn from_pending_outgoing(connection: Connection<PendingOutgoing>) -> Self {
+        connection.into_status()
+    }
+
+    /// Split the stream once the handshakes are through, so that the peer's messages can be read
+    /// while ours are written.
+    pub(super) fn established(self) -> Connection<BitfieldExchange> {
+        Connection::from_handshaking(self)
+    }
+
+    /// Close the connection after a failed handshake, returning the error for the caller to
+    /// report.
+    pub(super) fn fail(self, reason: CloseReason) -> io::Error {
+        let error = match &reason {
+            CloseReason::Io(e) => io::Error::new(e.kind(), e.to_string()),
+            reason => io::Error::other(reason.to_string()),
+        };
+
+        self.close(reason);
+        error
+    }
+}
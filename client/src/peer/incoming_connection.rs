@@ -1,13 +1,17 @@
 use std::io;
-use std::net::SocketAddr;
 use std::marker::PhantomData;
+use std::net::SocketAddr;
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpStream};
+use tokio::net::TcpStream;
 use tokio::sync::{mpsc, oneshot};
 
+use super::{
+    BitfieldExchange, CloseReason, Connection, Direction, Handshaking, Incoming, IncomingEvent,
+    Peer,
+};
+use crate::socket;
 use toytorrent_common as common;
-use super::{Active, Connection, Incoming, IncomingEvent, Peer};
+use toytorrent_net as net;
 
 #[derive(Debug)]
 pub struct PendingIncoming;
@@ -16,9 +20,11 @@ impl Connection<PendingIncoming> {
     pub async fn accept(
         stream_addr: io::Result<(TcpStream, SocketAddr)>,
         my_peer_id: common::PeerId,
+        my_reserved: [u8; 8],
         sender: mpsc::Sender<crate::Incoming>,
     ) -> io::Result<()> {
         let (stream, addr) = stream_addr?;
+        socket::tune(&stream)?;
 
         let connection = Self {
             sender,
@@ -27,6 +33,9 @@ impl Connection<PendingIncoming> {
             write_stream: None,
             addr,
             my_peer_id,
+            my_reserved,
+            listener: None,
+            debug: None,
             status: PhantomData,
         };
 
@@ -35,85 +44,81 @@ impl Connection<PendingIncoming> {
         Ok(())
     }
 
-    async fn handshake(mut self) -> io::Result<Peer> {
-        {
-            let mut buf = [0; common::peer::PRELUDE.len()];
-            self.stream().read_exact(&mut buf).await?;
-
-            if buf != common::peer::PRELUDE {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("Invalid handshake prelude: {:?}", buf),
-                ));
-            }
-
-            self.stream().write(common::peer::PRELUDE).await?;
+    async fn handshake(self) -> io::Result<Peer<BitfieldExchange>> {
+        let mut connection = self.start_handshake();
+
+        match exchange_handshakes(&mut connection).await {
+            Ok((info_hash, their_peer_id, their_reserved)) => Ok(Peer::new(
+                their_peer_id,
+                info_hash,
+                their_reserved,
+                Direction::Incoming,
+                connection.established(),
+            )),
+            Err(reason) => Err(connection.fail(reason)),
         }
+    }
 
-        {
-            let mut buf = [0; common::peer::PRELUDE_RESERVED.len()];
-            self.stream().read_exact(&mut buf).await?;
-
-            println!(
-                "{}: peer sent prelude {:02x} {:02x} {:02x} {:02x} {:02x} {:02x} {:02x} {:02x}",
-                self.addr, buf[0], buf[1], buf[2], buf[3], buf[4], buf[5], buf[6], buf[7],
-            );
-
-            self.stream().write(common::peer::PRELUDE_RESERVED).await?;
-        }
+    fn start_handshake(self) -> Connection<Handshaking> {
+        Connection::from_pending_incoming(self)
+    }
+}
 
-        let info_hash = {
-            let mut buf = [0; 20];
-            self.stream().read_exact(&mut buf).await?;
-            let info_hash: common::InfoHash = buf.into();
-
-            let (is_valid_sender, is_valid_receiver) = oneshot::channel();
-
-            self.sender
-                .send(
-                    Incoming {
-                        from_socket_addr: self.addr,
-                        event: IncomingEvent::HandshakeInfoHash {
-                            info_hash,
-                            is_valid_sender,
-                        },
-                    }
-                    .into(),
-                )
-                .await
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-
-            if is_valid_receiver.await != Ok(true) {
-                return Err(io::Error::new(
-                    io::ErrorKind::NotFound,
-                    format!("Infohash not found: {:?}", info_hash),
-                ));
+/// Answer the peer's handshake, once the session has said that we have the torrent it's for.
+async fn exchange_handshakes(
+    connection: &mut Connection<Handshaking>,
+) -> Result<(common::InfoHash, common::PeerId, [u8; 8]), CloseReason> {
+    let mut handshake = net::Handshake::new(connection.stream.as_mut().unwrap());
+
+    let their_reserved = handshake.receive_prelude().await?;
+
+    say!(
+        "{}: peer sent prelude {:02x} {:02x} {:02x} {:02x} {:02x} {:02x} {:02x} {:02x}",
+        connection.addr,
+        their_reserved[0],
+        their_reserved[1],
+        their_reserved[2],
+        their_reserved[3],
+        their_reserved[4],
+        their_reserved[5],
+        their_reserved[6],
+        their_reserved[7],
+    );
+
+    handshake.send_prelude(connection.my_reserved).await?;
+
+    let info_hash = handshake.receive_info_hash().await?;
+    let (is_valid_sender, is_valid_receiver) = oneshot::channel();
+
+    connection
+        .sender
+        .send(
+            Incoming {
+                from_socket_addr: connection.addr,
+                event: IncomingEvent::HandshakeInfoHash {
+                    info_hash,
+                    is_valid_sender,
+                },
             }
+            .into(),
+        )
+        .await
+        .map_err(io::Error::other)?;
+
+    if is_valid_receiver.await != Ok(true) {
+        let message = format!("Infohash not found: {:?}", info_hash);
+        return Err(CloseReason::Handshake(message.into()));
+    }
 
-            self.stream().write(info_hash.as_slice()).await?;
-
-            info_hash
-        };
-
-        let their_peer_id = {
-            let mut buf = [0; 20];
-            self.stream().read_exact(&mut buf).await?;
-            let their_peer_id: common::PeerId = buf.into();
-
-            let my_peer_id = self.my_peer_id.clone();
-            self.stream().write(my_peer_id.as_slice()).await?;
-
-            their_peer_id
-        };
+    handshake.send_info_hash(&info_hash).await?;
 
-        Ok(Peer::new(their_peer_id, info_hash, self.activate()))
-    }
+    let their_peer_id = handshake.receive_peer_id().await?;
+    handshake.send_peer_id(&connection.my_peer_id).await?;
 
-    fn stream(&mut self) -> &mut TcpStream {
-        self.stream.as_mut().unwrap()
+    // Our peer ID went out anyway, so that the dialling side sees it too and stops dialling.
+    if their_peer_id == connection.my_peer_id {
+        return Err(CloseReason::Handshake("Connected to ourselves".into()));
     }
 
-    fn activate(self) -> Connection<Active> {
-        Connection::from_pending_incoming(self)
-    }
+    Ok((info_hash, their_peer_id, their_reserved))
 }
@@ -1,36 +1,90 @@
 //! Handles the protocol-level communication with peers.
+mod active_connection;
+mod addr;
+mod bitfield_connection;
+mod closing_connection;
+mod debug;
+mod handshaking_connection;
 mod incoming_connection;
 mod outgoing_connection;
-mod active_connection;
+mod stats;
 
-use std::net::SocketAddr;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
 use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Instant;
 
 use tokio::net::tcp;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{mpsc, oneshot};
+use tokio::task;
+use tokio::time;
 
+pub use active_connection::Active;
+pub use addr::{AddrFilter, Candidates};
+pub use bitfield_connection::BitfieldExchange;
+pub use closing_connection::Closing;
+pub use debug::{init as init_debug, PeerFilter};
+pub use handshaking_connection::Handshaking;
 pub use incoming_connection::PendingIncoming;
 pub use outgoing_connection::PendingOutgoing;
-pub use active_connection::Active;
+pub use stats::{Direction, Stats, UploadQuota};
 
 use toytorrent_common as common;
 
+use super::backoff::{self, Backoff};
+
+/// The most pieces to leave out of a lazy bitfield.
+const LAZY_BITFIELD_MAX_WITHHELD: usize = 50;
+
+/// The most requests to queue for a peer, as advertised in our extended handshake. Any more are
+/// dropped, which the extension protocol allows.
+const MAX_PEER_REQUESTS: usize = 250;
+
+/// Addresses that turned out to be our own, typically from a tracker listing us among the peers.
+static OWN_ADDRS: Mutex<Vec<SocketAddr>> = Mutex::new(Vec::new());
+
+/// A peer we've exchanged handshakes with. It's handed to the session before our bitfield is sent,
+/// and can only exchange other messages once it has been.
 #[derive(Debug)]
 #[must_use]
-pub struct Peer {
+pub struct Peer<Status = Active> {
     pub peer_id: common::PeerId,
     pub info_hash: common::InfoHash,
-    pub connection: Connection<Active>,
+    pub direction: Direction,
+    pub reserved: [u8; 8],
+    pub connection: Connection<Status>,
+    pub stats: Stats,
     pub am_choking: bool,
     pub am_interested: bool,
     pub peer_choking: bool,
     pub peer_interested: bool,
-    pub bitfield: Vec<u8>,
+    /// The pieces the peer has, sized for the torrent once the session takes the peer on.
+    pub bitfield: common::Bitfield,
     pub am_requesting: Vec<common::BlockRef>,
+    /// Blocks of the pieces we've claimed to download from the peer that we have yet to request,
+    /// held back to keep no more requests queued than the peer's pipeline depth.
+    pub unrequested: VecDeque<common::BlockRef>,
+    /// Blocks the peer has asked us for that we haven't sent or had cancelled yet.
     pub peer_requesting: Vec<common::BlockRef>,
+    pub dht_port: Option<u16>,
+    /// The peer's extended handshake, once it has sent one.
+    pub extended: Option<common::peer::ExtendedHandshake>,
 }
 
+/// A connection to a peer, typed by how far along it is:
+///
+/// * [`PendingIncoming`] or [`PendingOutgoing`]: connected, with nothing exchanged yet
+/// * [`Handshaking`]: exchanging handshakes
+/// * [`BitfieldExchange`]: past the handshake, with our bitfield, which has to be the first
+///   message, still to be sent
+/// * [`Active`]: exchanging messages, with the peer's forwarded to the session as they arrive
+/// * [`Closing`]: closed, with the session still to be told
+///
+/// A connection can be [closed](Connection::close) at any point.
 #[derive(Debug)]
 #[must_use]
 pub struct Connection<Status = PendingIncoming> {
@@ -41,6 +95,11 @@ pub struct Connection<Status = PendingIncoming> {
     read_stream: Option<tcp::OwnedReadHalf>,
     write_stream: Option<tcp::OwnedWriteHalf>,
     my_peer_id: common::PeerId,
+    my_reserved: [u8; 8],
+    listener: Option<task::AbortHandle>,
+
+    /// How much to dump of the bytes exchanged, with `--debug-wire`.
+    debug: Option<common::DebugVerbosity>,
 
     status: PhantomData<Status>,
 }
@@ -61,39 +120,118 @@ pub enum IncomingEvent {
         is_valid_sender: oneshot::Sender<bool>,
     },
     Connected {
-        peer: Peer,
+        peer: Box<Peer<BitfieldExchange>>,
+    },
+    /// Sent once for every connection that ends after it's established, whether or not the
+    /// handshake went through.
+    Closed {
+        reason: CloseReason,
     },
-    Closed,
 }
 
-impl Peer {
+/// Why a connection to a peer ended.
+#[derive(Debug)]
+pub enum CloseReason {
+    /// Reading from or writing to the peer failed, including the peer hanging up.
+    Io(io::Error),
+
+    /// The peer's handshake was for a torrent we don't have or didn't ask for, or came from us.
+    Handshake(common::Error),
+
+    /// The peer broke the protocol, on this connection or an earlier one.
+    Banned,
+
+    /// We already have a connection to the peer, and kept that one.
+    Duplicate,
+
+    /// We closed the connection, for instance because its torrent was removed.
+    Local,
+}
+
+impl Peer<BitfieldExchange> {
     pub fn new(
         peer_id: common::PeerId,
         info_hash: common::InfoHash,
-        connection: Connection<Active>,
+        reserved: [u8; 8],
+        direction: Direction,
+        connection: Connection<BitfieldExchange>,
     ) -> Self {
         Self {
             peer_id,
             info_hash,
+            reserved,
+            direction,
             connection,
+            // Restarted on the session's clock once the session takes the peer on.
+            stats: Stats::new(Instant::now()),
             am_choking: true,
             am_interested: false,
             peer_choking: true,
             peer_interested: false,
-            bitfield: Vec::default(),
+            bitfield: common::Bitfield::default(),
             am_requesting: Vec::default(),
+            unrequested: VecDeque::default(),
             peer_requesting: Vec::default(),
+            dht_port: None,
+            extended: None,
         }
     }
 
-    async fn send(self) {
+    /// Send our bitfield to the peer and start exchanging messages. Nothing is sent if we don't
+    /// have any pieces yet. If sending fails, the connection is closed.
+    ///
+    /// With `lazy` set, a random handful of pieces is left out of the bitfield and announced with
+    /// Have messages afterwards instead. Some ISPs throttle connections that open with a full
+    /// bitfield, taking them to be seeds.
+    pub async fn send_bitfield(
+        self,
+        have: &common::Bitfield,
+        lazy: bool,
+    ) -> io::Result<Peer<Active>> {
+        let mut peer = self.activate();
+
+        match peer.announce_pieces(have, lazy).await {
+            Ok(()) => Ok(peer),
+            Err(e) => {
+                let error = io::Error::new(e.kind(), e.to_string());
+                peer.close(CloseReason::Io(e));
+                Err(error)
+            }
+        }
+    }
+
+    fn activate(self) -> Peer<Active> {
+        Peer {
+            peer_id: self.peer_id,
+            info_hash: self.info_hash,
+            direction: self.direction,
+            reserved: self.reserved,
+            connection: self.connection.activate(),
+            stats: self.stats,
+            am_choking: self.am_choking,
+            am_interested: self.am_interested,
+            peer_choking: self.peer_choking,
+            peer_interested: self.peer_interested,
+            bitfield: self.bitfield,
+            am_requesting: self.am_requesting,
+            unrequested: self.unrequested,
+            peer_requesting: self.peer_requesting,
+            dht_port: self.dht_port,
+            extended: self.extended,
+        }
+    }
+
+    async fn send(mut self) {
+        self.connection.debug = debug::verbosity(&self.connection.addr, &self.peer_id);
         self.connection
             .sender
             .clone()
             .send(
                 Incoming {
                     from_socket_addr: self.connection.addr,
-                    event: IncomingEvent::Connected { peer: self },
+                    event: IncomingEvent::Connected {
+                        peer: Box::new(self),
+                    },
                 }
                 .into(),
             )
@@ -102,8 +240,327 @@ impl Peer {
     }
 }
 
+impl<Status> Peer<Status> {
+    /// Update our view of the peer's state from a message it sent. Fails if the peer claims pieces
+    /// that the torrent doesn't have, in which case it should be disconnected.
+    pub fn receive(
+        &mut self,
+        message: &common::peer::PeerMessage,
+        now: Instant,
+    ) -> Result<(), common::Error> {
+        use common::peer::PeerMessage;
+
+        let piece_count = self.bitfield.piece_count();
+
+        match message {
+            PeerMessage::Choke => {
+                self.peer_choking = true;
+                self.stats.discard_requests();
+            }
+            PeerMessage::Unchoke => self.peer_choking = false,
+            PeerMessage::Interested => self.peer_interested = true,
+            PeerMessage::NotInterested => self.peer_interested = false,
+            PeerMessage::Have { index } if *index >= piece_count => {
+                return Err(format!("Have for piece {} of {}", index, piece_count).into());
+            }
+            PeerMessage::Have { index } => {
+                self.bitfield.insert(*index);
+            }
+            PeerMessage::Bitfield { bitfield }
+                if !common::Bitfield::is_valid_bytes(bitfield, piece_count) =>
+            {
+                return Err(format!("Malformed bitfield for {} pieces", piece_count).into());
+            }
+            PeerMessage::Bitfield { bitfield } => {
+                self.bitfield = common::Bitfield::from_bytes(bitfield, piece_count);
+            }
+            PeerMessage::Request { .. } if self.peer_requesting.len() >= MAX_PEER_REQUESTS => {}
+            PeerMessage::Request { block } if !self.peer_requesting.contains(block) => {
+                self.peer_requesting.push(block.clone());
+            }
+            PeerMessage::Piece { data, .. } => self.stats.record_block(data.len(), now),
+            PeerMessage::Cancel { block } => {
+                let queued = self.peer_requesting.len();
+                self.peer_requesting.retain(|requested| requested != block);
+
+                if self.peer_requesting.len() < queued {
+                    self.stats.cancelled += 1;
+                }
+            }
+            PeerMessage::Port { port } => self.dht_port = Some(*port),
+            PeerMessage::Extended {
+                id: common::peer::EXTENDED_HANDSHAKE_ID,
+                payload,
+            } => {
+                let handshake = common::peer::ExtendedHandshake::try_from(&payload[..])
+                    .map_err(|e| format!("Malformed extended handshake: {}", e))?;
+                self.extended = Some(handshake);
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Whether to keep this connection rather than `existing`, another one to the same peer. A
+    /// reconnect replaces the old connection, which is likely dead. When both sides dialled each
+    /// other at once, both keep the connection dialled by the side with the lower peer ID.
+    pub fn supersedes(&self, existing: &Peer, my_peer_id: &common::PeerId) -> bool {
+        if self.direction == existing.direction {
+            return true;
+        }
+
+        match self.direction {
+            Direction::Outgoing => my_peer_id < &self.peer_id,
+            Direction::Incoming => &self.peer_id < my_peer_id,
+        }
+    }
+
+    pub fn supports_dht(&self) -> bool {
+        common::peer::supports_dht(&self.reserved)
+    }
+
+    pub fn supports_v2(&self) -> bool {
+        common::peer::supports_v2(&self.reserved)
+    }
+
+    pub fn supports_extensions(&self) -> bool {
+        common::peer::supports_extensions(&self.reserved)
+    }
+
+    /// The most requests the peer queues for us, if its extended handshake said.
+    pub fn request_limit(&self) -> Option<usize> {
+        self.extended
+            .as_ref()
+            .and_then(|extended| extended.reqq)
+            .filter(|&reqq| reqq > 0)
+            .map(|reqq| usize::try_from(reqq).unwrap_or(usize::MAX))
+    }
+
+    pub fn has_piece(&self, index: u32) -> bool {
+        self.bitfield.contains(index)
+    }
+
+    /// The fraction of the torrent that the peer claims to have.
+    pub fn progress(&self) -> f64 {
+        match self.bitfield.piece_count() {
+            0 => 1.0,
+            piece_count => self.bitfield.count() as f64 / piece_count as f64,
+        }
+    }
+
+    /// A line describing the peer for the status display, with its flags as of `now`.
+    pub fn summary(&self, now: Instant) -> String {
+        format!(
+            "{:21} {:24} {:3} {:>10}/s down {:>10}/s up {:>7} rtt {}",
+            self.connection.addr,
+            self.peer_id
+                .client()
+                .unwrap_or_else(|| "unknown".to_string()),
+            self.direction,
+            common::Bytes::from(self.stats.download.per_second() as u64),
+            common::Bytes::from(self.stats.upload.per_second() as u64),
+            self.stats
+                .round_trip
+                .map_or_else(|| "-".to_string(), |rtt| format!("{}ms", rtt.as_millis())),
+            self.flags(now),
+        )
+    }
+
+    /// Status flags in the style of other clients' peer lists:
+    ///
+    /// * D: downloading from the peer
+    /// * d: we are interested, but the peer is choking us
+    /// * U: uploading to the peer
+    /// * u: the peer is interested, but we are choking it
+    /// * K: the peer has unchoked us, but we are not interested
+    /// * ?: we have unchoked the peer, but it is not interested
+    /// * S: the peer is snubbing us
+    /// * L: the peer is slow to serve our requests
+    /// * I: the peer connected to us
+    pub fn flags(&self, now: Instant) -> String {
+        let mut flags = String::new();
+
+        match (self.am_interested, self.peer_choking) {
+            (true, false) => flags.push('D'),
+            (true, true) => flags.push('d'),
+            (false, false) => flags.push('K'),
+            (false, true) => {}
+        }
+
+        match (self.peer_interested, self.am_choking) {
+            (true, false) => flags.push('U'),
+            (true, true) => flags.push('u'),
+            (false, false) => flags.push('?'),
+            (false, true) => {}
+        }
+
+        if self.am_interested && self.stats.is_snubbing(now) {
+            flags.push('S');
+        }
+
+        if self.stats.is_slow() {
+            flags.push('L');
+        }
+
+        if self.direction == Direction::Incoming {
+            flags.push('I');
+        }
+
+        flags
+    }
+
+    /// Close the connection to the peer, telling the session why.
+    pub fn close(self, reason: CloseReason) {
+        self.connection.close(reason);
+    }
+}
+
+impl Peer {
+    /// Send a message to the peer, keeping the upload statistics and the peer's queued requests
+    /// up to date.
+    pub async fn send_message(&mut self, message: common::peer::PeerMessage) -> io::Result<()> {
+        match &message {
+            common::peer::PeerMessage::Piece { block, data } => {
+                self.peer_requesting.retain(|requested| requested != block);
+                self.stats.record_upload(data.len());
+            }
+            // Choking discards every request the peer has queued with us.
+            common::peer::PeerMessage::Choke => self.peer_requesting.clear(),
+            _ => {}
+        }
+
+        self.connection.send(message).await?;
+        Ok(())
+    }
+
+    /// Send the peer the bitfield of the pieces in `have`, holding some back to send as Have
+    /// messages with `lazy`.
+    async fn announce_pieces(&mut self, have: &common::Bitfield, lazy: bool) -> io::Result<()> {
+        if have.is_empty() {
+            return Ok(());
+        }
+
+        let mut have = have.clone();
+
+        let withheld: Vec<u32> = if lazy {
+            let count = (have.count() as usize / 10).clamp(1, LAZY_BITFIELD_MAX_WITHHELD);
+            rand::seq::index::sample(&mut rand::thread_rng(), have.count() as usize, count)
+                .into_iter()
+                .filter_map(|n| have.select(n as u32))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        for &index in withheld.iter() {
+            have.remove(index);
+        }
+
+        self.send_message(common::peer::PeerMessage::Bitfield {
+            bitfield: have.to_bytes(),
+        })
+        .await?;
+
+        for index in withheld {
+            self.send_message(common::peer::PeerMessage::Have { index })
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Send the peer our extended handshake, naming ourselves `client` and offering `extensions`,
+    /// each with the message ID for the peer to send its messages with, if the peer supports the
+    /// extension protocol.
+    pub async fn send_extended_handshake(
+        &mut self,
+        client: &str,
+        extensions: &[(&str, u8)],
+    ) -> io::Result<()> {
+        if !self.supports_extensions() {
+            return Ok(());
+        }
+
+        let handshake = common::peer::ExtendedHandshake {
+            extensions: extensions
+                .iter()
+                .map(|&(name, id)| (name.to_string(), id))
+                .collect(),
+            client: Some(client.to_string()),
+            reqq: Some(MAX_PEER_REQUESTS as u64),
+            yourip: Some(self.connection.addr.ip()),
+            metadata_size: None,
+        };
+
+        self.send_message(common::peer::PeerMessage::Extended {
+            id: common::peer::EXTENDED_HANDSHAKE_ID,
+            payload: handshake.encode(),
+        })
+        .await
+    }
+
+    /// Tell the peer that we now have a piece. If `suppress_redundant` is set, the message is
+    /// skipped for peers that already advertise the piece themselves, since they can't want it
+    /// from us. Returns whether the message was sent.
+    pub async fn send_have(&mut self, index: u32, suppress_redundant: bool) -> io::Result<bool> {
+        if suppress_redundant && self.has_piece(index) {
+            return Ok(false);
+        }
+
+        self.send_message(common::peer::PeerMessage::Have { index })
+            .await?;
+        Ok(true)
+    }
+}
+
+impl<Status> Connection<Status> {
+    /// Stop reading from and writing to the peer, and tell the session that the connection is
+    /// closed and why. The session is told from a task of its own, since it may be the one
+    /// closing the connection.
+    pub fn close(self, reason: CloseReason) {
+        tokio::spawn(Connection::<Closing>::from_open(self).notify(reason));
+    }
+
+    /// The same connection in another state. Only the transitions that the states allow are made
+    /// with this, so that they're enforced outside of this module.
+    fn into_status<Next>(self) -> Connection<Next> {
+        Connection {
+            sender: self.sender,
+            addr: self.addr,
+            stream: self.stream,
+            read_stream: self.read_stream,
+            write_stream: self.write_stream,
+            my_peer_id: self.my_peer_id,
+            my_reserved: self.my_reserved,
+            listener: self.listener,
+            debug: self.debug,
+            status: PhantomData,
+        }
+    }
+}
+
+impl From<io::Error> for CloseReason {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Handshake(e) => write!(f, "Handshake failed: {}", e),
+            Self::Banned => write!(f, "Banned"),
+            Self::Duplicate => write!(f, "Duplicate connection"),
+            Self::Local => write!(f, "Closed by us"),
+        }
+    }
+}
+
 pub async fn listen(
     my_peer_id: common::PeerId,
+    my_reserved: [u8; 8],
     listener: TcpListener,
     sender: mpsc::Sender<super::Incoming>,
 ) {
@@ -111,11 +568,316 @@ pub async fn listen(
         if let Err(e) = Connection::<PendingIncoming>::accept(
             listener.accept().await,
             my_peer_id,
+            my_reserved,
             sender.clone(),
         )
         .await
         {
-            println!("Error accepting connection: {:?}", e);
+            say!("Error accepting connection: {:?}", e);
+        }
+    }
+}
+
+/// Dial a peer, retrying according to [`backoff::PEER_DIAL`] until the connection succeeds or the
+/// policy gives up. Addresses that turn out to be our own aren't dialled again.
+pub async fn connect(
+    addrs: Vec<SocketAddr>,
+    my_peer_id: common::PeerId,
+    my_reserved: [u8; 8],
+    info_hash: common::InfoHash,
+    sender: mpsc::Sender<super::Incoming>,
+) {
+    if addrs.iter().any(is_own_addr) {
+        return;
+    }
+
+    let mut backoff = Backoff::new(backoff::PEER_DIAL);
+
+    loop {
+        match Connection::<PendingOutgoing>::connect_to(
+            &addrs[..],
+            my_peer_id,
+            my_reserved,
+            info_hash,
+            sender.clone(),
+        )
+        .await
+        {
+            Ok(()) => return,
+            Err(_) if addrs.iter().any(is_own_addr) => {
+                say!("Not connecting to {:?}, which is ourselves", addrs);
+                return;
+            }
+            Err(e) => match backoff.failed() {
+                Some(delay) => {
+                    say!(
+                        "Error connecting to {:?} (attempt {}), retrying in {:?}: {:?}",
+                        addrs,
+                        backoff.failures(),
+                        delay,
+                        e,
+                    );
+                    time::sleep(delay).await;
+                }
+                None => {
+                    say!(
+                        "Error connecting to {:?}, giving up after {} attempts: {:?}",
+                        addrs,
+                        backoff.failures(),
+                        e,
+                    );
+                    return;
+                }
+            },
+        }
+    }
+}
+
+fn flag_own_addr(addr: SocketAddr) {
+    let mut own_addrs = OWN_ADDRS.lock().unwrap();
+
+    if !own_addrs.contains(&addr) {
+        own_addrs.push(addr);
+    }
+}
+
+fn is_own_addr(addr: &SocketAddr) -> bool {
+    OWN_ADDRS.lock().unwrap().contains(addr)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use tokio::task::JoinSet;
+
+    fn peer_id() -> common::PeerId {
+        common::PeerId::create(super::super::PEER_ID_CLIENT, super::super::PEER_ID_VERSION).unwrap()
+    }
+
+    /// Connect two peers for `info_hash` over localhost, returning the channels that each side's
+    /// events arrive on, the listening side's first.
+    async fn connect_pair(
+        info_hash: common::InfoHash,
+        processes: &mut JoinSet<()>,
+    ) -> (
+        mpsc::Receiver<super::super::Incoming>,
+        mpsc::Receiver<super::super::Incoming>,
+    ) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (listener_sender, listener_receiver) = mpsc::channel(100);
+        let (dialler_sender, dialler_receiver) = mpsc::channel(100);
+        let reserved = common::peer::reserved_bytes(false);
+
+        processes.spawn(listen(peer_id(), reserved, listener, listener_sender));
+        processes.spawn(connect(
+            vec![addr],
+            peer_id(),
+            reserved,
+            info_hash,
+            dialler_sender,
+        ));
+
+        (listener_receiver, dialler_receiver)
+    }
+
+    /// The next event from a peer, answering handshakes for `info_hash` along the way.
+    async fn next_event(
+        receiver: &mut mpsc::Receiver<super::super::Incoming>,
+        info_hash: common::InfoHash,
+    ) -> IncomingEvent {
+        loop {
+            let event = match time::timeout(Duration::from_secs(10), receiver.recv()).await {
+                Ok(Some(super::super::Incoming::Peer(Incoming { event, .. }))) => event,
+                Ok(_) => continue,
+                Err(_) => panic!("Timed out waiting for the peer"),
+            };
+
+            match event {
+                IncomingEvent::HandshakeInfoHash {
+                    info_hash: their_info_hash,
+                    is_valid_sender,
+                } => {
+                    is_valid_sender.send(their_info_hash == info_hash).ok();
+                }
+                event => return event,
+            }
+        }
+    }
+
+    /// Take on the connected peer.
+    async fn activate(
+        receiver: &mut mpsc::Receiver<super::super::Incoming>,
+        info_hash: common::InfoHash,
+    ) -> Peer {
+        match next_event(receiver, info_hash).await {
+            IncomingEvent::Connected { peer } => peer
+                .send_bitfield(&common::Bitfield::new(1), false)
+                .await
+                .unwrap(),
+            event => panic!("Expected a connection, got {:?}", event),
+        }
+    }
+
+    /// Take on the connected peer and send it our extended handshake as `client`, then wait for
+    /// its own.
+    async fn exchange_extended_handshakes(
+        receiver: &mut mpsc::Receiver<super::super::Incoming>,
+        info_hash: common::InfoHash,
+        client: &str,
+    ) -> Peer {
+        let mut peer = activate(receiver, info_hash).await;
+        peer.send_extended_handshake(client, &[]).await.unwrap();
+
+        while peer.extended.is_none() {
+            match next_event(receiver, info_hash).await {
+                IncomingEvent::Message { message } => {
+                    peer.receive(&message, Instant::now()).unwrap();
+                }
+                event => panic!("Expected a message, got {:?}", event),
+            }
+        }
+
+        peer
+    }
+
+    #[tokio::test]
+    async fn extended_handshake_test() {
+        let info_hash = common::InfoHash::from([5; 20]);
+        let mut processes = JoinSet::new();
+        let (mut listener, mut dialler) = connect_pair(info_hash, &mut processes).await;
+
+        let (seeder, leecher) = tokio::join!(
+            exchange_extended_handshakes(&mut listener, info_hash, "Seeder/1.0"),
+            exchange_extended_handshakes(&mut dialler, info_hash, "Leecher/2.0"),
+        );
+
+        assert!(seeder.supports_extensions());
+        assert!(leecher.supports_extensions());
+
+        let extended = seeder.extended.as_ref().unwrap();
+        assert_eq!(Some("Leecher/2.0"), extended.client.as_deref());
+        assert_eq!(Some(Ipv4Addr::LOCALHOST.into()), extended.yourip);
+        assert_eq!(Some(MAX_PEER_REQUESTS), seeder.request_limit());
+
+        let extended = leecher.extended.as_ref().unwrap();
+        assert_eq!(Some("Seeder/1.0"), extended.client.as_deref());
+        assert_eq!(Some(Ipv4Addr::LOCALHOST.into()), extended.yourip);
+        assert_eq!(Some(MAX_PEER_REQUESTS), leecher.request_limit());
+    }
+
+    #[tokio::test]
+    async fn connect_race_test() {
+        let info_hash = common::InfoHash::from([8; 20]);
+        let mut processes = JoinSet::new();
+        let reserved = common::peer::reserved_bytes(false);
+
+        // The peer's first address refuses connections, so the dial falls through to its second.
+        let closed = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let closed_addr = closed.local_addr().unwrap();
+        drop(closed);
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (listener_sender, mut listener_receiver) = mpsc::channel(100);
+        let (dialler_sender, mut dialler_receiver) = mpsc::channel(100);
+
+        processes.spawn(listen(peer_id(), reserved, listener, listener_sender));
+        processes.spawn(connect(
+            vec![closed_addr, addr],
+            peer_id(),
+            reserved,
+            info_hash,
+            dialler_sender,
+        ));
+
+        let (_seeder, leecher) = tokio::join!(
+            activate(&mut listener_receiver, info_hash),
+            activate(&mut dialler_receiver, info_hash),
+        );
+
+        assert_eq!(addr, leecher.connection.addr);
+    }
+
+    #[tokio::test]
+    async fn have_test() {
+        use common::peer::PeerMessage;
+
+        let info_hash = common::InfoHash::from([7; 20]);
+        let mut processes = JoinSet::new();
+        let (mut listener, mut dialler) = connect_pair(info_hash, &mut processes).await;
+
+        let (mut seeder, mut leecher) = tokio::join!(
+            activate(&mut listener, info_hash),
+            activate(&mut dialler, info_hash)
+        );
+        seeder.bitfield = common::Bitfield::new(8);
+
+        // A piece past the end of the torrent is a protocol error, and isn't recorded.
+        for index in [7, 8, u32::MAX] {
+            leecher
+                .send_message(PeerMessage::Have { index })
+                .await
+                .unwrap();
+        }
+
+        for (index, is_valid) in [(7, true), (8, false), (u32::MAX, false)] {
+            match next_event(&mut listener, info_hash).await {
+                IncomingEvent::Message { message } => {
+                    assert_eq!(PeerMessage::Have { index }, message);
+                    assert_eq!(is_valid, seeder.receive(&message, Instant::now()).is_ok());
+                }
+                event => panic!("Expected a Have message, got {:?}", event),
+            }
+        }
+
+        assert!(seeder.has_piece(7));
+        assert!(!seeder.has_piece(8));
+        assert_eq!(8, seeder.bitfield.piece_count());
+        assert_eq!(1, seeder.bitfield.count());
+    }
+
+    #[tokio::test]
+    async fn invalid_messages_test() {
+        use tokio::io::AsyncWriteExt;
+
+        let info_hash = common::InfoHash::from([6; 20]);
+        let mut processes = JoinSet::new();
+        let (mut listener, mut dialler) = connect_pair(info_hash, &mut processes).await;
+
+        let (_seeder, mut leecher) = tokio::join!(
+            activate(&mut listener, info_hash),
+            activate(&mut dialler, info_hash)
+        );
+
+        // A Have message one byte short, 99 times, then a valid message, then the 100th.
+        let invalid = [0, 0, 0, 4, 4, 0, 0, 0];
+        let mut data = invalid.repeat(99);
+        common::peer::PeerMessage::Unchoke
+            .write_to(&mut data)
+            .await
+            .unwrap();
+        data.extend_from_slice(&invalid);
+
+        let write_stream = leecher.connection.write_stream.as_mut().unwrap();
+        write_stream.write_all(&data).await.unwrap();
+
+        match next_event(&mut listener, info_hash).await {
+            IncomingEvent::Message { message } => {
+                assert_eq!(common::peer::PeerMessage::Unchoke, message);
+            }
+            event => panic!("Expected the valid message, got {:?}", event),
+        }
+
+        match next_event(&mut listener, info_hash).await {
+            IncomingEvent::Closed {
+                reason: CloseReason::Io(e),
+            } => assert_eq!(io::ErrorKind::InvalidData, e.kind()),
+            event => panic!("Expected the connection to close, got {:?}", event),
         }
     }
 }
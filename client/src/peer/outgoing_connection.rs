@@ -1,25 +1,27 @@
 use std::io;
-use std::net::SocketAddr;
 use std::marker::PhantomData;
+use std::net::SocketAddr;
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpStream};
-use tokio::sync::{mpsc};
+use tokio::sync::mpsc;
 
+use super::{BitfieldExchange, CloseReason, Connection, Direction, Handshaking, Peer};
+use crate::{resolver, socket};
 use toytorrent_common as common;
-use super::{Active, Connection, Peer};
+use toytorrent_net as net;
 
 #[derive(Debug)]
 pub struct PendingOutgoing;
 
 impl Connection<PendingOutgoing> {
-    async fn connect_to(
-        addr: SocketAddr,
+    pub async fn connect_to(
+        addrs: &[SocketAddr],
         my_peer_id: common::PeerId,
+        my_reserved: [u8; 8],
         info_hash: common::InfoHash,
         sender: mpsc::Sender<crate::Incoming>,
     ) -> io::Result<()> {
-        let stream = TcpStream::connect(addr).await?;
+        let stream = resolver::connect_with(addrs, socket::connect).await?;
+        let addr = stream.peer_addr()?;
 
         let connection = Self {
             sender,
@@ -28,6 +30,9 @@ impl Connection<PendingOutgoing> {
             write_stream: None,
             addr,
             my_peer_id,
+            my_reserved,
+            listener: None,
+            debug: None,
             status: PhantomData,
         };
 
@@ -36,58 +41,54 @@ impl Connection<PendingOutgoing> {
         Ok(())
     }
 
-    async fn handshake(mut self, info_hash: common::InfoHash) -> io::Result<Peer> {
-        {
-            self.stream().write(common::peer::PRELUDE).await?;
-
-            let mut buf = [0; common::peer::PRELUDE.len()];
-            self.stream().read_exact(&mut buf).await?;
-
-            if buf != common::peer::PRELUDE {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("Invalid handshake prelude: {:?}", buf),
-                ));
-            }
+    async fn handshake(self, info_hash: common::InfoHash) -> io::Result<Peer<BitfieldExchange>> {
+        let mut connection = self.start_handshake();
+
+        match exchange_handshakes(&mut connection, &info_hash).await {
+            Ok((their_peer_id, their_reserved)) => Ok(Peer::new(
+                their_peer_id,
+                info_hash,
+                their_reserved,
+                Direction::Outgoing,
+                connection.established(),
+            )),
+            Err(reason) => Err(connection.fail(reason)),
         }
+    }
 
-        {
-            self.stream().write(info_hash.as_slice()).await?;
-
-            let mut buf = [0; 20];
-            self.stream().read_exact(&mut buf).await?;
-
-            if buf != info_hash.as_slice() {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!(
-                        "Their {:?} does not match our {:?}",
-                        common::InfoHash::from(buf),
-                        info_hash
-                    ),
-                ));
-            }
-        }
-
-        let their_peer_id = {
-            let my_peer_id = self.my_peer_id.clone();
-            self.stream().write(my_peer_id.as_slice()).await?;
-
-            let mut buf = [0; 20];
-            self.stream().read_exact(&mut buf).await?;
-            let their_peer_id: common::PeerId = buf.into();
-
-            their_peer_id
-        };
-
-        Ok(Peer::new(their_peer_id, info_hash, self.activate()))
+    fn start_handshake(self) -> Connection<Handshaking> {
+        Connection::from_pending_outgoing(self)
     }
+}
 
-    fn stream(&mut self) -> &mut TcpStream {
-        self.stream.as_mut().unwrap()
+/// Send our handshake for the torrent with `info_hash`, and check the peer's answer.
+async fn exchange_handshakes(
+    connection: &mut Connection<Handshaking>,
+    info_hash: &common::InfoHash,
+) -> Result<(common::PeerId, [u8; 8]), CloseReason> {
+    let mut handshake = net::Handshake::new(connection.stream.as_mut().unwrap());
+
+    handshake.send_prelude(connection.my_reserved).await?;
+    let their_reserved = handshake.receive_prelude().await?;
+
+    handshake.send_info_hash(info_hash).await?;
+    let their_info_hash = handshake.receive_info_hash().await?;
+
+    if &their_info_hash != info_hash {
+        let message = format!(
+            "Their {:?} does not match our {:?}",
+            their_info_hash, info_hash
+        );
+        return Err(CloseReason::Handshake(message.into()));
     }
 
-    fn activate(self) -> Connection<Active> {
-        Connection::from_pending_outgoing(self)
+    handshake.send_peer_id(&connection.my_peer_id).await?;
+    let their_peer_id = handshake.receive_peer_id().await?;
+
+    if their_peer_id == connection.my_peer_id {
+        super::flag_own_addr(connection.addr);
+        return Err(CloseReason::Handshake("Connected to ourselves".into()));
     }
+
+    Ok((their_peer_id, their_reserved))
 }
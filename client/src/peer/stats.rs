@@ -0,0 +1,260 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// A peer that hasn't sent us a block in this long, despite our interest, is considered to be
+/// snubbing us.
+const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How many seconds' worth of a peer's download rate to keep requested from it, so that it has
+/// the next block to hand whenever it finishes sending one.
+const REQUEST_QUEUE_TIME: Duration = Duration::from_secs(3);
+
+/// Requests to keep queued with a peer before we've timed any of them.
+const INITIAL_PIPELINE_DEPTH: usize = 8;
+const MIN_PIPELINE_DEPTH: usize = 2;
+const MAX_PIPELINE_DEPTH: usize = 128;
+
+/// A peer whose requests take longer than this to serve is considered slow.
+const SLOW_ROUND_TRIP: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+#[derive(Clone, Debug)]
+pub struct Stats {
+    pub download: Rate,
+    pub upload: Rate,
+    pub connected_at: Instant,
+    pub last_block_at: Option<Instant>,
+
+    /// Requests the peer cancelled before we got around to serving them.
+    pub cancelled: u64,
+
+    /// Pieces the peer sent us that failed verification.
+    pub hash_failures: u32,
+
+    /// The time from sending a request to receiving its block, smoothed over recent requests.
+    pub round_trip: Option<Duration>,
+
+    /// When each request that hasn't been served yet was sent, oldest first. Peers serve requests
+    /// in the order they were sent.
+    requested_at: VecDeque<Instant>,
+
+    /// Bytes uploaded to the peer since it last sent us a block or the quota window restarted.
+    unreciprocated: u64,
+    quota_window_start: Instant,
+}
+
+/// How much to upload to a peer that gives us nothing back, regardless of what the choker decides.
+/// Keeps free riders from draining us in small swarms, where the choker unchokes whoever is there.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct UploadQuota {
+    pub bytes: u64,
+
+    /// The quota is restored this long after it was last reset, so that a peer that had nothing to
+    /// give us yet isn't cut off for good.
+    pub window: Duration,
+}
+
+/// A running byte count with a transfer rate that is recalculated on each call to `update()`.
+#[derive(Clone, Debug)]
+pub struct Rate {
+    total: u64,
+    last_total: u64,
+    last_update: Instant,
+    per_second: f64,
+}
+
+impl Stats {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            download: Rate::new(now),
+            upload: Rate::new(now),
+            connected_at: now,
+            last_block_at: None,
+            cancelled: 0,
+            hash_failures: 0,
+            round_trip: None,
+            requested_at: VecDeque::new(),
+            unreciprocated: 0,
+            quota_window_start: now,
+        }
+    }
+
+    pub fn record_request(&mut self, now: Instant) {
+        self.requested_at.push_back(now);
+    }
+
+    pub fn record_block(&mut self, len: usize, now: Instant) {
+        if let Some(sent) = self.requested_at.pop_front() {
+            let sample = now.duration_since(sent);
+
+            self.round_trip = Some(match self.round_trip {
+                Some(round_trip) => round_trip * 7 / 8 + sample / 8,
+                None => sample,
+            });
+        }
+
+        self.download.add(len);
+        self.last_block_at = Some(now);
+        self.unreciprocated = 0;
+        self.quota_window_start = now;
+    }
+
+    /// Forget one of the requests waiting to be served, after cancelling it. Any of them could
+    /// have been cancelled, and forgetting the newest errs on the side of a longer round trip.
+    pub fn record_cancel(&mut self) {
+        self.requested_at.pop_back();
+    }
+
+    /// Forget the requests that are waiting to be served, which the peer discards when it chokes
+    /// us.
+    pub fn discard_requests(&mut self) {
+        self.requested_at.clear();
+    }
+
+    pub fn record_upload(&mut self, len: usize) {
+        self.upload.add(len);
+        self.unreciprocated += len as u64;
+    }
+
+    pub fn update(&mut self, now: Instant) {
+        self.download.update(now);
+        self.upload.update(now);
+    }
+
+    pub fn is_snubbing(&self, now: Instant) -> bool {
+        now.duration_since(self.last_block_at.unwrap_or(self.connected_at)) > SNUB_TIMEOUT
+    }
+
+    /// Whether the peer takes so long to serve requests that it's holding up the pieces we claimed
+    /// from it.
+    pub fn is_slow(&self) -> bool {
+        self.round_trip
+            .is_some_and(|round_trip| round_trip > SLOW_ROUND_TRIP)
+    }
+
+    /// How many blocks of `block_length` to keep requested from the peer at once: enough to keep a
+    /// fast peer's link busy, but few enough that a slow peer doesn't sit on blocks that others
+    /// could send us sooner.
+    pub fn pipeline_depth(&self, block_length: u64) -> usize {
+        if self.round_trip.is_none() {
+            return INITIAL_PIPELINE_DEPTH;
+        }
+
+        let queued = self.download.per_second() * REQUEST_QUEUE_TIME.as_secs_f64();
+
+        ((queued / block_length as f64).ceil() as usize)
+            .clamp(MIN_PIPELINE_DEPTH, MAX_PIPELINE_DEPTH)
+    }
+
+    /// Whether we've uploaded the whole quota to the peer without getting a block back, starting a
+    /// new window first if the current one is over.
+    pub fn exceeds_quota(&mut self, quota: &UploadQuota, now: Instant) -> bool {
+        if now.duration_since(self.quota_window_start) >= quota.window {
+            self.unreciprocated = 0;
+            self.quota_window_start = now;
+        }
+
+        self.unreciprocated >= quota.bytes
+    }
+}
+
+impl Rate {
+    fn new(now: Instant) -> Self {
+        Self {
+            total: 0,
+            last_total: 0,
+            last_update: now,
+            per_second: 0.0,
+        }
+    }
+
+    pub fn add(&mut self, len: usize) {
+        self.total += len as u64;
+    }
+
+    pub fn update(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_update).as_secs_f64();
+
+        if elapsed > 0.0 {
+            self.per_second = (self.total - self.last_total) as f64 / elapsed;
+            self.last_total = self.total;
+            self.last_update = now;
+        }
+    }
+
+    pub fn per_second(&self) -> f64 {
+        self.per_second
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.pad(match self {
+            Self::Incoming => "in",
+            Self::Outgoing => "out",
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn exceeds_quota_test() {
+        let start = Instant::now();
+        let quota = UploadQuota {
+            bytes: 100,
+            window: Duration::from_secs(60),
+        };
+        let mut stats = Stats::new(start);
+
+        stats.record_upload(99);
+        assert!(!stats.exceeds_quota(&quota, start));
+        stats.record_upload(1);
+        assert!(stats.exceeds_quota(&quota, start + Duration::from_secs(30)));
+
+        stats.record_block(10, start + Duration::from_secs(40));
+        assert!(!stats.exceeds_quota(&quota, start + Duration::from_secs(40)));
+
+        stats.record_upload(100);
+        assert!(stats.exceeds_quota(&quota, start + Duration::from_secs(90)));
+        assert!(!stats.exceeds_quota(&quota, start + Duration::from_secs(100)));
+    }
+
+    #[test]
+    fn pipeline_depth_test() {
+        let start = Instant::now();
+        let mut stats = Stats::new(start);
+        assert_eq!(INITIAL_PIPELINE_DEPTH, stats.pipeline_depth(16384));
+
+        stats.record_request(start);
+        stats.record_request(start);
+        stats.record_block(16384, start + Duration::from_millis(800));
+        assert_eq!(Some(Duration::from_millis(800)), stats.round_trip);
+
+        stats.record_block(16384, start + Duration::from_millis(1600));
+        assert_eq!(Some(Duration::from_millis(900)), stats.round_trip);
+
+        // An unsolicited block leaves the round trip alone.
+        stats.record_block(16384, start + Duration::from_secs(2));
+        assert_eq!(Some(Duration::from_millis(900)), stats.round_trip);
+        assert!(!stats.is_slow());
+
+        stats.update(start + Duration::from_secs(1));
+        assert_eq!(9, stats.pipeline_depth(16384));
+
+        stats.update(start + Duration::from_secs(60));
+        assert_eq!(MIN_PIPELINE_DEPTH, stats.pipeline_depth(16384));
+
+        stats.download.add(100 * 1024 * 1024);
+        stats.update(start + Duration::from_secs(61));
+        assert_eq!(MAX_PIPELINE_DEPTH, stats.pipeline_depth(16384));
+    }
+}
@@ -0,0 +1,88 @@
+//! Strategies for choosing which piece to download next. The session is generic over
+//! [`PiecePicker`], so other strategies can be plugged in without touching the session loop.
+
+use std::fmt;
+
+use rand::seq::SliceRandom;
+
+pub trait PiecePicker: fmt::Debug {
+    /// Pick the next piece to request from a peer.
+    ///
+    /// * `candidates`: the pieces that the peer has and that we neither have nor are already
+    ///   downloading, in ascending order. Never empty.
+    /// * `availability`: for each piece in the torrent, the number of connected peers that have it.
+    /// * `have`: the number of pieces we have so far.
+    fn pick(&mut self, candidates: &[u32], availability: &[usize], have: usize) -> Option<u32>;
+}
+
+/// Download the pieces that the fewest peers have first, so that they don't disappear from the
+/// swarm and so that we have something other peers want. Ties are broken at random.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RarestFirst;
+
+/// Download pieces in order, for instance for streaming. Bad for the health of the swarm.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Sequential;
+
+/// Download a few pieces at random before switching to rarest-first. The first pieces are best
+/// finished quickly so that we have something to trade, and the rarest pieces are slow to get.
+#[derive(Clone, Copy, Debug)]
+pub struct RandomFirst {
+    pub threshold: usize,
+}
+
+impl PiecePicker for RarestFirst {
+    fn pick(&mut self, candidates: &[u32], availability: &[usize], _have: usize) -> Option<u32> {
+        let count = |index: u32| availability.get(index as usize).copied().unwrap_or(0);
+        let rarest = candidates.iter().map(|&index| count(index)).min()?;
+
+        let rarest_candidates: Vec<u32> = candidates
+            .iter()
+            .copied()
+            .filter(|&index| count(index) == rarest)
+            .collect();
+
+        rarest_candidates.choose(&mut rand::thread_rng()).copied()
+    }
+}
+
+impl PiecePicker for Sequential {
+    fn pick(&mut self, candidates: &[u32], _availability: &[usize], _have: usize) -> Option<u32> {
+        candidates.first().copied()
+    }
+}
+
+impl Default for RandomFirst {
+    fn default() -> Self {
+        Self { threshold: 4 }
+    }
+}
+
+impl PiecePicker for RandomFirst {
+    fn pick(&mut self, candidates: &[u32], availability: &[usize], have: usize) -> Option<u32> {
+        if have < self.threshold {
+            candidates.choose(&mut rand::thread_rng()).copied()
+        } else {
+            RarestFirst.pick(candidates, availability, have)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pick_test() {
+        let availability = [3, 1, 2, 1];
+
+        assert_eq!(Some(0), Sequential.pick(&[0, 2, 3], &availability, 0));
+        assert_eq!(Some(3), RarestFirst.pick(&[0, 2, 3], &availability, 0));
+        assert_eq!(Some(2), RarestFirst.pick(&[0, 2], &availability, 0));
+        assert_eq!(None, RarestFirst.pick(&[], &availability, 0));
+        assert_eq!(
+            Some(3),
+            RandomFirst { threshold: 1 }.pick(&[0, 2, 3], &availability, 1),
+        );
+    }
+}
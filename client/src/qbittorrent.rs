@@ -0,0 +1,331 @@
+//! The commonly used part of the qBittorrent WebAPI (v2), so that tools built for qBittorrent can
+//! manage the client: logging in, listing, adding, pausing, resuming and deleting torrents. Logging
+//! in checks the API's own credentials and hands out a session cookie in their place.
+
+use std::path::PathBuf;
+
+use tokio::sync::mpsc;
+
+use toytorrent_common as common;
+
+use super::{control, feed, http, json, rpc, status};
+
+pub const PREFIX: &str = "/api/v2/";
+pub const LOGIN_PATH: &str = "/api/v2/auth/login";
+
+/// The qBittorrent version to claim, which some tools check before using the API.
+const APP_VERSION: &str = "v4.6.0";
+const WEBAPI_VERSION: &str = "2.9.3";
+
+/// The ETA qBittorrent reports when it doesn't know, 100 days.
+const UNKNOWN_ETA: u64 = 8_640_000;
+
+pub fn login(request: &http::Request, access: &rpc::Access) -> http::Response {
+    let form = request.form();
+
+    match access.login(&field(&form, "username"), &field(&form, "password")) {
+        Some(session_id) => http::Response::new("200 OK", "text/plain", "Ok.").with_header(
+            "Set-Cookie",
+            format!("SID={}; HttpOnly; SameSite=Strict; path=/", session_id),
+        ),
+        None => http::Response::new("200 OK", "text/plain", "Fails."),
+    }
+}
+
+pub async fn route(
+    request: &http::Request,
+    http_client: &reqwest::Client,
+    sender: &mpsc::Sender<super::Incoming>,
+) -> http::Response {
+    match (request.method.as_str(), &request.path[PREFIX.len()..]) {
+        (_, "auth/logout") => http::Response::empty("200 OK"),
+        (_, "app/version") => http::Response::new("200 OK", "text/plain", APP_VERSION),
+        (_, "app/webapiVersion") => http::Response::new("200 OK", "text/plain", WEBAPI_VERSION),
+        (_, "torrents/info") => info(&request.query_params(), sender).await,
+        ("POST", "torrents/add") => add(&request.form(), http_client, sender).await,
+        ("POST", "torrents/delete") => delete(&request.form(), sender).await,
+        ("POST", "torrents/pause") => apply(&request.form(), status::Action::Pause, sender).await,
+        ("POST", "torrents/resume") => {
+            apply(&request.form(), status::Action::Unpause, sender).await
+        }
+        (_, "torrents/add" | "torrents/delete" | "torrents/pause" | "torrents/resume") => {
+            http::Response::empty("405 Method Not Allowed")
+        }
+        _ => http::Response::empty("404 Not Found"),
+    }
+}
+
+async fn info(
+    params: &[(String, Vec<u8>)],
+    sender: &mpsc::Sender<super::Incoming>,
+) -> http::Response {
+    let Some(statuses) = rpc::torrents(sender).await else {
+        return http::Response::empty("503 Service Unavailable");
+    };
+
+    let hashes = params
+        .iter()
+        .any(|(name, _)| name == "hashes")
+        .then(|| parse_hashes(&field(params, "hashes")));
+
+    let torrents: Vec<String> = statuses
+        .iter()
+        .filter(|status| match &hashes {
+            Some(hashes) => hashes.contains(&status.info_hash),
+            None => true,
+        })
+        .map(torrent_json)
+        .collect();
+
+    http::Response::new(
+        "200 OK",
+        "application/json",
+        format!("[{}]", torrents.join(",")),
+    )
+}
+
+/// Add the uploaded `torrents` and the torrents at the `urls`, one per line. The torrents of
+/// magnet links are added once their metadata has been fetched from peers.
+async fn add(
+    form: &[(String, Vec<u8>)],
+    http_client: &reqwest::Client,
+    sender: &mpsc::Sender<super::Incoming>,
+) -> http::Response {
+    let download_dir = Some(field(form, "savepath"))
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from);
+
+    let mut files: Vec<Vec<u8>> = form
+        .iter()
+        .filter(|(name, _)| name == "torrents")
+        .map(|(_, data)| data.clone())
+        .collect();
+
+    let mut added_any = false;
+
+    for url in field(form, "urls")
+        .lines()
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+    {
+        if url.starts_with("magnet:") {
+            match url.parse() {
+                Ok(magnet) => {
+                    if rpc::add_magnet(sender, magnet, download_dir.clone())
+                        .await
+                        .is_none()
+                    {
+                        return http::Response::empty("503 Service Unavailable");
+                    }
+                    added_any = true;
+                }
+                Err(e) => say!("Not adding {}: {}", url, e),
+            }
+            continue;
+        }
+
+        match feed::fetch(http_client, url).await {
+            Ok(data) => files.push(data),
+            Err(e) => say!("Unable to fetch {}: {}", url, e),
+        }
+    }
+
+    for data in files {
+        match common::metainfo::MetainfoFile::try_from(&data[..]) {
+            Ok(metainfo) => {
+                if rpc::add_torrent(sender, metainfo, download_dir.clone())
+                    .await
+                    .is_none()
+                {
+                    return http::Response::empty("503 Service Unavailable");
+                }
+                added_any = true;
+            }
+            Err(e) => say!("Not adding an invalid torrent: {}", e),
+        }
+    }
+
+    if added_any {
+        http::Response::new("200 OK", "text/plain", "Ok.")
+    } else {
+        http::Response::new("415 Unsupported Media Type", "text/plain", "Fails.")
+    }
+}
+
+/// Remove the torrents in `hashes`, separated by `|`, or all of them, along with their files if
+/// `deleteFiles` is `true`.
+async fn delete(
+    form: &[(String, Vec<u8>)],
+    sender: &mpsc::Sender<super::Incoming>,
+) -> http::Response {
+    let Some(info_hashes) = hashes(form, sender).await else {
+        return http::Response::empty("503 Service Unavailable");
+    };
+
+    let incoming = rpc::Incoming::Remove {
+        info_hashes,
+        delete_files: field(form, "deleteFiles") == "true",
+    };
+
+    match sender.send(incoming.into()).await {
+        Ok(()) => http::Response::empty("200 OK"),
+        Err(_) => http::Response::empty("503 Service Unavailable"),
+    }
+}
+
+/// Pause or resume the torrents in `hashes`, separated by `|`, or all of them. Torrents that are
+/// already paused or running are left alone.
+async fn apply(
+    form: &[(String, Vec<u8>)],
+    action: status::Action,
+    sender: &mpsc::Sender<super::Incoming>,
+) -> http::Response {
+    let Some(info_hashes) = hashes(form, sender).await else {
+        return http::Response::empty("503 Service Unavailable");
+    };
+
+    let Some(statuses) = rpc::torrents(sender).await else {
+        return http::Response::empty("503 Service Unavailable");
+    };
+
+    for status in statuses {
+        if !info_hashes.contains(&status.info_hash) || status.status.allows(action).is_err() {
+            continue;
+        }
+
+        let command = control::Command::Apply {
+            info_hash: status.info_hash,
+            action,
+        };
+
+        if sender.send(command.into()).await.is_err() {
+            return http::Response::empty("503 Service Unavailable");
+        }
+    }
+
+    http::Response::empty("200 OK")
+}
+
+/// The info hashes in the `hashes` field, separated by `|`, or of every torrent if it's `all`.
+/// Returns `None` if the session has ended.
+async fn hashes(
+    form: &[(String, Vec<u8>)],
+    sender: &mpsc::Sender<super::Incoming>,
+) -> Option<Vec<common::InfoHash>> {
+    let hashes = field(form, "hashes");
+
+    if hashes == "all" {
+        let statuses = rpc::torrents(sender).await?;
+        Some(statuses.iter().map(|status| status.info_hash).collect())
+    } else {
+        Some(parse_hashes(&hashes))
+    }
+}
+
+fn torrent_json(status: &rpc::TorrentStatus) -> String {
+    let complete = status.pieces_have == status.pieces;
+    let progress = if status.pieces == 0 {
+        1.0
+    } else {
+        f64::from(status.pieces_have) / f64::from(status.pieces)
+    };
+
+    let state = match (status.status, complete, status.peers > 0) {
+        (status::Status::Errored, ..) => "error",
+        (status::Status::Checking, true, _) => "checkingUP",
+        (status::Status::Checking, false, _) => "checkingDL",
+        (status::Status::Paused, true, _) => "pausedUP",
+        (status::Status::Paused, false, _) => "pausedDL",
+        (status::Status::Queued, ..) => "queuedDL",
+        (_, true, true) => "uploading",
+        (_, true, false) => "stalledUP",
+        (_, false, true) => "downloading",
+        (_, false, false) => "stalledDL",
+    };
+
+    let save_path = status
+        .download_dir
+        .as_ref()
+        .map(|dir| dir.display().to_string())
+        .unwrap_or_default();
+
+    format!(
+        "{{\"hash\":{},\"name\":{},\"size\":{},\"total_size\":{},\"progress\":{},\
+         \"downloaded\":{},\"amount_left\":{},\"state\":{},\"num_seeds\":0,\"num_leechs\":{},\
+         \"dlspeed\":0,\"upspeed\":0,\"eta\":{},\"save_path\":{},\"category\":\"\",\"tags\":\"\"}}",
+        json::string(&status.info_hash.to_string()),
+        json::string(&status.name),
+        status.length,
+        status.length,
+        progress,
+        status.downloaded,
+        status.left,
+        json::string(state),
+        status.peers,
+        if complete { 0 } else { UNKNOWN_ETA },
+        json::string(&save_path),
+    )
+}
+
+/// The value of the form field `name` as text, or an empty string if there is none.
+fn field(form: &[(String, Vec<u8>)], name: &str) -> String {
+    form.iter()
+        .find(|(field, _)| field == name)
+        .map(|(_, value)| String::from_utf8_lossy(value).into_owned())
+        .unwrap_or_default()
+}
+
+/// Parse info hashes in hex separated by `|`, skipping any that are invalid.
+fn parse_hashes(input: &str) -> Vec<common::InfoHash> {
+    input
+        .split('|')
+        .filter_map(|hash| common::InfoHash::from_hex(hash.trim()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_hashes_test() {
+        let info_hash = common::InfoHash::from([0xab; 20]);
+
+        assert_eq!(
+            vec![info_hash, info_hash],
+            parse_hashes(&format!(
+                "{}|{}|xyz",
+                info_hash,
+                info_hash.to_string().to_uppercase()
+            )),
+        );
+        assert!(parse_hashes("").is_empty());
+        assert!(parse_hashes(&"zz".repeat(20)).is_empty());
+    }
+
+    #[tokio::test]
+    async fn add_magnet_test() {
+        let info_hash = common::InfoHash::from([0xab; 20]);
+        let form = [
+            (
+                "urls".to_string(),
+                format!("magnet:?xt=urn:btih:{}\n", info_hash).into_bytes(),
+            ),
+            ("savepath".to_string(), b"/srv/downloads".to_vec()),
+        ];
+        let (sender, mut receiver) = mpsc::channel(1);
+
+        add(&form, &reqwest::Client::new(), &sender).await;
+
+        match receiver.recv().await {
+            Some(super::super::Incoming::Rpc(rpc::Incoming::AddMagnet {
+                magnet,
+                download_dir,
+            })) => {
+                assert_eq!(info_hash, magnet.info_hash);
+                assert_eq!(Some(PathBuf::from("/srv/downloads")), download_dir);
+            }
+            _ => panic!("Expected the magnet link to be added"),
+        }
+    }
+}
@@ -0,0 +1,195 @@
+//! Per-tracker adjustments to announces, for trackers that expect something other than what the
+//! spec (or most clients) would send.
+//!
+//! Quirks are listed in a plain text file. `host` starts a new rule, matching the announce URL's
+//! host exactly, or any subdomain with a leading `*.`, or every tracker with `*`. The quirks after
+//! it apply to trackers that match:
+//!
+//! ```text
+//! # Comments start with `#`.
+//! host tracker.example.org
+//! support-crypto
+//! no-compact
+//!
+//! host *.example.net
+//! retry-without-event
+//! ```
+//!
+//! A tracker that matches more than one rule gets the quirks of all of them.
+
+use std::fs;
+use std::path::Path;
+
+use toytorrent_common as common;
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Quirks(Vec<Rule>);
+
+/// The quirks to apply to one tracker.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Quirk {
+    /// Send `supportcrypto=1` on every announce.
+    pub support_crypto: bool,
+
+    /// Ask for a compact peer list with `compact=1`.
+    pub compact: bool,
+
+    /// Send `compact=0`, for trackers that don't understand compact peer lists.
+    pub no_compact: bool,
+
+    /// If an announce with an event is refused, try it again once without the event.
+    pub retry_without_event: bool,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Rule {
+    host: String,
+    quirk: Quirk,
+}
+
+impl Quirks {
+    /// Read the quirks at `path`.
+    pub fn load(path: &Path) -> Result<Self, common::Error> {
+        let config = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::parse_config(&config)
+    }
+
+    fn parse_config(config: &str) -> Result<Self, common::Error> {
+        let mut rules: Vec<Rule> = Vec::new();
+
+        for (i, line) in config.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(host) = line.strip_prefix("host ") {
+                rules.push(Rule {
+                    host: host.trim().to_ascii_lowercase(),
+                    quirk: Quirk::default(),
+                });
+                continue;
+            }
+
+            let rule = rules
+                .last_mut()
+                .ok_or_else(|| format!("Line {}: `{}` must follow a `host`", i + 1, line))?;
+
+            match line {
+                "support-crypto" => rule.quirk.support_crypto = true,
+                "compact" => rule.quirk.compact = true,
+                "no-compact" => rule.quirk.no_compact = true,
+                "retry-without-event" => rule.quirk.retry_without_event = true,
+                _ => return Err(format!("Line {}: unknown quirk `{}`", i + 1, line).into()),
+            }
+
+            if rule.quirk.compact && rule.quirk.no_compact {
+                return Err(
+                    format!("Line {}: `compact` conflicts with `no-compact`", i + 1).into(),
+                );
+            }
+        }
+
+        Ok(Self(rules))
+    }
+
+    /// The quirks of the tracker at `announce_url`, combined from every rule that matches it.
+    pub fn for_url(&self, announce_url: &str) -> Quirk {
+        let Some(host) = reqwest::Url::parse(announce_url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_ascii_lowercase))
+        else {
+            return Quirk::default();
+        };
+
+        self.0
+            .iter()
+            .filter(|rule| rule.matches(&host))
+            .fold(Quirk::default(), |quirk, rule| Quirk {
+                support_crypto: quirk.support_crypto || rule.quirk.support_crypto,
+                compact: quirk.compact || rule.quirk.compact,
+                no_compact: quirk.no_compact || rule.quirk.no_compact,
+                retry_without_event: quirk.retry_without_event || rule.quirk.retry_without_event,
+            })
+    }
+}
+
+impl Quirk {
+    pub fn apply(&self, request: &mut common::tracker::Request) {
+        if self.support_crypto {
+            request.supportcrypto = Some(true);
+        }
+
+        if self.compact {
+            request.compact = Some(true);
+        } else if self.no_compact {
+            request.compact = Some(false);
+        }
+    }
+}
+
+impl Rule {
+    fn matches(&self, host: &str) -> bool {
+        match self.host.strip_prefix("*.") {
+            Some(domain) => host
+                .strip_suffix(domain)
+                .is_some_and(|subdomain| subdomain.ends_with('.')),
+            None => self.host == "*" || self.host == host,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn for_url_test() {
+        let quirks = Quirks::parse_config(
+            "# Comment\n\
+             host tracker.example.org\n\
+             support-crypto\n\
+             \n\
+             host *.Example.org\n\
+             no-compact\n\
+             retry-without-event\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            Quirk {
+                support_crypto: true,
+                no_compact: true,
+                retry_without_event: true,
+                ..Quirk::default()
+            },
+            quirks.for_url("http://Tracker.example.org:6969/announce"),
+        );
+        assert_eq!(
+            Quirk {
+                no_compact: true,
+                retry_without_event: true,
+                ..Quirk::default()
+            },
+            quirks.for_url("https://other.example.org/announce?passkey=abc"),
+        );
+        assert_eq!(
+            Quirk::default(),
+            quirks.for_url("http://example.org/announce")
+        );
+        assert_eq!(
+            Quirk::default(),
+            quirks.for_url("http://badexample.org/announce")
+        );
+        assert_eq!(Quirk::default(), quirks.for_url("not a url"));
+    }
+
+    #[test]
+    fn parse_config_test() {
+        assert!(Quirks::parse_config("support-crypto\n").is_err());
+        assert!(Quirks::parse_config("host a\nsend-everything\n").is_err());
+        assert!(Quirks::parse_config("host a\ncompact\nno-compact\n").is_err());
+        assert_eq!(Ok(Quirks::default()), Quirks::parse_config("\n# host a\n"));
+    }
+}
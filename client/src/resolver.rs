@@ -0,0 +1,184 @@
+//! Hostname resolution and connection establishment. Lookups are cached for a few minutes so that
+//! repeated announces don't hit the system resolver every time, and connections race IPv6 and
+//! IPv4 candidates per RFC 8305 ("happy eyeballs") so that a broken address family doesn't stall
+//! the dial.
+
+use std::collections::{HashMap, VecDeque};
+use std::error;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::net::{self, TcpStream};
+use tokio::task::JoinSet;
+use tokio::time;
+
+const CACHE_TTL: Duration = Duration::from_secs(300);
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Debug, Default)]
+pub struct Resolver {
+    cache: Arc<Mutex<HashMap<(String, u16), CacheEntry>>>,
+}
+
+/// A failed lookup, kept distinct so that it can still be told apart from other connection errors
+/// once reqwest has wrapped it.
+#[derive(Debug)]
+pub struct ResolveError(io::Error);
+
+#[derive(Clone, Debug)]
+struct CacheEntry {
+    addrs: Vec<SocketAddr>,
+    expires: Instant,
+}
+
+impl Resolver {
+    pub async fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let key = (host.to_string(), port);
+
+        if let Some(entry) = self.cache.lock().unwrap().get(&key) {
+            if entry.expires > Instant::now() {
+                return Ok(entry.addrs.clone());
+            }
+        }
+
+        let addrs = interleave(net::lookup_host((host, port)).await?.collect());
+
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No addresses found for {host}"),
+            ));
+        }
+
+        self.cache.lock().unwrap().insert(
+            key,
+            CacheEntry {
+                addrs: addrs.clone(),
+                expires: Instant::now() + CACHE_TTL,
+            },
+        );
+
+        Ok(addrs)
+    }
+}
+
+impl reqwest::dns::Resolve for Resolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let resolver = self.clone();
+
+        Box::pin(async move {
+            // reqwest overrides the port with the one from the URL, so any value will do here.
+            let addrs = resolver
+                .lookup(name.as_str(), 0)
+                .await
+                .map_err(ResolveError)?;
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl error::Error for ResolveError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+/// Connect to the first responsive address out of `addrs`, dialling each one with `dial`. A new
+/// attempt is started every [`CONNECTION_ATTEMPT_DELAY`] (or immediately when an attempt fails)
+/// until one succeeds, alternating between address families. Trackers are dialled by reqwest,
+/// which looks them up through [`Resolver`] and races address families itself.
+pub async fn connect_with<F, D>(addrs: &[SocketAddr], dial: D) -> io::Result<TcpStream>
+where
+    D: Fn(SocketAddr) -> F,
+    F: Future<Output = io::Result<TcpStream>> + Send + 'static,
+{
+    let mut pending: VecDeque<SocketAddr> = interleave(addrs.to_vec()).into();
+    let mut attempts = JoinSet::new();
+    let mut last_error = None;
+
+    loop {
+        if let Some(addr) = pending.pop_front() {
+            attempts.spawn(dial(addr));
+        } else if attempts.is_empty() {
+            return Err(last_error.unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "No addresses to connect to")
+            }));
+        }
+
+        let result = if pending.is_empty() {
+            Ok(attempts.join_next().await)
+        } else {
+            time::timeout(CONNECTION_ATTEMPT_DELAY, attempts.join_next()).await
+        };
+
+        match result {
+            // Dropping the JoinSet aborts any attempts still in flight.
+            Ok(Some(Ok(Ok(stream)))) => return Ok(stream),
+            Ok(Some(Ok(Err(e)))) => last_error = Some(e),
+            Ok(Some(Err(e))) => last_error = Some(io::Error::other(e)),
+            Ok(None) | Err(_) => {}
+        }
+    }
+}
+
+/// Order addresses IPv6-first, alternating between families.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (mut v6, mut v4): (VecDeque<_>, VecDeque<_>) =
+        addrs.into_iter().partition(SocketAddr::is_ipv6);
+    let mut result = Vec::with_capacity(v6.len() + v4.len());
+
+    loop {
+        match (v6.pop_front(), v4.pop_front()) {
+            (None, None) => break,
+            (a, b) => result.extend(a.into_iter().chain(b)),
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn interleave_test() {
+        let a: SocketAddr = "[::1]:1".parse().unwrap();
+        let b: SocketAddr = "[::1]:2".parse().unwrap();
+        let c: SocketAddr = "127.0.0.1:3".parse().unwrap();
+        let d: SocketAddr = "127.0.0.1:4".parse().unwrap();
+        let e: SocketAddr = "127.0.0.1:5".parse().unwrap();
+
+        assert_eq!(vec![a, c, b, d, e], interleave(vec![c, d, a, e, b]));
+    }
+
+    #[tokio::test]
+    async fn connect_with_test() {
+        let closed = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_addr = closed.local_addr().unwrap();
+        drop(closed);
+
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let stream = connect_with(&[closed_addr, addr], TcpStream::connect)
+            .await
+            .unwrap();
+        assert_eq!(addr, stream.peer_addr().unwrap());
+
+        assert!(connect_with(&[closed_addr], TcpStream::connect)
+            .await
+            .is_err());
+        assert!(connect_with(&[], TcpStream::connect).await.is_err());
+    }
+}
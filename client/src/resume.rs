@@ -0,0 +1,196 @@
+//! Per-torrent state that should survive a restart, kept as a bencoded dictionary in the state
+//! directory.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use toytorrent_common as common;
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ResumeData {
+    /// The key sent to trackers, which lets them recognize us across IP address changes. It is
+    /// only useful if it stays the same from one run to the next.
+    pub key: Option<common::PeerKey>,
+
+    /// Where the torrent's files are saved, if not the default.
+    pub download_dir: Option<PathBuf>,
+
+    /// Whether the user paused the torrent, so that it stays paused once restored.
+    pub paused: bool,
+}
+
+impl ResumeData {
+    pub fn path(state_dir: &Path, info_hash: &common::InfoHash) -> PathBuf {
+        state_dir.join(format!("{}.resume", info_hash))
+    }
+
+    /// Where a copy of the torrent's metainfo is kept while it's part of the session. It's the
+    /// copies that get the session restored on the next run.
+    pub fn metainfo_path(state_dir: &Path, info_hash: &common::InfoHash) -> PathBuf {
+        state_dir.join(format!("{}.torrent", info_hash))
+    }
+
+    /// Load the resume data at `path`, or the default if there is none yet.
+    pub fn load(path: &Path) -> Result<Self, common::Error> {
+        match fs::read(path) {
+            Ok(bytes) => Self::try_from(&bytes[..]),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(format!("{}: {}", path.display(), e).into()),
+        }
+    }
+
+    /// Write the resume data to a temporary file before moving it into place, so that a crash
+    /// halfway through doesn't leave a truncated file behind.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let temp_path = path.with_extension("resume.tmp");
+        fs::write(&temp_path, Vec::<u8>::from(self))?;
+        fs::rename(&temp_path, path)
+    }
+}
+
+/// Keep a torrent in the session across restarts, with its resume data. The metainfo is written
+/// with its info dictionary exactly as it was added, so that it comes back with the same info
+/// hash.
+pub fn save_torrent(
+    state_dir: &Path,
+    metainfo: &common::metainfo::MetainfoFile,
+    resume_data: &ResumeData,
+) -> io::Result<()> {
+    let info_hash = metainfo.info_hash();
+    resume_data.save(&ResumeData::path(state_dir, info_hash))?;
+
+    let path = ResumeData::metainfo_path(state_dir, info_hash);
+    let temp_path = path.with_extension("torrent.tmp");
+    fs::write(&temp_path, Vec::<u8>::from(metainfo))?;
+    fs::rename(&temp_path, path)
+}
+
+/// Leave a torrent out of the session from now on. Its resume data is kept, so that it gets the
+/// same key if it's added again.
+pub fn forget_torrent(state_dir: &Path, info_hash: &common::InfoHash) -> io::Result<()> {
+    match fs::remove_file(ResumeData::metainfo_path(state_dir, info_hash)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// The torrents saved in the state directory by earlier runs. Unreadable ones are skipped.
+pub fn saved_torrents(state_dir: &Path) -> Vec<common::metainfo::MetainfoFile> {
+    let entries = match fs::read_dir(state_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Vec::new(),
+        Err(e) => {
+            say!(
+                "Unable to restore the session from {}: {}",
+                state_dir.display(),
+                e
+            );
+            return Vec::new();
+        }
+    };
+
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "torrent")
+        })
+        .collect();
+    paths.sort();
+
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let metainfo = fs::read(&path)
+                .map_err(|e| common::Error::from(e.to_string()))
+                .and_then(|bytes| common::metainfo::MetainfoFile::try_from(&bytes[..]));
+
+            match metainfo {
+                Ok(metainfo) => Some(metainfo),
+                Err(e) => {
+                    say!("Not restoring {}: {}", path.display(), e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+impl TryFrom<&[u8]> for ResumeData {
+    type Error = common::Error;
+
+    fn try_from(input: &[u8]) -> Result<Self, Self::Error> {
+        let mut dict = common::BencodeValue::decode(input)?
+            .to_dict()
+            .ok_or("Resume data must be a bencoded dict")?;
+
+        let key = dict
+            .remove(&b"key"[..])
+            .map(|key| key.to_bytes().ok_or("`key` must be a string"))
+            .transpose()?
+            .map(|key| common::PeerKey::from(&key[..]));
+
+        let download_dir = dict
+            .remove(&b"download_dir"[..])
+            .map(|dir| dir.to_string().ok_or("`download_dir` must be a string"))
+            .transpose()?
+            .map(PathBuf::from);
+
+        let paused = dict
+            .remove(&b"paused"[..])
+            .map(|paused| paused.to_u64().ok_or("`paused` must be an integer"))
+            .transpose()?
+            .is_some_and(|paused| paused != 0);
+
+        Ok(Self {
+            key,
+            download_dir,
+            paused,
+        })
+    }
+}
+
+impl From<&ResumeData> for Vec<u8> {
+    fn from(input: &ResumeData) -> Self {
+        let download_dir = input
+            .download_dir
+            .as_ref()
+            .map(|dir| common::BencodeValue::from(dir.to_string_lossy().into_owned()));
+
+        input
+            .key
+            .iter()
+            .map(|key| ("key", common::BencodeValue::from(key.as_slice())))
+            .chain(download_dir.map(|dir| ("download_dir", dir)))
+            .chain(
+                input
+                    .paused
+                    .then(|| ("paused", common::BencodeValue::from(1u64))),
+            )
+            .collect::<common::BencodeValue>()
+            .encode()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip_test() {
+        let resume_data = ResumeData {
+            key: Some(common::PeerKey::generate()),
+            download_dir: Some(PathBuf::from("/srv/torrents")),
+            paused: true,
+        };
+        let bytes: Vec<u8> = (&resume_data).into();
+
+        assert_eq!(Ok(resume_data), ResumeData::try_from(&bytes[..]));
+        assert_eq!(Ok(ResumeData::default()), ResumeData::try_from(&b"de"[..]));
+    }
+}
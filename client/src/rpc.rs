@@ -0,0 +1,672 @@
+//! A JSON API over HTTP for managing the client remotely, for instance from a web UI. If a token
+//! or a user name and password are configured, every request must carry one of them, and web
+//! pages from other origins may only use the API if their origin is allowed.
+//!
+//! Whether or not credentials are required, requests naming a host other than the address the
+//! API is served on or `localhost` are refused, so that a page can't reach the API by pointing
+//! its own domain at it. Requests that change anything are refused if they come from a web page
+//! on an origin that isn't allowed, since a browser will send those without asking.
+//!
+//! * `GET /api/torrents`: the torrents in the session and their progress.
+//! * `POST /api/torrents`: add the torrent whose metainfo file or magnet link is the request body.
+//!   A magnet link's torrent is only added once its metadata has been fetched, so it's accepted
+//!   with `202 Accepted` rather than `201 Created`.
+//! * `POST /api/standby` and `POST /api/resume`: the `standby` and `resume` control commands.
+//! * `POST /api/pause` and `POST /api/unpause`: pause or unpause the torrent whose info hash in hex
+//!   is the request body.
+//! * `POST /api/retry`: resume the torrent whose info hash in hex is the request body, after a
+//!   storage error paused it.
+//!
+//! The web UI is served from `/`, and asks for a token itself if the API wants one. The commonly
+//! used part of the qBittorrent WebAPI is served under `/api/v2/`.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use base64::Engine as _;
+use rand::Rng;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task;
+
+use toytorrent_common as common;
+
+use super::{control, http, json, qbittorrent, status, ui};
+
+/// The longest request body to accept, enough for the metainfo files of large torrents.
+const MAX_BODY_LEN: usize = 8 * 1024 * 1024;
+
+/// A request from the server to the session.
+#[derive(Debug)]
+pub enum Incoming {
+    /// The status of every torrent in the session.
+    Torrents {
+        reply: oneshot::Sender<Vec<TorrentStatus>>,
+    },
+
+    /// Add a torrent, replying whether it wasn't in the session already.
+    Add {
+        metainfo: Box<common::metainfo::MetainfoFile>,
+        download_dir: Option<PathBuf>,
+        reply: oneshot::Sender<bool>,
+    },
+
+    /// Add the torrent that a magnet link names, once its metadata has been fetched from peers
+    /// unless it was kept from an earlier session.
+    AddMagnet {
+        magnet: common::MagnetUri,
+        download_dir: Option<PathBuf>,
+    },
+
+    /// Remove torrents from the session, and delete their files with `delete_files`.
+    Remove {
+        info_hashes: Vec<common::InfoHash>,
+        delete_files: bool,
+    },
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TorrentStatus {
+    pub name: String,
+    pub info_hash: common::InfoHash,
+    pub status: status::Status,
+    pub length: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub pieces: u32,
+    pub pieces_have: u32,
+    pub peers: usize,
+    pub download_dir: Option<PathBuf>,
+
+    /// The storage error that paused the torrent, if any.
+    pub error: Option<String>,
+}
+
+/// Who may use the API.
+#[derive(Debug)]
+pub struct Access {
+    /// The accepted `Authorization` headers, split into scheme and credentials. Anyone may use the
+    /// API if there are none.
+    credentials: Vec<(&'static str, String)>,
+
+    /// The origins of web pages that may use the API, or `*` for any.
+    cors_origins: Vec<String>,
+
+    /// The cookie handed out by the qBittorrent login, which stands in for the credentials.
+    session_id: String,
+}
+
+impl Access {
+    pub fn new(token: Option<&str>, user: Option<(&str, &str)>, cors_origins: Vec<String>) -> Self {
+        let credentials = token
+            .map(|token| ("Bearer", token.to_string()))
+            .into_iter()
+            .chain(user.map(|(user, password)| ("Basic", basic_credentials(user, password))))
+            .collect();
+
+        let session_id = rand::thread_rng()
+            .sample_iter(rand::distributions::Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+
+        Self {
+            credentials,
+            cors_origins,
+            session_id,
+        }
+    }
+
+    pub fn requires_auth(&self) -> bool {
+        !self.credentials.is_empty()
+    }
+
+    fn is_authorized(&self, request: &http::Request) -> bool {
+        if !self.requires_auth() {
+            return true;
+        }
+
+        let has_session = request
+            .header("cookie")
+            .into_iter()
+            .flat_map(|cookies| cookies.split(';'))
+            .filter_map(|cookie| cookie.trim().strip_prefix("SID="))
+            .any(|id| constant_time_eq(id.as_bytes(), self.session_id.as_bytes()));
+
+        has_session
+            || request
+                .header("authorization")
+                .and_then(|header| header.split_once(' '))
+                .is_some_and(|(scheme, credentials)| self.accepts(scheme, credentials.trim()))
+    }
+
+    /// Check a user name and password, which may instead be the token with any user name. Returns
+    /// the session ID to hand out if they're right.
+    pub fn login(&self, user: &str, password: &str) -> Option<&str> {
+        let basic = basic_credentials(user, password);
+
+        (!self.requires_auth() || self.accepts("Basic", &basic) || self.accepts("Bearer", password))
+            .then_some(self.session_id.as_str())
+    }
+
+    fn accepts(&self, scheme: &str, credentials: &str) -> bool {
+        self.credentials.iter().any(|(expected_scheme, expected)| {
+            scheme.eq_ignore_ascii_case(expected_scheme)
+                && constant_time_eq(credentials.as_bytes(), expected.as_bytes())
+        })
+    }
+
+    /// The request's `Origin`, if it's allowed to use the API from a web page.
+    fn cors_origin<'a>(&self, request: &'a http::Request) -> Option<&'a str> {
+        request
+            .header("origin")
+            .filter(|origin| self.is_allowed_origin(origin))
+    }
+
+    fn is_allowed_origin(&self, origin: &str) -> bool {
+        self.cors_origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+    }
+
+    /// Whether the request would change something and comes from a web page on an origin other
+    /// than the API's own or an allowed one. Requests that don't name their origin aren't from a
+    /// browser, or are from the API's own pages.
+    fn is_cross_site(&self, request: &http::Request) -> bool {
+        if matches!(request.method.as_str(), "GET" | "HEAD" | "OPTIONS") {
+            return false;
+        }
+
+        let origin = match (request.header("origin"), request.header("referer")) {
+            (Some(origin), _) => origin,
+            (None, Some(referer)) => origin_of(referer),
+            (None, None) => return false,
+        };
+
+        let own = request.header("host").is_some_and(|host| {
+            origin
+                .split_once("://")
+                .is_some_and(|(_, authority)| authority.eq_ignore_ascii_case(host))
+        });
+
+        !own && !self.is_allowed_origin(origin)
+    }
+
+    /// The challenge to send with a 401 response.
+    fn challenge(&self) -> &'static str {
+        if self
+            .credentials
+            .iter()
+            .any(|(scheme, _)| *scheme == "Basic")
+        {
+            "Basic realm=\"toytorrent\""
+        } else {
+            "Bearer"
+        }
+    }
+}
+
+/// Whether the request's `Host` names the address it was received on or `localhost`.
+fn is_allowed_host(request: &http::Request, local_ip: IpAddr) -> bool {
+    let Some(host) = request.header("host") else {
+        return true;
+    };
+
+    let name = match host.strip_prefix('[') {
+        Some(bracketed) => bracketed.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+
+    name.eq_ignore_ascii_case("localhost")
+        || name
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.to_canonical() == local_ip.to_canonical())
+}
+
+/// The scheme, host and port of a URL.
+fn origin_of(url: &str) -> &str {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url;
+    };
+
+    let authority_len = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    &url[..scheme.len() + "://".len() + authority_len]
+}
+
+impl TorrentStatus {
+    fn to_json(&self) -> String {
+        format!(
+            "{{\"name\":{},\"info_hash\":{},\"status\":{},\"length\":{},\"downloaded\":{},\
+             \"left\":{},\"pieces\":{},\"pieces_have\":{},\"peers\":{},\"error\":{}}}",
+            json::string(&self.name),
+            json::string(&self.info_hash.to_string()),
+            json::string(self.status.as_str()),
+            self.length,
+            self.downloaded,
+            self.left,
+            self.pieces,
+            self.pieces_have,
+            self.peers,
+            self.error
+                .as_deref()
+                .map_or("null".to_string(), json::string),
+        )
+    }
+}
+
+pub async fn listen(
+    listener: TcpListener,
+    access: Access,
+    http_client: reqwest::Client,
+    sender: mpsc::Sender<super::Incoming>,
+) {
+    let access = Arc::new(access);
+
+    loop {
+        let Ok((stream, addr)) = listener.accept().await else {
+            continue;
+        };
+
+        let access = access.clone();
+        let http_client = http_client.clone();
+        let sender = sender.clone();
+
+        task::spawn(async move {
+            if let Err(e) = serve(stream, addr, &access, &http_client, sender).await {
+                say!("{:21} API error: {}", addr, e);
+            }
+        });
+    }
+}
+
+async fn serve(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    access: &Access,
+    http_client: &reqwest::Client,
+    sender: mpsc::Sender<super::Incoming>,
+) -> io::Result<()> {
+    let request = http::read_request(&mut stream, MAX_BODY_LEN).await?;
+    let local_ip = stream.local_addr()?.ip();
+    let cors_origin = access.cors_origin(&request);
+
+    let response = if !is_allowed_host(&request, local_ip) {
+        println!(
+            "{:21} API request for unknown host {:?}",
+            addr,
+            request.header("host")
+        );
+
+        http::Response::empty("400 Bad Request")
+    } else if request.method == "OPTIONS" {
+        // Browsers send preflight requests without credentials.
+        let response = http::Response::empty("204 No Content");
+
+        match cors_origin {
+            Some(_) => response
+                .with_header("Access-Control-Allow-Methods", "GET, POST, OPTIONS")
+                .with_header(
+                    "Access-Control-Allow-Headers",
+                    "Authorization, Content-Type",
+                )
+                .with_header("Access-Control-Max-Age", "600"),
+            None => response,
+        }
+    } else if let Some((content_type, body)) =
+        ui::asset(&request.path).filter(|_| request.method == "GET")
+    {
+        http::Response::new("200 OK", content_type, body)
+            .with_header("Content-Security-Policy", "default-src 'self'")
+    } else if access.is_cross_site(&request) {
+        println!("{:21} Cross-site API request for {}", addr, request.path);
+
+        http::Response::empty("403 Forbidden")
+    } else if request.path == qbittorrent::LOGIN_PATH {
+        qbittorrent::login(&request, access)
+    } else if !access.is_authorized(&request) {
+        say!("{:21} Unauthorized API request for {}", addr, request.path);
+
+        // qBittorrent clients log in again when they're forbidden.
+        if request.path.starts_with(qbittorrent::PREFIX) {
+            http::Response::new("403 Forbidden", "text/plain", "Forbidden")
+        } else {
+            http::Response::new("401 Unauthorized", "application/json", "{}")
+                .with_header("WWW-Authenticate", access.challenge())
+        }
+    } else if request.path.starts_with(qbittorrent::PREFIX) {
+        qbittorrent::route(&request, http_client, &sender).await
+    } else {
+        route(&request, &sender).await
+    };
+
+    let response = match cors_origin {
+        Some(origin) => response.with_header("Access-Control-Allow-Origin", origin),
+        None => response,
+    };
+
+    response
+        .with_header("Vary", "Origin")
+        .write_to(&mut stream)
+        .await
+}
+
+async fn route(request: &http::Request, sender: &mpsc::Sender<super::Incoming>) -> http::Response {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/api/torrents") => match torrents(sender).await {
+            Some(statuses) => {
+                let json: Vec<String> = statuses.iter().map(TorrentStatus::to_json).collect();
+                let body = format!("[{}]", json.join(","));
+                http::Response::new("200 OK", "application/json", body)
+            }
+            None => http::Response::empty("503 Service Unavailable"),
+        },
+        ("POST", "/api/torrents") => add(sender, &request.body).await,
+        ("POST", "/api/standby") => command(sender, control::Command::Standby).await,
+        ("POST", "/api/resume") => command(sender, control::Command::Resume).await,
+        ("POST", "/api/pause") => apply(sender, &request.body, status::Action::Pause).await,
+        ("POST", "/api/unpause") => apply(sender, &request.body, status::Action::Unpause).await,
+        ("POST", "/api/retry") => apply(sender, &request.body, status::Action::Retry).await,
+        (
+            _,
+            "/api/torrents" | "/api/standby" | "/api/resume" | "/api/pause" | "/api/unpause"
+            | "/api/retry",
+        ) => http::Response::empty("405 Method Not Allowed"),
+        _ => http::Response::empty("404 Not Found"),
+    }
+}
+
+async fn add(sender: &mpsc::Sender<super::Incoming>, body: &[u8]) -> http::Response {
+    if body.starts_with(b"magnet:") {
+        return add_magnet_link(sender, body).await;
+    }
+
+    let metainfo = match common::metainfo::MetainfoFile::try_from(body) {
+        Ok(metainfo) => metainfo,
+        Err(e) => {
+            let body = format!("{{\"error\":{}}}", json::string(&e));
+            return http::Response::new("400 Bad Request", "application/json", body);
+        }
+    };
+
+    let info_hash = *metainfo.info_hash();
+    let body = format!("{{\"info_hash\":{}}}", json::string(&info_hash.to_string()));
+
+    match add_torrent(sender, metainfo, None).await {
+        Some(true) => http::Response::new("201 Created", "application/json", body),
+        Some(false) => http::Response::new("200 OK", "application/json", body),
+        None => http::Response::empty("503 Service Unavailable"),
+    }
+}
+
+async fn add_magnet_link(sender: &mpsc::Sender<super::Incoming>, body: &[u8]) -> http::Response {
+    let magnet = match String::from_utf8_lossy(body)
+        .trim()
+        .parse::<common::MagnetUri>()
+    {
+        Ok(magnet) => magnet,
+        Err(e) => {
+            let body = format!("{{\"error\":{}}}", json::string(&e));
+            return http::Response::new("400 Bad Request", "application/json", body);
+        }
+    };
+
+    let body = format!(
+        "{{\"info_hash\":{}}}",
+        json::string(&magnet.info_hash.to_string())
+    );
+
+    match add_magnet(sender, magnet, None).await {
+        Some(()) => http::Response::new("202 Accepted", "application/json", body),
+        None => http::Response::empty("503 Service Unavailable"),
+    }
+}
+
+/// Pause, unpause or retry the torrent whose info hash in hex is `body`.
+async fn apply(
+    sender: &mpsc::Sender<super::Incoming>,
+    body: &[u8],
+    action: status::Action,
+) -> http::Response {
+    let info_hash = std::str::from_utf8(body)
+        .ok()
+        .and_then(|body| common::InfoHash::from_hex(body.trim()));
+
+    match info_hash {
+        Some(info_hash) => command(sender, control::Command::Apply { info_hash, action }).await,
+        None => {
+            let body = format!(
+                "{{\"error\":{}}}",
+                json::string("Expected an info hash in hex")
+            );
+            http::Response::new("400 Bad Request", "application/json", body)
+        }
+    }
+}
+
+/// Ask the session for the status of its torrents. Returns `None` if it has ended.
+pub async fn torrents(sender: &mpsc::Sender<super::Incoming>) -> Option<Vec<TorrentStatus>> {
+    let (reply, receiver) = oneshot::channel();
+    sender
+        .send(Incoming::Torrents { reply }.into())
+        .await
+        .ok()?;
+    receiver.await.ok()
+}
+
+/// Add a torrent to the session, returning whether it wasn't there already, or `None` if the
+/// session has ended.
+pub async fn add_torrent(
+    sender: &mpsc::Sender<super::Incoming>,
+    metainfo: common::metainfo::MetainfoFile,
+    download_dir: Option<PathBuf>,
+) -> Option<bool> {
+    let (reply, receiver) = oneshot::channel();
+
+    let incoming = Incoming::Add {
+        metainfo: Box::new(metainfo),
+        download_dir,
+        reply,
+    };
+
+    sender.send(incoming.into()).await.ok()?;
+    receiver.await.ok()
+}
+
+/// Have the session add the torrent that `magnet` names once it has its metadata. Returns `None`
+/// if the session has ended.
+pub async fn add_magnet(
+    sender: &mpsc::Sender<super::Incoming>,
+    magnet: common::MagnetUri,
+    download_dir: Option<PathBuf>,
+) -> Option<()> {
+    let incoming = Incoming::AddMagnet {
+        magnet,
+        download_dir,
+    };
+
+    sender.send(incoming.into()).await.ok()
+}
+
+async fn command(
+    sender: &mpsc::Sender<super::Incoming>,
+    command: control::Command,
+) -> http::Response {
+    match sender.send(command.into()).await {
+        Ok(()) => http::Response::empty("204 No Content"),
+        Err(_) => http::Response::empty("503 Service Unavailable"),
+    }
+}
+
+fn basic_credentials(user: &str, password: &str) -> String {
+    base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password))
+}
+
+/// Compare secrets without giving away how much of them matched through the time taken.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> http::Request {
+        request_with_method("GET", headers)
+    }
+
+    fn request_with_method(method: &str, headers: &[(&str, &str)]) -> http::Request {
+        let head: String = headers
+            .iter()
+            .map(|(name, value)| format!("{}: {}\r\n", name, value))
+            .collect();
+
+        http::Request::parse_head(&format!("{} /api/standby HTTP/1.1\r\n{}\r\n", method, head))
+            .unwrap()
+    }
+
+    #[test]
+    fn is_authorized_test() {
+        let open = Access::new(None, None, Vec::new());
+        assert!(open.login("anyone", "").is_some());
+        assert!(open.is_authorized(&request(&[])));
+
+        let access = Access::new(Some("s3cret"), Some(("admin", "hunter2")), Vec::new());
+        assert!(access.requires_auth());
+        assert!(!access.is_authorized(&request(&[])));
+        assert!(access.is_authorized(&request(&[("Authorization", "Bearer s3cret")])));
+        assert!(access.is_authorized(&request(&[("Authorization", "bearer s3cret")])));
+        assert!(!access.is_authorized(&request(&[("Authorization", "Bearer s3cre")])));
+        assert!(!access.is_authorized(&request(&[("Authorization", "Basic s3cret")])));
+
+        assert_eq!(None, access.login("admin", "hunter3"));
+        assert_eq!(None, access.login("root", "hunter2"));
+        assert!(access.login("anyone", "s3cret").is_some());
+        let session_id = access.login("admin", "hunter2").unwrap();
+        let cookie = format!("lang=en; SID={}", session_id);
+        assert!(access.is_authorized(&request(&[("Cookie", &cookie)])));
+        assert!(!access.is_authorized(&request(&[("Cookie", "SID=")])));
+
+        // "admin:hunter2"
+        let basic = "Basic YWRtaW46aHVudGVyMg==";
+        assert!(access.is_authorized(&request(&[("Authorization", basic)])));
+        assert_eq!("Basic realm=\"toytorrent\"", access.challenge());
+    }
+
+    #[test]
+    fn cors_origin_test() {
+        let access = Access::new(None, None, vec!["https://ui.example.com".to_string()]);
+        let allowed = [("Origin", "https://ui.example.com")];
+        let other = [("Origin", "https://evil.example")];
+        assert_eq!(
+            Some("https://ui.example.com"),
+            access.cors_origin(&request(&allowed))
+        );
+        assert_eq!(None, access.cors_origin(&request(&other)));
+        assert_eq!(None, access.cors_origin(&request(&[])));
+
+        let any = Access::new(None, None, vec!["*".to_string()]);
+        let origin = [("Origin", "https://evil.example")];
+        assert_eq!(
+            Some("https://evil.example"),
+            any.cors_origin(&request(&origin))
+        );
+    }
+
+    #[test]
+    fn is_allowed_host_test() {
+        let loopback = IpAddr::from([127, 0, 0, 1]);
+        let lan = IpAddr::from([192, 168, 1, 5]);
+
+        assert!(is_allowed_host(&request(&[]), loopback));
+        assert!(is_allowed_host(
+            &request(&[("Host", "127.0.0.1:8080")]),
+            loopback
+        ));
+        assert!(is_allowed_host(
+            &request(&[("Host", "localhost:8080")]),
+            loopback
+        ));
+        assert!(is_allowed_host(
+            &request(&[("Host", "LOCALHOST")]),
+            loopback
+        ));
+        assert!(is_allowed_host(
+            &request(&[("Host", "192.168.1.5:8080")]),
+            lan
+        ));
+        assert!(is_allowed_host(
+            &request(&[("Host", "[::1]:8080")]),
+            "::1".parse().unwrap()
+        ));
+        assert!(is_allowed_host(
+            &request(&[("Host", "127.0.0.1:8080")]),
+            "::ffff:127.0.0.1".parse().unwrap()
+        ));
+
+        assert!(!is_allowed_host(
+            &request(&[("Host", "evil.example:8080")]),
+            loopback
+        ));
+        assert!(!is_allowed_host(
+            &request(&[("Host", "127.0.0.1.evil.example")]),
+            loopback
+        ));
+        assert!(!is_allowed_host(
+            &request(&[("Host", "192.168.1.5:8080")]),
+            loopback
+        ));
+    }
+
+    #[test]
+    fn is_cross_site_test() {
+        let access = Access::new(None, None, vec!["https://ui.example.com".to_string()]);
+        let post = |headers: &[(&str, &str)]| request_with_method("POST", headers);
+        let host = ("Host", "127.0.0.1:8080");
+
+        assert!(!access.is_cross_site(&post(&[host])));
+        assert!(!access.is_cross_site(&post(&[host, ("Origin", "http://127.0.0.1:8080")])));
+        assert!(!access.is_cross_site(&post(&[host, ("Origin", "https://ui.example.com")])));
+        assert!(!access.is_cross_site(&post(&[
+            host,
+            ("Referer", "http://127.0.0.1:8080/index.html")
+        ])));
+        assert!(!access.is_cross_site(&post(&[
+            host,
+            ("Referer", "https://ui.example.com/torrents?page=2")
+        ])));
+
+        assert!(access.is_cross_site(&post(&[host, ("Origin", "https://evil.example")])));
+        assert!(access.is_cross_site(&post(&[host, ("Origin", "null")])));
+        assert!(access.is_cross_site(&post(&[
+            host,
+            ("Referer", "https://evil.example/http://127.0.0.1:8080")
+        ])));
+        assert!(access.is_cross_site(&post(&[("Origin", "http://127.0.0.1:8080")])));
+
+        // Reading is left to CORS.
+        let origin = ("Origin", "https://evil.example");
+        assert!(!access.is_cross_site(&request(&[host, origin])));
+    }
+
+    #[tokio::test]
+    async fn add_magnet_test() {
+        let info_hash = common::InfoHash::from([0xcd; 20]);
+        let (sender, mut receiver) = mpsc::channel(1);
+
+        add(
+            &sender,
+            format!("magnet:?xt=urn:btih:{}\n", info_hash).as_bytes(),
+        )
+        .await;
+
+        match receiver.recv().await {
+            Some(super::super::Incoming::Rpc(Incoming::AddMagnet {
+                magnet,
+                download_dir: None,
+            })) => assert_eq!(info_hash, magnet.info_hash),
+            _ => panic!("Expected the magnet link to be added"),
+        }
+    }
+}
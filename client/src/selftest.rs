@@ -0,0 +1,379 @@
+//! A built-in end-to-end check. A seeder and a leecher are started inside the process, connected
+//! over localhost and used to transfer a randomly generated payload. Each stage of the transfer is
+//! reported separately, so that a failure points at the part of the pipeline that broke.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio::time;
+
+use toytorrent_common as common;
+
+use super::{peer, Incoming};
+use common::peer::PeerMessage;
+
+const PIECE_LENGTH: u64 = 32 * 1024;
+const BLOCK_LENGTH: u64 = 16 * 1024;
+
+/// Deliberately not a multiple of the piece length, so that the short final piece is exercised.
+const PAYLOAD_LENGTH: usize = 3 * PIECE_LENGTH as usize + 1234;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Stage {
+    Handshake,
+    Bitfield,
+    Requests,
+    Hashing,
+    Storage,
+}
+
+impl Stage {
+    const ALL: [Stage; 5] = [
+        Stage::Handshake,
+        Stage::Bitfield,
+        Stage::Requests,
+        Stage::Hashing,
+        Stage::Storage,
+    ];
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Self::Handshake => write!(f, "handshake"),
+            Self::Bitfield => write!(f, "bitfield exchange"),
+            Self::Requests => write!(f, "block requests"),
+            Self::Hashing => write!(f, "piece hashing"),
+            Self::Storage => write!(f, "storage"),
+        }
+    }
+}
+
+/// Run the self-test and print a report, returning whether every stage passed.
+pub async fn run() -> bool {
+    let mut payload = vec![0u8; PAYLOAD_LENGTH];
+    rand::thread_rng().fill(&mut payload[..]);
+
+    let metainfo = metainfo_for(&payload);
+    let info_hash = *metainfo.info_hash();
+
+    let listener = match TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("Unable to bind a local port for the seeder: {}", e);
+            return false;
+        }
+    };
+    let seeder_addr = listener.local_addr().unwrap();
+
+    let (seeder_sender, seeder_receiver) = mpsc::channel::<Incoming>(100);
+    let (leecher_sender, leecher_receiver) = mpsc::channel::<Incoming>(100);
+
+    // Dropping the JoinSet at the end of the test shuts everything down.
+    let mut processes = JoinSet::new();
+    processes.spawn(peer::listen(
+        peer_id(),
+        common::peer::reserved_bytes(false),
+        listener,
+        seeder_sender,
+    ));
+    processes.spawn(seed(info_hash, payload.clone(), seeder_receiver));
+    processes.spawn(peer::connect(
+        vec![seeder_addr],
+        peer_id(),
+        common::peer::reserved_bytes(false),
+        info_hash,
+        leecher_sender,
+    ));
+
+    let mut passed = Vec::new();
+    let result = time::timeout(
+        TIMEOUT,
+        leech(&metainfo, &payload, leecher_receiver, &mut passed),
+    )
+    .await;
+
+    for stage in Stage::ALL {
+        if passed.contains(&stage) {
+            println!("[PASS] {}", stage);
+        } else {
+            println!("[FAIL] {}", stage);
+        }
+    }
+
+    match result {
+        Ok(Ok(())) => {
+            println!("Self-test passed");
+            true
+        }
+        Ok(Err(e)) => {
+            println!("Self-test failed: {}", e);
+            false
+        }
+        Err(_) => {
+            println!("Self-test failed: timed out after {:?}", TIMEOUT);
+            false
+        }
+    }
+}
+
+fn peer_id() -> common::PeerId {
+    common::PeerId::create(super::PEER_ID_CLIENT, super::PEER_ID_VERSION).unwrap()
+}
+
+fn metainfo_for(payload: &[u8]) -> common::metainfo::MetainfoFile {
+    let info = common::metainfo::Info::SingleFile {
+        piece_length: PIECE_LENGTH,
+        pieces: payload
+            .chunks(PIECE_LENGTH as usize)
+            .map(|chunk| <[u8; 20]>::from(Sha1::digest(chunk)).into())
+            .collect(),
+        name: "toytorrent-self-test".to_string(),
+        length: payload.len() as u64,
+        md5sum: None,
+    };
+
+    let fields: [(&str, common::BencodeValue); 2] = [
+        ("announce", "http://localhost/announce".into()),
+        ("info", (&info).into()),
+    ];
+
+    fields
+        .into_iter()
+        .collect::<common::BencodeValue>()
+        .try_into()
+        .expect("Generated metainfo should be valid")
+}
+
+/// Serve the whole payload to anyone who asks for it.
+async fn seed(
+    info_hash: common::InfoHash,
+    payload: Vec<u8>,
+    mut receiver: mpsc::Receiver<Incoming>,
+) {
+    let have = common::Bitfield::full(payload.len().div_ceil(PIECE_LENGTH as usize) as u32);
+    let mut peers: HashMap<SocketAddr, peer::Peer> = HashMap::new();
+
+    while let Some(incoming) = receiver.recv().await {
+        let Incoming::Peer(peer::Incoming {
+            from_socket_addr,
+            event,
+        }) = incoming
+        else {
+            continue;
+        };
+
+        match event {
+            peer::IncomingEvent::HandshakeInfoHash {
+                info_hash: their_info_hash,
+                is_valid_sender,
+            } => {
+                is_valid_sender.send(their_info_hash == info_hash).ok();
+            }
+            peer::IncomingEvent::Connected { peer } => {
+                match peer.send_bitfield(&have, false).await {
+                    Ok(peer) => {
+                        peers.insert(from_socket_addr, peer);
+                    }
+                    Err(e) => println!("Seeder: unable to send bitfield: {}", e),
+                }
+            }
+            peer::IncomingEvent::Message { message } => {
+                let Some(peer) = peers.get_mut(&from_socket_addr) else {
+                    continue;
+                };
+
+                if let Err(e) = peer.receive(&message, Instant::now()) {
+                    println!("Seeder: {}", e);
+                    continue;
+                }
+
+                let result = match message {
+                    PeerMessage::Interested => {
+                        peer.am_choking = false;
+                        peer.send_message(PeerMessage::Unchoke).await
+                    }
+                    PeerMessage::Request { block } => {
+                        let start =
+                            block.index() as usize * PIECE_LENGTH as usize + block.begin() as usize;
+                        let end = start + block.length() as usize;
+
+                        match payload.get(start..end) {
+                            Some(data) => {
+                                peer.send_message(PeerMessage::Piece {
+                                    block,
+                                    data: data.to_vec(),
+                                })
+                                .await
+                            }
+                            None => Ok(()),
+                        }
+                    }
+                    _ => Ok(()),
+                };
+
+                if let Err(e) = result {
+                    println!("Seeder: unable to respond: {}", e);
+                }
+            }
+            peer::IncomingEvent::Closed { .. } => {
+                peers.remove(&from_socket_addr);
+            }
+        }
+    }
+}
+
+/// Download the payload from the seeder, recording each stage as it passes.
+async fn leech(
+    metainfo: &common::metainfo::MetainfoFile,
+    payload: &[u8],
+    mut receiver: mpsc::Receiver<Incoming>,
+    passed: &mut Vec<Stage>,
+) -> Result<(), common::Error> {
+    let piece_count = metainfo.info.pieces().len();
+
+    // The self test keeps what it downloads to itself, so pieces are assembled in memory.
+    let mut storage = vec![0u8; payload.len()];
+    let mut received = vec![0u64; piece_count];
+    let mut verified = vec![false; piece_count];
+    let mut seeder: Option<peer::Peer> = None;
+
+    while let Some(incoming) = receiver.recv().await {
+        let Incoming::Peer(peer::Incoming {
+            from_socket_addr,
+            event,
+        }) = incoming
+        else {
+            continue;
+        };
+
+        match event {
+            peer::IncomingEvent::HandshakeInfoHash {
+                is_valid_sender, ..
+            } => {
+                is_valid_sender.send(false).ok();
+            }
+            peer::IncomingEvent::Connected { mut peer } => {
+                passed.push(Stage::Handshake);
+
+                peer.bitfield = common::Bitfield::new(piece_count as u32);
+
+                // We have nothing, so no bitfield goes out.
+                let mut peer = peer
+                    .send_bitfield(&common::Bitfield::new(piece_count as u32), false)
+                    .await
+                    .map_err(|e| format!("Unable to start exchanging messages: {}", e))?;
+
+                peer.am_interested = true;
+                peer.send_message(PeerMessage::Interested)
+                    .await
+                    .map_err(|e| format!("Unable to send Interested: {}", e))?;
+
+                seeder = Some(peer);
+            }
+            peer::IncomingEvent::Message { message } => {
+                let Some(peer) = seeder.as_mut() else {
+                    continue;
+                };
+
+                peer.receive(&message, Instant::now())
+                    .map_err(|e| format!("The seeder sent an invalid message: {}", e))?;
+
+                match message {
+                    PeerMessage::Bitfield { .. } => {
+                        if !peer.bitfield.is_full() {
+                            return Err("The seeder's bitfield is missing pieces".into());
+                        }
+
+                        passed.push(Stage::Bitfield);
+                    }
+                    PeerMessage::Unchoke => {
+                        for index in 0..piece_count as u32 {
+                            let range = metainfo.info.piece_range(index).unwrap();
+                            let length = range.end - range.start;
+
+                            for begin in (0..length).step_by(BLOCK_LENGTH as usize) {
+                                let block = common::BlockRef::new(
+                                    index,
+                                    begin as u32,
+                                    BLOCK_LENGTH.min(length - begin) as u32,
+                                );
+
+                                peer.am_requesting.push(block.clone());
+                                peer.send_message(PeerMessage::Request { block })
+                                    .await
+                                    .map_err(|e| format!("Unable to send Request: {}", e))?;
+                            }
+                        }
+                    }
+                    PeerMessage::Piece { block, data } => {
+                        if !passed.contains(&Stage::Requests) {
+                            passed.push(Stage::Requests);
+                        }
+
+                        let index = block.index();
+                        let range = metainfo
+                            .info
+                            .piece_range(index)
+                            .ok_or_else(|| format!("Received a block for piece {}", index))?;
+                        let start = (range.start + block.begin() as u64) as usize;
+
+                        storage
+                            .get_mut(start..start + data.len())
+                            .ok_or_else(|| format!("Received an out-of-bounds block {:?}", block))?
+                            .copy_from_slice(&data);
+                        peer.am_requesting.retain(|requested| requested != &block);
+                        received[index as usize] += data.len() as u64;
+
+                        if received[index as usize] < range.end - range.start {
+                            continue;
+                        }
+
+                        let hash: [u8; 20] =
+                            Sha1::digest(&storage[range.start as usize..range.end as usize]).into();
+
+                        if common::metainfo::Piece::from(hash)
+                            != metainfo.info.pieces()[index as usize]
+                        {
+                            return Err(format!("Piece {} failed its hash check", index).into());
+                        }
+
+                        verified[index as usize] = true;
+
+                        if verified.iter().all(|&verified| verified) {
+                            passed.push(Stage::Hashing);
+
+                            if storage != payload {
+                                return Err("The stored data doesn't match the payload".into());
+                            }
+
+                            passed.push(Stage::Storage);
+                            return Ok(());
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            peer::IncomingEvent::Closed { reason }
+                if seeder
+                    .as_ref()
+                    .is_some_and(|peer| peer.connection.addr == from_socket_addr) =>
+            {
+                return Err(format!("The seeder closed the connection: {}", reason).into());
+            }
+            // A failed attempt to connect, which will be retried.
+            peer::IncomingEvent::Closed { .. } => {}
+        }
+    }
+
+    Err("The event channel closed unexpectedly".into())
+}
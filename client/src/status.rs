@@ -0,0 +1,93 @@
+//! What a torrent is doing, as reported to the web UI and the APIs, and the changes to that which
+//! the user may ask for.
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Status {
+    /// Its data on disk is being hashed.
+    Checking,
+    Downloading,
+    Seeding,
+
+    /// Paused by the user.
+    Paused,
+
+    /// Paused after a storage error, until it's retried.
+    Errored,
+
+    /// Waiting to download until something outside the torrent allows, such as free disk space.
+    Queued,
+}
+
+/// A change of status that the user asked for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+    Pause,
+    Unpause,
+
+    /// Resume after a storage error, once it's been dealt with.
+    Retry,
+}
+
+impl Status {
+    /// Check that `action` makes sense for a torrent with this status. An errored torrent can be
+    /// paused, and is then still paused once retried.
+    pub fn allows(self, action: Action) -> Result<(), &'static str> {
+        match (self, action) {
+            (Self::Checking, _) => Err("The torrent is being checked"),
+            (Self::Paused, Action::Pause) => Err("The torrent is paused already"),
+            (_, Action::Pause) => Ok(()),
+            (Self::Paused, Action::Unpause) => Ok(()),
+            (_, Action::Unpause) => Err("The torrent isn't paused"),
+            (Self::Errored, Action::Retry) => Ok(()),
+            (_, Action::Retry) => Err("The torrent hasn't had an error"),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Checking => "checking",
+            Self::Downloading => "downloading",
+            Self::Seeding => "seeding",
+            Self::Paused => "paused",
+            Self::Errored => "errored",
+            Self::Queued => "queued",
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.pad(self.as_str())
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.pad(match self {
+            Self::Pause => "pause",
+            Self::Unpause => "unpause",
+            Self::Retry => "retry",
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn allows_test() {
+        assert!(Status::Downloading.allows(Action::Pause).is_ok());
+        assert!(Status::Errored.allows(Action::Pause).is_ok());
+        assert!(Status::Paused.allows(Action::Pause).is_err());
+        assert!(Status::Checking.allows(Action::Pause).is_err());
+
+        assert!(Status::Paused.allows(Action::Unpause).is_ok());
+        assert!(Status::Seeding.allows(Action::Unpause).is_err());
+
+        assert!(Status::Errored.allows(Action::Retry).is_ok());
+        assert!(Status::Queued.allows(Action::Retry).is_err());
+    }
+}
@@ -0,0 +1,496 @@
+//! Where the data of verified pieces goes. Pieces are written to the torrent's [`Files`] unless
+//! something else is given: they can be kept in [`Memory`], or handed on in order, to a pipe or to
+//! an [`ExportStream`]. Library users can plug in their own [`Storage`], for instance to upload to
+//! object storage. The session reads and writes through [`unblocked`], so that a slow disk doesn't
+//! hold it up.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use tokio::sync::mpsc;
+
+use toytorrent_common as common;
+
+use super::files;
+
+pub trait Storage: fmt::Debug + Send {
+    /// Keep a verified piece. Pieces arrive in the order they complete, which is rarely the order
+    /// of their indexes.
+    fn write_piece(&mut self, index: u32, data: Vec<u8>) -> io::Result<()>;
+
+    /// The data of a piece written earlier, if this storage can give it back.
+    fn read_piece(&mut self, index: u32) -> io::Result<Option<Cow<'_, [u8]>>>;
+
+    /// The data of a block of a piece written earlier, if this storage can give it back. By
+    /// default the whole piece is read and the block cut out of it.
+    fn read_block(&mut self, block: &common::BlockRef) -> io::Result<Option<Vec<u8>>> {
+        let begin = block.begin() as usize;
+        let end = begin + block.length() as usize;
+
+        Ok(self
+            .read_piece(block.index())?
+            .and_then(|piece| piece.get(begin..end).map(<[u8]>::to_vec)))
+    }
+
+    /// The piece that this storage is waiting on before it can make progress, if it has to take
+    /// pieces in order. The session downloads it ahead of everything but streams.
+    fn next_wanted(&self) -> Option<u32> {
+        None
+    }
+
+    /// The pieces whose data may have been changed by something else since they were written,
+    /// each reported once. Storages that keep the data to themselves have none.
+    fn modified(&mut self) -> Vec<u32> {
+        Vec::new()
+    }
+}
+
+/// Keeps every piece in memory.
+#[derive(Debug, Default)]
+pub struct Memory(HashMap<u32, Vec<u8>>);
+
+/// Keeps the torrent's files on disk, laid out as the metainfo describes, and reads pieces and
+/// blocks back from them. A piece that spans files is split between them. BEP 47 padding files
+/// aren't written, and read back as zeroes. Files that change size or modification time after
+/// they're written or read are reported as [modified](Storage::modified).
+#[derive(Debug)]
+pub struct Files {
+    files: Vec<(PathBuf, Range<u64>)>,
+    stamps: Vec<Option<Stamp>>,
+    piece_length: u64,
+    length: u64,
+}
+
+/// Writes the torrent's data in order, for instance to standard output for piping into another
+/// program. Pieces that complete early are held in memory until the ones before them arrive, so
+/// this is best used with the sequential picker.
+#[derive(Debug)]
+pub struct Pipe<W> {
+    writer: W,
+    reorder: Reorder,
+}
+
+/// Sends the torrent's data in order to an [`ExportStream`], as verified byte ranges. Like
+/// [`Pipe`], it holds pieces that complete early until the ones before them arrive.
+#[derive(Debug)]
+pub struct Export {
+    sender: mpsc::UnboundedSender<ByteRange>,
+    reorder: Reorder,
+}
+
+/// The receiving end of an [`Export`]. Ranges arrive in order of offset with no gaps, and aren't
+/// limited in number, so a consumer that falls behind holds them in memory.
+#[derive(Debug)]
+pub struct ExportStream(mpsc::UnboundedReceiver<ByteRange>);
+
+/// Verified data at an offset in the torrent, counting through its files in order.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ByteRange {
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+/// What a file looked like when we last wrote to it or read from it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Stamp {
+    len: u64,
+    modified: SystemTime,
+}
+
+/// Puts pieces back in order as they complete.
+#[derive(Debug, Default)]
+struct Reorder {
+    next: u32,
+    offset: u64,
+    pending: BTreeMap<u32, Vec<u8>>,
+}
+
+impl Storage for Memory {
+    fn write_piece(&mut self, index: u32, data: Vec<u8>) -> io::Result<()> {
+        self.0.insert(index, data);
+        Ok(())
+    }
+
+    fn read_piece(&mut self, index: u32) -> io::Result<Option<Cow<'_, [u8]>>> {
+        Ok(self.0.get(&index).map(|data| Cow::Borrowed(&data[..])))
+    }
+}
+
+impl Files {
+    /// The files of the torrent with `info` under `dir`, which needn't exist yet.
+    pub fn new(dir: &Path, info: &common::metainfo::Info) -> Result<Self, common::Error> {
+        let padding: Vec<bool> = match info {
+            common::metainfo::Info::SingleFile { .. } => vec![false],
+            common::metainfo::Info::MultiFile { files, .. } => files
+                .iter()
+                .map(common::metainfo::File::is_padding)
+                .collect(),
+        };
+
+        let files: Vec<_> = files::paths(dir, info)?
+            .into_iter()
+            .zip(
+                info.file_spans()
+                    .iter()
+                    .map(common::metainfo::FileSpan::range),
+            )
+            .zip(padding)
+            .filter(|(_, padding)| !padding)
+            .map(|(file, _)| file)
+            .collect();
+
+        Ok(Self {
+            stamps: vec![None; files.len()],
+            files,
+            piece_length: info.piece_length(),
+            length: info.length(),
+        })
+    }
+
+    /// The parts of the files that hold the piece at `index`, each with the range of the piece
+    /// it holds and where in the file that is.
+    fn parts(&self, index: u32) -> impl Iterator<Item = (&Path, Range<usize>, u64)> {
+        let (piece_start, piece_end) = self.piece_range(index);
+        self.parts_of(piece_start..piece_end)
+    }
+
+    /// The parts of the files that hold the bytes in `range` of the torrent, each with the part
+    /// of `range` it holds, counting from its start, and where in the file that is.
+    fn parts_of(&self, range: Range<u64>) -> impl Iterator<Item = (&Path, Range<usize>, u64)> {
+        self.files
+            .iter()
+            // Empty files hold no part of any piece, even at a piece's boundary.
+            .filter(move |(_, file)| {
+                file.start < file.end && file.start < range.end && range.start < file.end
+            })
+            .map(move |(path, file)| {
+                let start = range.start.max(file.start);
+                let end = range.end.min(file.end);
+                let part = (start - range.start) as usize..(end - range.start) as usize;
+
+                (path.as_path(), part, start - file.start)
+            })
+    }
+
+    /// Read the bytes in `range` of the torrent. They're reported as not there if any of the
+    /// files holding them is missing or too short, or if the range is past the end.
+    fn read_range(&self, range: Range<u64>) -> io::Result<Option<Vec<u8>>> {
+        if range.start >= range.end.min(self.length) {
+            return Ok(None);
+        }
+
+        let mut data = vec![0; (range.end - range.start) as usize];
+
+        for (path, part, offset) in self.parts_of(range) {
+            let mut file = match fs::File::open(path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e),
+            };
+
+            file.seek(SeekFrom::Start(offset))?;
+
+            match file.read_exact(&mut data[part]) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(Some(data))
+    }
+
+    fn piece_range(&self, index: u32) -> (u64, u64) {
+        let piece_start = u64::from(index) * self.piece_length;
+        (
+            piece_start,
+            (piece_start + self.piece_length).min(self.length),
+        )
+    }
+
+    /// Note what the files holding the piece at `index` look like now, replacing what was noted
+    /// before only with `replace`.
+    fn stamp(&mut self, index: u32, replace: bool) {
+        let (piece_start, piece_end) = self.piece_range(index);
+
+        for ((path, file), stamp) in self.files.iter().zip(&mut self.stamps) {
+            if file.start < piece_end && piece_start < file.end && (replace || stamp.is_none()) {
+                *stamp = Stamp::of(path).ok();
+            }
+        }
+    }
+}
+
+impl Storage for Files {
+    fn write_piece(&mut self, index: u32, data: Vec<u8>) -> io::Result<()> {
+        for (path, range, offset) in self.parts(index) {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            let mut file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(false)
+                .open(path)?;
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&data[range])?;
+        }
+
+        self.stamp(index, true);
+        Ok(())
+    }
+
+    /// Pieces whose files are missing or too short are reported as not there.
+    fn read_piece(&mut self, index: u32) -> io::Result<Option<Cow<'_, [u8]>>> {
+        let (piece_start, piece_end) = self.piece_range(index);
+
+        let Some(data) = self.read_range(piece_start..piece_end)? else {
+            return Ok(None);
+        };
+
+        self.stamp(index, false);
+        Ok(Some(Cow::Owned(data)))
+    }
+
+    /// Only the block is read, from wherever it falls in the files.
+    fn read_block(&mut self, block: &common::BlockRef) -> io::Result<Option<Vec<u8>>> {
+        let (piece_start, piece_end) = self.piece_range(block.index());
+        let start = piece_start + u64::from(block.begin());
+        let end = start + u64::from(block.length());
+
+        if end > piece_end {
+            return Ok(None);
+        }
+
+        let Some(data) = self.read_range(start..end)? else {
+            return Ok(None);
+        };
+
+        self.stamp(block.index(), false);
+        Ok(Some(data))
+    }
+
+    fn modified(&mut self) -> Vec<u32> {
+        let mut pieces = Vec::new();
+
+        for ((path, file), stamp) in self.files.iter().zip(&mut self.stamps) {
+            let Some(old) = *stamp else {
+                continue;
+            };
+
+            *stamp = Stamp::of(path).ok();
+
+            if *stamp != Some(old) && !file.is_empty() {
+                let first = file.start / self.piece_length;
+                let last = (file.end - 1) / self.piece_length;
+                pieces.extend(first as u32..=last as u32);
+            }
+        }
+
+        pieces.sort_unstable();
+        pieces.dedup();
+        pieces
+    }
+}
+
+/// Run `f` with `storage` on the blocking thread pool, so that a slow disk doesn't hold up the
+/// session, and put it back once it's done. Returns `None` if there's no storage.
+pub(crate) async fn unblocked<T: Send + 'static>(
+    storage: &mut Option<Box<dyn Storage>>,
+    f: impl FnOnce(&mut dyn Storage) -> io::Result<T> + Send + 'static,
+) -> Option<io::Result<T>> {
+    let mut taken = storage.take()?;
+
+    let task = tokio::task::spawn_blocking(move || {
+        let result = f(taken.as_mut());
+        (taken, result)
+    });
+
+    let (taken, result) = match task.await {
+        Ok(done) => done,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    };
+
+    *storage = Some(taken);
+    Some(result)
+}
+
+impl Stamp {
+    fn of(path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+
+        Ok(Self {
+            len: metadata.len(),
+            modified: metadata.modified()?,
+        })
+    }
+}
+
+impl<W> Pipe<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            reorder: Reorder::default(),
+        }
+    }
+}
+
+impl<W: Write + fmt::Debug + Send> Storage for Pipe<W> {
+    fn write_piece(&mut self, index: u32, data: Vec<u8>) -> io::Result<()> {
+        for range in self.reorder.push(index, data) {
+            self.writer.write_all(&range.data)?;
+        }
+
+        self.writer.flush()
+    }
+
+    /// What's been written can't be read back.
+    fn read_piece(&mut self, _index: u32) -> io::Result<Option<Cow<'_, [u8]>>> {
+        Ok(None)
+    }
+
+    fn next_wanted(&self) -> Option<u32> {
+        Some(self.reorder.next)
+    }
+}
+
+impl Export {
+    pub fn new() -> (Self, ExportStream) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let export = Self {
+            sender,
+            reorder: Reorder::default(),
+        };
+
+        (export, ExportStream(receiver))
+    }
+}
+
+impl Storage for Export {
+    fn write_piece(&mut self, index: u32, data: Vec<u8>) -> io::Result<()> {
+        for range in self.reorder.push(index, data) {
+            self.sender
+                .send(range)
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Export stream closed"))?;
+        }
+
+        Ok(())
+    }
+
+    /// What's been sent can't be read back.
+    fn read_piece(&mut self, _index: u32) -> io::Result<Option<Cow<'_, [u8]>>> {
+        Ok(None)
+    }
+
+    fn next_wanted(&self) -> Option<u32> {
+        Some(self.reorder.next)
+    }
+}
+
+impl ExportStream {
+    /// The next range of data, or `None` once the session is done with the torrent.
+    pub async fn recv(&mut self) -> Option<ByteRange> {
+        self.0.recv().await
+    }
+}
+
+impl Reorder {
+    /// Take a piece, returning the data that's now ready in order, if any. Pieces that were
+    /// already passed on are ignored.
+    fn push(&mut self, index: u32, data: Vec<u8>) -> Vec<ByteRange> {
+        if index < self.next {
+            return Vec::new();
+        }
+
+        self.pending.insert(index, data);
+
+        let mut ready = Vec::new();
+
+        while let Some(data) = self.pending.remove(&self.next) {
+            let len = data.len() as u64;
+
+            ready.push(ByteRange {
+                offset: self.offset,
+                data,
+            });
+
+            self.next += 1;
+            self.offset += len;
+        }
+
+        ready
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pipe_test() {
+        let mut pipe = Pipe::new(Vec::new());
+
+        pipe.write_piece(1, b"b".to_vec()).unwrap();
+        pipe.write_piece(2, b"c".to_vec()).unwrap();
+        assert_eq!(b"", &pipe.writer[..]);
+        assert_eq!(Some(0), pipe.next_wanted());
+
+        pipe.write_piece(0, b"a".to_vec()).unwrap();
+        pipe.write_piece(1, b"x".to_vec()).unwrap();
+        assert_eq!(b"abc", &pipe.writer[..]);
+        assert_eq!(Some(3), pipe.next_wanted());
+        assert_eq!(None, pipe.read_piece(0).unwrap());
+    }
+
+    #[test]
+    fn files_parts_test() {
+        let files = Files {
+            files: vec![
+                (PathBuf::from("a"), 0..3),
+                (PathBuf::from("b"), 3..3),
+                (PathBuf::from("c"), 3..10),
+            ],
+            stamps: vec![None; 3],
+            piece_length: 4,
+            length: 10,
+        };
+
+        assert_eq!(
+            vec![(Path::new("a"), 0..3, 0), (Path::new("c"), 3..4, 0)],
+            files.parts(0).collect::<Vec<_>>(),
+        );
+        assert_eq!(
+            vec![(Path::new("c"), 0..2, 5)],
+            files.parts(2).collect::<Vec<_>>()
+        );
+        assert!(files.parts(3).next().is_none());
+    }
+
+    #[tokio::test]
+    async fn export_test() {
+        let (mut export, mut stream) = Export::new();
+
+        export.write_piece(1, b"cd".to_vec()).unwrap();
+        export.write_piece(0, b"ab".to_vec()).unwrap();
+        export.write_piece(2, b"e".to_vec()).unwrap();
+        drop(export);
+
+        let range = |offset, data: &[u8]| ByteRange {
+            offset,
+            data: data.to_vec(),
+        };
+
+        assert_eq!(Some(range(0, b"ab")), stream.recv().await);
+        assert_eq!(Some(range(2, b"cd")), stream.recv().await);
+        assert_eq!(Some(range(4, b"e")), stream.recv().await);
+        assert_eq!(None, stream.recv().await);
+    }
+}
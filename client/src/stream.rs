@@ -0,0 +1,298 @@
+//! A local HTTP server for a torrent's files, so that a media player can play them while they
+//! download. Players seek with Range requests, and the pieces they're waiting on jump the queue.
+
+use std::io;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::{task, time};
+
+use toytorrent_common as common;
+
+use super::http;
+
+/// How long to wait before asking again for a piece we don't have yet.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A request from the server to the session for data at `offset` in the torrent. The reply holds
+/// data from there to at most the end of its piece, or `None` if we don't have the piece yet.
+#[derive(Debug)]
+pub struct Incoming {
+    pub info_hash: common::InfoHash,
+    pub offset: u64,
+    pub length: u64,
+    pub reply: oneshot::Sender<Option<Vec<u8>>>,
+}
+
+/// A file of the torrent, located within the torrent's concatenated data.
+#[derive(Clone, Debug)]
+pub struct StreamFile {
+    pub path: String,
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl StreamFile {
+    pub fn all(metainfo: &common::metainfo::MetainfoFile) -> Vec<Self> {
+        metainfo
+            .info
+            .file_spans()
+            .into_iter()
+            .map(|span| Self {
+                path: span.path.join("/"),
+                offset: span.offset,
+                length: span.length,
+            })
+            .collect()
+    }
+
+    fn name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or(&self.path)
+    }
+}
+
+pub async fn listen(
+    listener: TcpListener,
+    info_hash: common::InfoHash,
+    files: Vec<StreamFile>,
+    sender: mpsc::Sender<super::Incoming>,
+) {
+    let files = Arc::new(files);
+
+    loop {
+        let Ok((stream, addr)) = listener.accept().await else {
+            continue;
+        };
+
+        let files = files.clone();
+        let sender = sender.clone();
+
+        task::spawn(async move {
+            if let Err(e) = serve(stream, info_hash, &files, sender).await {
+                say!("{:21} Stream error: {}", addr, e);
+            }
+        });
+    }
+}
+
+/// Print where the server can be reached.
+pub fn print_urls(addr: SocketAddr, files: &[StreamFile]) {
+    for (i, file) in files.iter().enumerate() {
+        say!(
+            "Streaming {} at http://{}/{}/{}",
+            file.path,
+            addr,
+            i,
+            file.name()
+        );
+    }
+}
+
+async fn serve(
+    mut stream: TcpStream,
+    info_hash: common::InfoHash,
+    files: &[StreamFile],
+    sender: mpsc::Sender<super::Incoming>,
+) -> io::Result<()> {
+    let request = http::read_request(&mut stream, 0).await?;
+
+    let head_only = match request.method.as_str() {
+        "GET" => false,
+        "HEAD" => true,
+        _ => {
+            return http::Response::empty("405 Method Not Allowed")
+                .write_to(&mut stream)
+                .await
+        }
+    };
+
+    if request.path == "/" {
+        let listing: String = files
+            .iter()
+            .enumerate()
+            .map(|(i, file)| format!("/{}/{}\n", i, file.name()))
+            .collect();
+
+        return http::Response::new("200 OK", "text/plain", listing)
+            .write_to(&mut stream)
+            .await;
+    }
+
+    let Some(file) = request
+        .path
+        .trim_start_matches('/')
+        .split('/')
+        .next()
+        .and_then(|i| i.parse::<usize>().ok())
+        .and_then(|i| files.get(i))
+    else {
+        return http::Response::new("404 Not Found", "text/plain", "No such file\n")
+            .write_to(&mut stream)
+            .await;
+    };
+
+    let (status, range) = match request
+        .header("range")
+        .map(|header| parse_range(header, file.length))
+    {
+        None | Some(Err(RangeError::Unsupported)) => ("200 OK", 0..file.length),
+        Some(Ok(range)) => ("206 Partial Content", range),
+        Some(Err(RangeError::Unsatisfiable)) => {
+            return http::Response::empty("416 Range Not Satisfiable")
+                .with_header("Content-Range", format!("bytes */{}", file.length))
+                .write_to(&mut stream)
+                .await;
+        }
+    };
+
+    let mut head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\n\
+         Connection: close\r\n",
+        status,
+        content_type(&file.path),
+        range.end - range.start,
+    );
+
+    if status.starts_with("206") {
+        head += &format!(
+            "Content-Range: bytes {}-{}/{}\r\n",
+            range.start,
+            range.end - 1,
+            file.length,
+        );
+    }
+
+    head += "\r\n";
+    stream.write_all(head.as_bytes()).await?;
+
+    if head_only {
+        return Ok(());
+    }
+
+    let mut position = file.offset + range.start;
+    let end = file.offset + range.end;
+
+    while position < end {
+        let (reply, receiver) = oneshot::channel();
+
+        sender
+            .send(
+                Incoming {
+                    info_hash,
+                    offset: position,
+                    length: end - position,
+                    reply,
+                }
+                .into(),
+            )
+            .await
+            .map_err(|e| io::Error::other(e.to_string()))?;
+
+        match receiver.await.ok().flatten() {
+            Some(data) if !data.is_empty() => {
+                stream.write_all(&data).await?;
+                position += data.len() as u64;
+            }
+            _ => time::sleep(POLL_INTERVAL).await,
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum RangeError {
+    /// Not a single byte range, so the whole file is served instead.
+    Unsupported,
+
+    /// Entirely past the end of the file.
+    Unsatisfiable,
+}
+
+/// Parse a `Range` header for a file of `length` bytes. Only single byte ranges are supported:
+/// `bytes=<start>-<end>`, `bytes=<start>-` and `bytes=-<suffix length>`.
+fn parse_range(header: &str, length: u64) -> Result<Range<u64>, RangeError> {
+    let (start, end) = header
+        .strip_prefix("bytes=")
+        .filter(|spec| !spec.contains(','))
+        .and_then(|spec| spec.split_once('-'))
+        .ok_or(RangeError::Unsupported)?;
+
+    let parse = |s: &str| s.trim().parse::<u64>().map_err(|_| RangeError::Unsupported);
+
+    let range = match (start.trim(), end.trim()) {
+        ("", "") => return Err(RangeError::Unsupported),
+        ("", suffix) => length.saturating_sub(parse(suffix)?)..length,
+        (start, "") => parse(start)?..length,
+        (start, end) => {
+            let (start, end) = (parse(start)?, parse(end)?);
+
+            // A backwards range is invalid rather than unsatisfiable, so it's ignored.
+            if end < start {
+                return Err(RangeError::Unsupported);
+            }
+
+            start..end.saturating_add(1).min(length)
+        }
+    };
+
+    if range.start >= range.end {
+        Err(RangeError::Unsatisfiable)
+    } else {
+        Ok(range)
+    }
+}
+
+fn content_type(path: &str) -> &'static str {
+    let extension = path
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("mp4" | "m4v") => "video/mp4",
+        Some("mkv") => "video/x-matroska",
+        Some("webm") => "video/webm",
+        Some("avi") => "video/x-msvideo",
+        Some("mp3") => "audio/mpeg",
+        Some("flac") => "audio/flac",
+        Some("ogg" | "oga") => "audio/ogg",
+        Some("m4a") => "audio/mp4",
+        Some("txt" | "nfo") => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_range_test() {
+        assert_eq!(Ok(0..100), parse_range("bytes=0-99", 1000));
+        assert_eq!(Ok(500..1000), parse_range("bytes=500-", 1000));
+        assert_eq!(Ok(900..1000), parse_range("bytes=-100", 1000));
+        assert_eq!(Ok(0..1000), parse_range("bytes=-5000", 1000));
+        assert_eq!(Ok(990..1000), parse_range("bytes=990-2000", 1000));
+
+        assert_eq!(
+            Err(RangeError::Unsatisfiable),
+            parse_range("bytes=1000-", 1000)
+        );
+        assert_eq!(
+            Err(RangeError::Unsatisfiable),
+            parse_range("bytes=-0", 1000)
+        );
+        assert_eq!(Err(RangeError::Unsupported), parse_range("bytes=5-4", 1000));
+        assert_eq!(
+            Err(RangeError::Unsupported),
+            parse_range("bytes=0-1,5-6", 1000)
+        );
+        assert_eq!(Err(RangeError::Unsupported), parse_range("items=0-1", 1000));
+        assert_eq!(Err(RangeError::Unsupported), parse_range("bytes=a-b", 1000));
+    }
+}
@@ -1,20 +1,344 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::error::Error as _;
+use std::fmt;
 use std::iter;
-use std::net::IpAddr;
-use std::time::Duration;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
 use tokio::sync::mpsc;
 
 use toytorrent_common as common;
 
+use super::backoff::{self, Backoff};
+use super::quirks::Quirks;
+use super::resolver::{ResolveError, Resolver};
+
+/// How long to keep an idle connection to a tracker open for the next announce or scrape. Most
+/// trackers close idle connections well before their announce interval is up anyway.
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How long a tracker has to answer a request. Every torrent announces from the one task, so a
+/// tracker that takes the connection and then stalls would otherwise hold up all of them.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The shortest announce interval to honour, whatever a tracker asks for, so that a broken
+/// tracker can't have us announce in a tight loop.
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The most by which to randomly delay the retries of torrents waiting on the same unreachable
+/// tracker host, so that they come back to it one by one rather than all at once.
+const HOST_STAGGER: Duration = Duration::from_secs(30);
+
+/// The most by which to randomly delay a torrent's first announce to each tracker, so that a
+/// session starting with many torrents doesn't announce them all to a tracker at once.
+const FIRST_ANNOUNCE_STAGGER: Duration = Duration::from_secs(10);
+
 pub struct Incoming {
     pub info_hash: common::InfoHash,
     pub event: IncomingEvent,
 }
 
 pub enum IncomingEvent {
-    AnnounceResponse { response: common::tracker::Response },
-    AnnounceError { url: String, error: String },
-    ShouldAnnounce,
+    AnnounceResponse {
+        url: String,
+        response: common::tracker::SuccessResponse,
+    },
+    AnnounceError {
+        url: String,
+        error: TrackerError,
+        retry_in: Option<Duration>,
+    },
+}
+
+/// Why a request to a tracker failed, sorted into the categories that decide what to do about it.
+#[derive(Debug)]
+pub enum TrackerError {
+    /// The URL is invalid, or uses a scheme that we don't speak (yet), such as `udp://`.
+    UnsupportedUrl(String),
+    Dns(String),
+    Timeout,
+    Connection(String),
+    Status(reqwest::StatusCode),
+
+    /// The response isn't a valid bencoded tracker response.
+    Parse(common::Error),
+
+    /// The response is larger than we're willing to accept.
+    TooLarge(common::Bytes),
+
+    /// The tracker understood the request, but refused it.
+    Failure(common::tracker::FailureResponse),
+
+    /// An announce to another torrent on the same host failed recently, so this one wasn't sent.
+    HostDown(String),
+}
+
+impl TrackerError {
+    /// Whether trying again later is pointless, either by its nature or because the tracker said
+    /// so.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            Self::UnsupportedUrl(_) => true,
+            Self::Failure(failure) => failure.retry_in == Some(common::tracker::RetryIn::Never),
+            _ => false,
+        }
+    }
+
+    /// Whether the failure is down to the tracker's host rather than the torrent, so that every
+    /// torrent announcing to the same host should expect the same failure.
+    pub fn is_host_down(&self) -> bool {
+        match self {
+            Self::Dns(_) | Self::Timeout | Self::Connection(_) | Self::HostDown(_) => true,
+            Self::Status(status) => status.is_server_error(),
+            _ => false,
+        }
+    }
+
+    /// How long the tracker asked us to wait before trying again, if it did.
+    pub fn retry_in(&self) -> Option<Duration> {
+        match self {
+            Self::Failure(failure) => failure.retry_in.and_then(|retry_in| retry_in.duration()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for TrackerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Self::UnsupportedUrl(reason) => write!(f, "Unsupported tracker URL: {}", reason),
+            Self::Dns(reason) => write!(f, "Unable to resolve tracker: {}", reason),
+            Self::Timeout => write!(f, "Timed out"),
+            Self::Connection(reason) => write!(f, "Connection failed: {}", reason),
+            Self::Status(status) => write!(f, "HTTP status {}", status),
+            Self::Parse(reason) => write!(f, "Invalid response: {}", reason),
+            Self::TooLarge(limit) => write!(f, "Response exceeds the limit of {}", limit),
+            Self::Failure(failure) => write!(f, "Tracker failure: {}", failure.failure_reason),
+            Self::HostDown(host) => write!(f, "{} is unreachable, not retrying yet", host),
+        }
+    }
+}
+
+impl From<reqwest::Error> for TrackerError {
+    fn from(e: reqwest::Error) -> Self {
+        let mut source = e.source();
+
+        while let Some(error) = source {
+            if let Some(resolve_error) = error.downcast_ref::<ResolveError>() {
+                return Self::Dns(resolve_error.to_string());
+            }
+
+            source = error.source();
+        }
+
+        if e.is_timeout() {
+            Self::Timeout
+        } else if let Some(status) = e.status() {
+            Self::Status(status)
+        } else if e.is_decode() || e.is_body() {
+            Self::Parse(e.to_string().into())
+        } else {
+            Self::Connection(e.to_string())
+        }
+    }
+}
+
+/// Tracker IDs handed out in announce responses. An ID must be echoed back in every later
+/// announce about the same torrent to the same tracker, up to and including the `stopped` one.
+/// They aren't tied to HTTP, so that other tracker transports can share them.
+#[derive(Debug, Default)]
+pub struct TrackerIds(HashMap<(common::InfoHash, String), Vec<u8>>);
+
+impl TrackerIds {
+    pub fn get(&self, info_hash: &common::InfoHash, announce_url: &str) -> Option<Vec<u8>> {
+        self.0.get(&(*info_hash, announce_url.to_string())).cloned()
+    }
+
+    /// Remember the tracker ID from a response, if it has one. Responses without one leave any
+    /// earlier ID in place.
+    pub fn update(
+        &mut self,
+        info_hash: &common::InfoHash,
+        announce_url: &str,
+        response: &common::tracker::SuccessResponse,
+    ) {
+        if let Some(tracker_id) = &response.tracker_id {
+            self.0
+                .insert((*info_hash, announce_url.to_string()), tracker_id.clone());
+        }
+    }
+
+    /// Drop the tracker ID once the tracker has been told that we stopped.
+    pub fn forget(&mut self, info_hash: &common::InfoHash, announce_url: &str) {
+        self.0.remove(&(*info_hash, announce_url.to_string()));
+    }
+}
+
+/// Failures shared between every torrent announcing to the same tracker host, so that when a
+/// host goes down, one announce finds out and the rest wait for it to come back instead of each
+/// trying it in turn.
+#[derive(Debug, Default)]
+struct Hosts(HashMap<String, Host>);
+
+#[derive(Debug)]
+struct Host {
+    backoff: Backoff,
+
+    /// When to next try the host, if it's down.
+    retry_at: Option<Instant>,
+}
+
+impl Hosts {
+    /// How long to wait before announcing to `host`, if it's down. Each torrent is given its own
+    /// share of [`HOST_STAGGER`] on top of the host's retry time.
+    fn retry_in(&self, host: &str, now: Instant) -> Option<Duration> {
+        let retry_at = self.0.get(host)?.retry_at?;
+
+        (retry_at > now).then(|| {
+            let stagger = rand::thread_rng().gen_range(Duration::ZERO..=HOST_STAGGER);
+            retry_at - now + stagger
+        })
+    }
+
+    fn succeeded(&mut self, host: &str) {
+        self.0.remove(host);
+    }
+
+    /// Record that `host` is down, returning how long every torrent announcing to it should wait.
+    fn failed(&mut self, host: &str, now: Instant) -> Option<Duration> {
+        let host = self.0.entry(host.to_string()).or_insert_with(|| Host {
+            backoff: Backoff::new(backoff::TRACKER_ANNOUNCE),
+            retry_at: None,
+        });
+
+        let delay = host.backoff.failed();
+        host.retry_at = delay.map(|delay| now + delay);
+        delay
+    }
+}
+
+/// The host and port of a tracker URL, which torrents announcing to the same tracker share even
+/// when their passkeys or paths differ.
+fn host_of(announce_url: &str) -> Option<String> {
+    let url = reqwest::Url::parse(announce_url).ok()?;
+    let host = url.host_str()?;
+
+    Some(match url.port_or_known_default() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    })
+}
+
+/// When to announce one torrent to each of its trackers. A tracker is announced to again once its
+/// interval is up, or its retry delay after a failure. Trackers that refused the torrent for good
+/// aren't announced to again.
+#[derive(Debug)]
+pub struct Schedule(Vec<Scheduled>);
+
+#[derive(Debug)]
+struct Scheduled {
+    url: String,
+
+    /// When the next announce is due, or `None` while one is in flight or if the tracker won't
+    /// have the torrent.
+    next: Option<Instant>,
+
+    /// Whether the tracker has acknowledged a `started` event.
+    started: bool,
+
+    /// Whether the tracker has been sent `stopped` for standby, so that the response to it isn't
+    /// taken for the response to an announce.
+    stopping: bool,
+}
+
+impl Schedule {
+    /// Schedule an announce to each of `urls`, each at a random point within
+    /// [`FIRST_ANNOUNCE_STAGGER`] of `now`.
+    pub fn new(urls: &[&str], now: Instant) -> Self {
+        let mut rng = rand::thread_rng();
+
+        Self(
+            urls.iter()
+                .map(|url| Scheduled {
+                    url: url.to_string(),
+                    next: Some(now + rng.gen_range(Duration::ZERO..=FIRST_ANNOUNCE_STAGGER)),
+                    started: false,
+                    stopping: false,
+                })
+                .collect(),
+        )
+    }
+
+    /// Take the announces that are due at `now`, with the event to send to each. Until a tracker
+    /// has acknowledged `started`, every announce to it is a `started` one.
+    pub fn due(&mut self, now: Instant) -> Vec<(String, Option<common::tracker::Event>)> {
+        self.0
+            .iter_mut()
+            .filter(|scheduled| scheduled.next.is_some_and(|next| next <= now))
+            .map(|scheduled| {
+                scheduled.next = None;
+                let event = (!scheduled.started).then_some(common::tracker::Event::Started);
+                (scheduled.url.clone(), event)
+            })
+            .collect()
+    }
+
+    /// The trackers that have acknowledged `started`, and so should hear about `completed` and
+    /// `stopped`.
+    pub fn started(&self) -> impl Iterator<Item = &str> {
+        self.0
+            .iter()
+            .filter(|scheduled| scheduled.started)
+            .map(|scheduled| scheduled.url.as_str())
+    }
+
+    /// Stop announcing for standby, taking the trackers that have acknowledged `started` and so
+    /// should hear `stopped`. Each tracker that's still to be announced to is due again at `now`,
+    /// with `started`, for whenever announces are taken again.
+    pub fn stop(&mut self, now: Instant) -> Vec<String> {
+        let mut stopped = Vec::new();
+
+        for scheduled in self.0.iter_mut() {
+            if scheduled.next.is_some() || scheduled.started {
+                scheduled.next = Some(now);
+            }
+
+            if scheduled.started {
+                stopped.push(scheduled.url.clone());
+            }
+
+            scheduled.stopping = scheduled.started;
+            scheduled.started = false;
+        }
+
+        stopped
+    }
+
+    /// Schedule the next announce to `url` after a response asking for `interval`.
+    pub fn succeeded(&mut self, url: &str, interval: Duration, now: Instant) {
+        if let Some(scheduled) = self.0.iter_mut().find(|scheduled| scheduled.url == url) {
+            if std::mem::take(&mut scheduled.stopping) {
+                return;
+            }
+
+            scheduled.started = true;
+            scheduled.next = Some(now + interval.max(MIN_ANNOUNCE_INTERVAL));
+        }
+    }
+
+    /// Schedule the next announce to `url` after a failure, or none at all if `retry_in` is
+    /// `None`.
+    pub fn failed(&mut self, url: &str, retry_in: Option<Duration>, now: Instant) {
+        if let Some(scheduled) = self.0.iter_mut().find(|scheduled| scheduled.url == url) {
+            if std::mem::take(&mut scheduled.stopping) {
+                return;
+            }
+
+            scheduled.next = retry_in.map(|retry_in| now + retry_in);
+        }
+    }
 }
 
 pub struct Outgoing {
@@ -25,44 +349,73 @@ pub struct Outgoing {
     pub left: u64,
     pub event: Option<common::tracker::Event>,
     pub numwant: Option<u64>,
+    pub key: Option<common::PeerKey>,
 }
 
+/// Make the announces that the session asks for, one at a time, and send back what came of each.
+/// Tracker IDs, trackers that refused a torrent for good and the failures of tracker hosts are
+/// all remembered here, across every torrent.
 pub async fn announce(
     sender: mpsc::Sender<super::Incoming>,
-    mut receiver: mpsc::Receiver<Outgoing>,
+    mut receiver: mpsc::UnboundedReceiver<Outgoing>,
     peer_id: common::PeerId,
-    key: Option<common::PeerKey>,
-    ip: Option<IpAddr>,
     port: u16,
+    client: reqwest::Client,
+    max_response: common::Bytes,
+    quirks: Quirks,
 ) {
-    let mut tracker_ids: HashMap<common::InfoHash, Vec<u8>> = HashMap::new();
+    let mut tracker_ids = TrackerIds::default();
+    let mut backoffs: HashMap<(common::InfoHash, String), Backoff> = HashMap::new();
+    let mut hosts = Hosts::default();
 
-    let reqwest_client = reqwest::Client::builder()
-        .connect_timeout(Duration::from_secs(5))
-        .default_headers(
-            iter::once((
-                reqwest::header::USER_AGENT,
-                reqwest::header::HeaderValue::from_static(super::USER_AGENT),
-            ))
-            .collect(),
-        )
-        .build()
-        .unwrap();
+    // Trackers that told us never to come back, per BEP 31.
+    let mut disabled: HashSet<(common::InfoHash, String)> = HashSet::new();
 
     while let Some(outgoing) = receiver.recv().await {
-        let request = common::tracker::Request {
+        let key = (outgoing.info_hash, outgoing.announce_url.clone());
+
+        if disabled.contains(&key) {
+            continue;
+        }
+
+        let host = host_of(&outgoing.announce_url);
+
+        if let Some(host) = &host {
+            if let Some(retry_in) = hosts.retry_in(host, Instant::now()) {
+                sender
+                    .send(
+                        Incoming {
+                            info_hash: outgoing.info_hash,
+                            event: IncomingEvent::AnnounceError {
+                                url: outgoing.announce_url,
+                                error: TrackerError::HostDown(host.clone()),
+                                retry_in: Some(retry_in),
+                            },
+                        }
+                        .into(),
+                    )
+                    .await
+                    .ok();
+
+                continue;
+            }
+        }
+
+        let quirk = quirks.for_url(&outgoing.announce_url);
+
+        let mut request = common::tracker::Request {
             info_hash: outgoing.info_hash,
             uploaded: outgoing.uploaded,
             downloaded: outgoing.downloaded,
             left: outgoing.left,
             event: outgoing.event,
             numwant: outgoing.numwant,
+            key: outgoing.key,
 
-            ip,
-            key: key.clone(),
+            ip: None,
             peer_id,
             port,
-            trackerid: tracker_ids.get(&outgoing.info_hash).cloned(),
+            trackerid: tracker_ids.get(&outgoing.info_hash, &outgoing.announce_url),
 
             compact: None,
             supportcrypto: None,
@@ -70,23 +423,48 @@ pub async fn announce(
             no_peer_id: None,
         };
 
-        match do_announce(&reqwest_client, &outgoing.announce_url, request).await {
+        quirk.apply(&mut request);
+
+        let backoff = backoffs
+            .entry(key.clone())
+            .or_insert_with(|| Backoff::new(backoff::TRACKER_ANNOUNCE));
+
+        let url = &outgoing.announce_url;
+        let mut result = do_announce(&client, url, request.clone(), max_response).await;
+
+        if quirk.retry_without_event
+            && request.event.is_some()
+            && matches!(
+                result,
+                Err(TrackerError::Status(_) | TrackerError::Parse(_) | TrackerError::Failure(_))
+            )
+        {
+            request.event = None;
+            result = do_announce(&client, url, request, max_response).await;
+        }
+
+        match result {
             Ok(response) => {
-                // If the server responds with a tracker ID, we are expected to include that ID in
-                // future requests.
-                if let common::tracker::Response::Success(common::tracker::SuccessResponse {
-                    tracker_id: Some(tracker_id),
-                    ..
-                }) = &response
-                {
-                    tracker_ids.insert(outgoing.info_hash, tracker_id.clone());
+                backoff.succeeded();
+
+                if let Some(host) = &host {
+                    hosts.succeeded(host);
+                }
+
+                if outgoing.event == Some(common::tracker::Event::Stopped) {
+                    tracker_ids.forget(&outgoing.info_hash, &outgoing.announce_url);
+                } else {
+                    tracker_ids.update(&outgoing.info_hash, &outgoing.announce_url, &response);
                 }
 
                 sender
                     .send(
                         Incoming {
                             info_hash: outgoing.info_hash,
-                            event: IncomingEvent::AnnounceResponse { response },
+                            event: IncomingEvent::AnnounceResponse {
+                                url: outgoing.announce_url,
+                                response,
+                            },
                         }
                         .into(),
                     )
@@ -94,13 +472,26 @@ pub async fn announce(
                     .ok();
             }
             Err(e) => {
+                // The tracker's own idea of when to retry takes precedence over ours.
+                let retry_in = if e.is_permanent() {
+                    disabled.insert(key);
+                    None
+                } else if let Some(host) = host.as_ref().filter(|_| e.is_host_down()) {
+                    let delay = hosts.failed(host, Instant::now());
+                    e.retry_in().or(delay)
+                } else {
+                    let delay = backoff.failed();
+                    e.retry_in().or(delay)
+                };
+
                 sender
                     .send(
                         Incoming {
                             info_hash: outgoing.info_hash,
                             event: IncomingEvent::AnnounceError {
                                 url: outgoing.announce_url,
-                                error: e.into_owned(),
+                                error: e,
+                                retry_in,
                             },
                         }
                         .into(),
@@ -112,11 +503,141 @@ pub async fn announce(
     }
 }
 
+/// An HTTP client for talking to trackers, sending `user_agent`, which must be a valid header
+/// value. The session shares one, so that torrents announcing to the same tracker reuse its
+/// pooled connections, multiplexed over HTTP/2 where the tracker offers it over HTTPS.
+pub fn http_client(resolver: Resolver, user_agent: &str) -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(5))
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .dns_resolver(Arc::new(resolver))
+        // Some trackers compress long peer lists.
+        .gzip(true)
+        .deflate(true)
+        .default_headers(
+            iter::once((
+                reqwest::header::USER_AGENT,
+                reqwest::header::HeaderValue::from_str(user_agent).unwrap(),
+            ))
+            .collect(),
+        )
+        .build()
+        .unwrap()
+}
+
+/// Announce once to the tracker at `announce_url`, outside of the announce task, and return the
+/// addresses of the peers it lists. This finds peers for a magnet link before its torrent has any
+/// metadata to join the session with.
+pub async fn find_peers(
+    client: &reqwest::Client,
+    announce_url: &str,
+    info_hash: common::InfoHash,
+    peer_id: common::PeerId,
+    port: u16,
+    max_response: common::Bytes,
+) -> Result<Vec<SocketAddr>, TrackerError> {
+    // How much is left isn't known without the metadata. Claiming a little keeps the tracker from
+    // taking us for a seed and leaving the other seeds out.
+    let left = common::peer::METADATA_PIECE_LEN as u64;
+    let request = common::tracker::Request::new(info_hash, peer_id, port, 0, 0, left);
+
+    let response = do_announce(client, announce_url, request, max_response).await?;
+    Ok(response.peers.iter().map(|peer| peer.addr).collect())
+}
+
+pub async fn scrape(
+    client: &reqwest::Client,
+    announce_url: &str,
+    info_hash: common::InfoHash,
+    max_response: common::Bytes,
+) -> Result<common::tracker::ScrapeFile, TrackerError> {
+    check_url(announce_url)?;
+
+    let scrape_url = common::tracker::scrape_url(announce_url).ok_or_else(|| {
+        TrackerError::UnsupportedUrl("the tracker does not support scraping".to_string())
+    })?;
+
+    let request = common::tracker::ScrapeRequest {
+        info_hashes: vec![info_hash],
+    };
+
+    let url = if scrape_url.contains('?') {
+        format!("{scrape_url}&{}", request.as_query_string())
+    } else {
+        format!("{scrape_url}?{}", request.as_query_string())
+    };
+
+    let response = client
+        .get(&url)
+        .timeout(REQUEST_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    match common::tracker::ScrapeResponse::try_from(&read_body(response, max_response).await?[..])
+        .map_err(TrackerError::Parse)?
+    {
+        common::tracker::ScrapeResponse::Success(mut success) => {
+            success.files.remove(&info_hash).ok_or_else(|| {
+                TrackerError::Failure(common::tracker::FailureResponse {
+                    failure_reason: "no stats were returned for this torrent".to_string(),
+                    ..Default::default()
+                })
+            })
+        }
+        common::tracker::ScrapeResponse::Failure(failure) => Err(TrackerError::Failure(failure)),
+    }
+}
+
+/// Read a response body as it arrives, giving up as soon as it grows past `limit` rather than
+/// buffering whatever a broken or malicious tracker sends.
+pub async fn read_body(
+    mut response: reqwest::Response,
+    limit: common::Bytes,
+) -> Result<Vec<u8>, TrackerError> {
+    let limit_bytes = u64::from(limit);
+
+    if response
+        .content_length()
+        .is_some_and(|len| len > limit_bytes)
+    {
+        return Err(TrackerError::TooLarge(limit));
+    }
+
+    let mut body = Vec::new();
+
+    while let Some(chunk) = response.chunk().await? {
+        if (body.len() + chunk.len()) as u64 > limit_bytes {
+            return Err(TrackerError::TooLarge(limit));
+        }
+
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body)
+}
+
+/// Reject URLs that we can't announce to before trying, so that the error says why.
+fn check_url(url: &str) -> Result<(), TrackerError> {
+    let url = reqwest::Url::parse(url).map_err(|e| TrackerError::UnsupportedUrl(e.to_string()))?;
+
+    match url.scheme() {
+        "http" | "https" => Ok(()),
+        scheme => Err(TrackerError::UnsupportedUrl(format!(
+            "{}:// trackers are not supported",
+            scheme,
+        ))),
+    }
+}
+
 async fn do_announce(
     client: &reqwest::Client,
     announce_url: &str,
     request: common::tracker::Request,
-) -> Result<common::tracker::Response, common::Error> {
+    max_response: common::Bytes,
+) -> Result<common::tracker::SuccessResponse, TrackerError> {
+    check_url(announce_url)?;
+
     let url = if announce_url.contains('?') {
         format!("{announce_url}&{}", request.as_query_string())
     } else {
@@ -125,9 +646,519 @@ async fn do_announce(
 
     let response = client
         .get(&url)
+        .timeout(REQUEST_TIMEOUT)
         .send()
-        .await
-        .map_err(|e| format!("{e:?}"))?;
+        .await?
+        .error_for_status()?;
+
+    match common::tracker::Response::try_from(&read_body(response, max_response).await?[..])
+        .map_err(TrackerError::Parse)?
+    {
+        common::tracker::Response::Success(success) => Ok(success),
+        common::tracker::Response::Failure(failure) => Err(TrackerError::Failure(failure)),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn check_url_test() {
+        assert!(check_url("http://tracker.example/announce").is_ok());
+        assert!(check_url("https://tracker.example/announce?passkey=abc").is_ok());
+
+        for url in [
+            "udp://tracker.example:6969",
+            "wss://tracker.example",
+            "tracker.example",
+        ] {
+            let error = check_url(url).unwrap_err();
+            assert!(
+                matches!(error, TrackerError::UnsupportedUrl(_)),
+                "{url}: {error}"
+            );
+            assert!(error.is_permanent());
+        }
+    }
+
+    #[test]
+    fn hosts_test() {
+        assert_eq!(
+            Some("tracker.example:80".to_string()),
+            host_of("http://tracker.example/announce?passkey=abc"),
+        );
+        assert_eq!(
+            host_of("https://tracker.example:443/a/announce"),
+            host_of("https://tracker.example/b/announce"),
+        );
+        assert_eq!(None, host_of("tracker.example"));
+
+        let now = Instant::now();
+        let mut hosts = Hosts::default();
+        assert_eq!(None, hosts.retry_in("tracker.example:80", now));
+
+        let delay = hosts.failed("tracker.example:80", now).unwrap();
+        let retry_in = hosts.retry_in("tracker.example:80", now).unwrap();
+        assert!(
+            retry_in >= delay && retry_in <= delay + HOST_STAGGER,
+            "{retry_in:?}"
+        );
+        assert_eq!(None, hosts.retry_in("other.example:80", now));
+        assert_eq!(None, hosts.retry_in("tracker.example:80", now + delay));
+
+        hosts.succeeded("tracker.example:80");
+        assert_eq!(None, hosts.retry_in("tracker.example:80", now));
+
+        assert!(TrackerError::Timeout.is_host_down());
+        assert!(TrackerError::Status(reqwest::StatusCode::BAD_GATEWAY).is_host_down());
+        assert!(!TrackerError::Status(reqwest::StatusCode::NOT_FOUND).is_host_down());
+        assert!(!TrackerError::Parse("bad".into()).is_host_down());
+    }
+
+    #[test]
+    fn schedule_test() {
+        let now = Instant::now();
+        let mut schedule = Schedule::new(&["http://a.example/", "http://b.example/"], now);
+
+        // The first announces are spread out, but all fall within the stagger.
+        assert!(schedule.0.iter().all(|scheduled| {
+            scheduled
+                .next
+                .is_some_and(|next| next <= now + FIRST_ANNOUNCE_STAGGER)
+        }));
+        assert_eq!(
+            vec![
+                (
+                    "http://a.example/".to_string(),
+                    Some(common::tracker::Event::Started)
+                ),
+                (
+                    "http://b.example/".to_string(),
+                    Some(common::tracker::Event::Started)
+                ),
+            ],
+            schedule.due(now + FIRST_ANNOUNCE_STAGGER),
+        );
+        assert!(schedule.due(now + FIRST_ANNOUNCE_STAGGER).is_empty());
+
+        // Torrents starting together don't all announce to a tracker at the same time.
+        let firsts: HashSet<Instant> = (0..100)
+            .filter_map(|_| Schedule::new(&["http://a.example/"], now).0[0].next)
+            .collect();
+        assert!(firsts.len() > 1);
+        assert_eq!(0, schedule.started().count());
+
+        schedule.succeeded("http://a.example/", Duration::from_secs(1), now);
+        schedule.failed("http://b.example/", None, now);
+        assert_eq!(
+            vec!["http://a.example/"],
+            schedule.started().collect::<Vec<_>>()
+        );
+        assert!(schedule.due(now + Duration::from_secs(1)).is_empty());
+        assert_eq!(
+            vec![("http://a.example/".to_string(), None)],
+            schedule.due(now + MIN_ANNOUNCE_INTERVAL),
+        );
+
+        schedule.failed("http://a.example/", Some(Duration::from_secs(5)), now);
+        assert_eq!(1, schedule.due(now + Duration::from_secs(5)).len());
+
+        // Standby sends `stopped` to the started tracker, and `started` again on resuming. The
+        // response to `stopped` doesn't count as acknowledging it.
+        assert_eq!(vec!["http://a.example/".to_string()], schedule.stop(now));
+        assert_eq!(0, schedule.started().count());
+        assert_eq!(
+            vec![(
+                "http://a.example/".to_string(),
+                Some(common::tracker::Event::Started)
+            )],
+            schedule.due(now),
+        );
+        schedule.succeeded("http://a.example/", Duration::from_secs(1), now);
+        assert_eq!(0, schedule.started().count());
+        schedule.succeeded("http://a.example/", Duration::from_secs(1), now);
+        assert_eq!(
+            vec!["http://a.example/"],
+            schedule.started().collect::<Vec<_>>()
+        );
+    }
+
+    /// Run a fake tracker that answers each request it gets with the next of `responses`, a status
+    /// code and body, and passes on the request line. Returns its announce URL.
+    pub(crate) async fn fake_tracker(
+        responses: Vec<(u16, Vec<u8>)>,
+    ) -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+
+                while !request.ends_with(b"\r\n\r\n") {
+                    let mut buf = [0; 1024];
+                    let len = stream.read(&mut buf).await.unwrap();
+                    request.extend(&buf[..len]);
+                }
+
+                let request = String::from_utf8_lossy(&request);
+                sender
+                    .send(request.lines().next().unwrap().to_string())
+                    .ok();
+
+                let header = format!(
+                    "HTTP/1.1 {status} Whatever\r\nContent-Length: {}\r\n\
+                    Connection: close\r\n\r\n",
+                    body.len(),
+                );
+
+                stream.write_all(header.as_bytes()).await.unwrap();
+                stream.write_all(&body).await.unwrap();
+            }
+        });
+
+        (url, receiver)
+    }
+
+    /// Spawn the announce task, returning the channels to talk to it through.
+    pub(crate) fn spawn_announce(
+        max_response: common::Bytes,
+        quirks: Quirks,
+    ) -> (
+        mpsc::UnboundedSender<Outgoing>,
+        mpsc::Receiver<super::super::Incoming>,
+    ) {
+        let (outgoing_sender, outgoing_receiver) = mpsc::unbounded_channel();
+        let (incoming_sender, incoming_receiver) = mpsc::channel(10);
+        let client = http_client(Resolver::default(), super::super::USER_AGENT);
+
+        tokio::spawn(announce(
+            incoming_sender,
+            outgoing_receiver,
+            [2; 20].into(),
+            6881,
+            client,
+            max_response,
+            quirks,
+        ));
+
+        (outgoing_sender, incoming_receiver)
+    }
+
+    fn outgoing(url: &str, info_hash: u8, event: Option<common::tracker::Event>) -> Outgoing {
+        Outgoing {
+            announce_url: url.to_string(),
+            info_hash: [info_hash; 20].into(),
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            event,
+            numwant: None,
+            key: None,
+        }
+    }
+
+    async fn next_event(receiver: &mut mpsc::Receiver<super::super::Incoming>) -> Incoming {
+        match receiver.recv().await {
+            Some(super::super::Incoming::Tracker(incoming)) => incoming,
+            _ => panic!("Expected a tracker event"),
+        }
+    }
+
+    #[tokio::test]
+    async fn announce_test() {
+        let body = b"d8:intervali900e5:peers0:e".to_vec();
+        let (url, mut requests) = fake_tracker(vec![(200, body.clone()), (200, body)]).await;
+        let (announcer, mut receiver) = spawn_announce(1024.into(), Quirks::default());
+
+        announcer
+            .send(outgoing(&url, 1, Some(common::tracker::Event::Started)))
+            .unwrap();
+
+        match next_event(&mut receiver).await {
+            Incoming {
+                info_hash,
+                event:
+                    IncomingEvent::AnnounceResponse {
+                        url: response_url,
+                        response: success,
+                    },
+            } => {
+                assert_eq!(common::InfoHash::from([1; 20]), info_hash);
+                assert_eq!(url, response_url);
+                assert_eq!(900, success.interval);
+            }
+            _ => panic!("Expected a successful announce"),
+        }
+
+        assert!(requests.recv().await.unwrap().contains("event=started"));
+
+        announcer.send(outgoing(&url, 1, None)).unwrap();
+        next_event(&mut receiver).await;
+        assert!(!requests.recv().await.unwrap().contains("event="));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_tracker_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+
+        // Take the connection, then never answer.
+        tokio::spawn(async move {
+            let _stream = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let (announcer, mut receiver) = spawn_announce(1024.into(), Quirks::default());
+        let start = tokio::time::Instant::now();
+        announcer.send(outgoing(&url, 1, None)).unwrap();
+
+        match next_event(&mut receiver).await.event {
+            IncomingEvent::AnnounceError {
+                error: TrackerError::Timeout,
+                retry_in,
+                ..
+            } => assert!(retry_in.is_some()),
+            _ => panic!("Expected the request to time out"),
+        }
+
+        assert!(start.elapsed() >= REQUEST_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn host_down_test() {
+        let (url, mut requests) = fake_tracker(vec![(503, Vec::new())]).await;
+        let (announcer, mut receiver) = spawn_announce(1024.into(), Quirks::default());
+
+        announcer.send(outgoing(&url, 1, None)).unwrap();
+        announcer.send(outgoing(&url, 2, None)).unwrap();
+
+        match next_event(&mut receiver).await.event {
+            IncomingEvent::AnnounceError {
+                error: TrackerError::Status(status),
+                retry_in,
+                ..
+            } => {
+                assert_eq!(reqwest::StatusCode::SERVICE_UNAVAILABLE, status);
+                assert!(retry_in.is_some());
+            }
+            _ => panic!("Expected the tracker's 503"),
+        }
+
+        match next_event(&mut receiver).await {
+            Incoming {
+                info_hash,
+                event:
+                    IncomingEvent::AnnounceError {
+                        error: TrackerError::HostDown(_),
+                        retry_in,
+                        ..
+                    },
+            } => {
+                assert_eq!(common::InfoHash::from([2; 20]), info_hash);
+                assert!(retry_in.is_some());
+            }
+            _ => panic!("Expected the second torrent to skip the host"),
+        }
+
+        assert!(requests.recv().await.is_some());
+        assert!(requests.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn tracker_id_test() {
+        let with_id = b"d8:intervali900e5:peers0:10:tracker id3:abce".to_vec();
+        let without_id = b"d8:intervali900e5:peers0:e".to_vec();
+        let (url, mut requests) = fake_tracker(vec![
+            (200, with_id),
+            (200, without_id.clone()),
+            (200, without_id.clone()),
+            (200, without_id),
+        ])
+        .await;
+        let (announcer, mut receiver) = spawn_announce(1024.into(), Quirks::default());
+
+        for event in [
+            Some(common::tracker::Event::Started),
+            None,
+            Some(common::tracker::Event::Stopped),
+            Some(common::tracker::Event::Started),
+        ] {
+            announcer.send(outgoing(&url, 1, event)).unwrap();
+            next_event(&mut receiver).await;
+        }
+
+        assert!(!requests.recv().await.unwrap().contains("trackerid="));
+        assert!(requests.recv().await.unwrap().contains("trackerid=abc"));
+        assert!(requests.recv().await.unwrap().contains("trackerid=abc"));
+        assert!(!requests.recv().await.unwrap().contains("trackerid="));
+    }
+
+    #[tokio::test]
+    async fn retry_in_test() {
+        let never = b"d14:failure reason12:Unregistered8:retry in5:nevere".to_vec();
+        let later = b"d14:failure reason4:Busy8:retry ini5ee".to_vec();
+        let (url, mut requests) = fake_tracker(vec![(200, never), (200, later)]).await;
+        let (announcer, mut receiver) = spawn_announce(1024.into(), Quirks::default());
+
+        announcer.send(outgoing(&url, 1, None)).unwrap();
+
+        match next_event(&mut receiver).await.event {
+            IncomingEvent::AnnounceError {
+                error: TrackerError::Failure(_),
+                retry_in: None,
+                ..
+            } => {}
+            _ => panic!("Expected a failure never to retry"),
+        }
 
-    Ok(response.bytes().await.map_err(|e| format!("{e:?}"))?[..].try_into()?)
+        // The first torrent is never announced to the tracker again, but others still are.
+        announcer.send(outgoing(&url, 1, None)).unwrap();
+        announcer.send(outgoing(&url, 2, None)).unwrap();
+
+        match next_event(&mut receiver).await {
+            Incoming {
+                info_hash,
+                event:
+                    IncomingEvent::AnnounceError {
+                        error: TrackerError::Failure(_),
+                        retry_in,
+                        ..
+                    },
+            } => {
+                assert_eq!(common::InfoHash::from([2; 20]), info_hash);
+                assert_eq!(Some(Duration::from_secs(300)), retry_in);
+            }
+            _ => panic!("Expected a failure to retry in five minutes"),
+        }
+
+        assert_eq!(2, std::iter::from_fn(|| requests.try_recv().ok()).count());
+    }
+
+    #[tokio::test]
+    async fn too_large_test() {
+        let body = b"d8:intervali900e5:peers0:e".to_vec();
+        let (url, _requests) = fake_tracker(vec![(200, body)]).await;
+        let (announcer, mut receiver) = spawn_announce(16.into(), Quirks::default());
+
+        announcer.send(outgoing(&url, 1, None)).unwrap();
+
+        match next_event(&mut receiver).await.event {
+            IncomingEvent::AnnounceError {
+                error: TrackerError::TooLarge(limit),
+                retry_in,
+                ..
+            } => {
+                assert_eq!(common::Bytes::from(16), limit);
+                assert!(retry_in.is_some());
+            }
+            _ => panic!("Expected the response to be too large"),
+        }
+    }
+
+    #[tokio::test]
+    async fn quirks_test() {
+        let body = b"d8:intervali900e5:peers0:e".to_vec();
+        let (url, mut requests) = fake_tracker(vec![(200, body.clone()), (200, body)]).await;
+
+        let path = std::env::temp_dir().join(format!("toytorrent-quirks-{}", std::process::id()));
+        std::fs::write(&path, "host 127.0.0.1\nsupport-crypto\nno-compact\n").unwrap();
+        let quirks = Quirks::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let (announcer, mut receiver) = spawn_announce(1024.into(), quirks);
+
+        announcer.send(outgoing(&url, 1, None)).unwrap();
+        next_event(&mut receiver).await;
+
+        let request = requests.recv().await.unwrap();
+        assert!(request.contains("supportcrypto=1"), "{request}");
+        assert!(request.contains("compact=0"), "{request}");
+
+        let other_url = url.replace("127.0.0.1", "localhost");
+        announcer.send(outgoing(&other_url, 1, None)).unwrap();
+        next_event(&mut receiver).await;
+
+        let request = requests.recv().await.unwrap();
+        assert!(!request.contains("supportcrypto="), "{request}");
+    }
+
+    /// Wrap `data` in a gzip stream without compressing it, using a single stored deflate block.
+    fn gzip_stored(data: &[u8]) -> Vec<u8> {
+        let crc = !data.iter().fold(!0u32, |crc, &byte| {
+            (0..8).fold(crc ^ byte as u32, |crc, _| {
+                (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+            })
+        });
+        let len = data.len() as u16;
+
+        let mut gzip = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff, 1];
+        gzip.extend(len.to_le_bytes());
+        gzip.extend((!len).to_le_bytes());
+        gzip.extend(data);
+        gzip.extend(crc.to_le_bytes());
+        gzip.extend((data.len() as u32).to_le_bytes());
+        gzip
+    }
+
+    #[tokio::test]
+    async fn gzip_response_test() {
+        let body = b"d8:intervali900e5:peers6:\x7f\0\0\x01\x1a\xe1e";
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+
+            while !request.ends_with(b"\r\n\r\n") {
+                let mut buf = [0; 1024];
+                let len = stream.read(&mut buf).await.unwrap();
+                request.extend(&buf[..len]);
+            }
+
+            let gzip = gzip_stored(body);
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\
+                Connection: close\r\n\r\n",
+                gzip.len(),
+            );
+
+            stream.write_all(header.as_bytes()).await.unwrap();
+            stream.write_all(&gzip).await.unwrap();
+        });
+
+        let request = common::tracker::Request {
+            info_hash: [1; 20].into(),
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            event: None,
+            numwant: None,
+            peer_id: [2; 20].into(),
+            key: None,
+            ip: None,
+            port: 6881,
+            compact: Some(true),
+            supportcrypto: None,
+            requirecrypto: None,
+            no_peer_id: None,
+            trackerid: None,
+        };
+
+        let client = http_client(Resolver::default(), super::super::USER_AGENT);
+        let url = format!("http://{}/announce", addr);
+
+        let response = do_announce(&client, &url, request, common::Bytes::from(1024))
+            .await
+            .unwrap();
+        assert_eq!(900, response.interval);
+        assert_eq!(1, response.peers.len());
+    }
 }
@@ -0,0 +1,12 @@
+//! The web UI: a single page that lists the torrents and adds new ones through the JSON API. It's
+//! compiled into the binary so that there's nothing to install alongside it.
+
+/// The content type and contents of the asset at `path`, if there is one.
+pub fn asset(path: &str) -> Option<(&'static str, &'static str)> {
+    match path {
+        "/" | "/index.html" => Some(("text/html; charset=utf-8", include_str!("ui/index.html"))),
+        "/app.js" => Some(("text/javascript; charset=utf-8", include_str!("ui/app.js"))),
+        "/style.css" => Some(("text/css; charset=utf-8", include_str!("ui/style.css"))),
+        _ => None,
+    }
+}
@@ -0,0 +1,181 @@
+"use strict";
+
+const REFRESH_INTERVAL_MS = 2000;
+const UNITS = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+
+let token = sessionStorage.getItem("token");
+
+// Send a request to the API, asking for a token and trying again if it wants one. Basic auth is
+// left to the browser.
+async function api(method, path, body, contentType) {
+  const headers = {};
+
+  if (token) {
+    headers["Authorization"] = "Bearer " + token;
+  }
+
+  if (contentType) {
+    headers["Content-Type"] = contentType;
+  }
+
+  const response = await fetch(path, { method, headers, body });
+  const challenge = response.headers.get("WWW-Authenticate") || "";
+
+  if (response.status === 401 && challenge.startsWith("Bearer")) {
+    token = prompt("API token");
+
+    if (token) {
+      sessionStorage.setItem("token", token);
+      return api(method, path, body, contentType);
+    }
+  }
+
+  if (!response.ok) {
+    const error = await response.json().catch(() => ({}));
+    throw new Error(error.error || response.status + " " + response.statusText);
+  }
+
+  return response;
+}
+
+function formatBytes(bytes) {
+  let unit = 0;
+
+  while (bytes >= 1024 && unit < UNITS.length - 1) {
+    bytes /= 1024;
+    unit++;
+  }
+
+  return (unit === 0 ? bytes : bytes.toFixed(1)) + " " + UNITS[unit];
+}
+
+function showMessage(text, ok) {
+  const message = document.getElementById("message");
+  message.textContent = text;
+  message.className = ok ? "ok" : "";
+  message.hidden = false;
+}
+
+function row(torrent) {
+  const tr = document.createElement("tr");
+
+  const name = document.createElement("td");
+  name.className = "name";
+  name.textContent = torrent.name;
+  name.title = torrent.info_hash;
+
+  const size = document.createElement("td");
+  size.textContent = formatBytes(torrent.length);
+
+  const progress = document.createElement("td");
+  const bar = document.createElement("progress");
+  bar.max = torrent.pieces;
+  bar.value = torrent.pieces_have;
+  const percent = torrent.length ? (100 * torrent.downloaded) / torrent.length : 100;
+  progress.append(bar, " " + percent.toFixed(1) + "%");
+
+  const peers = document.createElement("td");
+  peers.textContent = torrent.peers;
+
+  if (torrent.error) {
+    const error = document.createElement("div");
+    error.className = "error";
+    error.textContent = torrent.error + " ";
+
+    const retry = document.createElement("button");
+    retry.textContent = "Retry";
+    retry.addEventListener("click", async () => {
+      try {
+        await api("POST", "/api/retry", torrent.info_hash, "text/plain");
+        refresh();
+      } catch (e) {
+        showMessage("Unable to retry " + torrent.name + ": " + e.message, false);
+      }
+    });
+
+    error.append(retry);
+    name.append(error);
+  }
+
+  const status = document.createElement("td");
+  status.textContent = torrent.status + " ";
+
+  if (torrent.status !== "checking") {
+    const paused = torrent.status === "paused";
+    const toggle = document.createElement("button");
+    toggle.textContent = paused ? "Unpause" : "Pause";
+    toggle.addEventListener("click", async () => {
+      const action = paused ? "unpause" : "pause";
+      try {
+        await api("POST", "/api/" + action, torrent.info_hash, "text/plain");
+        refresh();
+      } catch (e) {
+        showMessage("Unable to " + action + " " + torrent.name + ": " + e.message, false);
+      }
+    });
+    status.append(toggle);
+  }
+
+  tr.append(name, size, progress, peers, status);
+  return tr;
+}
+
+async function refresh() {
+  try {
+    const torrents = await (await api("GET", "/api/torrents")).json();
+    torrents.sort((a, b) => a.name.localeCompare(b.name));
+    document.getElementById("torrents").replaceChildren(...torrents.map(row));
+  } catch (e) {
+    showMessage("Unable to load torrents: " + e.message, false);
+  }
+}
+
+document.getElementById("add").addEventListener("change", async (event) => {
+  const file = event.target.files[0];
+  event.target.value = "";
+
+  if (!file) {
+    return;
+  }
+
+  try {
+    const response = await api(
+      "POST",
+      "/api/torrents",
+      await file.arrayBuffer(),
+      "application/x-bittorrent",
+    );
+    showMessage(response.status === 201 ? "Added " + file.name : "Already added", true);
+    refresh();
+  } catch (e) {
+    showMessage("Unable to add " + file.name + ": " + e.message, false);
+  }
+});
+
+// The torrent is only added once its metadata has been fetched from peers.
+document.getElementById("add-magnet").addEventListener("submit", async (event) => {
+  event.preventDefault();
+  const magnet = document.getElementById("magnet");
+  const uri = magnet.value.trim();
+
+  try {
+    await api("POST", "/api/torrents", uri, "text/plain");
+    magnet.value = "";
+    showMessage("Fetching the metadata of " + uri, true);
+  } catch (e) {
+    showMessage("Unable to add " + uri + ": " + e.message, false);
+  }
+});
+
+for (const command of ["standby", "resume"]) {
+  document.getElementById(command).addEventListener("click", async () => {
+    try {
+      await api("POST", "/api/" + command);
+    } catch (e) {
+      showMessage("Unable to " + command + ": " + e.message, false);
+    }
+  });
+}
+
+refresh();
+setInterval(refresh, REFRESH_INTERVAL_MS);
@@ -0,0 +1,104 @@
+//! The order in which to serve the blocks that peers request from us. Rather than answering each
+//! peer's requests as they arrive, which lets a peer that floods us with requests take every disk
+//! read and all of our upstream, the unchoked peers with requests queued take turns. Each turn
+//! serves a peer as many blocks as its weight, which grows with how much it sends us back.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+
+/// The most blocks a peer can be served in one turn, however much it sends us.
+pub const MAX_WEIGHT: u32 = 4;
+
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    /// The peers waiting for a turn, with the one whose turn it is at the front.
+    order: VecDeque<SocketAddr>,
+
+    /// The blocks left in the turn of the peer at the front.
+    credit: u32,
+}
+
+impl Scheduler {
+    /// The peer to serve a block to next, out of `waiting`: the peers that have requests we can
+    /// serve, each with its weight.
+    pub fn next(&mut self, waiting: &HashMap<SocketAddr, u32>) -> Option<SocketAddr> {
+        if self
+            .order
+            .front()
+            .is_some_and(|addr| !waiting.contains_key(addr))
+        {
+            self.credit = 0;
+        }
+
+        self.order.retain(|addr| waiting.contains_key(addr));
+
+        let mut new: Vec<SocketAddr> = waiting
+            .keys()
+            .filter(|addr| !self.order.contains(addr))
+            .copied()
+            .collect();
+        new.sort();
+        self.order.extend(new);
+
+        let addr = *self.order.front()?;
+
+        if self.credit == 0 {
+            self.credit = waiting[&addr].clamp(1, MAX_WEIGHT);
+        }
+
+        self.credit -= 1;
+
+        if self.credit == 0 {
+            self.order.rotate_left(1);
+        }
+
+        Some(addr)
+    }
+}
+
+/// A peer's weight by how fast it sends us data compared to how fast we send it data: 1 for a
+/// peer that gives nothing back, up to [`MAX_WEIGHT`] for one that gives back as much as it takes.
+/// `min_upload` stands in for slower uploads, so that a trickle either way doesn't count for much.
+pub fn weight(download_rate: f64, upload_rate: f64, min_upload: f64) -> u32 {
+    let reciprocation = (download_rate / upload_rate.max(min_upload)).clamp(0.0, 1.0);
+
+    1 + (reciprocation * f64::from(MAX_WEIGHT - 1)).round() as u32
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn next_test() {
+        let greedy: SocketAddr = "192.0.2.1:6881".parse().unwrap();
+        let generous: SocketAddr = "192.0.2.2:6881".parse().unwrap();
+
+        let mut scheduler = Scheduler::default();
+        let mut waiting = HashMap::from([(greedy, 1), (generous, 3)]);
+
+        let served: Vec<SocketAddr> = (0..8).filter_map(|_| scheduler.next(&waiting)).collect();
+        assert_eq!(
+            vec![greedy, generous, generous, generous, greedy, generous, generous, generous],
+            served,
+        );
+
+        // A peer that runs out of requests loses the rest of its turn.
+        scheduler.next(&waiting);
+        assert_eq!(Some(generous), scheduler.next(&waiting));
+        waiting.remove(&generous);
+        assert_eq!(Some(greedy), scheduler.next(&waiting));
+        assert_eq!(Some(greedy), scheduler.next(&waiting));
+
+        waiting.clear();
+        assert_eq!(None, scheduler.next(&waiting));
+    }
+
+    #[test]
+    fn weight_test() {
+        assert_eq!(1, weight(0.0, 0.0, 16384.0));
+        assert_eq!(1, weight(1000.0, 0.0, 16384.0));
+        assert_eq!(MAX_WEIGHT, weight(100_000.0, 50_000.0, 16384.0));
+        assert_eq!(3, weight(30_000.0, 50_000.0, 16384.0));
+    }
+}
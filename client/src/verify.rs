@@ -0,0 +1,217 @@
+//! Checking data on disk against a torrent's hashes without joining its swarm, with
+//! `toytorrent verify`, for instance to make sure that an archived download is still intact. Long
+//! checks, here or when importing a download, report their progress as they go.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use toytorrent_common as common;
+
+use crate::output;
+use crate::storage::{Files, Storage};
+
+/// How often to report the progress of a check.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum PieceState {
+    Valid,
+
+    /// The data is there, but doesn't match the piece's hash.
+    Invalid,
+
+    /// A file holding part of the piece is missing or too short.
+    Missing,
+}
+
+/// Reports the progress of hashing a torrent's pieces as [`Checking`](output::Event::Checking)
+/// events.
+#[derive(Debug)]
+pub struct Progress {
+    info_hash: common::InfoHash,
+    name: String,
+    pieces: u32,
+    checked: u32,
+    started: Instant,
+    reported: Instant,
+}
+
+/// Hash the data of the torrent in `file` that was downloaded into `dir`, printing which files are
+/// complete and how much of the torrent is, and with `pieces` the state of every piece. Returns
+/// whether the whole torrent is there.
+pub fn run(file: &Path, dir: &Path, pieces: bool) -> Result<bool, common::Error> {
+    let bytes = fs::read(file).map_err(|e| format!("{}: {}", file.display(), e))?;
+    let metainfo = common::metainfo::MetainfoFile::try_from(bytes.as_slice())
+        .map_err(|e| format!("{}: {}", file.display(), e))?;
+    let info = &metainfo.info;
+
+    let mut files = Files::new(dir, info)?;
+    let mut states = Vec::with_capacity(info.pieces().len());
+    let mut progress = Progress::new(&metainfo, Instant::now());
+
+    for index in 0..info.pieces().len() as u32 {
+        let state = match files
+            .read_piece(index)
+            .map_err(|e| format!("Unable to read piece {}: {}", index, e))?
+        {
+            Some(data) if metainfo.verify_piece(index, &data) => PieceState::Valid,
+            Some(_) => PieceState::Invalid,
+            None => PieceState::Missing,
+        };
+
+        if pieces {
+            say!("Piece {:>6} {}", index, state);
+        }
+
+        states.push(state);
+        progress.piece(Instant::now());
+    }
+
+    progress.finish(Instant::now());
+
+    for span in info.file_spans() {
+        let range = span.range();
+        let bad = if range.is_empty() {
+            0
+        } else {
+            let first = (range.start / info.piece_length()) as usize;
+            let last = ((range.end - 1) / info.piece_length()) as usize;
+
+            states
+                .iter()
+                .skip(first)
+                .take(last + 1 - first)
+                .filter(|&&state| state != PieceState::Valid)
+                .count()
+        };
+
+        let path = span.path.join("/");
+
+        match bad {
+            0 => say!("{:>8} {}", "ok", path),
+            bad => say!("{:>8} {} ({} bad pieces)", "bad", path, bad),
+        }
+    }
+
+    let valid = (0..)
+        .zip(&states)
+        .filter(|(_, state)| **state == PieceState::Valid)
+        .filter_map(|(index, _)| info.piece_range(index))
+        .map(|range| range.end - range.start)
+        .sum::<u64>();
+
+    let complete = match info.length() {
+        0 => 100.0,
+        length => valid as f64 / length as f64 * 100.0,
+    };
+
+    say!(
+        "{} of {} verified ({:.1}% complete)",
+        common::Bytes::from(valid),
+        common::Bytes::from(info.length()),
+        complete,
+    );
+
+    Ok(states.iter().all(|&state| state == PieceState::Valid))
+}
+
+impl Progress {
+    pub fn new(metainfo: &common::metainfo::MetainfoFile, now: Instant) -> Self {
+        Self {
+            info_hash: *metainfo.info_hash(),
+            name: metainfo.info.name().to_string(),
+            pieces: metainfo.info.pieces().len() as u32,
+            checked: 0,
+            started: now,
+            reported: now,
+        }
+    }
+
+    /// Count a checked piece, reporting the progress so far if it hasn't been for a while.
+    pub fn piece(&mut self, now: Instant) {
+        self.checked += 1;
+
+        if now.duration_since(self.reported) >= REPORT_INTERVAL {
+            self.reported = now;
+            output::event(self.event(now));
+        }
+    }
+
+    /// Report how the check ended.
+    pub fn finish(&self, now: Instant) {
+        output::event(self.event(now));
+    }
+
+    fn event(&self, now: Instant) -> output::Event {
+        let elapsed = now.duration_since(self.started).as_secs_f64();
+        let pieces_per_second = if elapsed > 0.0 {
+            f64::from(self.checked) / elapsed
+        } else {
+            0.0
+        };
+        let left = f64::from(self.pieces.saturating_sub(self.checked));
+
+        output::Event::Checking {
+            info_hash: self.info_hash,
+            name: self.name.clone(),
+            checked: self.checked,
+            pieces: self.pieces,
+            pieces_per_second,
+            eta: (pieces_per_second > 0.0)
+                .then(|| Duration::from_secs_f64(left / pieces_per_second)),
+        }
+    }
+}
+
+impl fmt::Display for PieceState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.pad(match self {
+            Self::Valid => "ok",
+            Self::Invalid => "bad",
+            Self::Missing => "missing",
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::output::Event;
+
+    #[test]
+    fn progress_test() {
+        let start = Instant::now();
+        let mut progress = Progress {
+            info_hash: common::InfoHash::from([1; 20]),
+            name: "a".to_string(),
+            pieces: 100,
+            checked: 0,
+            started: start,
+            reported: start,
+        };
+
+        let Event::Checking { eta, .. } = progress.event(start) else {
+            unreachable!();
+        };
+        assert_eq!(None, eta);
+
+        for _ in 0..10 {
+            progress.piece(start + Duration::from_millis(500));
+        }
+
+        assert_eq!(
+            Event::Checking {
+                info_hash: progress.info_hash,
+                name: "a".to_string(),
+                checked: 10,
+                pieces: 100,
+                pieces_per_second: 5.0,
+                eta: Some(Duration::from_secs(18)),
+            },
+            progress.event(start + Duration::from_secs(2)),
+        );
+    }
+}
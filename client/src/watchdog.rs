@@ -0,0 +1,45 @@
+//! Watches the address that the client is bound to and reports when it disappears, for instance
+//! when a VPN connection drops, so that the client can stop all traffic rather than let it leak
+//! out over another interface.
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time;
+
+#[derive(Debug)]
+pub struct Incoming {
+    pub ip: IpAddr,
+    pub available: bool,
+}
+
+/// Check the address every `interval`, sending an event each time it disappears or comes back.
+pub async fn watch(ip: IpAddr, interval: Duration, sender: mpsc::Sender<super::Incoming>) {
+    let mut available = true;
+    let mut tick = time::interval(interval);
+
+    loop {
+        tick.tick().await;
+
+        if is_available(ip).await == available {
+            continue;
+        }
+
+        available = !available;
+
+        if sender
+            .send(Incoming { ip, available }.into())
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Binding to an address fails with `AddrNotAvailable` once no interface carries it any more.
+async fn is_available(ip: IpAddr) -> bool {
+    UdpSocket::bind((ip, 0)).await.is_ok()
+}
@@ -0,0 +1,252 @@
+//! Fetching piece data over plain HTTP, for torrents that list web seeds. Two conventions are
+//! supported: BEP 19 `url-list` seeds, which are ordinary file servers accessed with range
+//! requests, and BEP 17 `httpseeds`, which are scripts that serve a piece given its index.
+
+use std::time::{Duration, Instant};
+
+use reqwest::header;
+use reqwest::StatusCode;
+use tokio::sync::mpsc;
+
+use toytorrent_common as common;
+
+pub struct Incoming {
+    pub info_hash: common::InfoHash,
+    pub index: u32,
+    pub result: Result<Vec<u8>, common::Error>,
+}
+
+/// Decides when to give up on the swarm and fetch the remaining pieces from web seeds instead.
+/// The fallback kicks in once no block has arrived from any peer for `stall_after`.
+#[derive(Clone, Debug)]
+pub struct Fallback {
+    stall_after: Duration,
+    last_progress: Instant,
+    active: bool,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum WebSeed {
+    /// BEP 19 (GetRight-style) seed
+    UrlList(String),
+
+    /// BEP 17 (Hoffman-style) seed
+    HttpSeed(String),
+}
+
+impl WebSeed {
+    /// All web seeds listed in a metainfo file.
+    pub fn all(metainfo: &common::metainfo::MetainfoFile) -> Vec<WebSeed> {
+        metainfo
+            .url_list
+            .iter()
+            .flatten()
+            .map(|url| WebSeed::UrlList(url.clone()))
+            .chain(
+                metainfo
+                    .httpseeds
+                    .iter()
+                    .flatten()
+                    .map(|url| WebSeed::HttpSeed(url.clone())),
+            )
+            .collect()
+    }
+
+    pub fn url(&self) -> &str {
+        match self {
+            Self::UrlList(url) | Self::HttpSeed(url) => url,
+        }
+    }
+
+    /// Download the complete piece at `index`. The data is not verified.
+    pub async fn fetch_piece(
+        &self,
+        client: &reqwest::Client,
+        metainfo: &common::metainfo::MetainfoFile,
+        index: u32,
+    ) -> Result<Vec<u8>, common::Error> {
+        let range = metainfo
+            .info
+            .piece_range(index)
+            .ok_or("Piece index out of range")?;
+
+        let data = match self {
+            Self::UrlList(base_url) => {
+                let mut data = Vec::with_capacity((range.end - range.start) as usize);
+                let is_single_file =
+                    matches!(metainfo.info, common::metainfo::Info::SingleFile { .. });
+
+                for span in metainfo.info.file_spans() {
+                    let start = range.start.max(span.offset);
+                    let end = range.end.min(span.offset + span.length);
+
+                    if start >= end {
+                        continue;
+                    }
+
+                    // A single-file seed URL may point directly at the file.
+                    let url = if is_single_file && !base_url.ends_with('/') {
+                        base_url.clone()
+                    } else {
+                        let path = span
+                            .path
+                            .iter()
+                            .map(|component| common::url_encode(component.as_bytes()))
+                            .collect::<Vec<_>>()
+                            .join("/");
+
+                        format!("{}/{}", base_url.trim_end_matches('/'), path)
+                    };
+
+                    data.extend(
+                        fetch_range(client, &url, start - span.offset, end - span.offset).await?,
+                    );
+                }
+
+                data
+            }
+            Self::HttpSeed(base_url) => {
+                let separator = if base_url.contains('?') { '&' } else { '?' };
+                let url = format!(
+                    "{base_url}{separator}info_hash={}&piece={index}",
+                    common::url_encode(metainfo.info_hash().as_slice()),
+                );
+
+                let response = client
+                    .get(&url)
+                    .send()
+                    .await
+                    .map_err(|e| format!("{e:?}"))?;
+
+                if response.status() == StatusCode::SERVICE_UNAVAILABLE {
+                    // The body of a 503 is the number of seconds to wait before retrying.
+                    return Err(format!(
+                        "HTTP seed busy, retry in {} seconds",
+                        response.text().await.unwrap_or_default().trim(),
+                    )
+                    .into());
+                } else if !response.status().is_success() {
+                    return Err(format!("HTTP seed responded with {}", response.status()).into());
+                }
+
+                response
+                    .bytes()
+                    .await
+                    .map_err(|e| format!("{e:?}"))?
+                    .to_vec()
+            }
+        };
+
+        if data.len() as u64 != range.end - range.start {
+            return Err(format!(
+                "Web seed returned {} bytes, expected {}",
+                data.len(),
+                range.end - range.start,
+            )
+            .into());
+        }
+
+        Ok(data)
+    }
+}
+
+impl Fallback {
+    pub fn new(stall_after: Duration, now: Instant) -> Self {
+        Self {
+            stall_after,
+            last_progress: now,
+            active: false,
+        }
+    }
+
+    /// Record that a block was received from a peer.
+    pub fn record_progress(&mut self, now: Instant) {
+        self.last_progress = now;
+    }
+
+    /// Returns true exactly once, when the swarm is first found to be stalled.
+    pub fn should_activate(&mut self, now: Instant) -> bool {
+        if !self.active && now.duration_since(self.last_progress) >= self.stall_after {
+            self.active = true;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Fetch each of `indexes` in turn, moving on to the next seed whenever one fails, and report
+/// each piece back to the session.
+pub async fn fetch_pieces(
+    sender: mpsc::Sender<super::Incoming>,
+    client: reqwest::Client,
+    metainfo: common::metainfo::MetainfoFile,
+    seeds: Vec<WebSeed>,
+    indexes: Vec<u32>,
+) {
+    let mut seed_index = 0;
+
+    for index in indexes {
+        let mut result = Err("No web seeds available".into());
+
+        for attempt in 0..seeds.len() {
+            let seed = &seeds[(seed_index + attempt) % seeds.len()];
+
+            match seed.fetch_piece(&client, &metainfo, index).await {
+                Ok(data) => {
+                    result = Ok(data);
+                    seed_index = (seed_index + attempt) % seeds.len();
+                    break;
+                }
+                Err(e) => {
+                    say!("Web seed {} failed for piece {}: {}", seed.url(), index, e);
+                    result = Err(e);
+                }
+            }
+        }
+
+        let incoming = Incoming {
+            info_hash: *metainfo.info_hash(),
+            index,
+            result,
+        };
+
+        if sender.send(incoming.into()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Fetch bytes `start..end` of the file at `url`.
+async fn fetch_range(
+    client: &reqwest::Client,
+    url: &str,
+    start: u64,
+    end: u64,
+) -> Result<Vec<u8>, common::Error> {
+    let response = client
+        .get(url)
+        .header(header::RANGE, format!("bytes={}-{}", start, end - 1))
+        .send()
+        .await
+        .map_err(|e| format!("{e:?}"))?;
+
+    match response.status() {
+        StatusCode::PARTIAL_CONTENT => Ok(response
+            .bytes()
+            .await
+            .map_err(|e| format!("{e:?}"))?
+            .to_vec()),
+
+        // Servers that ignore the range header send the whole file.
+        StatusCode::OK => response
+            .bytes()
+            .await
+            .map_err(|e| format!("{e:?}"))?
+            .get(start as usize..end as usize)
+            .map(|slice| slice.to_vec())
+            .ok_or_else(|| "Web seed file is shorter than expected".into()),
+
+        status => Err(format!("Web seed responded with {status}").into()),
+    }
+}
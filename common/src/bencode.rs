@@ -7,7 +7,11 @@ use crate::Error;
 
 use nom::bytes::complete as bytes;
 use nom::IResult;
-use nom::{branch, character, combinator, multi, sequence};
+use nom::{branch, character, combinator, error, multi, sequence};
+
+/// How deeply lists and dicts may be nested, which is far deeper than any real document goes but
+/// keeps the parser well clear of overflowing the stack.
+const MAX_DEPTH: usize = 64;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum BencodeValue<'a> {
@@ -24,12 +28,19 @@ impl<'a> BencodeValue<'a> {
             .map_err(|e| format!("{}", e).into())
     }
 
+    /// Decode the value at the start of `input`, returning it with the bytes that follow it.
+    pub fn decode_prefix(input: &'a [u8]) -> Result<(Self, &'a [u8]), Error> {
+        parse_once(input)
+            .map(|(rest, v)| (v, rest))
+            .map_err(|e| format!("{}", e).into())
+    }
+
     pub fn encode(&self) -> Vec<u8> {
         match self {
             BencodeValue::Bytes(b) => iter::empty()
                 .chain(b.len().to_string().as_bytes())
                 .chain(b":")
-                .chain(b.into_iter())
+                .chain(&**b)
                 .copied()
                 .collect(),
             BencodeValue::Integer(i) => iter::empty()
@@ -39,22 +50,22 @@ impl<'a> BencodeValue<'a> {
                 .copied()
                 .collect(),
             BencodeValue::List(l) => iter::empty()
-                .chain(b"l".into_iter().copied())
-                .chain(l.into_iter().flat_map(|v| v.encode().into_iter()))
-                .chain(b"e".into_iter().copied())
+                .chain(b"l".iter().copied())
+                .chain(l.iter().flat_map(|v| v.encode().into_iter()))
+                .chain(b"e".iter().copied())
                 .collect(),
             BencodeValue::Dict(d) => {
                 let mut key_values: Vec<(&Cow<'_, [u8]>, &BencodeValue<'_>)> = d.iter().collect();
-                key_values.sort_by(|(a, _), (b, _)| a.cmp(b));
+                key_values.sort_by_key(|(a, _)| *a);
 
                 iter::empty()
-                    .chain(b"d".into_iter().copied())
+                    .chain(b"d".iter().copied())
                     .chain(key_values.into_iter().flat_map(|(k, v)| {
                         iter::empty()
-                            .chain(BencodeValue::Bytes(k.clone()).encode().into_iter())
-                            .chain(v.encode().into_iter())
+                            .chain(BencodeValue::Bytes(k.clone()).encode())
+                            .chain(v.encode())
                     }))
-                    .chain(b"e".into_iter().copied())
+                    .chain(b"e".iter().copied())
                     .collect()
             }
         }
@@ -104,14 +115,14 @@ impl<'a> BencodeValue<'a> {
     pub fn to_time(self) -> Option<SystemTime> {
         self.to_i128().and_then(|i| {
             if i.is_negative() {
-                (i * -1)
-                    .try_into()
-                    .map(|u| SystemTime::UNIX_EPOCH - Duration::from_secs(u))
+                (-i).try_into()
+                    .ok()
+                    .and_then(|u| SystemTime::UNIX_EPOCH.checked_sub(Duration::from_secs(u)))
             } else {
                 i.try_into()
-                    .map(|u| SystemTime::UNIX_EPOCH + Duration::from_secs(u))
+                    .ok()
+                    .and_then(|u| SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(u)))
             }
-            .ok()
         })
     }
 }
@@ -146,7 +157,7 @@ impl From<&SystemTime> for BencodeValue<'_> {
             input
                 .duration_since(SystemTime::UNIX_EPOCH)
                 .map(|u| u.as_secs().into())
-                .unwrap_or_else(|e| i128::from(e.duration().as_secs()) * -1),
+                .unwrap_or_else(|e| -i128::from(e.duration().as_secs())),
         )
     }
 }
@@ -186,15 +197,27 @@ impl<'a> iter::FromIterator<BencodeValue<'a>> for BencodeValue<'a> {
 }
 
 fn parse_once<'a>(b: &'a [u8]) -> IResult<&'a [u8], BencodeValue<'a>> {
+    parse_nested(b, 0)
+}
+
+/// Parse a value inside `depth` lists and dicts.
+fn parse_nested<'a>(b: &'a [u8], depth: usize) -> IResult<&'a [u8], BencodeValue<'a>> {
+    if depth > MAX_DEPTH {
+        return Err(nom::Err::Failure(error::Error::new(
+            b,
+            error::ErrorKind::TooLarge,
+        )));
+    }
+
     branch::alt((
         combinator::map(parse_bytes, |b| BencodeValue::Bytes(b.into())),
-        combinator::map(parse_integer, |i| BencodeValue::Integer(i)),
-        combinator::map(parse_list, |l| BencodeValue::List(l)),
-        combinator::map(parse_dict, |d| BencodeValue::Dict(d)),
+        combinator::map(parse_integer, BencodeValue::Integer),
+        combinator::map(|b| parse_list_nested(b, depth), BencodeValue::List),
+        combinator::map(|b| parse_dict_nested(b, depth), BencodeValue::Dict),
     ))(b)
 }
 
-fn parse_bytes<'a>(b: &'a [u8]) -> IResult<&'a [u8], &'a [u8]> {
+fn parse_bytes(b: &[u8]) -> IResult<&[u8], &[u8]> {
     combinator::complete(multi::length_data(sequence::delimited(
         combinator::peek(combinator::not(sequence::pair(
             bytes::tag("0"),
@@ -205,7 +228,7 @@ fn parse_bytes<'a>(b: &'a [u8]) -> IResult<&'a [u8], &'a [u8]> {
     )))(b)
 }
 
-fn parse_integer<'a>(b: &'a [u8]) -> IResult<&'a [u8], i128> {
+fn parse_integer(b: &[u8]) -> IResult<&[u8], i128> {
     branch::alt((
         combinator::map(bytes::tag("i0e"), |_| 0),
         sequence::delimited(
@@ -219,14 +242,14 @@ fn parse_integer<'a>(b: &'a [u8]) -> IResult<&'a [u8], i128> {
                             character::complete::u128,
                         ),
                     ),
-                    |u| i128::try_from(u).map(|i| i * -1),
+                    |u| i128::try_from(u).map(|i| -i),
                 ),
                 combinator::map_res(
                     sequence::preceded(
                         combinator::peek(character::complete::one_of("123456789")),
                         character::complete::u128,
                     ),
-                    |u| i128::try_from(u),
+                    i128::try_from,
                 ),
             ))),
             combinator::cut(bytes::tag("e")),
@@ -234,22 +257,38 @@ fn parse_integer<'a>(b: &'a [u8]) -> IResult<&'a [u8], i128> {
     ))(b)
 }
 
+#[cfg(test)]
 fn parse_list<'a>(b: &'a [u8]) -> IResult<&'a [u8], Vec<BencodeValue<'a>>> {
+    parse_list_nested(b, 0)
+}
+
+fn parse_list_nested<'a>(b: &'a [u8], depth: usize) -> IResult<&'a [u8], Vec<BencodeValue<'a>>> {
     combinator::map(
         sequence::preceded(
             bytes::tag("l"),
-            combinator::cut(multi::many_till(parse_once, bytes::tag("e"))),
+            combinator::cut(multi::many_till(
+                |b| parse_nested(b, depth + 1),
+                bytes::tag("e"),
+            )),
         ),
         |(l, _)| l,
     )(b)
 }
 
+#[cfg(test)]
 fn parse_dict<'a>(b: &'a [u8]) -> IResult<&'a [u8], HashMap<Cow<'a, [u8]>, BencodeValue<'a>>> {
+    parse_dict_nested(b, 0)
+}
+
+fn parse_dict_nested<'a>(
+    b: &'a [u8],
+    depth: usize,
+) -> IResult<&'a [u8], HashMap<Cow<'a, [u8]>, BencodeValue<'a>>> {
     combinator::map(
         sequence::preceded(
             bytes::tag("d"),
             combinator::cut(multi::many_till(
-                sequence::pair(parse_bytes, parse_once),
+                sequence::pair(parse_bytes, |b| parse_nested(b, depth + 1)),
                 bytes::tag("e"),
             )),
         ),
@@ -619,6 +658,34 @@ mod test {
             Err("Parsing Error: Error { input: [101], code: Eof }".into()),
             BencodeValue::decode(&b"0:e"[..]),
         );
+
+        let nested = |depth| [&b"l"[..].repeat(depth)[..], &b"e"[..].repeat(depth)[..]].concat();
+        assert!(BencodeValue::decode(&nested(MAX_DEPTH + 1)).is_ok());
+        assert!(BencodeValue::decode(&nested(MAX_DEPTH + 2)).is_err());
+        assert!(BencodeValue::decode(&nested(100_000)).is_err());
+    }
+
+    #[test]
+    fn decode_prefix_test() {
+        assert_eq!(
+            Ok((BencodeValue::Integer(1), &b"rest"[..])),
+            BencodeValue::decode_prefix(&b"i1erest"[..]),
+        );
+        assert_eq!(
+            Ok((BencodeValue::Dict(HashMap::new()), &b""[..])),
+            BencodeValue::decode_prefix(&b"de"[..]),
+        );
+        assert!(BencodeValue::decode_prefix(&b"i1"[..]).is_err());
+    }
+
+    #[test]
+    fn to_time_test() {
+        assert_eq!(
+            Some(SystemTime::UNIX_EPOCH),
+            BencodeValue::Integer(0).to_time()
+        );
+        assert_eq!(None, BencodeValue::Integer(i128::from(u64::MAX)).to_time());
+        assert_eq!(None, BencodeValue::Integer(-i128::from(u64::MAX)).to_time());
     }
 
     #[test]
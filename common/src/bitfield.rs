@@ -0,0 +1,415 @@
+//! Sets of pieces, such as the pieces that we or a peer have.
+//!
+//! Pieces are grouped into containers of 65536. A container that is empty or full takes no space
+//! at all, and any other is a plain bitmap with a cached count. Seeds and new peers, which are the
+//! common case, thus cost next to nothing even in torrents with hundreds of thousands of pieces,
+//! and the counts keep rank and select quick.
+
+use std::iter;
+
+const CONTAINER_BITS: u32 = 1 << 16;
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Bitfield {
+    piece_count: u32,
+    containers: Vec<Container>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Container {
+    Empty,
+    Full,
+    Bitmap { count: u32, words: Box<[u64]> },
+}
+
+impl Bitfield {
+    /// A set of `piece_count` pieces, none of them present.
+    pub fn new(piece_count: u32) -> Self {
+        Self {
+            piece_count,
+            containers: vec![Container::Empty; piece_count.div_ceil(CONTAINER_BITS) as usize],
+        }
+    }
+
+    /// A set of `piece_count` pieces, all of them present.
+    pub fn full(piece_count: u32) -> Self {
+        Self {
+            piece_count,
+            containers: vec![Container::Full; piece_count.div_ceil(CONTAINER_BITS) as usize],
+        }
+    }
+
+    /// Unpack a bitfield in the wire format, where the high bit of the first byte is piece 0.
+    /// Bits past `piece_count` are ignored, and missing bytes count as zeroes.
+    pub fn from_bytes(bytes: &[u8], piece_count: u32) -> Self {
+        let mut bitfield = Self::new(piece_count);
+
+        for (i, container) in bitfield.containers.iter_mut().enumerate() {
+            let base = i as u32 * CONTAINER_BITS;
+            let capacity = CONTAINER_BITS.min(piece_count - base);
+            let mut words = vec![0u64; capacity.div_ceil(64) as usize];
+            let mut count = 0;
+
+            for bit in 0..capacity {
+                let index = (base + bit) as usize;
+
+                if bytes
+                    .get(index / 8)
+                    .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+                {
+                    words[bit as usize / 64] |= 1 << (bit % 64);
+                    count += 1;
+                }
+            }
+
+            *container = Container::from_bitmap(count, words.into(), capacity);
+        }
+
+        bitfield
+    }
+
+    /// Whether `bytes` is a well-formed bitfield for `piece_count` pieces in the wire format: the
+    /// right length, with the spare bits at the end clear.
+    pub fn is_valid_bytes(bytes: &[u8], piece_count: u32) -> bool {
+        if bytes.len() != piece_count.div_ceil(8) as usize {
+            return false;
+        }
+
+        match (bytes.last(), piece_count % 8) {
+            (Some(last), spare @ 1..) => last & (0xff >> spare) == 0,
+            _ => true,
+        }
+    }
+
+    /// Pack the set into the wire format, the high bit of the first byte being piece 0.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0; self.piece_count.div_ceil(8) as usize];
+
+        for index in self.iter() {
+            bytes[index as usize / 8] |= 0x80 >> (index % 8);
+        }
+
+        bytes
+    }
+
+    /// The number of pieces in the torrent, whether present or not.
+    pub fn piece_count(&self) -> u32 {
+        self.piece_count
+    }
+
+    /// The number of pieces present.
+    pub fn count(&self) -> u32 {
+        self.containers
+            .iter()
+            .enumerate()
+            .map(|(i, container)| container.count(self.capacity(i)))
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.containers
+            .iter()
+            .all(|container| *container == Container::Empty)
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.containers
+            .iter()
+            .all(|container| *container == Container::Full)
+    }
+
+    pub fn contains(&self, index: u32) -> bool {
+        index < self.piece_count
+            && self.containers[(index / CONTAINER_BITS) as usize].contains(index % CONTAINER_BITS)
+    }
+
+    /// Add a piece, returning whether it was newly added. Pieces past the end are ignored.
+    pub fn insert(&mut self, index: u32) -> bool {
+        if index >= self.piece_count || self.contains(index) {
+            return false;
+        }
+
+        let i = (index / CONTAINER_BITS) as usize;
+        let capacity = self.capacity(i);
+        self.containers[i].insert(index % CONTAINER_BITS, capacity);
+        true
+    }
+
+    /// Remove a piece, returning whether it was present.
+    pub fn remove(&mut self, index: u32) -> bool {
+        if !self.contains(index) {
+            return false;
+        }
+
+        let i = (index / CONTAINER_BITS) as usize;
+        let capacity = self.capacity(i);
+        self.containers[i].remove(index % CONTAINER_BITS, capacity);
+        true
+    }
+
+    /// The number of pieces present below `index`.
+    pub fn rank(&self, index: u32) -> u32 {
+        let index = index.min(self.piece_count);
+        let i = (index / CONTAINER_BITS) as usize;
+
+        let before: u32 = self.containers[..i]
+            .iter()
+            .enumerate()
+            .map(|(i, container)| container.count(self.capacity(i)))
+            .sum();
+
+        before
+            + self
+                .containers
+                .get(i)
+                .map_or(0, |container| container.rank(index % CONTAINER_BITS))
+    }
+
+    /// The index of the `n`th piece present, counting from zero.
+    pub fn select(&self, mut n: u32) -> Option<u32> {
+        for (i, container) in self.containers.iter().enumerate() {
+            let count = container.count(self.capacity(i));
+
+            if n < count {
+                return Some(i as u32 * CONTAINER_BITS + container.select(n));
+            }
+
+            n -= count;
+        }
+
+        None
+    }
+
+    /// The pieces present, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        self.containers
+            .iter()
+            .enumerate()
+            .flat_map(move |(i, container)| {
+                let base = i as u32 * CONTAINER_BITS;
+
+                let (full, words): (u32, &[u64]) = match container {
+                    Container::Empty => (0, &[]),
+                    Container::Full => (self.capacity(i), &[]),
+                    Container::Bitmap { words, .. } => (0, &words[..]),
+                };
+
+                (base..base + full).chain(words.iter().enumerate().flat_map(move |(w, &word)| {
+                    bits(word).map(move |bit| base + w as u32 * 64 + bit)
+                }))
+            })
+    }
+
+    /// The number of pieces in the `i`th container, which is less than [`CONTAINER_BITS`] only
+    /// for the last one.
+    fn capacity(&self, i: usize) -> u32 {
+        CONTAINER_BITS.min(self.piece_count - i as u32 * CONTAINER_BITS)
+    }
+}
+
+impl From<&[bool]> for Bitfield {
+    fn from(input: &[bool]) -> Self {
+        let mut bitfield = Self::new(input.len() as u32);
+
+        for (index, &have) in input.iter().enumerate() {
+            if have {
+                bitfield.insert(index as u32);
+            }
+        }
+
+        bitfield
+    }
+}
+
+impl Container {
+    fn from_bitmap(count: u32, words: Box<[u64]>, capacity: u32) -> Self {
+        if count == 0 {
+            Self::Empty
+        } else if count == capacity {
+            Self::Full
+        } else {
+            Self::Bitmap { count, words }
+        }
+    }
+
+    fn count(&self, capacity: u32) -> u32 {
+        match self {
+            Self::Empty => 0,
+            Self::Full => capacity,
+            Self::Bitmap { count, .. } => *count,
+        }
+    }
+
+    fn contains(&self, bit: u32) -> bool {
+        match self {
+            Self::Empty => false,
+            Self::Full => true,
+            Self::Bitmap { words, .. } => words[bit as usize / 64] & (1 << (bit % 64)) != 0,
+        }
+    }
+
+    /// Set a bit that isn't set yet.
+    fn insert(&mut self, bit: u32, capacity: u32) {
+        let (mut count, mut words) = self.take_bitmap(capacity);
+        words[bit as usize / 64] |= 1 << (bit % 64);
+        count += 1;
+        *self = Self::from_bitmap(count, words, capacity);
+    }
+
+    /// Clear a bit that is set.
+    fn remove(&mut self, bit: u32, capacity: u32) {
+        let (mut count, mut words) = self.take_bitmap(capacity);
+        words[bit as usize / 64] &= !(1 << (bit % 64));
+        count -= 1;
+        *self = Self::from_bitmap(count, words, capacity);
+    }
+
+    fn take_bitmap(&mut self, capacity: u32) -> (u32, Box<[u64]>) {
+        let word_count = capacity.div_ceil(64);
+
+        match std::mem::replace(self, Self::Empty) {
+            Self::Empty => (0, vec![0; word_count as usize].into()),
+            Self::Full => (
+                capacity,
+                (0..word_count)
+                    .map(|w| match capacity - w * 64 {
+                        bits @ 0..=63 => (1 << bits) - 1,
+                        _ => u64::MAX,
+                    })
+                    .collect(),
+            ),
+            Self::Bitmap { count, words } => (count, words),
+        }
+    }
+
+    fn rank(&self, bit: u32) -> u32 {
+        match self {
+            Self::Empty => 0,
+            Self::Full => bit,
+            Self::Bitmap { words, .. } => {
+                let whole: u32 = words[..bit as usize / 64]
+                    .iter()
+                    .map(|word| word.count_ones())
+                    .sum();
+                let partial = words
+                    .get(bit as usize / 64)
+                    .map_or(0, |word| (word & ((1 << (bit % 64)) - 1)).count_ones());
+
+                whole + partial
+            }
+        }
+    }
+
+    /// The `n`th set bit. `n` must be less than the count.
+    fn select(&self, mut n: u32) -> u32 {
+        let Self::Bitmap { words, .. } = self else {
+            return n;
+        };
+
+        for (w, &word) in words.iter().enumerate() {
+            let count = word.count_ones();
+
+            if n < count {
+                return w as u32 * 64 + bits(word).nth(n as usize).unwrap();
+            }
+
+            n -= count;
+        }
+
+        unreachable!("select past the end of a container");
+    }
+}
+
+/// The set bits of a word, lowest first.
+fn bits(mut word: u64) -> impl Iterator<Item = u32> {
+    iter::from_fn(move || {
+        if word == 0 {
+            None
+        } else {
+            let bit = word.trailing_zeros();
+            word &= word - 1;
+            Some(bit)
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bytes_test() {
+        assert_eq!(Vec::<u8>::new(), Bitfield::new(0).to_bytes());
+
+        let bitfield = Bitfield::from(
+            &[
+                true, false, false, false, false, false, false, true, false, true,
+            ][..],
+        );
+        assert_eq!(vec![0b1000_0001, 0b0100_0000], bitfield.to_bytes());
+        assert_eq!(
+            bitfield,
+            Bitfield::from_bytes(&[0b1000_0001, 0b0111_1111], 10)
+        );
+
+        assert!(Bitfield::is_valid_bytes(&[0b1000_0001, 0b0100_0000], 10));
+        assert!(Bitfield::is_valid_bytes(&[0xff], 8));
+        assert!(Bitfield::is_valid_bytes(&[], 0));
+        assert!(!Bitfield::is_valid_bytes(&[0b1000_0001, 0b0110_0000], 10));
+        assert!(!Bitfield::is_valid_bytes(&[0b1000_0001], 10));
+        assert!(!Bitfield::is_valid_bytes(&[0, 0, 0], 10));
+    }
+
+    #[test]
+    fn set_test() {
+        let piece_count = CONTAINER_BITS * 2 + 100;
+        let mut bitfield = Bitfield::new(piece_count);
+        assert!(bitfield.is_empty());
+
+        assert!(bitfield.insert(3));
+        assert!(!bitfield.insert(3));
+        assert!(!bitfield.insert(piece_count));
+        assert!(bitfield.insert(CONTAINER_BITS + 5));
+        assert!(bitfield.insert(piece_count - 1));
+
+        assert_eq!(3, bitfield.count());
+        assert_eq!(
+            vec![3, CONTAINER_BITS + 5, piece_count - 1],
+            bitfield.iter().collect::<Vec<_>>(),
+        );
+        assert_eq!(1, bitfield.rank(CONTAINER_BITS + 5));
+        assert_eq!(2, bitfield.rank(CONTAINER_BITS + 6));
+        assert_eq!(3, bitfield.rank(piece_count));
+        assert_eq!(Some(CONTAINER_BITS + 5), bitfield.select(1));
+        assert_eq!(None, bitfield.select(3));
+
+        assert!(bitfield.remove(3));
+        assert!(!bitfield.contains(3));
+        assert_eq!(
+            Bitfield::from_bytes(&bitfield.to_bytes(), piece_count),
+            bitfield
+        );
+    }
+
+    #[test]
+    fn full_test() {
+        let piece_count = CONTAINER_BITS + 70;
+        let mut bitfield = Bitfield::full(piece_count);
+        assert!(bitfield.is_full());
+        assert_eq!(piece_count, bitfield.count());
+        assert_eq!(Some(CONTAINER_BITS + 69), bitfield.select(piece_count - 1));
+
+        assert!(bitfield.remove(CONTAINER_BITS + 1));
+        assert!(!bitfield.is_full());
+        assert_eq!(piece_count - 1, bitfield.count());
+        assert_eq!(CONTAINER_BITS + 1, bitfield.rank(CONTAINER_BITS + 2));
+        assert_eq!(
+            Some(CONTAINER_BITS + 2),
+            bitfield.select(CONTAINER_BITS + 1)
+        );
+
+        // Filling a container back up collapses it again.
+        assert!(bitfield.insert(CONTAINER_BITS + 1));
+        assert_eq!(Bitfield::full(piece_count), bitfield);
+    }
+}
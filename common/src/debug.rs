@@ -1,6 +1,28 @@
+//! Hex dumps of the bytes going through a reader or writer, printed to standard error. There are
+//! wrappers for both blocking and async I/O.
+
 use std::io;
 use std::io::prelude::*;
 use std::io::BufReader;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// How much of the data to dump.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum DebugVerbosity {
+    /// Only the length of each read and write.
+    Lengths,
+
+    /// The first 32 bytes of each read and write.
+    #[default]
+    Head,
+
+    /// Every byte.
+    Full,
+}
 
 pub struct DebugBufReader<R: ?Sized> {
     prefix: String,
@@ -12,6 +34,31 @@ pub struct DebugWriter<W: ?Sized> {
     inner: W,
 }
 
+pub struct AsyncDebugReader<R> {
+    prefix: String,
+    verbosity: DebugVerbosity,
+    inner: R,
+}
+
+pub struct AsyncDebugWriter<W> {
+    prefix: String,
+    verbosity: DebugVerbosity,
+    inner: W,
+}
+
+impl FromStr for DebugVerbosity {
+    type Err = &'static str;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "lengths" => Ok(Self::Lengths),
+            "head" => Ok(Self::Head),
+            "full" => Ok(Self::Full),
+            _ => Err("Expected `lengths`, `head` or `full`"),
+        }
+    }
+}
+
 impl<R: Read> DebugBufReader<R> {
     pub fn new(prefix: String, inner: R) -> Self {
         Self {
@@ -27,12 +74,32 @@ impl<W: Write> DebugWriter<W> {
     }
 }
 
+impl<R> AsyncDebugReader<R> {
+    pub fn new(prefix: String, verbosity: DebugVerbosity, inner: R) -> Self {
+        Self {
+            prefix,
+            verbosity,
+            inner,
+        }
+    }
+}
+
+impl<W> AsyncDebugWriter<W> {
+    pub fn new(prefix: String, verbosity: DebugVerbosity, inner: W) -> Self {
+        Self {
+            prefix,
+            verbosity,
+            inner,
+        }
+    }
+}
+
 impl<R: ?Sized + Read> Read for DebugBufReader<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let result = self.inner.read(buf);
         match result {
             Ok(len) => {
-                print_debug(&self.prefix, "<-", &buf[0..len]);
+                print_debug(&self.prefix, "<-", &buf[0..len], DebugVerbosity::Head);
                 Ok(len)
             }
             Err(e) => {
@@ -46,7 +113,7 @@ impl<R: ?Sized + Read> Read for DebugBufReader<R> {
         let result = self.inner.read_exact(buf);
         match result {
             Ok(()) => {
-                print_debug(&self.prefix, "<-", &buf);
+                print_debug(&self.prefix, "<-", buf, DebugVerbosity::Head);
                 Ok(())
             }
             Err(e) => {
@@ -72,7 +139,7 @@ impl<W: ?Sized + Write> Write for DebugWriter<W> {
         let result = self.inner.write(buf);
         match result {
             Ok(len) => {
-                print_debug(&self.prefix, "->", &buf);
+                print_debug(&self.prefix, "->", buf, DebugVerbosity::Head);
                 Ok(len)
             }
             Err(e) => {
@@ -87,7 +154,54 @@ impl<W: ?Sized + Write> Write for DebugWriter<W> {
     }
 }
 
-fn print_debug(prefix: &str, symbol: &str, data: &[u8]) {
+impl<R: AsyncRead + Unpin> AsyncRead for AsyncDebugReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let start = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+
+        match &result {
+            Poll::Ready(Ok(())) => {
+                print_debug(&self.prefix, "<-", &buf.filled()[start..], self.verbosity)
+            }
+            Poll::Ready(Err(e)) => print_error(&self.prefix, "<!", e),
+            Poll::Pending => {}
+        }
+
+        result
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for AsyncDebugWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+
+        match &result {
+            Poll::Ready(Ok(len)) => print_debug(&self.prefix, "->", &buf[..*len], self.verbosity),
+            Poll::Ready(Err(e)) => print_error(&self.prefix, "!>", e),
+            Poll::Pending => {}
+        }
+
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+fn print_debug(prefix: &str, symbol: &str, data: &[u8], verbosity: DebugVerbosity) {
     let print_debug_line = |prefix: &str, symbol: &str, data: &[u8]| {
         let mut output = String::with_capacity(82);
 
@@ -111,19 +225,23 @@ fn print_debug(prefix: &str, symbol: &str, data: &[u8]) {
             }
         });
 
-        println!("{output}");
+        eprintln!("{output}");
     };
 
-    match data.len() {
-        0..=16 => print_debug_line(prefix, symbol, data),
-        17..=32 => {
+    match (verbosity, data.len()) {
+        (DebugVerbosity::Lengths, len) => eprintln!("{prefix:21} {symbol} {len} bytes"),
+        (DebugVerbosity::Full, _) => data
+            .chunks(16)
+            .for_each(|chunk| print_debug_line(prefix, symbol, chunk)),
+        (DebugVerbosity::Head, 0..=16) => print_debug_line(prefix, symbol, data),
+        (DebugVerbosity::Head, 17..=32) => {
             print_debug_line(prefix, symbol, &data[0..16]);
             print_debug_line(prefix, symbol, &data[16..]);
         }
-        33.. => {
+        (DebugVerbosity::Head, 33..) => {
             print_debug_line(prefix, symbol, &data[0..16]);
             print_debug_line(prefix, symbol, &data[16..32]);
-            println!(
+            eprintln!(
                 "{prefix:21} {symbol} ..... {} bytes total .....",
                 data.len()
             );
@@ -132,5 +250,5 @@ fn print_debug(prefix: &str, symbol: &str, data: &[u8]) {
 }
 
 fn print_error(prefix: &str, symbol: &str, error: &io::Error) {
-    println!("{prefix:21} {symbol} {error:?}");
+    eprintln!("{prefix:21} {symbol} {error:?}");
 }
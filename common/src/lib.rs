@@ -3,17 +3,25 @@ pub mod peer;
 pub mod tracker;
 
 pub use bencode::BencodeValue;
+pub use bitfield::Bitfield;
+pub use clock::{Clock, ManualClock, SystemClock};
 pub use debug::DebugBufReader;
 pub use debug::DebugWriter;
+pub use debug::{AsyncDebugReader, AsyncDebugWriter, DebugVerbosity};
+pub use magnet::MagnetUri;
 
 pub type Error = Cow<'static, str>;
 
 mod bencode;
+mod bitfield;
+mod clock;
 mod debug;
+mod magnet;
 
 use std::borrow::Cow;
 use std::fmt;
 use std::iter;
+use std::ops;
 use std::str::FromStr;
 
 use rand::prelude::*;
@@ -27,6 +35,8 @@ pub struct PeerId([u8; 20]);
 #[derive(Clone, Eq, Hash, PartialEq)]
 pub struct PeerKey(Vec<u8>);
 
+/// A quantity of data. Displayed and parsed with binary (1024-based) units.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct Bytes(u64);
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -36,38 +46,145 @@ impl InfoHash {
     pub fn as_slice(&self) -> &[u8] {
         &self.0[..]
     }
+
+    /// Parse an info hash written as 40 hex digits, as it's displayed.
+    pub fn from_hex(input: &str) -> Option<Self> {
+        if input.len() != 40 || !input.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+
+        let mut bytes = [0; 20];
+
+        for (byte, hex) in bytes.iter_mut().zip(input.as_bytes().chunks(2)) {
+            *byte = u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?;
+        }
+
+        Some(Self(bytes))
+    }
 }
 
 impl PeerId {
-    pub fn create(client_id: &str, version: &str) -> PeerId {
+    /// Generate an Azureus-style peer ID: `-CCVVVV-` followed by random characters, where `CC`
+    /// identifies the client and `VVVV` its version.
+    pub fn create(client_id: &str, version: &str) -> Result<PeerId, &'static str> {
+        Self::check_client_id(client_id)?;
+        Self::check_client_version(version)?;
+
+        Self::with_prefix(&format!("-{client_id}{version}-"))
+    }
+
+    /// Check that `client_id` can identify the client in an Azureus-style peer ID.
+    pub fn check_client_id(client_id: &str) -> Result<(), &'static str> {
+        if client_id.len() != 2 || !client_id.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            return Err("Client ID must be two alphanumeric characters");
+        }
+
+        Ok(())
+    }
+
+    /// Check that `version` can be the client version in an Azureus-style peer ID.
+    pub fn check_client_version(version: &str) -> Result<(), &'static str> {
+        if version.len() != 4 || !version.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            return Err("Client version must be four alphanumeric characters");
+        }
+
+        Ok(())
+    }
+
+    /// Generate a peer ID starting with `prefix`, padded out to 20 bytes with random alphanumeric
+    /// characters so that it needs no escaping in URLs.
+    pub fn with_prefix(prefix: &str) -> Result<PeerId, &'static str> {
+        if prefix.len() > 20 {
+            return Err("Peer ID prefix must be at most 20 bytes long");
+        }
+
+        if !prefix.bytes().all(|b| b.is_ascii_graphic()) {
+            return Err("Peer ID prefix must consist of printable ASCII characters");
+        }
+
         let mut bytes = [0u8; 20];
         let mut rng = rand::thread_rng();
 
-        iter::empty()
-            .chain(iter::once('-' as u8))
-            .chain(client_id.bytes())
-            .chain(version.bytes())
-            .chain(iter::once('-' as u8))
-            .chain(iter::from_fn(|| rng.gen()))
-            .enumerate()
-            .take(20)
-            .for_each(|(i, b)| bytes[i] = b);
+        prefix
+            .bytes()
+            .chain(iter::repeat_with(|| {
+                rng.sample(rand::distributions::Alphanumeric)
+            }))
+            .zip(bytes.iter_mut())
+            .for_each(|(b, byte)| *byte = b);
 
-        PeerId(bytes)
+        Ok(PeerId(bytes))
     }
 
     pub fn as_slice(&self) -> &[u8] {
         &self.0[..]
     }
+
+    /// The name and version of the client that generated this ID, if it follows the common
+    /// "-XX1234-" convention. Unknown client codes are returned as-is.
+    pub fn client(&self) -> Option<String> {
+        let [b'-', a, b, version @ .., b'-'] = &self.0[0..8] else {
+            return None;
+        };
+
+        if !version.iter().all(u8::is_ascii_alphanumeric) {
+            return None;
+        }
+
+        let code = [*a, *b];
+        let name = match &code {
+            b"AZ" => "Vuze",
+            b"BI" => "BiglyBT",
+            b"BT" => "BitTorrent",
+            b"DE" => "Deluge",
+            b"KT" => "KTorrent",
+            b"LT" => "libtorrent",
+            b"lt" => "rTorrent",
+            b"qB" => "qBittorrent",
+            b"TR" => "Transmission",
+            b"tt" => "ToyTorrent",
+            b"UT" => "µTorrent",
+            b"WW" => "WebTorrent",
+            _ => std::str::from_utf8(&code).ok()?,
+        };
+
+        // Each character is one component of the version; trailing zeroes are dropped.
+        let mut components: Vec<char> = version.iter().map(|&b| b as char).collect();
+        while components.len() > 2 && components.last() == Some(&'0') {
+            components.pop();
+        }
+
+        let version = components
+            .iter()
+            .map(char::to_string)
+            .collect::<Vec<_>>()
+            .join(".");
+
+        Some(format!("{name} {version}"))
+    }
 }
 
 impl PeerKey {
+    /// Generate a random key of eight hex digits, the format most clients use.
+    pub fn generate() -> PeerKey {
+        PeerKey(format!("{:08X}", rand::thread_rng().gen::<u32>()).into_bytes())
+    }
+
     pub fn as_slice(&self) -> &[u8] {
         &self.0[..]
     }
 }
 
 impl BlockRef {
+    pub fn new(index: u32, begin: u32, length: u32) -> BlockRef {
+        let mut ref_bytes = [0u8; 12];
+        ref_bytes[0..4].copy_from_slice(&index.to_be_bytes());
+        ref_bytes[4..8].copy_from_slice(&begin.to_be_bytes());
+        ref_bytes[8..12].copy_from_slice(&length.to_be_bytes());
+
+        BlockRef(ref_bytes)
+    }
+
     pub fn to_be_bytes(self) -> [u8; 12] {
         self.0
     }
@@ -151,7 +268,7 @@ impl fmt::Debug for InfoHash {
 
 impl fmt::Display for InfoHash {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        self.0.iter().try_for_each(|u| write!(f, "{:x}", u))
+        self.0.iter().try_for_each(|u| write!(f, "{:02x}", u))
     }
 }
 
@@ -179,21 +296,141 @@ impl fmt::Display for PeerKey {
     }
 }
 
-fn parse_qs_to_bytes<const N: usize, T: From<[u8; N]>>(input: &str) -> Result<T, &'static str> {
-    let mut input_iter = input.chars();
-    let mut result_arr = [0u8; N];
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+        if self.0 < 1024 {
+            return f.pad(&format!("{} B", self.0));
+        }
+
+        let mut value = self.0 as f64 / 1024.0;
+        let mut unit = 0;
 
-    for i in 0.. {
-        let c = input_iter.next();
-        if i >= N {
-            if c.is_some() {
-                return Err("Too many characters");
+        while value >= 1024.0 && unit < UNITS.len() - 1 {
+            value /= 1024.0;
+            unit += 1;
+        }
+
+        f.pad(&format!("{:.1} {}", value, UNITS[unit]))
+    }
+}
+
+impl FromStr for Bytes {
+    type Err = &'static str;
+
+    /// Parse a number with an optional unit, such as "512M", "1.5 GiB" or "100kb". Units are
+    /// case-insensitive and always binary, so "1K" is 1024 bytes.
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let input = input.trim();
+        let split = input
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(input.len());
+        let (number, unit) = input.split_at(split);
+
+        let shift = match unit.trim().to_ascii_lowercase().as_str() {
+            "" | "b" => 0,
+            "k" | "kb" | "kib" => 10,
+            "m" | "mb" | "mib" => 20,
+            "g" | "gb" | "gib" => 30,
+            "t" | "tb" | "tib" => 40,
+            "p" | "pb" | "pib" => 50,
+            _ => return Err("Unknown unit; expected B, K, M, G, T or P"),
+        };
+
+        if let Ok(integer) = number.parse::<u64>() {
+            integer
+                .checked_mul(1 << shift)
+                .map(Bytes)
+                .ok_or("Number of bytes is too large")
+        } else {
+            let value = number
+                .parse::<f64>()
+                .map_err(|_| "Invalid number of bytes")?;
+            let bytes = value * (1u64 << shift) as f64;
+
+            if bytes.is_finite() && bytes < u64::MAX as f64 {
+                Ok(Bytes(bytes.round() as u64))
             } else {
-                break;
+                Err("Number of bytes is too large")
             }
         }
+    }
+}
+
+impl From<u64> for Bytes {
+    fn from(input: u64) -> Self {
+        Self(input)
+    }
+}
+
+impl From<Bytes> for u64 {
+    fn from(input: Bytes) -> Self {
+        input.0
+    }
+}
+
+impl ops::Add for Bytes {
+    type Output = Bytes;
+
+    fn add(self, other: Bytes) -> Bytes {
+        Bytes(self.0 + other.0)
+    }
+}
+
+impl ops::AddAssign for Bytes {
+    fn add_assign(&mut self, other: Bytes) {
+        self.0 += other.0;
+    }
+}
+
+impl ops::Sub for Bytes {
+    type Output = Bytes;
+
+    fn sub(self, other: Bytes) -> Bytes {
+        Bytes(self.0 - other.0)
+    }
+}
+
+impl ops::SubAssign for Bytes {
+    fn sub_assign(&mut self, other: Bytes) {
+        self.0 -= other.0;
+    }
+}
+
+impl ops::Mul<u64> for Bytes {
+    type Output = Bytes;
+
+    fn mul(self, other: u64) -> Bytes {
+        Bytes(self.0 * other)
+    }
+}
+
+impl ops::Div<u64> for Bytes {
+    type Output = Bytes;
 
-        match c {
+    fn div(self, other: u64) -> Bytes {
+        Bytes(self.0 / other)
+    }
+}
+
+impl iter::Sum for Bytes {
+    fn sum<I: Iterator<Item = Bytes>>(iter: I) -> Bytes {
+        iter.fold(Bytes(0), |total, bytes| total + bytes)
+    }
+}
+
+/// Percent-encode everything but ASCII alphanumerics, as trackers and web seeds expect.
+pub fn url_encode(slice: &[u8]) -> String {
+    tracker::Request::url_encode(slice)
+}
+
+fn parse_qs_to_bytes<const N: usize, T: From<[u8; N]>>(input: &str) -> Result<T, &'static str> {
+    let mut input_iter = input.chars();
+    let mut result_arr = [0u8; N];
+
+    for byte in result_arr.iter_mut() {
+        match input_iter.next() {
             Some('%') => {
                 let [Some(a), Some(b)] = [
                     input_iter.next().and_then(|c| c.to_digit(16)),
@@ -202,14 +439,18 @@ fn parse_qs_to_bytes<const N: usize, T: From<[u8; N]>>(input: &str) -> Result<T,
                     return Err("Expected % to be followed by two hex characters");
                 };
 
-                result_arr[i] = (a * 16 + b).try_into().unwrap();
+                *byte = (a * 16 + b).try_into().unwrap();
             }
-            Some(c) if c >= '\0' && c <= '\u{7f}' => result_arr[i] = c.try_into().unwrap(),
+            Some(c) if ('\0'..='\u{7f}').contains(&c) => *byte = c.try_into().unwrap(),
             Some(_) => return Err("Unexpected non-ASCII character"),
             None => return Err("Too few characters"),
         }
     }
 
+    if input_iter.next().is_some() {
+        return Err("Too many characters");
+    }
+
     Ok(result_arr.into())
 }
 
@@ -222,8 +463,71 @@ mod test {
     fn peerid_hash_test() {
         let mut set: HashSet<PeerId> = HashSet::new();
 
-        assert_eq!(true, set.insert([0; 20].into()));
-        assert_eq!(false, set.insert([0; 20].into()));
+        assert!(set.insert([0; 20].into()));
+        assert!(!set.insert([0; 20].into()));
         assert_eq!(1, set.len());
     }
+
+    #[test]
+    fn peerid_create_test() {
+        let peer_id = PeerId::create("tt", "0100").unwrap();
+
+        assert_eq!(b"-tt0100-", &peer_id.as_slice()[..8]);
+        assert!(peer_id.as_slice()[8..]
+            .iter()
+            .all(|b| b.is_ascii_alphanumeric()));
+
+        assert!(PeerId::create("t", "0100").is_err());
+        assert!(PeerId::create("tt", "01000").is_err());
+        assert!(PeerId::create("t-", "0100").is_err());
+
+        assert_eq!(
+            b"-XX0000-abc",
+            &PeerId::with_prefix("-XX0000-abc").unwrap().as_slice()[..11],
+        );
+        assert!(PeerId::with_prefix("-XX0000-abcdefghijklm").is_err());
+        assert!(PeerId::with_prefix("-XX 000-").is_err());
+    }
+
+    #[test]
+    fn bytes_display_test() {
+        assert_eq!("0 B", Bytes(0).to_string());
+        assert_eq!("1023 B", Bytes(1023).to_string());
+        assert_eq!("1.0 KiB", Bytes(1024).to_string());
+        assert_eq!("1.5 MiB", Bytes(3 << 19).to_string());
+        assert_eq!("  1.0 GiB", format!("{:>9}", Bytes(1 << 30)));
+    }
+
+    #[test]
+    fn bytes_from_str_test() {
+        assert_eq!(Ok(Bytes(512)), "512".parse());
+        assert_eq!(Ok(Bytes(512 << 20)), "512M".parse());
+        assert_eq!(Ok(Bytes(3 << 29)), "1.5 GiB".parse());
+        assert_eq!(Ok(Bytes(100 << 10)), "100kb".parse());
+        assert!("12 parsecs".parse::<Bytes>().is_err());
+        assert!("99999999P".parse::<Bytes>().is_err());
+        assert!("".parse::<Bytes>().is_err());
+    }
+
+    #[test]
+    fn peerid_client_test() {
+        assert_eq!(
+            Some("Transmission 4.0.5".to_string()),
+            PeerId::try_from("-TR4050-mtwvc5ch9psu".as_bytes())
+                .unwrap()
+                .client(),
+        );
+        assert_eq!(
+            Some("XX 1.2".to_string()),
+            PeerId::try_from("-XX1200-000000000000".as_bytes())
+                .unwrap()
+                .client(),
+        );
+        assert_eq!(
+            None,
+            PeerId::try_from("M7-2-0--000000000000".as_bytes())
+                .unwrap()
+                .client(),
+        );
+    }
 }
@@ -0,0 +1,199 @@
+//! Magnet links (BEP 9), which name a torrent by its info hash alone. Its metadata has to be
+//! fetched from peers before it can be downloaded.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::{Error, InfoHash};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MagnetUri {
+    pub info_hash: InfoHash,
+
+    /// `dn`: a name to show for the torrent until its metadata arrives.
+    pub display_name: Option<String>,
+
+    /// `tr`: trackers to announce to.
+    pub trackers: Vec<String>,
+
+    /// `x.pe`: peers to connect to, as `host:port`.
+    pub peers: Vec<String>,
+}
+
+impl MagnetUri {
+    /// The display name if there is one, or else the info hash.
+    pub fn name(&self) -> String {
+        self.display_name
+            .clone()
+            .unwrap_or_else(|| self.info_hash.to_string())
+    }
+}
+
+/// Parse a `magnet:?` link. The info hash may be given as 40 hex digits or 32 base32 characters.
+/// Parameters other than `xt`, `dn`, `tr` and `x.pe` are ignored.
+impl FromStr for MagnetUri {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let query = input
+            .strip_prefix("magnet:?")
+            .ok_or("Magnet links must start with \"magnet:?\"")?;
+
+        let mut info_hash: Option<InfoHash> = None;
+        let mut display_name: Option<String> = None;
+        let mut trackers: Vec<String> = Vec::new();
+        let mut peers: Vec<String> = Vec::new();
+
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            let value = decode(value).ok_or_else(|| format!("Invalid \"{}\" value", key))?;
+
+            match key {
+                "xt" => {
+                    // Other exact topics, such as BitTorrent v2's urn:btmh, can't be used yet.
+                    let Some(btih) = value.strip_prefix("urn:btih:") else {
+                        continue;
+                    };
+
+                    let parsed = parse_btih(btih).ok_or("Invalid info hash")?;
+
+                    if info_hash.is_some_and(|info_hash| info_hash != parsed) {
+                        return Err("Magnet link has more than one info hash".into());
+                    }
+
+                    info_hash = Some(parsed);
+                }
+                "dn" => display_name = Some(value),
+                "tr" => trackers.push(value),
+                "x.pe" => peers.push(value),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            info_hash: info_hash.ok_or("Magnet link has no BitTorrent info hash")?,
+            display_name,
+            trackers,
+            peers,
+        })
+    }
+}
+
+impl fmt::Display for MagnetUri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "magnet:?xt=urn:btih:{}", self.info_hash)?;
+
+        if let Some(display_name) = &self.display_name {
+            write!(f, "&dn={}", crate::url_encode(display_name.as_bytes()))?;
+        }
+
+        for tracker in self.trackers.iter() {
+            write!(f, "&tr={}", crate::url_encode(tracker.as_bytes()))?;
+        }
+
+        for peer in self.peers.iter() {
+            write!(f, "&x.pe={}", crate::url_encode(peer.as_bytes()))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_btih(input: &str) -> Option<InfoHash> {
+    match input.len() {
+        40 => InfoHash::from_hex(input),
+        32 => decode_base32(input).map(InfoHash::from),
+        _ => None,
+    }
+}
+
+/// Decode 32 characters of RFC 4648 base32, in either case, into the 20 bytes they encode.
+fn decode_base32(input: &str) -> Option<[u8; 20]> {
+    let mut bytes = [0; 20];
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    let mut len = 0;
+
+    for c in input.bytes().map(|c| c.to_ascii_uppercase()) {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+
+        buffer = (buffer << 5) | u32::from(value);
+        bits += 5;
+
+        if bits >= 8 {
+            bits -= 8;
+            *bytes.get_mut(len)? = (buffer >> bits) as u8;
+            buffer &= (1 << bits) - 1;
+            len += 1;
+        }
+    }
+
+    (len == bytes.len()).then_some(bytes)
+}
+
+/// Undo the percent-encoding of a query string value, in which `+` also stands for a space.
+fn decode(input: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(input.len());
+    let mut input_iter = input.bytes();
+
+    while let Some(b) = input_iter.next() {
+        match b {
+            b'%' => {
+                let hex = [input_iter.next()?, input_iter.next()?];
+                bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            b'+' => bytes.push(b' '),
+            b => bytes.push(b),
+        }
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn from_str_test() {
+        let info_hash = InfoHash::from_hex("c12fe1c06bba254a9dc9f519b335aa7c1367a88a").unwrap();
+
+        let magnet: MagnetUri = "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a\
+            &dn=Some+Linux%20ISO&tr=http%3A%2F%2Ftracker.example%2Fannounce\
+            &tr=udp://tracker.example:6969&x.pe=10.0.0.1:6881&xl=1024"
+            .parse()
+            .unwrap();
+
+        assert_eq!(
+            MagnetUri {
+                info_hash,
+                display_name: Some("Some Linux ISO".to_string()),
+                trackers: vec![
+                    "http://tracker.example/announce".to_string(),
+                    "udp://tracker.example:6969".to_string(),
+                ],
+                peers: vec!["10.0.0.1:6881".to_string()],
+            },
+            magnet,
+        );
+
+        assert_eq!(magnet, magnet.to_string().parse::<MagnetUri>().unwrap());
+
+        let base32: MagnetUri = "magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK"
+            .parse()
+            .unwrap();
+        assert_eq!(info_hash, base32.info_hash);
+        assert_eq!(info_hash.to_string(), base32.name());
+
+        assert!("magnet:?dn=name".parse::<MagnetUri>().is_err());
+        assert!("magnet:?xt=urn:btih:c12fe1c0".parse::<MagnetUri>().is_err());
+        assert!("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKE1"
+            .parse::<MagnetUri>()
+            .is_err());
+        assert!("http://example.com/a.torrent".parse::<MagnetUri>().is_err());
+    }
+}
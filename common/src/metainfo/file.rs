@@ -8,6 +8,16 @@ pub struct File {
     pub length: u64,
     pub md5sum: Option<Md5Value>,
     pub path: Vec<String>,
+
+    /// BEP 47 file attributes, one character each. `p` marks a padding file, which holds only
+    /// zeroes to align the next file to a piece boundary and is never written out.
+    pub attr: Option<String>,
+}
+
+impl File {
+    pub fn is_padding(&self) -> bool {
+        self.attr.as_ref().is_some_and(|attr| attr.contains('p'))
+    }
 }
 
 impl TryFrom<BencodeValue<'_>> for File {
@@ -28,6 +38,11 @@ impl TryFrom<BencodeValue<'_>> for File {
             .map(Md5Value::try_from)
             .transpose()?;
 
+        let attr = input_dict
+            .remove("attr".as_bytes())
+            .map(|benc| benc.to_string().ok_or("`attr` value must be a string"))
+            .transpose()?;
+
         let path = path_list
             .into_iter()
             .map(|benc| benc.to_string().ok_or("`path` components must be strings"))
@@ -37,6 +52,7 @@ impl TryFrom<BencodeValue<'_>> for File {
             length: length.try_into().map_err(|e| format!("{}", e))?,
             md5sum,
             path,
+            attr,
         })
     }
 }
@@ -56,6 +72,7 @@ impl<'a> From<&'a File> for BencodeValue<'a> {
         ]
         .into_iter()
         .chain(input.md5sum.iter().map(|md5sum| ("md5sum", md5sum.into())))
+        .chain(input.attr.iter().map(|attr| ("attr", attr.as_str().into())))
         .collect()
     }
 }
@@ -1,6 +1,10 @@
+use std::ops::Range;
+
+use sha1::{Digest, Sha1};
+
 use super::{File, Md5Value, Piece};
 use crate::bencode::BencodeValue;
-use crate::Error;
+use crate::{Error, InfoHash};
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Info {
@@ -19,13 +23,115 @@ pub enum Info {
     },
 }
 
+/// The location of one file within the concatenated data of a torrent.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileSpan<'a> {
+    /// The path components of the file, starting with the torrent name.
+    pub path: Vec<&'a str>,
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl FileSpan<'_> {
+    pub fn range(&self) -> Range<u64> {
+        self.offset..self.offset + self.length
+    }
+}
+
 impl Info {
+    /// Parse a bare bencoded info dict, as found in a torrent file, along with its info hash.
+    pub fn decode(input: &[u8]) -> Result<(Self, InfoHash), Error> {
+        let info_hash: [u8; 20] = Sha1::new_with_prefix(input).finalize().into();
+
+        Ok((BencodeValue::decode(input)?.try_into()?, info_hash.into()))
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Self::SingleFile { name, .. } | Self::MultiFile { name, .. } => name,
+        }
+    }
+
+    pub fn pieces(&self) -> &[Piece] {
+        match self {
+            Self::SingleFile { pieces, .. } | Self::MultiFile { pieces, .. } => pieces,
+        }
+    }
+
     pub fn length(&self) -> u64 {
         match self {
             Self::SingleFile { length, .. } => *length,
             Self::MultiFile { files, .. } => files.iter().map(|f| f.length).sum(),
         }
     }
+
+    pub fn piece_length(&self) -> u64 {
+        match self {
+            Self::SingleFile { piece_length, .. } | Self::MultiFile { piece_length, .. } => {
+                *piece_length
+            }
+        }
+    }
+
+    /// The range of bytes covered by the piece at `index`, if there is one. All pieces are
+    /// `piece_length` long except possibly the last.
+    pub fn piece_range(&self, index: u32) -> Option<Range<u64>> {
+        if index as usize >= self.pieces().len() {
+            return None;
+        }
+
+        let start = u64::from(index) * self.piece_length();
+        let end = (start + self.piece_length()).min(self.length());
+
+        Some(start..end)
+    }
+
+    /// The number of bytes within `range` that belong to BEP 47 padding files.
+    pub fn padding_in(&self, range: Range<u64>) -> u64 {
+        let Self::MultiFile { files, .. } = self else {
+            return 0;
+        };
+
+        self.file_spans()
+            .into_iter()
+            .zip(files)
+            .filter(|(_, file)| file.is_padding())
+            .map(|(span, _)| {
+                let span = span.range();
+                span.end
+                    .min(range.end)
+                    .saturating_sub(span.start.max(range.start))
+            })
+            .sum()
+    }
+
+    pub fn file_spans(&self) -> Vec<FileSpan<'_>> {
+        match self {
+            Self::SingleFile { name, length, .. } => vec![FileSpan {
+                path: vec![name.as_str()],
+                offset: 0,
+                length: *length,
+            }],
+            Self::MultiFile { name, files, .. } => {
+                let mut offset = 0;
+
+                files
+                    .iter()
+                    .map(|file| {
+                        let span = FileSpan {
+                            path: std::iter::once(name.as_str())
+                                .chain(file.path.iter().map(String::as_str))
+                                .collect(),
+                            offset,
+                            length: file.length,
+                        };
+                        offset += file.length;
+                        span
+                    })
+                    .collect()
+            }
+        }
+    }
 }
 
 impl TryFrom<BencodeValue<'_>> for Info {
@@ -62,12 +168,18 @@ impl TryFrom<BencodeValue<'_>> for Info {
             );
         };
 
-        match (
+        let piece_length: u64 = piece_length.try_into().map_err(|e| format!("{}", e))?;
+
+        if piece_length == 0 {
+            return Err("`piece length` must be positive".into());
+        }
+
+        let info = match (
             input_dict.remove("length".as_bytes()),
             input_dict.remove("files".as_bytes()),
         ) {
-            (Some(BencodeValue::Integer(length)), None) => Ok(Info::SingleFile {
-                piece_length: piece_length.try_into().map_err(|e| format!("{}", e))?,
+            (Some(BencodeValue::Integer(length)), None) => Info::SingleFile {
+                piece_length,
                 pieces,
                 name,
                 length: length.try_into().map_err(|e| format!("{}", e))?,
@@ -75,18 +187,28 @@ impl TryFrom<BencodeValue<'_>> for Info {
                     .remove("md5sum".as_bytes())
                     .map(Md5Value::try_from)
                     .transpose()?,
-            }),
-            (None, Some(BencodeValue::List(files))) => Ok(Info::MultiFile {
-                piece_length: piece_length.try_into().map_err(|e| format!("{}", e))?,
+            },
+            (None, Some(BencodeValue::List(files))) => Info::MultiFile {
+                piece_length,
                 pieces,
                 name,
                 files: files
                     .into_iter()
                     .map(|file| file.try_into())
                     .collect::<Result<_, _>>()?,
-            }),
-            _ => Err("Exactly one of `length` or `files` keys must be present".into()),
+            },
+            _ => return Err("Exactly one of `length` or `files` keys must be present".into()),
+        };
+
+        // Offsets into the torrent are u64s, so the whole torrent has to fit in one.
+        if let Info::MultiFile { files, .. } = &info {
+            files
+                .iter()
+                .try_fold(0u64, |length, file| length.checked_add(file.length))
+                .ok_or("The files are too long in total")?;
         }
+
+        Ok(info)
     }
 }
 
@@ -105,8 +227,7 @@ impl<'a> From<&'a Info> for BencodeValue<'a> {
                     "pieces",
                     pieces
                         .iter()
-                        .map(|piece| piece.iter())
-                        .flatten()
+                        .flat_map(|piece| piece.iter())
                         .copied()
                         .collect::<Vec<u8>>()
                         .into(),
@@ -115,7 +236,7 @@ impl<'a> From<&'a Info> for BencodeValue<'a> {
                 ("length", (*length).into()),
             ]
             .into_iter()
-            .chain(md5sum.into_iter().map(|md5sum| ("md5sum", md5sum.into())))
+            .chain(md5sum.iter().map(|md5sum| ("md5sum", md5sum.into())))
             .collect(),
             Info::MultiFile {
                 piece_length,
@@ -128,8 +249,7 @@ impl<'a> From<&'a Info> for BencodeValue<'a> {
                     "pieces",
                     pieces
                         .iter()
-                        .map(|piece| piece.iter())
-                        .flatten()
+                        .flat_map(|piece| piece.iter())
                         .copied()
                         .collect::<Vec<u8>>()
                         .into(),
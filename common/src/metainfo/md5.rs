@@ -37,8 +37,7 @@ impl From<&Md5Value> for BencodeValue<'_> {
         input
             .0
             .iter()
-            .map(|u| format!("{:x}", u).into_bytes())
-            .flatten()
+            .flat_map(|u| format!("{:x}", u).into_bytes())
             .collect::<Vec<u8>>()
             .into()
     }
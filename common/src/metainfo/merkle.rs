@@ -0,0 +1,262 @@
+//! The SHA-256 hash trees of BitTorrent v2 (BEP 52). Each file is hashed in 16 KiB blocks, and
+//! the block hashes are paired up layer by layer until only the file's `pieces root` is left.
+//! Layers that don't fill a power of two are padded with the hashes of all-zero subtrees.
+
+use std::fmt;
+
+use sha2::{Digest, Sha256};
+
+use crate::Error;
+
+pub const BLOCK_SIZE: usize = 16 * 1024;
+
+#[derive(Clone, Copy, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct MerkleHash([u8; 32]);
+
+impl MerkleHash {
+    pub const ZERO: Self = Self([0; 32]);
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.0[..]
+    }
+
+    /// The leaf hash of a block of data.
+    pub fn of_block(data: &[u8]) -> Self {
+        Self(Sha256::digest(data).into())
+    }
+
+    /// The hash of the parent node of `self` and `right`.
+    pub fn join(&self, right: &Self) -> Self {
+        Self(
+            Sha256::new()
+                .chain_update(self.0)
+                .chain_update(right.0)
+                .finalize()
+                .into(),
+        )
+    }
+
+    /// The root of an all-zero subtree `height` layers above the leaves, used to pad that layer.
+    pub fn padding(height: u32) -> Self {
+        (0..height).fold(Self::ZERO, |hash, _| hash.join(&hash))
+    }
+}
+
+impl TryFrom<&[u8]> for MerkleHash {
+    type Error = Error;
+
+    fn try_from(input: &[u8]) -> Result<Self, Self::Error> {
+        Ok(MerkleHash(input.try_into().map_err(|e| format!("{}", e))?))
+    }
+}
+
+impl From<[u8; 32]> for MerkleHash {
+    fn from(input: [u8; 32]) -> Self {
+        MerkleHash(input)
+    }
+}
+
+impl fmt::Debug for MerkleHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "MerkleHash({})", self)
+    }
+}
+
+impl fmt::Display for MerkleHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        self.0.iter().try_for_each(|u| write!(f, "{:02x}", u))
+    }
+}
+
+/// The number of layers between a piece's hash and its blocks.
+pub fn piece_height(piece_length: u64) -> u32 {
+    (piece_length / BLOCK_SIZE as u64).max(1).ilog2()
+}
+
+/// The hash of one piece, as found in a piece layer. The last piece of a file is padded out to
+/// the full piece length with zero hashes.
+pub fn piece_hash(data: &[u8], piece_length: u64) -> MerkleHash {
+    root(
+        &block_hashes(data),
+        1 << piece_height(piece_length),
+        MerkleHash::ZERO,
+    )
+}
+
+/// The `pieces root` of a file no longer than one piece, which has no piece layer.
+pub fn file_root(data: &[u8]) -> MerkleHash {
+    root(&block_hashes(data), 1, MerkleHash::ZERO)
+}
+
+/// Check a file's piece layer against its `pieces root`.
+pub fn verify_piece_layer(
+    layer: &[MerkleHash],
+    pieces_root: &MerkleHash,
+    piece_length: u64,
+) -> bool {
+    let pad = MerkleHash::padding(piece_height(piece_length));
+    root(layer, 1, pad) == *pieces_root
+}
+
+/// The root of the tree over `leaves`, padded with `pad` to at least `width` leaves.
+pub fn root(leaves: &[MerkleHash], width: usize, pad: MerkleHash) -> MerkleHash {
+    let layers = layers(leaves, width, pad);
+    let (top, pad) = layers.last().unwrap();
+    top.first().copied().unwrap_or(*pad)
+}
+
+/// The hashes to answer a request for `length` hashes of `layer` from `index` on, followed by the
+/// uncle hashes of their subtree for `proof_layers` layers up. `pad` is the padding of `layer`.
+/// Returns `None` if the request doesn't fit the layer.
+pub fn proof(
+    layer: &[MerkleHash],
+    pad: MerkleHash,
+    index: usize,
+    length: usize,
+    proof_layers: u32,
+) -> Option<Vec<MerkleHash>> {
+    if !length.is_power_of_two() || !index.is_multiple_of(length) || index >= layer.len() {
+        return None;
+    }
+
+    let layers = layers(layer, index + length, pad);
+    let height = length.ilog2() as usize;
+
+    // The proof can't go past the root.
+    if height + proof_layers as usize >= layers.len() {
+        return None;
+    }
+
+    let mut hashes: Vec<MerkleHash> = (index..index + length)
+        .map(|i| layer.get(i).copied().unwrap_or(pad))
+        .collect();
+
+    let mut position = index / length;
+
+    for (nodes, pad) in layers.iter().skip(height).take(proof_layers as usize) {
+        hashes.push(nodes.get(position ^ 1).copied().unwrap_or(*pad));
+        position /= 2;
+    }
+
+    Some(hashes)
+}
+
+/// Check a run of hashes starting at `index` in a layer, followed by the uncle hashes of their
+/// subtree, against the root of the tree.
+pub fn verify_proof(
+    hashes: &[MerkleHash],
+    index: usize,
+    uncles: &[MerkleHash],
+    pieces_root: &MerkleHash,
+) -> bool {
+    if !hashes.len().is_power_of_two() || !index.is_multiple_of(hashes.len()) {
+        return false;
+    }
+
+    let mut node = root(hashes, hashes.len(), MerkleHash::ZERO);
+    let mut position = index / hashes.len();
+
+    for uncle in uncles {
+        node = if position.is_multiple_of(2) {
+            node.join(uncle)
+        } else {
+            uncle.join(&node)
+        };
+        position /= 2;
+    }
+
+    position == 0 && node == *pieces_root
+}
+
+fn block_hashes(data: &[u8]) -> Vec<MerkleHash> {
+    data.chunks(BLOCK_SIZE).map(MerkleHash::of_block).collect()
+}
+
+/// Every layer of the tree over `leaves`, from the leaves up to the root, each with its padding.
+/// Only the nodes that aren't entirely padding are kept.
+fn layers(
+    leaves: &[MerkleHash],
+    width: usize,
+    pad: MerkleHash,
+) -> Vec<(Vec<MerkleHash>, MerkleHash)> {
+    let mut width = width.max(leaves.len()).max(1).next_power_of_two();
+    let mut layers = vec![(leaves.to_vec(), pad)];
+
+    while width > 1 {
+        let (nodes, pad) = layers.last().unwrap();
+
+        let parents: Vec<MerkleHash> = nodes
+            .chunks(2)
+            .map(|pair| pair[0].join(pair.get(1).unwrap_or(pad)))
+            .collect();
+        let pad = pad.join(pad);
+
+        layers.push((parents, pad));
+        width /= 2;
+    }
+
+    layers
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn root_test() {
+        let a = MerkleHash::of_block(b"a");
+        let b = MerkleHash::of_block(b"b");
+        let c = MerkleHash::of_block(b"c");
+        let zero = MerkleHash::ZERO;
+
+        assert_eq!(a, root(&[a], 1, zero));
+        assert_eq!(a.join(&b), root(&[a, b], 1, zero));
+        assert_eq!(a.join(&b).join(&c.join(&zero)), root(&[a, b, c], 1, zero));
+        assert_eq!(a.join(&zero).join(&zero.join(&zero)), root(&[a], 4, zero));
+        assert_eq!(MerkleHash::padding(2), root(&[], 4, zero));
+    }
+
+    #[test]
+    fn piece_layer_test() {
+        let piece_length = 2 * BLOCK_SIZE as u64;
+        // Every block differs, so that swapping two pieces changes the layer.
+        let data: Vec<u8> = (0..5 * BLOCK_SIZE + 100)
+            .map(|i| (i / BLOCK_SIZE) as u8)
+            .collect();
+
+        let layer: Vec<MerkleHash> = data
+            .chunks(piece_length as usize)
+            .map(|piece| piece_hash(piece, piece_length))
+            .collect();
+
+        let pieces_root = file_root(&data);
+
+        assert_eq!(3, layer.len());
+        assert!(verify_piece_layer(&layer, &pieces_root, piece_length));
+        assert!(!verify_piece_layer(&layer[..2], &pieces_root, piece_length));
+        assert!(!verify_piece_layer(
+            &[layer[1], layer[0], layer[2]],
+            &pieces_root,
+            piece_length
+        ));
+    }
+
+    #[test]
+    fn proof_test() {
+        let leaves: Vec<MerkleHash> = (0..5u8).map(|i| MerkleHash::of_block(&[i])).collect();
+        let pieces_root = root(&leaves, 1, MerkleHash::ZERO);
+
+        let hashes = proof(&leaves, MerkleHash::ZERO, 4, 2, 2).unwrap();
+        assert_eq!(4, hashes.len());
+        assert!(verify_proof(&hashes[..2], 4, &hashes[2..], &pieces_root));
+        assert!(!verify_proof(&hashes[..2], 0, &hashes[2..], &pieces_root));
+        assert!(!verify_proof(&hashes[..2], 4, &hashes[2..3], &pieces_root));
+
+        let hashes = proof(&leaves, MerkleHash::ZERO, 0, 8, 0).unwrap();
+        assert!(verify_proof(&hashes, 0, &[], &pieces_root));
+
+        assert_eq!(None, proof(&leaves, MerkleHash::ZERO, 1, 2, 0));
+        assert_eq!(None, proof(&leaves, MerkleHash::ZERO, 0, 8, 1));
+        assert_eq!(None, proof(&leaves, MerkleHash::ZERO, 8, 2, 0));
+    }
+}
@@ -1,12 +1,17 @@
+pub mod merkle;
+
 mod file;
 mod info;
 mod md5;
 mod piece;
+mod v2;
 
 pub use file::File;
-pub use info::Info;
+pub use info::{FileSpan, Info};
 pub use md5::Md5Value;
+pub use merkle::MerkleHash;
 pub use piece::Piece;
+pub use v2::{V2File, V2Info};
 
 use crate::bencode::BencodeValue;
 use crate::{Error, InfoHash};
@@ -25,13 +30,121 @@ pub struct MetainfoFile {
     pub created_by: Option<String>,
     pub encoding: Option<String>,
 
+    /// BEP 19 web seeds: plain HTTP servers hosting the torrent's files.
+    pub url_list: Option<Vec<String>>,
+
+    /// BEP 17 HTTP seeds: scripts serving pieces by index.
+    pub httpseeds: Option<Vec<String>>,
+
+    /// The v2 file tree and piece layers of a hybrid torrent (BEP 52).
+    pub v2: Option<V2Info>,
+
     info_hash: InfoHash,
+
+    /// The info dictionary as it was hashed, with any keys that `info` doesn't keep, such as
+    /// `private`. It's written back as is, so that the torrent keeps its info hash.
+    info_bytes: Vec<u8>,
 }
 
 impl MetainfoFile {
     pub fn info_hash(&self) -> &InfoHash {
         &self.info_hash
     }
+
+    /// All tracker URLs, in the order they should be tried. Per BEP 12, `announce` is ignored if
+    /// `announce-list` is present.
+    pub fn announce_urls(&self) -> Vec<&str> {
+        let mut urls = Vec::new();
+
+        if let Some(announce_list) = &self.announce_list {
+            for url in announce_list.iter().flatten() {
+                if !urls.contains(&url.as_str()) {
+                    urls.push(url.as_str());
+                }
+            }
+        }
+
+        if urls.is_empty() {
+            urls.push(self.announce.as_str());
+        }
+
+        urls
+    }
+
+    /// Check a downloaded piece against its SHA-1 hash and, for hybrid torrents, against the v2
+    /// hash tree as far as its piece layer is known.
+    pub fn verify_piece(&self, index: u32, data: &[u8]) -> bool {
+        let Some(piece) = self.info.pieces().get(index as usize) else {
+            return false;
+        };
+
+        if !piece.iter().eq(Sha1::digest(data).iter()) {
+            return false;
+        }
+
+        let Some((v2, (file, file_index, length))) = self.v2.as_ref().zip(self.v2_piece(index))
+        else {
+            return true;
+        };
+
+        // The rest of the piece is padding up to the next file.
+        let (Some(pieces_root), Some(data)) = (file.pieces_root, data.get(..length as usize))
+        else {
+            return false;
+        };
+
+        let piece_length = self.info.piece_length();
+
+        if file.length <= piece_length {
+            return merkle::file_root(data) == pieces_root;
+        }
+
+        match v2
+            .piece_layers
+            .get(&pieces_root)
+            .and_then(|layer| layer.get(file_index as usize))
+        {
+            Some(hash) => merkle::piece_hash(data, piece_length) == *hash,
+            None => true,
+        }
+    }
+
+    /// The v2 file that the piece at `index` starts in, the index of the piece within the file,
+    /// and how much of the piece is the file's data. Files in hybrid torrents are padded to start
+    /// on piece boundaries, so the piece can't span files.
+    pub fn v2_piece(&self, index: u32) -> Option<(&V2File, u32, u64)> {
+        let v2 = self.v2.as_ref()?;
+        let range = self.info.piece_range(index)?;
+        let piece_length = self.info.piece_length();
+
+        let span = self
+            .info
+            .file_spans()
+            .into_iter()
+            .find(|span| span.range().contains(&range.start))?;
+
+        if span.offset % piece_length != 0 {
+            return None;
+        }
+
+        // The v2 file tree is relative to the torrent's directory, but a single file is named
+        // after the torrent itself.
+        let path = match self.info {
+            Info::SingleFile { .. } => &span.path[..],
+            Info::MultiFile { .. } => &span.path[1..],
+        };
+
+        let file = v2
+            .files
+            .iter()
+            .find(|file| file.path.iter().eq(path.iter()))?;
+
+        Some((
+            file,
+            ((range.start - span.offset) / piece_length) as u32,
+            range.end.min(span.range().end) - range.start,
+        ))
+    }
 }
 
 impl TryFrom<&[u8]> for MetainfoFile {
@@ -59,7 +172,15 @@ impl TryFrom<BencodeValue<'_>> for MetainfoFile {
             return Err("Torrent file must contain `info` and `announce` keys".into());
         };
 
-        let info_hash_array: [u8; 20] = Sha1::new_with_prefix(info_benc.encode()).finalize().into();
+        let info_bytes = info_benc.encode();
+        let info_hash_array: [u8; 20] = Sha1::new_with_prefix(&info_bytes).finalize().into();
+
+        let info: Info = info_benc.clone().try_into()?;
+        let v2 = V2Info::parse(
+            &info_benc.to_dict().unwrap_or_default(),
+            input_dict.remove(&b"piece layers"[..]),
+            info.piece_length(),
+        )?;
 
         let announce_list =
             if let Some(announce_tiers_benc) = input_dict.remove(&b"announce-list"[..]) {
@@ -125,15 +246,52 @@ impl TryFrom<BencodeValue<'_>> for MetainfoFile {
             })
             .transpose()?;
 
+        // `url-list` may be a single string or a list of strings.
+        let url_list = input_dict
+            .remove(&b"url-list"[..])
+            .map(|url_list_benc| match url_list_benc {
+                BencodeValue::List(list) => list
+                    .into_iter()
+                    .map(|benc| {
+                        benc.to_string()
+                            .ok_or("`url-list` must contain only strings")
+                    })
+                    .collect::<Result<Vec<_>, _>>(),
+                benc => benc
+                    .to_string()
+                    .map(|s| vec![s])
+                    .ok_or("`url-list` must be a string or a list"),
+            })
+            .transpose()?;
+
+        let httpseeds = input_dict
+            .remove(&b"httpseeds"[..])
+            .map(|httpseeds_benc| {
+                httpseeds_benc
+                    .to_list()
+                    .ok_or("`httpseeds` must be a list")?
+                    .into_iter()
+                    .map(|benc| {
+                        benc.to_string()
+                            .ok_or("`httpseeds` must contain only strings")
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .transpose()?;
+
         Ok(MetainfoFile {
-            info: info_benc.try_into()?,
+            info,
             info_hash: info_hash_array.into(),
+            info_bytes,
             announce,
             announce_list,
             creation_date,
             comment,
             created_by,
             encoding,
+            url_list,
+            httpseeds,
+            v2,
         })
     }
 }
@@ -146,44 +304,69 @@ impl From<&MetainfoFile> for Vec<u8> {
 
 impl<'a> From<&'a MetainfoFile> for BencodeValue<'a> {
     fn from(input: &'a MetainfoFile) -> Self {
-        [
-            ("info", (&input.info).into()),
-            ("announce", input.announce.as_str().into()),
-        ]
-        .into_iter()
-        .chain(input.announce_list.iter().map(|announce_list| {
-            (
-                "announce-list",
-                announce_list
+        let info = BencodeValue::decode(&input.info_bytes)
+            .expect("The info dictionary was encoded when the metainfo was parsed");
+
+        [("info", info), ("announce", input.announce.as_str().into())]
+            .into_iter()
+            .chain(input.announce_list.iter().map(|announce_list| {
+                (
+                    "announce-list",
+                    announce_list
+                        .iter()
+                        .map(|v| {
+                            v.iter()
+                                .map(|s| BencodeValue::from(s.as_str()))
+                                .collect::<BencodeValue<'_>>()
+                        })
+                        .collect(),
+                )
+            }))
+            .chain(
+                input
+                    .creation_date
                     .iter()
-                    .map(|v| {
-                        v.into_iter()
-                            .map(|s| BencodeValue::from(s.as_str()))
-                            .collect::<BencodeValue<'_>>()
-                    })
-                    .collect(),
+                    .map(|d| ("creation date", d.into())),
+            )
+            .chain(input.comment.iter().map(|s| ("comment", s.as_str().into())))
+            .chain(
+                input
+                    .created_by
+                    .iter()
+                    .map(|s| ("created by", s.as_str().into())),
+            )
+            .chain(
+                input
+                    .encoding
+                    .iter()
+                    .map(|s| ("encoding", s.as_str().into())),
             )
-        }))
-        .chain(
-            input
-                .creation_date
-                .iter()
-                .map(|d| ("creation date", d.into())),
-        )
-        .chain(input.comment.iter().map(|s| ("comment", s.as_str().into())))
-        .chain(
-            input
-                .created_by
-                .iter()
-                .map(|s| ("created by", s.as_str().into())),
-        )
-        .chain(
-            input
-                .encoding
-                .iter()
-                .map(|s| ("encoding", s.as_str().into())),
-        )
-        .collect()
+            .chain(input.url_list.iter().map(|url_list| {
+                (
+                    "url-list",
+                    url_list
+                        .iter()
+                        .map(|s| BencodeValue::from(s.as_str()))
+                        .collect(),
+                )
+            }))
+            .chain(input.httpseeds.iter().map(|httpseeds| {
+                (
+                    "httpseeds",
+                    httpseeds
+                        .iter()
+                        .map(|s| BencodeValue::from(s.as_str()))
+                        .collect(),
+                )
+            }))
+            .chain(
+                input
+                    .v2
+                    .iter()
+                    .filter(|v2| !v2.piece_layers.is_empty())
+                    .map(|v2| ("piece layers", v2.piece_layers())),
+            )
+            .collect()
     }
 }
 
@@ -248,4 +431,19 @@ mod test {
         // Validate that the input file is byte-for-byte the same as the output
         assert_eq!(metainfo_bytes[..], Vec::<u8>::from(&metainfo)[..]);
     }
+
+    #[test]
+    fn private_round_trip_test() {
+        let bytes = &b"d8:announce16:http://localhost4:infod6:lengthi3e4:name1:a\
+            12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1e5:x-key1:yee"[..];
+        let metainfo = MetainfoFile::try_from(bytes).unwrap();
+        let encoded = Vec::<u8>::from(&metainfo);
+
+        // `private` and other keys that Info doesn't keep are part of the info hash.
+        assert_eq!(bytes, &encoded[..]);
+        assert_eq!(
+            metainfo.info_hash(),
+            MetainfoFile::try_from(&encoded[..]).unwrap().info_hash(),
+        );
+    }
 }
@@ -2,7 +2,7 @@ use crate::Error;
 
 use std::fmt;
 
-#[derive(Clone, Eq, PartialEq)]
+#[derive(Clone, Eq, Hash, PartialEq)]
 pub struct Piece([u8; 20]);
 
 impl Piece {
@@ -0,0 +1,290 @@
+//! The BitTorrent v2 parts of a hybrid torrent (BEP 52): a `file tree` in the info dict giving
+//! each file's `pieces root`, and the `piece layers` alongside the info dict.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use super::merkle::{self, MerkleHash};
+use crate::bencode::BencodeValue;
+use crate::Error;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct V2File {
+    pub path: Vec<String>,
+    pub length: u64,
+
+    /// The root of the file's hash tree. Empty files have none.
+    pub pieces_root: Option<MerkleHash>,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct V2Info {
+    /// The files of the `file tree`, in order.
+    pub files: Vec<V2File>,
+
+    /// The hashes of the pieces of each file longer than one piece, by `pieces root`. Layers that
+    /// are missing can be fetched from peers.
+    pub piece_layers: HashMap<MerkleHash, Vec<MerkleHash>>,
+}
+
+impl V2Info {
+    /// Parse the v2 keys of an info dict, if it has them, along with the `piece layers` from the
+    /// torrent file. Piece layers that don't match their file are rejected.
+    pub fn parse(
+        info_dict: &HashMap<Cow<'_, [u8]>, BencodeValue<'_>>,
+        piece_layers: Option<BencodeValue<'_>>,
+        piece_length: u64,
+    ) -> Result<Option<Self>, Error> {
+        match info_dict.get(&b"meta version"[..]) {
+            None => return Ok(None),
+            Some(BencodeValue::Integer(2)) => {}
+            Some(_) => return Err("Only `meta version` 2 is supported".into()),
+        }
+
+        // Blocks are hashed in 16 KiB leaves, and pieces are whole subtrees of them.
+        if !piece_length.is_power_of_two() || piece_length < merkle::BLOCK_SIZE as u64 {
+            return Err("`piece length` must be a power of two of at least 16 KiB".into());
+        }
+
+        let file_tree = info_dict
+            .get(&b"file tree"[..])
+            .cloned()
+            .ok_or("`info` dict must contain `file tree` with `meta version` 2")?;
+
+        let mut files = Vec::new();
+        parse_file_tree(file_tree, &mut Vec::new(), &mut files)?;
+
+        let mut v2 = V2Info {
+            files,
+            piece_layers: HashMap::new(),
+        };
+
+        let Some(piece_layers) = piece_layers else {
+            return Ok(Some(v2));
+        };
+
+        for (pieces_root, layer) in piece_layers
+            .to_dict()
+            .ok_or("`piece layers` must be a dict")?
+        {
+            let pieces_root = MerkleHash::try_from(&pieces_root[..])?;
+            let layer = layer
+                .to_bytes()
+                .filter(|layer| layer.len() % 32 == 0)
+                .ok_or("`piece layers` values must be strings of 32-byte hashes")?;
+
+            v2.insert_piece_layer(
+                pieces_root,
+                layer
+                    .chunks_exact(32)
+                    .map(|a| a.try_into().unwrap())
+                    .collect(),
+                piece_length,
+            )?;
+        }
+
+        Ok(Some(v2))
+    }
+
+    /// Add a file's piece layer, checking it against the file's `pieces root`.
+    pub fn insert_piece_layer(
+        &mut self,
+        pieces_root: MerkleHash,
+        layer: Vec<MerkleHash>,
+        piece_length: u64,
+    ) -> Result<(), Error> {
+        let Some(file) = self.file(&pieces_root) else {
+            return Err(format!("No file has pieces root {}", pieces_root).into());
+        };
+
+        if layer.len() as u64 != file.length.div_ceil(piece_length)
+            || !merkle::verify_piece_layer(&layer, &pieces_root, piece_length)
+        {
+            return Err(format!("The piece layer of {} is invalid", file.path.join("/")).into());
+        }
+
+        self.piece_layers.insert(pieces_root, layer);
+        Ok(())
+    }
+
+    pub fn file(&self, pieces_root: &MerkleHash) -> Option<&V2File> {
+        self.files
+            .iter()
+            .find(|file| file.pieces_root.as_ref() == Some(pieces_root))
+    }
+
+    /// The files longer than one piece whose piece layers we don't have.
+    pub fn missing_piece_layers(&self, piece_length: u64) -> impl Iterator<Item = &V2File> {
+        self.files.iter().filter(move |file| {
+            file.length > piece_length
+                && file
+                    .pieces_root
+                    .is_some_and(|root| !self.piece_layers.contains_key(&root))
+        })
+    }
+
+    /// The `file tree` value for the info dict.
+    pub fn file_tree(&self) -> BencodeValue<'_> {
+        let mut tree = HashMap::new();
+
+        for file in self.files.iter() {
+            let mut dir = &mut tree;
+
+            for component in file.path.iter() {
+                let entry = dir
+                    .entry(Cow::Borrowed(component.as_bytes()))
+                    .or_insert_with(|| BencodeValue::Dict(HashMap::new()));
+
+                let BencodeValue::Dict(next) = entry else {
+                    unreachable!();
+                };
+
+                dir = next;
+            }
+
+            dir.insert(
+                Cow::Borrowed(&b""[..]),
+                [("length", file.length.into())]
+                    .into_iter()
+                    .chain(
+                        file.pieces_root
+                            .iter()
+                            .map(|root| ("pieces root", root.as_slice().into())),
+                    )
+                    .collect(),
+            );
+        }
+
+        BencodeValue::Dict(tree)
+    }
+
+    /// The `piece layers` value for the torrent file.
+    pub fn piece_layers(&self) -> BencodeValue<'_> {
+        BencodeValue::Dict(
+            self.piece_layers
+                .iter()
+                .map(|(root, layer)| {
+                    (
+                        Cow::Borrowed(root.as_slice()),
+                        layer
+                            .iter()
+                            .flat_map(|hash| hash.as_slice().iter().copied())
+                            .collect::<Vec<u8>>()
+                            .into(),
+                    )
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Collect the files under a node of the `file tree`. A file is a dict with an empty key holding
+/// its length and `pieces root`; anything else is a directory.
+fn parse_file_tree(
+    node: BencodeValue<'_>,
+    path: &mut Vec<String>,
+    files: &mut Vec<V2File>,
+) -> Result<(), Error> {
+    let mut node = node.to_dict().ok_or("`file tree` nodes must be dicts")?;
+
+    if let Some(file) = node.remove(&b""[..]) {
+        let mut file = file.to_dict().ok_or("`file tree` files must be dicts")?;
+
+        let length = file
+            .remove(&b"length"[..])
+            .and_then(BencodeValue::to_u64)
+            .ok_or("`file tree` files must have a `length`")?;
+
+        let pieces_root = file
+            .remove(&b"pieces root"[..])
+            .map(|root| {
+                root.to_bytes()
+                    .ok_or_else(|| Error::from("`pieces root` must be a string"))
+                    .and_then(|root| MerkleHash::try_from(&root[..]))
+            })
+            .transpose()?;
+
+        if length > 0 && pieces_root.is_none() {
+            return Err("`file tree` files must have a `pieces root`".into());
+        }
+
+        files.push(V2File {
+            path: path.clone(),
+            length,
+            pieces_root,
+        });
+
+        return Ok(());
+    }
+
+    let mut children: Vec<_> = node.into_iter().collect();
+    children.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    for (name, child) in children {
+        path.push(String::from_utf8_lossy(&name).into());
+        parse_file_tree(child, path, files)?;
+        path.pop();
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip_test() {
+        let piece_length = merkle::BLOCK_SIZE as u64;
+        // Every block differs, so that swapping two of them changes the layer.
+        let data: Vec<u8> = (0..3 * merkle::BLOCK_SIZE)
+            .map(|i| (i / merkle::BLOCK_SIZE) as u8)
+            .collect();
+        let layer: Vec<MerkleHash> = data
+            .chunks(merkle::BLOCK_SIZE)
+            .map(MerkleHash::of_block)
+            .collect();
+        let pieces_root = merkle::file_root(&data);
+
+        let v2 = V2Info {
+            files: vec![
+                V2File {
+                    path: vec!["dir".to_string(), "a".to_string()],
+                    length: data.len() as u64,
+                    pieces_root: Some(pieces_root),
+                },
+                V2File {
+                    path: vec!["empty".to_string()],
+                    length: 0,
+                    pieces_root: None,
+                },
+            ],
+            piece_layers: [(pieces_root, layer.clone())].into_iter().collect(),
+        };
+
+        let info_dict: HashMap<_, _> = [
+            (
+                Cow::Borrowed(&b"meta version"[..]),
+                BencodeValue::Integer(2),
+            ),
+            (Cow::Borrowed(&b"file tree"[..]), v2.file_tree()),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(
+            Ok(Some(v2.clone())),
+            V2Info::parse(&info_dict, Some(v2.piece_layers()), piece_length),
+        );
+
+        let mut bad_layer = layer;
+        bad_layer.swap(0, 1);
+        let mut bad = v2.clone();
+        bad.piece_layers.insert(pieces_root, bad_layer);
+        assert!(V2Info::parse(&info_dict, Some(bad.piece_layers()), piece_length).is_err());
+
+        let mut missing = v2;
+        missing.piece_layers.clear();
+        assert_eq!(1, missing.missing_piece_layers(piece_length).count());
+    }
+}
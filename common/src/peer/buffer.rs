@@ -0,0 +1,139 @@
+use super::{ParsedPeerMessage, PeerMessage, PEERMESSAGE_MAX_LEN};
+
+/// Incremental decoder for the length-prefixed peer wire protocol. Bytes are fed in as they
+/// arrive, in chunks of any size, and complete messages are yielded as soon as they are
+/// available. This lets any transport that can produce a byte stream share the same framing.
+#[derive(Clone, Debug)]
+pub struct MessageBuffer {
+    buf: Vec<u8>,
+    start: usize,
+    max_len: usize,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MessageBufferError {
+    /// The peer announced a message longer than the buffer will accept. The stream can't be
+    /// resynchronized after this, so the buffer is cleared.
+    TooLong { len: usize, max_len: usize },
+
+    /// A complete message was received but could not be parsed. It has been skipped.
+    Invalid(Vec<u8>),
+}
+
+impl MessageBuffer {
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            start: 0,
+            max_len,
+        }
+    }
+
+    pub fn extend(&mut self, chunk: &[u8]) {
+        // Reclaim the space used by consumed messages before growing.
+        if self.start > 0 && self.start >= self.buf.len() / 2 {
+            self.buf.drain(..self.start);
+            self.start = 0;
+        }
+
+        self.buf.extend_from_slice(chunk);
+    }
+
+    /// Returns the next complete message, or `None` if more bytes are needed.
+    pub fn next_message(&mut self) -> Result<Option<PeerMessage>, MessageBufferError> {
+        if let Some(len_bytes) = self.buf.get(self.start..self.start + 4) {
+            let len = u32::from_be_bytes(len_bytes.try_into().unwrap()) as usize;
+
+            if len > self.max_len {
+                self.clear();
+                return Err(MessageBufferError::TooLong {
+                    len,
+                    max_len: self.max_len,
+                });
+            }
+        }
+
+        match ParsedPeerMessage::from(&self.buf[self.start..]) {
+            ParsedPeerMessage::Complete(message, remainder) => {
+                self.start = self.buf.len() - remainder.len();
+                Ok(Some(message))
+            }
+            ParsedPeerMessage::Incomplete(_) => Ok(None),
+            ParsedPeerMessage::Invalid(message, remainder) => {
+                let message = message.to_vec();
+                self.start = self.buf.len() - remainder.len();
+                Err(MessageBufferError::Invalid(message))
+            }
+        }
+    }
+
+    /// The number of bytes received but not yet consumed as messages.
+    pub fn len(&self) -> usize {
+        self.buf.len() - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.start = 0;
+    }
+}
+
+impl Default for MessageBuffer {
+    fn default() -> Self {
+        Self::new(PEERMESSAGE_MAX_LEN)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn message_buffer_test() {
+        let bytes = [
+            0, 0, 0, 1, 1, // Unchoke
+            0, 0, 0, 5, 4, 0, 0, 0, 9, // Have { index: 9 }
+            0, 0, 0, 0, // KeepAlive
+            0, 0, 0, 1, 99, // Unknown ID
+            0, 0, 0, 1, 2, // Interested
+        ];
+        let mut buffer = MessageBuffer::default();
+        let mut messages = Vec::new();
+
+        for chunk in bytes.chunks(3) {
+            buffer.extend(chunk);
+
+            while let Some(message) = buffer.next_message().transpose() {
+                messages.push(message);
+            }
+        }
+
+        assert_eq!(
+            vec![
+                Ok(PeerMessage::Unchoke),
+                Ok(PeerMessage::Have { index: 9 }),
+                Ok(PeerMessage::KeepAlive),
+                Err(MessageBufferError::Invalid(vec![0, 0, 0, 1, 99])),
+                Ok(PeerMessage::Interested),
+            ],
+            messages,
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn message_buffer_too_long_test() {
+        let mut buffer = MessageBuffer::new(4);
+        buffer.extend(&[0, 0, 0, 5, 4]);
+
+        assert_eq!(
+            Err(MessageBufferError::TooLong { len: 5, max_len: 4 }),
+            buffer.next_message(),
+        );
+        assert!(buffer.is_empty());
+    }
+}
@@ -0,0 +1,184 @@
+//! The extension protocol (BEP 10), which carries other extensions' messages inside Extended
+//! messages. Each side's extended handshake maps the names of the extensions it supports to the
+//! message IDs it wants to receive them with.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use crate::{BencodeValue, Error};
+
+/// The Extended message ID of the extended handshake itself.
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ExtendedHandshake {
+    /// `m`: the extensions supported, by name, with the message ID to send each one's messages
+    /// with. An ID of 0 means that the extension has been turned off.
+    pub extensions: HashMap<String, u8>,
+
+    /// `v`: the client's name and version.
+    pub client: Option<String>,
+
+    /// `reqq`: the most outstanding requests the client queues before dropping more.
+    pub reqq: Option<u64>,
+
+    /// `yourip`: the address that the client sees the receiving side connecting from.
+    pub yourip: Option<IpAddr>,
+
+    /// `metadata_size`: the length of the torrent's info dictionary, for `ut_metadata` (BEP 9).
+    pub metadata_size: Option<u64>,
+}
+
+impl ExtendedHandshake {
+    /// The message ID to send `extension`'s messages with, if the other side supports it.
+    pub fn extension_id(&self, extension: &str) -> Option<u8> {
+        self.extensions
+            .get(extension)
+            .copied()
+            .filter(|&id| id != EXTENDED_HANDSHAKE_ID)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        BencodeValue::from(self).encode()
+    }
+}
+
+impl TryFrom<&[u8]> for ExtendedHandshake {
+    type Error = Error;
+
+    fn try_from(input: &[u8]) -> Result<Self, Self::Error> {
+        BencodeValue::decode(input)?.try_into()
+    }
+}
+
+impl TryFrom<BencodeValue<'_>> for ExtendedHandshake {
+    type Error = Error;
+
+    /// Keys that other extensions add to the handshake are ignored, as are values of the wrong
+    /// type.
+    fn try_from(input: BencodeValue<'_>) -> Result<Self, Self::Error> {
+        let mut input_dict = input.to_dict().ok_or("Extended handshake must be a dict")?;
+
+        let extensions = input_dict
+            .remove("m".as_bytes())
+            .and_then(BencodeValue::to_dict)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(name, id)| {
+                Some((
+                    String::from_utf8(name.into_owned()).ok()?,
+                    u8::try_from(id.to_u64()?).ok()?,
+                ))
+            })
+            .collect();
+
+        let client = input_dict
+            .remove("v".as_bytes())
+            .and_then(BencodeValue::to_string);
+
+        let reqq = input_dict
+            .remove("reqq".as_bytes())
+            .and_then(BencodeValue::to_u64);
+
+        let yourip = input_dict
+            .remove("yourip".as_bytes())
+            .and_then(BencodeValue::to_bytes)
+            .and_then(|bytes| match bytes.len() {
+                4 => Some(IpAddr::from(<[u8; 4]>::try_from(&bytes[..]).unwrap())),
+                16 => Some(IpAddr::from(<[u8; 16]>::try_from(&bytes[..]).unwrap())),
+                _ => None,
+            });
+
+        let metadata_size = input_dict
+            .remove("metadata_size".as_bytes())
+            .and_then(BencodeValue::to_u64);
+
+        Ok(Self {
+            extensions,
+            client,
+            reqq,
+            yourip,
+            metadata_size,
+        })
+    }
+}
+
+impl<'a> From<&'a ExtendedHandshake> for BencodeValue<'a> {
+    fn from(input: &'a ExtendedHandshake) -> Self {
+        let extensions = BencodeValue::Dict(
+            input
+                .extensions
+                .iter()
+                .map(|(name, &id)| (name.as_bytes().into(), u64::from(id).into()))
+                .collect(),
+        );
+
+        [("m", extensions)]
+            .into_iter()
+            .chain(
+                input
+                    .metadata_size
+                    .map(|size| ("metadata_size", size.into())),
+            )
+            .chain(
+                input
+                    .client
+                    .iter()
+                    .map(|client| ("v", client.as_str().into())),
+            )
+            .chain(input.reqq.map(|reqq| ("reqq", reqq.into())))
+            .chain(input.yourip.map(|yourip| {
+                let bytes = match yourip {
+                    IpAddr::V4(ip) => ip.octets().to_vec(),
+                    IpAddr::V6(ip) => ip.octets().to_vec(),
+                };
+                ("yourip", bytes.into())
+            }))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn extended_handshake_test() {
+        let handshake = ExtendedHandshake {
+            extensions: [("ut_metadata".to_string(), 3), ("ut_pex".to_string(), 0)].into(),
+            client: Some("ToyTorrent/0.0".to_string()),
+            reqq: Some(250),
+            yourip: Some("10.0.0.1".parse().unwrap()),
+            metadata_size: Some(31235),
+        };
+
+        assert_eq!(
+            &b"d1:md11:ut_metadatai3e6:ut_pexi0ee13:metadata_sizei31235e4:reqqi250e\
+               1:v14:ToyTorrent/0.06:yourip4:\x0a\x00\x00\x01e"[..],
+            &handshake.encode()[..],
+        );
+        assert_eq!(
+            Ok(handshake.clone()),
+            ExtendedHandshake::try_from(&handshake.encode()[..])
+        );
+        assert_eq!(Some(3), handshake.extension_id("ut_metadata"));
+        assert_eq!(None, handshake.extension_id("ut_pex"));
+        assert_eq!(None, handshake.extension_id("lt_donthave"));
+
+        assert_eq!(
+            Ok(ExtendedHandshake {
+                extensions: [("ut_pex".to_string(), 1)].into(),
+                yourip: Some("::1".parse().unwrap()),
+                metadata_size: Some(1),
+                ..Default::default()
+            }),
+            ExtendedHandshake::try_from(
+                &b"d1:md6:ut_pexi1e4:badxi300ee13:metadata_sizei1e4:reqq2:no\
+                   6:yourip16:\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\x01e"[..],
+            ),
+        );
+
+        assert!(ExtendedHandshake::try_from(&b"li1ee"[..]).is_err());
+        assert!(ExtendedHandshake::try_from(&b"d1:m"[..]).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+//! The metadata extension (BEP 9), `ut_metadata`, which lets peers of a magnet link fetch the info
+//! dictionary from each other over the extension protocol, in pieces of [`METADATA_PIECE_LEN`].
+
+use crate::{BencodeValue, Error};
+
+/// The name of the extension in extended handshakes.
+pub const UT_METADATA: &str = "ut_metadata";
+
+/// The length of every piece of the metadata but the last.
+pub const METADATA_PIECE_LEN: usize = 16 * 1024;
+
+/// The longest `ut_metadata` message to accept: the message IDs, a dictionary with room for a few
+/// keys beyond the ones we know, and a whole piece.
+pub const PEERMESSAGE_METADATA_MAX_LEN: usize = 2 + 256 + METADATA_PIECE_LEN;
+
+const MSG_TYPE_REQUEST: u64 = 0;
+const MSG_TYPE_DATA: u64 = 1;
+const MSG_TYPE_REJECT: u64 = 2;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MetadataMessage {
+    Request {
+        piece: u32,
+    },
+
+    /// A piece of the metadata, along with the size of the whole.
+    Data {
+        piece: u32,
+        total_size: u64,
+        data: Vec<u8>,
+    },
+
+    /// The peer won't send the piece, such as because it doesn't have the metadata.
+    Reject {
+        piece: u32,
+    },
+}
+
+impl MetadataMessage {
+    pub fn piece(&self) -> u32 {
+        match self {
+            Self::Request { piece } | Self::Data { piece, .. } | Self::Reject { piece } => *piece,
+        }
+    }
+
+    /// The bencoded dict, followed by the piece itself for Data messages.
+    pub fn encode(&self) -> Vec<u8> {
+        let (msg_type, total_size, data) = match self {
+            Self::Request { .. } => (MSG_TYPE_REQUEST, None, &[][..]),
+            Self::Data {
+                total_size, data, ..
+            } => (MSG_TYPE_DATA, Some(*total_size), &data[..]),
+            Self::Reject { .. } => (MSG_TYPE_REJECT, None, &[][..]),
+        };
+
+        let mut encoded = [
+            ("msg_type", msg_type.into()),
+            ("piece", u64::from(self.piece()).into()),
+        ]
+        .into_iter()
+        .chain(total_size.map(|total_size| ("total_size", total_size.into())))
+        .collect::<BencodeValue>()
+        .encode();
+
+        encoded.extend_from_slice(data);
+        encoded
+    }
+}
+
+impl TryFrom<&[u8]> for MetadataMessage {
+    type Error = Error;
+
+    fn try_from(input: &[u8]) -> Result<Self, Self::Error> {
+        let (value, data) = BencodeValue::decode_prefix(input)?;
+        let mut input_dict = value.to_dict().ok_or("Metadata message must be a dict")?;
+
+        let msg_type = input_dict
+            .remove("msg_type".as_bytes())
+            .and_then(BencodeValue::to_u64)
+            .ok_or("Missing or invalid msg_type value")?;
+
+        let piece = input_dict
+            .remove("piece".as_bytes())
+            .and_then(BencodeValue::to_u64)
+            .and_then(|piece| u32::try_from(piece).ok())
+            .ok_or("Missing or invalid piece value")?;
+
+        match msg_type {
+            MSG_TYPE_REQUEST => Ok(Self::Request { piece }),
+            MSG_TYPE_DATA => {
+                let total_size = input_dict
+                    .remove("total_size".as_bytes())
+                    .and_then(BencodeValue::to_u64)
+                    .ok_or("Missing or invalid total_size value")?;
+
+                if data.len() > METADATA_PIECE_LEN {
+                    return Err(
+                        format!("Metadata piece of {} bytes is too long", data.len()).into(),
+                    );
+                }
+
+                Ok(Self::Data {
+                    piece,
+                    total_size,
+                    data: data.to_vec(),
+                })
+            }
+            MSG_TYPE_REJECT => Ok(Self::Reject { piece }),
+            msg_type => Err(format!("Unknown metadata msg_type {}", msg_type).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn metadata_message_test() {
+        let request = MetadataMessage::Request { piece: 2 };
+        assert_eq!(&b"d8:msg_typei0e5:piecei2ee"[..], &request.encode()[..]);
+        assert_eq!(
+            Ok(request.clone()),
+            MetadataMessage::try_from(&request.encode()[..])
+        );
+
+        let data = MetadataMessage::Data {
+            piece: 0,
+            total_size: 4,
+            data: b"d1:xe".to_vec(),
+        };
+        assert_eq!(
+            &b"d8:msg_typei1e5:piecei0e10:total_sizei4eed1:xe"[..],
+            &data.encode()[..],
+        );
+        assert_eq!(
+            Ok(data.clone()),
+            MetadataMessage::try_from(&data.encode()[..])
+        );
+
+        let reject = MetadataMessage::Reject { piece: 1 };
+        assert_eq!(
+            Ok(reject.clone()),
+            MetadataMessage::try_from(&reject.encode()[..])
+        );
+
+        assert!(MetadataMessage::try_from(&b"d8:msg_typei1e5:piecei0ee"[..]).is_err());
+        assert!(MetadataMessage::try_from(&b"d8:msg_typei3e5:piecei0ee"[..]).is_err());
+        assert!(MetadataMessage::try_from(&b"d5:piecei0ee"[..]).is_err());
+        assert!(MetadataMessage::try_from(&b"li1ee"[..]).is_err());
+    }
+}
@@ -1,11 +1,55 @@
+mod buffer;
+mod extension;
+mod metadata;
+
+pub use buffer::{MessageBuffer, MessageBufferError};
+pub use extension::{ExtendedHandshake, EXTENDED_HANDSHAKE_ID};
+pub use metadata::{
+    MetadataMessage, METADATA_PIECE_LEN, PEERMESSAGE_METADATA_MAX_LEN, UT_METADATA,
+};
+
 use std::io;
 
 use tokio::io::AsyncWriteExt;
 
+use super::metainfo::MerkleHash;
 use super::BlockRef;
 
-pub const PRELUDE: &'static [u8] = "\u{19}BitTorrent protocol".as_bytes();
-pub const PRELUDE_RESERVED: &'static [u8] = &[0; 8];
+pub const PRELUDE: &[u8] = "\u{19}BitTorrent protocol".as_bytes();
+pub const PRELUDE_RESERVED: &[u8] = &[0; 8];
+
+/// The bit in the last reserved handshake byte that signals DHT support (BEP 5).
+const RESERVED_DHT: u8 = 0x01;
+
+/// The bit in the last reserved handshake byte that signals BitTorrent v2 support (BEP 52).
+const RESERVED_V2: u8 = 0x10;
+
+/// The bit in the sixth reserved handshake byte that signals extension protocol support (BEP 10).
+const RESERVED_EXTENSION: u8 = 0x10;
+
+/// The reserved handshake bytes to send, advertising the extensions we support.
+pub fn reserved_bytes(dht: bool) -> [u8; 8] {
+    let mut reserved = [0; 8];
+    reserved[5] |= RESERVED_EXTENSION;
+
+    if dht {
+        reserved[7] |= RESERVED_DHT;
+    }
+
+    reserved
+}
+
+pub fn supports_dht(reserved: &[u8; 8]) -> bool {
+    reserved[7] & RESERVED_DHT != 0
+}
+
+pub fn supports_v2(reserved: &[u8; 8]) -> bool {
+    reserved[7] & RESERVED_V2 != 0
+}
+
+pub fn supports_extensions(reserved: &[u8; 8]) -> bool {
+    reserved[5] & RESERVED_EXTENSION != 0
+}
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum PeerMessage {
@@ -14,12 +58,56 @@ pub enum PeerMessage {
     Unchoke,
     Interested,
     NotInterested,
-    Have { index: u32 },
-    Bitfield { bitfield: Vec<u8> },
-    Request { block: BlockRef },
-    Piece { block: BlockRef, data: Vec<u8> },
-    Cancel { block: BlockRef },
-    Port { port: u16 },
+    Have {
+        index: u32,
+    },
+    Bitfield {
+        bitfield: Vec<u8>,
+    },
+    Request {
+        block: BlockRef,
+    },
+    Piece {
+        block: BlockRef,
+        data: Vec<u8>,
+    },
+    Cancel {
+        block: BlockRef,
+    },
+    Port {
+        port: u16,
+    },
+    /// A message of an extension negotiated with the extension protocol (BEP 10), with the ID
+    /// that the receiving side assigned it, or [`EXTENDED_HANDSHAKE_ID`] for the negotiation.
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    HashRequest {
+        request: HashRequest,
+    },
+    Hashes {
+        request: HashRequest,
+        hashes: Vec<MerkleHash>,
+    },
+    HashReject {
+        request: HashRequest,
+    },
+}
+
+/// A request for a run of hashes from one layer of a file's hash tree (BEP 52).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HashRequest {
+    pub pieces_root: MerkleHash,
+
+    /// The layer of the tree, counting up from the blocks at 0.
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+
+    /// The number of layers of uncle hashes to include above the requested hashes, so that they
+    /// can be checked against the root.
+    pub proof_layers: u32,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -45,6 +133,10 @@ const PEERMESSAGE_REQUEST: u8 = 6;
 const PEERMESSAGE_PIECE: u8 = 7;
 const PEERMESSAGE_CANCEL: u8 = 8;
 const PEERMESSAGE_PORT: u8 = 9;
+const PEERMESSAGE_EXTENDED: u8 = 20;
+const PEERMESSAGE_HASH_REQUEST: u8 = 21;
+const PEERMESSAGE_HASHES: u8 = 22;
+const PEERMESSAGE_HASH_REJECT: u8 = 23;
 
 const PEERMESSAGE_KEEP_ALIVE_LEN: u32 = 0;
 const PEERMESSAGE_CHOKE_LEN: u32 = 1;
@@ -57,9 +149,21 @@ const PEERMESSAGE_REQUEST_LEN: u32 = 13;
 const PEERMESSAGE_PIECE_MIN_LEN: u32 = 9;
 const PEERMESSAGE_CANCEL_LEN: u32 = 13;
 const PEERMESSAGE_PORT_LEN: u32 = 3;
+const PEERMESSAGE_EXTENDED_MIN_LEN: u32 = 2;
+const PEERMESSAGE_HASH_REQUEST_LEN: u32 = 49;
+const PEERMESSAGE_HASHES_MIN_LEN: u32 = 49;
+const PEERMESSAGE_HASH_REJECT_LEN: u32 = 49;
 
 const PIECE_MAX_LEN: u32 = 16 * 1024;
 pub const PEERMESSAGE_PIECE_MAX_LEN: usize = (PEERMESSAGE_PIECE_MIN_LEN + PIECE_MAX_LEN) as usize;
+
+/// The longest message of any kind to accept.
+pub const PEERMESSAGE_MAX_LEN: usize = if PEERMESSAGE_METADATA_MAX_LEN > PEERMESSAGE_PIECE_MAX_LEN {
+    PEERMESSAGE_METADATA_MAX_LEN
+} else {
+    PEERMESSAGE_PIECE_MAX_LEN
+};
+
 pub const PEERMESSAGE_OVERHEAD_MAX_LEN: usize = PEERMESSAGE_REQUEST_LEN as usize;
 
 impl PeerMessage {
@@ -129,6 +233,40 @@ impl PeerMessage {
                 l += w.write(&[PEERMESSAGE_PORT][..]).await?;
                 l += w.write(&port.to_be_bytes()[..]).await?;
             }
+            Self::Extended { id, payload } => {
+                l += w
+                    .write(&(PEERMESSAGE_EXTENDED_MIN_LEN + payload.len() as u32).to_be_bytes()[..])
+                    .await?;
+                l += w.write(&[PEERMESSAGE_EXTENDED, id][..]).await?;
+                l += w.write(&payload[..]).await?;
+            }
+            Self::HashRequest { request } => {
+                l += w
+                    .write(&PEERMESSAGE_HASH_REQUEST_LEN.to_be_bytes()[..])
+                    .await?;
+                l += w.write(&[PEERMESSAGE_HASH_REQUEST][..]).await?;
+                l += w.write(&request.to_be_bytes()[..]).await?;
+            }
+            Self::Hashes { request, hashes } => {
+                l += w
+                    .write(
+                        &(PEERMESSAGE_HASHES_MIN_LEN + 32 * hashes.len() as u32).to_be_bytes()[..],
+                    )
+                    .await?;
+                l += w.write(&[PEERMESSAGE_HASHES][..]).await?;
+                l += w.write(&request.to_be_bytes()[..]).await?;
+
+                for hash in hashes {
+                    l += w.write(hash.as_slice()).await?;
+                }
+            }
+            Self::HashReject { request } => {
+                l += w
+                    .write(&PEERMESSAGE_HASH_REJECT_LEN.to_be_bytes()[..])
+                    .await?;
+                l += w.write(&[PEERMESSAGE_HASH_REJECT][..]).await?;
+                l += w.write(&request.to_be_bytes()[..]).await?;
+            }
         }
 
         Ok(l)
@@ -144,12 +282,15 @@ impl<'a> From<&'a [u8]> for ParsedPeerMessage<'a> {
             return ParsedPeerMessage::Incomplete(input);
         };
 
-        if let Some(message) = input.get(1..len) {
-            let remainder = input.get(len..).unwrap_or(&[][..]);
+        // The length prefix does not include itself.
+        let end = len.saturating_add(4);
+
+        if let Some(message) = input.get(4..end) {
+            let remainder = &input[end..];
 
             PeerMessage::try_from(message)
                 .map(|m| ParsedPeerMessage::Complete(m, remainder))
-                .unwrap_or(ParsedPeerMessage::Invalid(&input[..len], remainder))
+                .unwrap_or(ParsedPeerMessage::Invalid(&input[..end], remainder))
         } else {
             ParsedPeerMessage::Incomplete(input)
         }
@@ -211,7 +352,198 @@ impl<'a> TryFrom<&'a [u8]> for PeerMessage {
                 port: u16::from_be_bytes(input[1..3].try_into().unwrap()),
             }),
             (PEERMESSAGE_PORT, len) => Err(PeerMessageError::BadLength("PORT", len, input)),
+            (PEERMESSAGE_EXTENDED, len) if len >= PEERMESSAGE_EXTENDED_MIN_LEN => {
+                Ok(PeerMessage::Extended {
+                    id: input[1],
+                    payload: input[2..].to_vec(),
+                })
+            }
+            (PEERMESSAGE_EXTENDED, len) => Err(PeerMessageError::BadLength("EXTENDED", len, input)),
+            (PEERMESSAGE_HASH_REQUEST, PEERMESSAGE_HASH_REQUEST_LEN) => {
+                Ok(PeerMessage::HashRequest {
+                    request: HashRequest::from_be_bytes(input[1..49].try_into().unwrap()),
+                })
+            }
+            (PEERMESSAGE_HASH_REQUEST, len) => {
+                Err(PeerMessageError::BadLength("HASH_REQUEST", len, input))
+            }
+            (PEERMESSAGE_HASHES, len)
+                if len >= PEERMESSAGE_HASHES_MIN_LEN
+                    && (len - PEERMESSAGE_HASHES_MIN_LEN).is_multiple_of(32) =>
+            {
+                Ok(PeerMessage::Hashes {
+                    request: HashRequest::from_be_bytes(input[1..49].try_into().unwrap()),
+                    hashes: input[49..]
+                        .chunks_exact(32)
+                        .map(|hash| hash.try_into().unwrap())
+                        .collect(),
+                })
+            }
+            (PEERMESSAGE_HASHES, len) => Err(PeerMessageError::BadLength("HASHES", len, input)),
+            (PEERMESSAGE_HASH_REJECT, PEERMESSAGE_HASH_REJECT_LEN) => Ok(PeerMessage::HashReject {
+                request: HashRequest::from_be_bytes(input[1..49].try_into().unwrap()),
+            }),
+            (PEERMESSAGE_HASH_REJECT, len) => {
+                Err(PeerMessageError::BadLength("HASH_REJECT", len, input))
+            }
             (i, _) => Err(PeerMessageError::UnknownId(i, input)),
         }
     }
 }
+
+impl HashRequest {
+    pub fn to_be_bytes(&self) -> [u8; 48] {
+        let mut bytes = [0; 48];
+        bytes[..32].copy_from_slice(self.pieces_root.as_slice());
+        bytes[32..36].copy_from_slice(&self.base_layer.to_be_bytes());
+        bytes[36..40].copy_from_slice(&self.index.to_be_bytes());
+        bytes[40..44].copy_from_slice(&self.length.to_be_bytes());
+        bytes[44..48].copy_from_slice(&self.proof_layers.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_be_bytes(bytes: &[u8; 48]) -> Self {
+        let u32_at = |i: usize| u32::from_be_bytes(bytes[i..i + 4].try_into().unwrap());
+
+        Self {
+            pieces_root: <[u8; 32]>::try_from(&bytes[..32]).unwrap().into(),
+            base_layer: u32_at(32),
+            index: u32_at(36),
+            length: u32_at(40),
+            proof_layers: u32_at(44),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn round_trip(message: PeerMessage) {
+        let mut buf = Vec::new();
+        let len = message.clone().write_to(&mut buf).await.unwrap();
+
+        assert_eq!(buf.len(), len, "{:?}", message);
+        assert_eq!(
+            ParsedPeerMessage::Complete(message.clone(), &[][..]),
+            ParsedPeerMessage::from(&buf[..]),
+        );
+        assert_eq!(Ok(message), PeerMessage::try_from(&buf[4..]));
+    }
+
+    fn block(length: u32) -> BlockRef {
+        BlockRef::from_be_bytes_with_len([0, 0, 0, 7, 0, 0, 0x40, 0], length)
+    }
+
+    #[tokio::test]
+    async fn round_trip_test() {
+        round_trip(PeerMessage::KeepAlive).await;
+        round_trip(PeerMessage::Choke).await;
+        round_trip(PeerMessage::Unchoke).await;
+        round_trip(PeerMessage::Interested).await;
+        round_trip(PeerMessage::NotInterested).await;
+        round_trip(PeerMessage::Have { index: 0 }).await;
+        round_trip(PeerMessage::Have { index: u32::MAX }).await;
+        round_trip(PeerMessage::Bitfield { bitfield: vec![] }).await;
+        round_trip(PeerMessage::Bitfield {
+            bitfield: vec![0xff, 0x00, 0x80],
+        })
+        .await;
+        round_trip(PeerMessage::Request { block: block(0) }).await;
+        round_trip(PeerMessage::Request {
+            block: block(PIECE_MAX_LEN),
+        })
+        .await;
+        round_trip(PeerMessage::Piece {
+            block: block(0),
+            data: vec![],
+        })
+        .await;
+        round_trip(PeerMessage::Piece {
+            block: block(PIECE_MAX_LEN),
+            data: vec![0xa5; PIECE_MAX_LEN as usize],
+        })
+        .await;
+        round_trip(PeerMessage::Cancel { block: block(1) }).await;
+        round_trip(PeerMessage::Port { port: 0 }).await;
+        round_trip(PeerMessage::Port { port: u16::MAX }).await;
+        round_trip(PeerMessage::Extended {
+            id: EXTENDED_HANDSHAKE_ID,
+            payload: ExtendedHandshake::default().encode(),
+        })
+        .await;
+        round_trip(PeerMessage::Extended {
+            id: 3,
+            payload: vec![],
+        })
+        .await;
+
+        let request = HashRequest {
+            pieces_root: [0xa5; 32].into(),
+            base_layer: 2,
+            index: 4,
+            length: 2,
+            proof_layers: 1,
+        };
+        round_trip(PeerMessage::HashRequest { request }).await;
+        round_trip(PeerMessage::Hashes {
+            request,
+            hashes: vec![[1; 32].into(), [2; 32].into(), [3; 32].into()],
+        })
+        .await;
+        round_trip(PeerMessage::HashReject { request }).await;
+    }
+
+    #[tokio::test]
+    async fn parsed_peer_message_test() {
+        let mut buf = Vec::new();
+        PeerMessage::Have { index: 3 }
+            .write_to(&mut buf)
+            .await
+            .unwrap();
+        PeerMessage::Unchoke.write_to(&mut buf).await.unwrap();
+
+        let ParsedPeerMessage::Complete(message, remainder) = ParsedPeerMessage::from(&buf[..])
+        else {
+            panic!("Expected a complete message");
+        };
+        assert_eq!(PeerMessage::Have { index: 3 }, message);
+        assert_eq!(&buf[9..], remainder);
+
+        assert_eq!(
+            ParsedPeerMessage::Complete(PeerMessage::Unchoke, &[][..]),
+            ParsedPeerMessage::from(remainder),
+        );
+
+        for i in 0..9 {
+            assert_eq!(
+                ParsedPeerMessage::Incomplete(&buf[..i]),
+                ParsedPeerMessage::from(&buf[..i]),
+            );
+        }
+    }
+
+    #[test]
+    fn parsed_peer_message_invalid_test() {
+        let buf = [0, 0, 0, 2, PEERMESSAGE_CHOKE, 0, 0, 0, 0, 0];
+
+        assert_eq!(
+            ParsedPeerMessage::Invalid(&buf[..6], &buf[6..]),
+            ParsedPeerMessage::from(&buf[..]),
+        );
+
+        assert_eq!(
+            Err(PeerMessageError::UnknownId(0xff, &[0xff][..])),
+            PeerMessage::try_from(&[0xff][..]),
+        );
+
+        assert_eq!(
+            Err(PeerMessageError::BadLength(
+                "HAVE",
+                4,
+                &[PEERMESSAGE_HAVE, 0, 0, 0][..],
+            )),
+            PeerMessage::try_from(&[PEERMESSAGE_HAVE, 0, 0, 0][..]),
+        );
+    }
+}
@@ -1,8 +1,10 @@
 mod peer;
 mod response;
+mod scrape;
 
 pub use peer::Peer;
-pub use response::{FailureResponse, Response, SuccessResponse};
+pub use response::{FailureResponse, Response, RetryIn, SuccessResponse};
+pub use scrape::{scrape_url, ScrapeFile, ScrapeRequest, ScrapeResponse, SuccessScrapeResponse};
 
 use std::iter;
 use std::net::{IpAddr, SocketAddr};
@@ -77,6 +79,7 @@ impl Request {
             key: self.key.clone(),
             supportcrypto: self.supportcrypto,
             requirecrypto: self.requirecrypto,
+            connectable: None,
         }
     }
 
@@ -137,9 +140,9 @@ impl Request {
         query_string
     }
 
-    fn url_encode(slice: &[u8]) -> String {
+    pub(crate) fn url_encode(slice: &[u8]) -> String {
         slice
-            .into_iter()
+            .iter()
             .flat_map(|&i| {
                 let is_legal = i.is_ascii_alphanumeric();
                 iter::once(if is_legal { i as char } else { '%' }).chain(
@@ -238,14 +241,12 @@ impl FromStr for Request {
             }
         }
 
-        if let (
-            Some(info_hash),
-            Some(peer_id),
-            Some(port),
-            Some(uploaded),
-            Some(downloaded),
-            Some(left),
-        ) = (info_hash, peer_id, port, uploaded, downloaded, left)
+        // A missing port is accepted so that the announce still counts towards the swarm stats,
+        // but port 0 marks the peer as not connectable.
+        let port = port.unwrap_or(0);
+
+        if let (Some(info_hash), Some(peer_id), Some(uploaded), Some(downloaded), Some(left)) =
+            (info_hash, peer_id, uploaded, downloaded, left)
         {
             Ok(Request {
                 info_hash,
@@ -5,7 +5,7 @@ use std::net::{IpAddr, SocketAddr};
 use std::time::Instant;
 
 use crate::bencode::BencodeValue;
-use crate::{Error, PeerId, PeerKey};
+use crate::{Bytes, Error, PeerId, PeerKey};
 
 #[derive(Clone, Debug, Eq)]
 pub struct Peer {
@@ -19,6 +19,7 @@ pub struct Peer {
     pub key: Option<PeerKey>,
     pub supportcrypto: Option<bool>,
     pub requirecrypto: Option<bool>,
+    pub connectable: Option<bool>,
 }
 
 impl fmt::Display for Peer {
@@ -30,23 +31,27 @@ impl fmt::Display for Peer {
         }
 
         if let Some(uploaded) = self.uploaded {
-            write!(f, "{} uploaded, ", uploaded)?;
+            write!(f, "{} uploaded, ", Bytes::from(uploaded))?;
         } else {
             write!(f, "? uploaded, ")?;
         }
 
         if let Some(downloaded) = self.downloaded {
-            write!(f, "{} downloaded, ", downloaded)?;
+            write!(f, "{} downloaded, ", Bytes::from(downloaded))?;
         } else {
             write!(f, "? downloaded, ")?;
         }
 
         if let Some(left) = self.left {
-            write!(f, "{} left", left)?;
+            write!(f, "{} left", Bytes::from(left))?;
         } else {
             write!(f, "? left")?;
         }
 
+        if self.connectable == Some(false) {
+            write!(f, " (unconnectable)")?;
+        }
+
         Ok(())
     }
 }
@@ -97,11 +102,15 @@ impl TryFrom<BencodeValue<'_>> for Peer {
     fn try_from(input: BencodeValue<'_>) -> Result<Self, Self::Error> {
         let mut input_dict = input.to_dict().ok_or("Peer value must be a dict")?;
 
+        // Trackers leave the peer ID out when asked to with `no_peer_id`.
         let peer_id = input_dict
-            .remove("peer_id".as_bytes())
-            .and_then(|benc| benc.to_bytes())
-            .and_then(|b| b.as_ref().try_into().ok())
-            .ok_or("Missing or invalid peer_id value")?;
+            .remove("peer id".as_bytes())
+            .map(|benc| {
+                benc.to_bytes()
+                    .and_then(|b| b.as_ref().try_into().ok())
+                    .ok_or("Invalid peer id value")
+            })
+            .transpose()?;
 
         let ip = input_dict
             .remove("ip".as_bytes())
@@ -117,7 +126,7 @@ impl TryFrom<BencodeValue<'_>> for Peer {
 
         Ok(Peer {
             last_seen: Instant::now(),
-            peer_id: Some(peer_id),
+            peer_id,
             addr: SocketAddr::new(ip, port),
             uploaded: None,
             downloaded: None,
@@ -125,6 +134,7 @@ impl TryFrom<BencodeValue<'_>> for Peer {
             key: None,
             supportcrypto: None,
             requirecrypto: None,
+            connectable: None,
         })
     }
 }
@@ -154,6 +164,7 @@ impl TryFrom<&[u8]> for Peer {
             key: None,
             supportcrypto: None,
             requirecrypto: None,
+            connectable: None,
         })
     }
 }
@@ -172,7 +183,7 @@ impl TryFrom<Peer> for [u8; 6] {
             .ip()
             .octets()
             .into_iter()
-            .chain(ipv4_addr.port().to_be_bytes().into_iter())
+            .chain(ipv4_addr.port().to_be_bytes())
             .enumerate()
             .for_each(|(i, v)| result[i] = v);
 
@@ -208,32 +219,55 @@ mod test {
 
         let mut set = HashSet::new();
 
-        assert_eq!(
-            true,
-            set.insert(Peer {
-                last_seen: Instant::now(),
-                peer_id: Some([0; 20].into()),
-                ip: Ipv4Addr::LOCALHOST.into(),
-                port: 65535,
-                uploaded: None,
-                downloaded: None,
-                left: None,
-            }),
-        );
+        assert!(set.insert(Peer {
+            last_seen: Instant::now(),
+            peer_id: Some([0; 20].into()),
+            addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 65535),
+            uploaded: None,
+            downloaded: None,
+            left: None,
+            key: None,
+            supportcrypto: None,
+            requirecrypto: None,
+            connectable: None,
+        }),);
 
+        assert!(!set.insert(Peer {
+            last_seen: Instant::now(),
+            peer_id: Some([0; 20].into()),
+            addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 65535),
+            uploaded: None,
+            downloaded: None,
+            left: None,
+            key: None,
+            supportcrypto: None,
+            requirecrypto: None,
+            connectable: None,
+        }),);
+
+        assert_eq!(1, set.len());
+    }
+
+    #[test]
+    fn dict_test() {
+        let peer = Peer::try_from(
+            BencodeValue::decode(b"d2:ip9:127.0.0.17:peer id20:-ts0000-abcdefghijkl4:porti6881ee")
+                .unwrap(),
+        )
+        .unwrap();
         assert_eq!(
-            false,
-            set.insert(Peer {
-                last_seen: Instant::now(),
-                peer_id: Some([0; 20].into()),
-                ip: Ipv4Addr::LOCALHOST.into(),
-                port: 65535,
-                uploaded: None,
-                downloaded: None,
-                left: None,
-            }),
+            Some(*b"-ts0000-abcdefghijkl"),
+            peer.peer_id.map(|peer_id| peer_id.0)
         );
+        assert_eq!(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 6881), peer.addr);
 
-        assert_eq!(1, set.len());
+        let peer = Peer::try_from(BencodeValue::decode(b"d2:ip9:127.0.0.14:porti6881ee").unwrap())
+            .unwrap();
+        assert_eq!(None, peer.peer_id);
+
+        assert!(Peer::try_from(
+            BencodeValue::decode(b"d2:ip9:127.0.0.17:peer id3:abc4:porti6881ee").unwrap()
+        )
+        .is_err());
     }
 }
@@ -1,3 +1,7 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::time::Duration;
+
 use super::Peer;
 
 use crate::bencode::BencodeValue;
@@ -17,12 +21,66 @@ pub struct SuccessResponse {
     pub tracker_id: Option<Vec<u8>>,
     pub complete: Option<u64>,
     pub incomplete: Option<u64>,
+
+    /// BEP 48: the number of times the torrent has been downloaded, as a scrape would report.
+    pub downloaded: Option<u64>,
+
+    /// BEP 48: the number of peers that are downloading right now.
+    pub downloaders: Option<u64>,
+
     pub peers: Vec<Peer>,
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
 pub struct FailureResponse {
     pub failure_reason: String,
+
+    /// BEP 31: a numeric code for the failure.
+    pub failure_code: Option<u64>,
+
+    /// BEP 31: when, if ever, to try this tracker again.
+    pub retry_in: Option<RetryIn>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RetryIn {
+    Minutes(u64),
+    Never,
+}
+
+impl FailureResponse {
+    /// Take the failure keys out of a response dict, if it is a failure.
+    pub(super) fn take_from(dict: &mut HashMap<Cow<'_, [u8]>, BencodeValue<'_>>) -> Option<Self> {
+        let failure_reason = dict
+            .remove("failure reason".as_bytes())
+            .and_then(BencodeValue::to_string)?;
+
+        let failure_code = dict
+            .remove("failure code".as_bytes())
+            .and_then(BencodeValue::to_u64);
+
+        let retry_in = match dict.remove("retry in".as_bytes()) {
+            Some(BencodeValue::Bytes(never)) if never.as_ref() == b"never" => Some(RetryIn::Never),
+            Some(minutes) => minutes.to_u64().map(RetryIn::Minutes),
+            None => None,
+        };
+
+        Some(Self {
+            failure_reason,
+            failure_code,
+            retry_in,
+        })
+    }
+}
+
+impl RetryIn {
+    /// How long to wait, or `None` to never try again.
+    pub fn duration(&self) -> Option<Duration> {
+        match self {
+            Self::Minutes(minutes) => Some(Duration::from_secs(minutes.saturating_mul(60))),
+            Self::Never => None,
+        }
+    }
 }
 
 impl From<SuccessResponse> for Response {
@@ -51,11 +109,8 @@ impl TryFrom<BencodeValue<'_>> for Response {
     fn try_from(input: BencodeValue<'_>) -> Result<Self, Self::Error> {
         let mut input_dict = input.to_dict().ok_or("Response value must be a dict")?;
 
-        if let Some(failure_reason) = input_dict
-            .remove("failure reason".as_bytes())
-            .and_then(BencodeValue::to_string)
-        {
-            Ok(Response::Failure(FailureResponse { failure_reason }))
+        if let Some(failure) = FailureResponse::take_from(&mut input_dict) {
+            Ok(Response::Failure(failure))
         } else if let (Some(BencodeValue::Integer(interval_value)), Some(peers_value)) = (
             input_dict.remove("interval".as_bytes()),
             input_dict.remove("peers".as_bytes()),
@@ -71,7 +126,7 @@ impl TryFrom<BencodeValue<'_>> for Response {
                 .and_then(BencodeValue::to_u64);
 
             let tracker_id = input_dict
-                .remove("tracker_id".as_bytes())
+                .remove("tracker id".as_bytes())
                 .and_then(BencodeValue::to_bytes)
                 .map(|v| v.to_vec());
 
@@ -83,16 +138,24 @@ impl TryFrom<BencodeValue<'_>> for Response {
                 .remove("incomplete".as_bytes())
                 .and_then(BencodeValue::to_u64);
 
+            let downloaded = input_dict
+                .remove("downloaded".as_bytes())
+                .and_then(BencodeValue::to_u64);
+
+            let downloaders = input_dict
+                .remove("downloaders".as_bytes())
+                .and_then(BencodeValue::to_u64);
+
             let peers = match peers_value {
                 BencodeValue::List(peer_list) => peer_list
                     .into_iter()
-                    .map(|peer| Peer::try_from(peer))
+                    .map(Peer::try_from)
                     .collect::<Result<Vec<_>, _>>()?,
                 BencodeValue::Bytes(peer_bytes) => {
                     if peer_bytes.len() % 6 == 0 {
                         peer_bytes
                             .chunks_exact(6)
-                            .map(|chunk| Peer::try_from(chunk))
+                            .map(Peer::try_from)
                             .collect::<Result<Vec<Peer>, _>>()?
                     } else {
                         return Err("Short peer list must be a multiple of 6 bytes long".into());
@@ -108,6 +171,8 @@ impl TryFrom<BencodeValue<'_>> for Response {
                 tracker_id,
                 complete,
                 incomplete,
+                downloaded,
+                downloaders,
                 peers,
             }))
         } else {
@@ -132,6 +197,8 @@ impl<'a> From<&'a Response> for BencodeValue<'a> {
                 tracker_id,
                 complete,
                 incomplete,
+                downloaded,
+                downloaders,
                 peers,
             }) => [
                 ("interval", (*interval).into()),
@@ -140,23 +207,94 @@ impl<'a> From<&'a Response> for BencodeValue<'a> {
             .into_iter()
             .chain(
                 warning_message
-                    .into_iter()
+                    .iter()
                     .map(|s| ("warning message", s.as_str().into())),
             )
+            .chain(min_interval.iter().map(|&i| ("min interval", i.into())))
+            .chain(tracker_id.iter().map(|b| ("tracker id", b[..].into())))
+            .chain(complete.iter().map(|&i| ("complete", i.into())))
+            .chain(incomplete.iter().map(|&i| ("incomplete", i.into())))
+            .chain(downloaded.iter().map(|&i| ("downloaded", i.into())))
+            .chain(downloaders.iter().map(|&i| ("downloaders", i.into())))
+            .collect(),
+            Response::Failure(failure) => failure.into(),
+        }
+    }
+}
+
+impl<'a> From<&'a FailureResponse> for BencodeValue<'a> {
+    fn from(input: &'a FailureResponse) -> Self {
+        [("failure reason", input.failure_reason.as_str().into())]
+            .into_iter()
             .chain(
-                min_interval
+                input
+                    .failure_code
                     .into_iter()
-                    .map(|&i| ("min interval", i.into())),
+                    .map(|code| ("failure code", code.into())),
             )
-            .chain(tracker_id.into_iter().map(|b| ("tracker id", b[..].into())))
-            .chain(complete.into_iter().map(|&i| ("complete", i.into())))
-            .chain(incomplete.into_iter().map(|&i| ("incomplete", i.into())))
-            .collect(),
-            Response::Failure(FailureResponse { failure_reason }) => {
-                [("failure reason", failure_reason.as_str().into())]
-                    .into_iter()
-                    .collect()
-            }
-        }
+            .chain(input.retry_in.into_iter().map(|retry_in| match retry_in {
+                RetryIn::Minutes(minutes) => ("retry in", minutes.into()),
+                RetryIn::Never => ("retry in", "never".into()),
+            }))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn failure_test() {
+        assert_eq!(
+            Response::Failure(FailureResponse {
+                failure_reason: "Unregistered torrent".to_string(),
+                failure_code: Some(200),
+                retry_in: Some(RetryIn::Never),
+            }),
+            Response::try_from(
+                &b"d14:failure reason20:Unregistered torrent12:failure codei200e8:retry in5:nevere"
+                    [..],
+            )
+            .unwrap(),
+        );
+
+        let failure = Response::Failure(FailureResponse {
+            failure_reason: "Slow down".to_string(),
+            failure_code: None,
+            retry_in: Some(RetryIn::Minutes(30)),
+        });
+        let encoded: Vec<u8> = (&failure).into();
+        assert_eq!(failure, Response::try_from(&encoded[..]).unwrap());
+
+        assert_eq!(
+            Some(Duration::from_secs(1800)),
+            RetryIn::Minutes(30).duration()
+        );
+        assert_eq!(None, RetryIn::Never.duration());
+    }
+
+    #[test]
+    fn swarm_stats_test() {
+        let response = Response::try_from(
+            &b"d8:completei5e10:downloadedi50e11:downloadersi3e10:incompletei10e8:intervali600e5:peers0:e"[..],
+        )
+        .unwrap();
+
+        let Response::Success(success) = &response else {
+            panic!("Expected a success response");
+        };
+        assert_eq!(
+            (Some(5), Some(10), Some(50), Some(3)),
+            (
+                success.complete,
+                success.incomplete,
+                success.downloaded,
+                success.downloaders
+            ),
+        );
+
+        let encoded: Vec<u8> = (&response).into();
+        assert_eq!(response, Response::try_from(&encoded[..]).unwrap());
     }
 }
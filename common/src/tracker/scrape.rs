@@ -0,0 +1,254 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use url::Url;
+
+use super::{FailureResponse, Request};
+
+use crate::bencode::BencodeValue;
+use crate::{Error, InfoHash};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ScrapeRequest {
+    pub info_hashes: Vec<InfoHash>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ScrapeResponse {
+    Success(SuccessScrapeResponse),
+    Failure(FailureResponse),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SuccessScrapeResponse {
+    pub files: BTreeMap<InfoHash, ScrapeFile>,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ScrapeFile {
+    pub complete: u64,
+    pub downloaded: u64,
+    pub incomplete: u64,
+    pub name: Option<String>,
+}
+
+/// Derive the scrape URL from an announce URL by the usual convention: if the last path
+/// component begins with "announce", replace that with "scrape". Trackers whose announce URLs
+/// don't follow this convention don't support scraping.
+pub fn scrape_url(announce_url: &str) -> Option<String> {
+    let mut url = Url::parse(announce_url).ok()?;
+    let mut segments: Vec<&str> = url.path_segments()?.collect();
+    let last = format!("scrape{}", segments.pop()?.strip_prefix("announce")?);
+    segments.push(&last);
+
+    // The segments are still percent-encoded, which setting the path leaves alone.
+    url.set_path(&segments.join("/"));
+
+    Some(url.into())
+}
+
+impl ScrapeRequest {
+    pub fn as_query_string(&self) -> String {
+        self.info_hashes
+            .iter()
+            .map(|info_hash| format!("info_hash={}", Request::url_encode(info_hash.as_slice())))
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+}
+
+impl FromStr for ScrapeRequest {
+    type Err = &'static str;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let mut info_hashes = Vec::new();
+
+        for clause in input.split('&') {
+            if let Some(("info_hash", value)) = clause.split_once('=') {
+                info_hashes.push(value.parse()?);
+            }
+        }
+
+        Ok(ScrapeRequest { info_hashes })
+    }
+}
+
+impl From<SuccessScrapeResponse> for ScrapeResponse {
+    fn from(input: SuccessScrapeResponse) -> Self {
+        ScrapeResponse::Success(input)
+    }
+}
+
+impl From<FailureResponse> for ScrapeResponse {
+    fn from(input: FailureResponse) -> Self {
+        ScrapeResponse::Failure(input)
+    }
+}
+
+impl TryFrom<&[u8]> for ScrapeResponse {
+    type Error = Error;
+
+    fn try_from(input: &[u8]) -> Result<Self, Self::Error> {
+        BencodeValue::decode(input)?.try_into()
+    }
+}
+
+impl TryFrom<BencodeValue<'_>> for ScrapeResponse {
+    type Error = Error;
+
+    fn try_from(input: BencodeValue<'_>) -> Result<Self, Self::Error> {
+        let mut input_dict = input
+            .to_dict()
+            .ok_or("Scrape response value must be a dict")?;
+
+        if let Some(failure) = FailureResponse::take_from(&mut input_dict) {
+            return Ok(ScrapeResponse::Failure(failure));
+        }
+
+        let files_dict = input_dict
+            .remove("files".as_bytes())
+            .and_then(BencodeValue::to_dict)
+            .ok_or("Scrape response must contain a \"files\" dict")?;
+
+        let mut files = BTreeMap::new();
+
+        for (key, value) in files_dict {
+            let info_hash = <[u8; 20]>::try_from(&key[..])
+                .map_err(|_| "Scrape response keys must be 20-byte info hashes")?;
+
+            let mut file_dict = value
+                .to_dict()
+                .ok_or("Scrape response file value must be a dict")?;
+
+            let mut get_u64 = |key: &str| {
+                file_dict
+                    .remove(key.as_bytes())
+                    .and_then(BencodeValue::to_u64)
+                    .unwrap_or(0)
+            };
+
+            let (complete, downloaded, incomplete) = (
+                get_u64("complete"),
+                get_u64("downloaded"),
+                get_u64("incomplete"),
+            );
+
+            let name = file_dict
+                .remove("name".as_bytes())
+                .and_then(BencodeValue::to_string);
+
+            files.insert(
+                info_hash.into(),
+                ScrapeFile {
+                    complete,
+                    downloaded,
+                    incomplete,
+                    name,
+                },
+            );
+        }
+
+        Ok(ScrapeResponse::Success(SuccessScrapeResponse { files }))
+    }
+}
+
+impl From<&ScrapeResponse> for Vec<u8> {
+    fn from(input: &ScrapeResponse) -> Self {
+        BencodeValue::from(input).encode()
+    }
+}
+
+impl<'a> From<&'a ScrapeResponse> for BencodeValue<'a> {
+    fn from(input: &'a ScrapeResponse) -> Self {
+        match input {
+            ScrapeResponse::Success(SuccessScrapeResponse { files }) => [(
+                "files",
+                BencodeValue::Dict(
+                    files
+                        .iter()
+                        .map(|(info_hash, file)| {
+                            (
+                                Cow::Borrowed(info_hash.as_slice()),
+                                BencodeValue::from(file),
+                            )
+                        })
+                        .collect(),
+                ),
+            )]
+            .into_iter()
+            .collect(),
+            ScrapeResponse::Failure(failure) => failure.into(),
+        }
+    }
+}
+
+impl<'a> From<&'a ScrapeFile> for BencodeValue<'a> {
+    fn from(input: &'a ScrapeFile) -> Self {
+        [
+            ("complete", input.complete.into()),
+            ("downloaded", input.downloaded.into()),
+            ("incomplete", input.incomplete.into()),
+        ]
+        .into_iter()
+        .chain(input.name.iter().map(|s| ("name", s.as_str().into())))
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scrape_url_test() {
+        assert_eq!(
+            Some("http://example.com/scrape".to_string()),
+            scrape_url("http://example.com/announce"),
+        );
+        assert_eq!(
+            Some("http://example.com/x/scrape.php?passkey=1".to_string()),
+            scrape_url("http://example.com/x/announce.php?passkey=1"),
+        );
+        assert_eq!(None, scrape_url("http://example.com/a"));
+        assert_eq!(None, scrape_url("http://example.com/x/announce/y"));
+
+        // Only the path is looked at, even if the query has slashes in it.
+        assert_eq!(
+            Some("http://example.com/scrape?next=/x/y".to_string()),
+            scrape_url("http://example.com/announce?next=/x/y"),
+        );
+        assert_eq!(None, scrape_url("http://example.com/a?next=/announce"));
+        assert_eq!(
+            Some("udp://example.com:6969/scrape%2Ephp".to_string()),
+            scrape_url("udp://example.com:6969/announce%2Ephp"),
+        );
+        assert_eq!(None, scrape_url("not a url/announce"));
+    }
+
+    #[test]
+    fn scrape_response_round_trip_test() {
+        let response: ScrapeResponse = SuccessScrapeResponse {
+            files: [(
+                InfoHash::from([0x61; 20]),
+                ScrapeFile {
+                    complete: 5,
+                    downloaded: 50,
+                    incomplete: 10,
+                    name: Some("file.iso".to_string()),
+                },
+            )]
+            .into_iter()
+            .collect(),
+        }
+        .into();
+
+        let bytes = Vec::<u8>::from(&response);
+
+        assert_eq!(
+            &b"d5:filesd20:aaaaaaaaaaaaaaaaaaaad8:completei5e10:downloadedi50e10:incompletei10e4:name8:file.isoeee"[..],
+            &bytes[..],
+        );
+        assert_eq!(Ok(response), ScrapeResponse::try_from(&bytes[..]));
+    }
+}
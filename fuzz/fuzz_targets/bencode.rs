@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use toytorrent_common as common;
+
+fuzz_target!(|data: &[u8]| {
+    // Whatever decodes has to survive encoding. The bytes may differ, since dict keys are sorted
+    // on the way out, but the value may not.
+    if let Ok(value) = common::BencodeValue::decode(data) {
+        let encoded = value.encode();
+        assert_eq!(Ok(value), common::BencodeValue::decode(&encoded));
+    }
+});
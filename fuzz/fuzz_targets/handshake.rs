@@ -0,0 +1,30 @@
+#![no_main]
+
+use std::io;
+
+use libfuzzer_sys::fuzz_target;
+use tokio::io::AsyncWriteExt;
+
+use toytorrent_net as net;
+
+fuzz_target!(|data: &[u8]| {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    runtime.block_on(receive(data)).ok();
+});
+
+/// Receive the other side's half of a handshake, as it arrives from a connection.
+async fn receive(data: &[u8]) -> io::Result<()> {
+    let (mut theirs, ours) = tokio::io::duplex(data.len().max(1));
+    theirs.write_all(data).await?;
+    drop(theirs);
+
+    let mut handshake = net::Handshake::new(ours);
+    handshake.receive_prelude().await?;
+    handshake.receive_info_hash().await?;
+    handshake.receive_peer_id().await?;
+
+    Ok(())
+}
@@ -0,0 +1,18 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use toytorrent_common as common;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(metainfo) = common::metainfo::MetainfoFile::try_from(data) {
+        // Everything the client works out from a torrent as soon as it's added.
+        let info = &metainfo.info;
+        let _ = info.file_spans();
+        let _ = info.piece_range(info.pieces().len().saturating_sub(1) as u32);
+        let _ = metainfo.v2_piece(0);
+        let _ = Vec::<u8>::from(&metainfo);
+    }
+
+    common::metainfo::Info::decode(data).ok();
+});
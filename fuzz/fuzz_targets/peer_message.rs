@@ -0,0 +1,22 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use toytorrent_common as common;
+
+fuzz_target!(|data: &[u8]| {
+    common::peer::PeerMessage::try_from(data).ok();
+    common::peer::ExtendedHandshake::try_from(data).ok();
+
+    // The same bytes as a stream of framed messages, arriving in two chunks.
+    let mut buffer = common::peer::MessageBuffer::default();
+    let (first, second) = data.split_at(data.len() / 2);
+
+    for chunk in [first, second] {
+        buffer.extend(chunk);
+
+        while let Ok(Some(_)) | Err(common::peer::MessageBufferError::Invalid(_)) =
+            buffer.next_message()
+        {}
+    }
+});
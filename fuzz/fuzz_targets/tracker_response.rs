@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use toytorrent_common as common;
+
+fuzz_target!(|data: &[u8]| {
+    common::tracker::Response::try_from(data).ok();
+    common::tracker::ScrapeResponse::try_from(data).ok();
+});
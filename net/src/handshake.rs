@@ -0,0 +1,103 @@
+use std::io;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use toytorrent_common as common;
+
+use super::Transport;
+
+/// The steps of the handshake that opens every peer connection. Each side sends the protocol
+/// prelude with its reserved bytes, the info hash and its peer ID; the order of sending and
+/// receiving is up to the caller, since the side that accepts the connection has to see the info
+/// hash before it can answer.
+#[derive(Debug)]
+pub struct Handshake<S> {
+    stream: S,
+}
+
+impl<S: Transport> Handshake<S> {
+    pub fn new(stream: S) -> Self {
+        Self { stream }
+    }
+
+    pub async fn send_prelude(&mut self, reserved: [u8; 8]) -> io::Result<()> {
+        self.stream.write_all(common::peer::PRELUDE).await?;
+        self.stream.write_all(&reserved).await
+    }
+
+    /// Receive the other side's prelude, returning its reserved bytes.
+    pub async fn receive_prelude(&mut self) -> io::Result<[u8; 8]> {
+        let mut prelude = [0; common::peer::PRELUDE.len()];
+        self.stream.read_exact(&mut prelude).await?;
+
+        if prelude != common::peer::PRELUDE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid handshake prelude: {:?}", prelude),
+            ));
+        }
+
+        let mut reserved = [0; 8];
+        self.stream.read_exact(&mut reserved).await?;
+
+        Ok(reserved)
+    }
+
+    pub async fn send_info_hash(&mut self, info_hash: &common::InfoHash) -> io::Result<()> {
+        self.stream.write_all(info_hash.as_slice()).await
+    }
+
+    pub async fn receive_info_hash(&mut self) -> io::Result<common::InfoHash> {
+        let mut buf = [0; 20];
+        self.stream.read_exact(&mut buf).await?;
+        Ok(buf.into())
+    }
+
+    pub async fn send_peer_id(&mut self, peer_id: &common::PeerId) -> io::Result<()> {
+        self.stream.write_all(peer_id.as_slice()).await
+    }
+
+    pub async fn receive_peer_id(&mut self) -> io::Result<common::PeerId> {
+        let mut buf = [0; 20];
+        self.stream.read_exact(&mut buf).await?;
+        Ok(buf.into())
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn handshake_test() {
+        let (a, b) = tokio::io::duplex(1024);
+        let (mut a, mut b) = (Handshake::new(a), Handshake::new(b));
+
+        let info_hash = common::InfoHash::from([0xab; 20]);
+        let peer_id = common::PeerId::create("tt", "0100").unwrap();
+
+        a.send_prelude([0, 0, 0, 0, 0, 0x10, 0, 1]).await.unwrap();
+        a.send_info_hash(&info_hash).await.unwrap();
+        a.send_peer_id(&peer_id).await.unwrap();
+
+        assert_eq!(
+            [0, 0, 0, 0, 0, 0x10, 0, 1],
+            b.receive_prelude().await.unwrap()
+        );
+        assert_eq!(info_hash, b.receive_info_hash().await.unwrap());
+        assert_eq!(peer_id, b.receive_peer_id().await.unwrap());
+
+        b.into_inner()
+            .write_all(b"\x13BitTorrent protocoX")
+            .await
+            .unwrap();
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            a.receive_prelude().await.unwrap_err().kind(),
+        );
+    }
+}
@@ -0,0 +1,33 @@
+//! The transport side of the peer wire protocol, shared by the client and the tracker: the
+//! handshake, reading framed messages off a stream, checking that a peer accepts connections, and
+//! taking listening sockets from systemd. Everything works over any [`Transport`], so tests can
+//! swap TCP for an in-memory pipe.
+
+mod handshake;
+mod reader;
+mod systemd;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::time;
+
+pub use handshake::Handshake;
+pub use reader::MessageReader;
+pub use systemd::inherited_listeners;
+
+/// A byte stream that peer connections can run over.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+/// Whether a TCP connection to `addr` can be opened within `timeout`. The connection is closed
+/// straight away.
+pub async fn is_connectable(addr: SocketAddr, timeout: Duration) -> bool {
+    matches!(
+        time::timeout(timeout, TcpStream::connect(addr)).await,
+        Ok(Ok(_))
+    )
+}
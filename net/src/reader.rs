@@ -0,0 +1,163 @@
+use std::fmt;
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use toytorrent_common as common;
+
+/// Of the invalid messages from a peer, only the first and every this many after are logged, so
+/// that a peer sending nothing but garbage doesn't drown out everything else.
+const INVALID_LOG_EVERY: u32 = 10;
+
+/// How many invalid messages a peer may send before we give up on the connection.
+const MAX_INVALID_MESSAGES: u32 = 100;
+
+/// Reads peer messages off a stream, however the bytes happen to be split up on the way.
+#[derive(Debug)]
+pub struct MessageReader<R> {
+    reader: R,
+    buffer: common::peer::MessageBuffer,
+    chunk: Vec<u8>,
+
+    /// Who the messages are from, to prefix log lines with.
+    label: String,
+
+    /// Where log lines go, so that they end up with the rest of the program's output.
+    log: fn(fmt::Arguments),
+    invalid: u32,
+}
+
+impl<R: AsyncRead + Unpin> MessageReader<R> {
+    pub fn new(reader: R, label: impl fmt::Display, log: fn(fmt::Arguments)) -> Self {
+        Self {
+            reader,
+            buffer: common::peer::MessageBuffer::default(),
+            chunk: vec![0u8; common::peer::PEERMESSAGE_PIECE_MAX_LEN],
+            label: label.to_string(),
+            log,
+            invalid: 0,
+        }
+    }
+
+    /// Wait for the next message. Messages that can't be parsed are skipped, up to
+    /// [`MAX_INVALID_MESSAGES`] of them, but one that's too long to accept is an error, since the
+    /// stream can't be resynchronized after it.
+    pub async fn next_message(&mut self) -> io::Result<common::peer::PeerMessage> {
+        loop {
+            match self.buffer.next_message() {
+                Ok(Some(message)) => return Ok(message),
+                Ok(None) => {}
+                Err(common::peer::MessageBufferError::TooLong { len, max_len }) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        format!(
+                            "Received message too long: max length was {} bytes, got {} bytes",
+                            max_len, len,
+                        ),
+                    ));
+                }
+                Err(common::peer::MessageBufferError::Invalid(message)) => {
+                    self.skip_invalid(&message)?;
+                    continue;
+                }
+            }
+
+            let len = self.reader.read(&mut self.chunk).await?;
+
+            if len == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            self.buffer.extend(&self.chunk[..len]);
+        }
+    }
+
+    /// Count an invalid message, logging it if it's one of the sampled ones, or fail once the peer
+    /// has sent too many.
+    fn skip_invalid(&mut self, message: &[u8]) -> io::Result<()> {
+        self.invalid += 1;
+
+        if self.invalid >= MAX_INVALID_MESSAGES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Received {} invalid messages", self.invalid),
+            ));
+        }
+
+        if self.invalid == 1 || self.invalid.is_multiple_of(INVALID_LOG_EVERY) {
+            (self.log)(format_args!(
+                "{:21} Skipping invalid message with ID {} and length {} ({} so far)",
+                self.label,
+                message
+                    .get(4)
+                    .map_or_else(|| "-".to_string(), u8::to_string),
+                message.len().saturating_sub(4),
+                self.invalid,
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::io::AsyncWriteExt;
+
+    use common::peer::PeerMessage;
+
+    #[tokio::test]
+    async fn next_message_test() {
+        let mut data = Vec::new();
+        PeerMessage::Have { index: 3 }
+            .write_to(&mut data)
+            .await
+            .unwrap();
+        PeerMessage::Unchoke.write_to(&mut data).await.unwrap();
+
+        let (mut writer, reader) = tokio::io::duplex(1024);
+        let mut reader = MessageReader::new(reader, "test", |_| {});
+
+        // Split the first message across writes.
+        writer.write_all(&data[..3]).await.unwrap();
+        writer.write_all(&data[3..]).await.unwrap();
+        drop(writer);
+
+        assert_eq!(
+            PeerMessage::Have { index: 3 },
+            reader.next_message().await.unwrap()
+        );
+        assert_eq!(PeerMessage::Unchoke, reader.next_message().await.unwrap());
+        assert_eq!(
+            io::ErrorKind::UnexpectedEof,
+            reader.next_message().await.unwrap_err().kind(),
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_message_test() {
+        // A Have message one byte short.
+        let invalid = [0, 0, 0, 4, 4, 0, 0, 0];
+
+        let mut data = Vec::new();
+        for _ in 0..MAX_INVALID_MESSAGES - 1 {
+            data.extend_from_slice(&invalid);
+        }
+        PeerMessage::Unchoke.write_to(&mut data).await.unwrap();
+        data.extend_from_slice(&invalid);
+        PeerMessage::Interested.write_to(&mut data).await.unwrap();
+
+        let (mut writer, reader) = tokio::io::duplex(data.len());
+        let mut reader = MessageReader::new(reader, "test", |_| {});
+        writer.write_all(&data).await.unwrap();
+
+        assert_eq!(PeerMessage::Unchoke, reader.next_message().await.unwrap());
+        assert_eq!(MAX_INVALID_MESSAGES - 1, reader.invalid);
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            reader.next_message().await.unwrap_err().kind(),
+        );
+    }
+}
@@ -0,0 +1,77 @@
+//! Listening sockets passed in by systemd socket activation, found the way `sd_listen_fds` finds
+//! them. Letting systemd open the sockets means that privileged ports can be bound without root,
+//! and that connections queue up rather than being refused while the service restarts.
+
+use std::io;
+use std::net::TcpListener;
+
+/// The first descriptor that systemd passes, after standard input, output and error.
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Take the TCP listeners that systemd opened for this process, in the order of the socket unit.
+/// Empty if the process wasn't socket activated. Only the first call takes the listeners.
+#[cfg(unix)]
+pub fn inherited_listeners() -> io::Result<Vec<TcpListener>> {
+    use std::env;
+    use std::os::fd::FromRawFd;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    static TAKEN: AtomicBool = AtomicBool::new(false);
+
+    let count = count(
+        env::var("LISTEN_PID").ok().as_deref(),
+        env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    );
+
+    if count == 0 || TAKEN.swap(true, Ordering::Relaxed) {
+        return Ok(Vec::new());
+    }
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // SAFETY: systemd hands this process the descriptors from LISTEN_FDS_START on, and
+            // they're only taken once.
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+
+            // Fails for descriptors that aren't sockets.
+            listener.local_addr()?;
+            listener.set_nonblocking(true)?;
+            Ok(listener)
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub fn inherited_listeners() -> io::Result<Vec<TcpListener>> {
+    Ok(Vec::new())
+}
+
+/// The number of descriptors passed, if they were passed to the process with `pid`. The variables
+/// are inherited by child processes, which have to ignore them.
+#[cfg_attr(not(unix), allow(dead_code))]
+fn count(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> i32 {
+    if listen_pid.and_then(|listen_pid| listen_pid.parse().ok()) != Some(pid) {
+        return 0;
+    }
+
+    listen_fds
+        .and_then(|listen_fds| listen_fds.parse().ok())
+        .filter(|&count: &i32| count > 0)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn count_test() {
+        assert_eq!(2, count(Some("42"), Some("2"), 42));
+        assert_eq!(0, count(Some("41"), Some("2"), 42));
+        assert_eq!(0, count(None, Some("2"), 42));
+        assert_eq!(0, count(Some("42"), None, 42));
+        assert_eq!(0, count(Some("42"), Some("-1"), 42));
+    }
+}
@@ -0,0 +1,334 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::{self, MissedTickBehavior};
+
+use toytorrent_common as common;
+
+/// The size reported by leeching peers, which is otherwise irrelevant to the tracker.
+const TORRENT_LENGTH: u64 = 1 << 30;
+
+/// Simulate a swarm of peers announcing to a tracker and report the tracker's response latency
+#[derive(Debug, Parser)]
+pub struct Args {
+    /// The tracker's announce URL
+    url: String,
+
+    /// The number of simulated peers
+    #[arg(long, default_value_t = 1000)]
+    peers: usize,
+
+    /// The number of torrents to spread the peers across
+    #[arg(long, default_value_t = 10)]
+    torrents: usize,
+
+    /// The number of announces to send per second
+    #[arg(long, default_value_t = 100.0)]
+    rate: f64,
+
+    /// How long to run the simulation for, in seconds
+    #[arg(long, default_value_t = 30)]
+    duration: u64,
+
+    /// The most announces to have in flight at once. Announces due beyond this are skipped and
+    /// reported, which indicates that the tracker can't keep up with the rate.
+    #[arg(long, default_value_t = 256)]
+    concurrency: usize,
+
+    /// The percentage of announces from leechers that report having completed the download
+    #[arg(long, default_value_t = 5.0)]
+    completed_percent: f64,
+
+    /// The percentage of announces from active peers that report stopping
+    #[arg(long, default_value_t = 5.0)]
+    stopped_percent: f64,
+}
+
+/// Every peer starts out stopped, so the start of a run is dominated by `started` announces, much
+/// like a newly published torrent.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum State {
+    Stopped,
+    Leeching,
+    Seeding,
+}
+
+#[derive(Debug)]
+struct SimPeer {
+    peer_id: common::PeerId,
+    info_hash: common::InfoHash,
+    port: u16,
+    state: State,
+}
+
+#[derive(Debug, Default)]
+struct Results {
+    latencies: Vec<Duration>,
+    failure_responses: usize,
+    errors: usize,
+    skipped: usize,
+
+    started: usize,
+    completed: usize,
+    stopped: usize,
+    regular: usize,
+}
+
+impl SimPeer {
+    /// Pick the event for this peer's next announce, advancing its state to match.
+    fn next_event(&mut self, args: &Args, rng: &mut impl Rng) -> Option<common::tracker::Event> {
+        use common::tracker::Event;
+
+        if self.state == State::Stopped {
+            self.state = State::Leeching;
+            return Some(Event::Started);
+        }
+
+        let roll = rng.gen_range(0.0..100.0);
+
+        if roll < args.stopped_percent {
+            self.state = State::Stopped;
+            Some(Event::Stopped)
+        } else if self.state == State::Leeching
+            && roll < args.stopped_percent + args.completed_percent
+        {
+            self.state = State::Seeding;
+            Some(Event::Completed)
+        } else {
+            None
+        }
+    }
+
+    fn request(&self, event: Option<common::tracker::Event>) -> common::tracker::Request {
+        let left = if self.state == State::Seeding {
+            0
+        } else {
+            TORRENT_LENGTH
+        };
+
+        let mut request =
+            common::tracker::Request::new(self.info_hash, self.peer_id, self.port, 0, 0, left);
+        request.event = event;
+        request
+    }
+}
+
+impl Results {
+    fn record_event(&mut self, event: Option<common::tracker::Event>) {
+        use common::tracker::Event;
+
+        match event {
+            Some(Event::Started) => self.started += 1,
+            Some(Event::Completed) => self.completed += 1,
+            Some(Event::Stopped) => self.stopped += 1,
+            None => self.regular += 1,
+        }
+    }
+
+    fn record_response(&mut self, result: Result<(Duration, bool), String>) {
+        match result {
+            Ok((latency, is_success)) => {
+                self.latencies.push(latency);
+
+                if !is_success {
+                    self.failure_responses += 1;
+                }
+            }
+            Err(e) => {
+                if self.errors == 0 {
+                    println!("First error: {}", e);
+                }
+
+                self.errors += 1;
+            }
+        }
+    }
+
+    /// Print the results, returning the p99 latency.
+    fn report(&mut self, elapsed: Duration) -> Option<Duration> {
+        let sent = self.started + self.completed + self.stopped + self.regular;
+
+        println!(
+            "Sent {} announces in {:.1} s ({:.1}/s): {} started, {} completed, {} stopped, {} regular",
+            sent,
+            elapsed.as_secs_f64(),
+            sent as f64 / elapsed.as_secs_f64(),
+            self.started,
+            self.completed,
+            self.stopped,
+            self.regular,
+        );
+
+        println!(
+            "Responses: {} ok, {} failure responses, {} errors, {} skipped at the concurrency limit",
+            self.latencies.len() - self.failure_responses,
+            self.failure_responses,
+            self.errors,
+            self.skipped,
+        );
+
+        self.latencies.sort();
+
+        if let (Some(p50), Some(p90), Some(p99), Some(max)) = (
+            percentile(&self.latencies, 50.0),
+            percentile(&self.latencies, 90.0),
+            percentile(&self.latencies, 99.0),
+            self.latencies.last(),
+        ) {
+            println!(
+                "Latency: p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+                p50, p90, p99, max,
+            );
+        }
+
+        percentile(&self.latencies, 99.0)
+    }
+}
+
+/// Run the simulation and print the results, returning the p99 latency if any announces were
+/// answered.
+pub async fn run(args: Args) -> Option<Duration> {
+    if args.peers == 0 || args.torrents == 0 || !args.rate.is_finite() || args.rate <= 0.0 {
+        println!("--peers, --torrents and --rate must all be greater than zero");
+        return None;
+    }
+
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()
+        .unwrap();
+    let mut rng = StdRng::from_entropy();
+
+    let info_hashes: Vec<common::InfoHash> = (0..args.torrents)
+        .map(|_| rng.gen::<[u8; 20]>().into())
+        .collect();
+
+    let mut peers: Vec<SimPeer> = (0..args.peers)
+        .map(|i| SimPeer {
+            peer_id: common::PeerId::create("ts", "0000").unwrap(),
+            info_hash: info_hashes[i % info_hashes.len()],
+            port: rng.gen_range(1024..=u16::MAX),
+            state: State::Stopped,
+        })
+        .collect();
+
+    println!(
+        "Simulating {} peers across {} torrents at {} announces/s for {} s",
+        args.peers, args.torrents, args.rate, args.duration,
+    );
+
+    let semaphore = Arc::new(Semaphore::new(args.concurrency));
+    let mut requests = JoinSet::new();
+    let mut results = Results::default();
+
+    // The timer only fires about once a millisecond, so at higher rates each tick sends every
+    // announce that has come due since the last one.
+    let period = Duration::from_secs_f64(1.0 / args.rate).max(Duration::from_millis(1));
+    let mut tick = time::interval(period);
+    tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    let start = Instant::now();
+    let end = start + Duration::from_secs(args.duration);
+    let mut due = 0;
+
+    while Instant::now() < end {
+        tokio::select! {
+            _ = tick.tick() => {
+                let sent = due;
+                due = (start.elapsed().as_secs_f64() * args.rate) as u64 + 1;
+
+                for _ in sent..due {
+                    let Ok(permit) = semaphore.clone().try_acquire_owned() else {
+                        results.skipped += 1;
+                        continue;
+                    };
+
+                    let peer = &mut peers[rng.gen_range(0..args.peers)];
+                    let event = peer.next_event(&args, &mut rng);
+                    results.record_event(event);
+
+                    let url = announce_url(&args.url, &peer.request(event));
+                    let client = client.clone();
+
+                    requests.spawn(async move {
+                        let result = announce(&client, &url).await;
+                        drop(permit);
+                        result
+                    });
+                }
+            }
+            Some(result) = requests.join_next() => {
+                results.record_response(result.map_err(|e| e.to_string()).and_then(|r| r));
+            }
+        }
+    }
+
+    while let Some(result) = requests.join_next().await {
+        results.record_response(result.map_err(|e| e.to_string()).and_then(|r| r));
+    }
+
+    results.report(start.elapsed())
+}
+
+fn announce_url(url: &str, request: &common::tracker::Request) -> String {
+    if url.contains('?') {
+        format!("{url}&{}", request.as_query_string())
+    } else {
+        format!("{url}?{}", request.as_query_string())
+    }
+}
+
+/// Send one announce, returning how long the tracker took to respond and whether the response was
+/// a success rather than a failure response.
+async fn announce(client: &reqwest::Client, url: &str) -> Result<(Duration, bool), String> {
+    let start = Instant::now();
+
+    let body = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| format!("{e:?}"))?
+        .bytes()
+        .await
+        .map_err(|e| format!("{e:?}"))?;
+
+    let latency = start.elapsed();
+
+    match common::tracker::Response::try_from(&body[..]) {
+        Ok(common::tracker::Response::Success(_)) => Ok((latency, true)),
+        Ok(common::tracker::Response::Failure(_)) => Ok((latency, false)),
+        Err(e) => Err(format!("Invalid response: {}", e)),
+    }
+}
+
+/// The nearest-rank percentile of a sorted list.
+fn percentile(sorted: &[Duration], percent: f64) -> Option<Duration> {
+    if sorted.is_empty() {
+        return None;
+    }
+
+    let rank = (percent / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted.get(rank.clamp(1, sorted.len()) - 1).copied()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn percentile_test() {
+        let sorted: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+
+        assert_eq!(None, percentile(&[], 50.0));
+        assert_eq!(Some(Duration::from_millis(50)), percentile(&sorted, 50.0));
+        assert_eq!(Some(Duration::from_millis(99)), percentile(&sorted, 99.0));
+        assert_eq!(Some(Duration::from_millis(100)), percentile(&sorted, 100.0));
+        assert_eq!(Some(Duration::from_millis(1)), percentile(&sorted, 0.0));
+    }
+}
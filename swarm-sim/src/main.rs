@@ -0,0 +1,10 @@
+use clap::Parser;
+
+use toytorrent_swarm_sim as swarm_sim;
+
+#[tokio::main]
+async fn main() {
+    let args = swarm_sim::Args::parse();
+
+    swarm_sim::run(args).await;
+}
@@ -0,0 +1,64 @@
+//! Announce latency under increasing load, from 1,000 up to 10,000 announces per second, against a
+//! tracker running on the loopback interface. With announces spread across shards, p99 latency
+//! should stay flat as the rate goes up rather than growing with the queue for a single lock.
+//!
+//! Run with `cargo bench -p toytorrent-tracker`.
+
+use std::net::{Ipv4Addr, TcpListener};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use clap::Parser;
+use tokio::time;
+
+use toytorrent_swarm_sim as swarm_sim;
+
+const RATES: [u32; 4] = [1_000, 2_500, 5_000, 10_000];
+
+#[tokio::main]
+async fn main() {
+    let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .and_then(|listener| listener.local_addr())
+        .unwrap()
+        .port()
+        .to_string();
+
+    // The tracker logs every announce, which would drown out the results.
+    let mut tracker = Command::new(env!("CARGO_BIN_EXE_toytorrent-tracker"))
+        .args(["--bind", "127.0.0.1", "--port", &port])
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    time::sleep(Duration::from_millis(500)).await;
+
+    let url = format!("http://127.0.0.1:{port}/announce");
+    let mut p99s = Vec::new();
+
+    for rate in RATES {
+        let rate = rate.to_string();
+        let args = swarm_sim::Args::parse_from([
+            "toytorrent-swarm-sim",
+            &url,
+            "--peers",
+            "100000",
+            "--torrents",
+            "1000",
+            "--rate",
+            &rate,
+            "--duration",
+            "10",
+            "--concurrency",
+            "1024",
+        ]);
+
+        p99s.push((rate, swarm_sim::run(args).await));
+        println!();
+    }
+
+    for (rate, p99) in p99s {
+        println!("{rate:>6} announces/s: p99 {p99:?}");
+    }
+
+    tracker.kill().unwrap();
+    tracker.wait().unwrap();
+}
@@ -0,0 +1,83 @@
+//! Endpoints for the tracker's operator, authenticated with the `--admin-token` bearer token.
+
+use std::sync::Arc;
+
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+
+use toytorrent_common as common;
+
+use super::Args;
+
+/// Register a torrent from the POSTed body, either a whole torrent file or just its info dict, so
+/// that its name and size show up in the stats.
+pub async fn register_torrent_route(
+    State(args): State<Arc<Args>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if !is_authorized(&args, &headers) {
+        return text_response(StatusCode::UNAUTHORIZED, "Missing or invalid admin token\n");
+    }
+
+    let (info, info_hash) = match decode(&body) {
+        Ok(decoded) => decoded,
+        Err(e) => return text_response(StatusCode::BAD_REQUEST, format!("{}\n", e)),
+    };
+
+    let name = info.name().to_string();
+    let length = common::Bytes::from(info.length());
+
+    let registered = super::shard::with(&info_hash, move |torrents| {
+        let torrent = torrents.get_or_insert(info_hash);
+        torrent.name = Some(name);
+        torrent.length = Some(length);
+    });
+
+    if registered.await.is_none() {
+        return text_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The tracker is unavailable\n",
+        );
+    }
+
+    println!(
+        "Registered {} {} ({})",
+        info_hash,
+        info.name(),
+        common::Bytes::from(info.length())
+    );
+
+    text_response(StatusCode::OK, format!("{} {}\n", info_hash, info.name()))
+}
+
+fn is_authorized(args: &Args, headers: &HeaderMap) -> bool {
+    let Some(token) = &args.admin_token else {
+        return false;
+    };
+
+    headers
+        .get_all(header::AUTHORIZATION)
+        .iter()
+        .next_back()
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| given == token.as_str())
+}
+
+fn text_response(status: StatusCode, body: impl Into<String>) -> Response {
+    (status, [(header::CONTENT_TYPE, "text/plain")], body.into()).into_response()
+}
+
+fn decode(body: &[u8]) -> Result<(common::metainfo::Info, common::InfoHash), common::Error> {
+    match common::metainfo::MetainfoFile::try_from(body) {
+        Ok(metainfo) => {
+            let info_hash = *metainfo.info_hash();
+            Ok((metainfo.info, info_hash))
+        }
+        Err(_) => common::metainfo::Info::decode(body)
+            .map_err(|e| format!("Expected a torrent file or an info dict: {}", e).into()),
+    }
+}
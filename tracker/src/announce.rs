@@ -2,48 +2,226 @@ use toytorrent_common as common;
 
 use std::net::IpAddr;
 
-pub async fn announce(
+use common::tracker::Event;
+
+use super::settings::Settings;
+use super::torrent::Torrents;
+
+/// The effect an announce has on the swarm, depending on its event and whether we already know
+/// the peer that sent it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Transition {
+    /// Add the peer, or refresh its stats.
+    Update,
+
+    /// Refresh the peer's stats and count a completed download.
+    Complete,
+
+    /// Remove the peer from the swarm.
+    Remove,
+
+    /// Do nothing.
+    Ignore,
+}
+
+/// Apply an announce to the shard holding its torrent, and respond to it.
+pub fn announce(
+    torrents: &mut Torrents,
     request: common::tracker::Request,
     remote_ip: IpAddr,
+    clock: &dyn common::Clock,
+    args: &super::Args,
+    settings: &Settings,
 ) -> common::tracker::Response {
-    let mut torrents = super::torrents();
     let torrent = torrents.get_or_insert(request.info_hash);
 
-    let peer = request.as_peer(request.ip.unwrap_or(remote_ip));
+    let mut peer = request.as_peer(request.ip.unwrap_or(remote_ip));
+    peer.last_seen = clock.now();
 
-    if request.event == Some(common::tracker::Event::Stopped) {
-        torrent.peers.remove(&peer);
-    } else {
-        torrent.peers.replace(peer.clone());
+    // Keep the result of any previous probe as long as the peer hasn't moved.
+    if let Some(existing) = torrent.peers.get(&peer) {
+        if existing.addr == peer.addr {
+            peer.connectable = existing.connectable;
+        }
     }
 
-    if request.event == Some(common::tracker::Event::Completed) {
-        torrent.downloaded += 1;
+    match transition(request.event, torrent.peers.get(&peer)) {
+        Transition::Update => torrent.peers.replace(peer.clone()),
+        Transition::Complete => {
+            torrent.peers.replace(peer.clone());
+            torrent.downloaded += 1;
+        }
+        Transition::Remove => torrent.peers.remove(&peer),
+        Transition::Ignore => {}
     }
 
-    torrent.update_counts();
+    if args.probe_peers && should_probe(&request, &peer, remote_ip) {
+        tokio::spawn(super::probe::probe(request.info_hash, peer.clone()));
+    }
+
+    let mut warnings = Vec::new();
+    let max_peers = settings.max_response_peers as usize;
 
-    let peer_count = request
+    let peer_count = match request
         .numwant
-        .and_then(|i| usize::try_from(i).ok())
-        .unwrap_or(usize::MAX)
-        .clamp(0, 50);
+        .map(|i| usize::try_from(i).unwrap_or(usize::MAX))
+    {
+        Some(numwant) if numwant > max_peers => {
+            warnings.push(format!(
+                "numwant={numwant} exceeds the maximum of {max_peers}"
+            ));
+            max_peers
+        }
+        Some(0) if request.event != Some(Event::Stopped) => {
+            warnings.push("numwant=0, so no peers were returned".to_string());
+            0
+        }
+        Some(numwant) => numwant,
+        None => max_peers,
+    };
+
+    if request.port == 0 {
+        warnings.push("No port given, so you will not be listed as a peer".to_string());
+    }
 
     let peers = torrent
         .peers
-        .get_multiple(peer_count, Some(&peer), peer.requirecrypto == Some(true))
+        .get_multiple(
+            peer_count,
+            Some(&peer),
+            peer.requirecrypto == Some(true),
+            clock.now(),
+        )
         .into_iter()
         .cloned()
         .collect();
 
+    // After choosing peers, which may have dropped some that expired.
+    torrent.update_counts();
+
     common::tracker::SuccessResponse {
-        warning_message: None,
-        interval: 60,
-        min_interval: None,
+        warning_message: if warnings.is_empty() {
+            None
+        } else {
+            Some(warnings.join("; "))
+        },
+        interval: settings.interval.into(),
+        min_interval: settings.min_interval.map(u64::from),
         tracker_id: None,
         complete: Some(torrent.complete),
         incomplete: Some(torrent.incomplete),
+        downloaded: Some(torrent.downloaded),
+        downloaders: args.announce_downloaders.then_some(torrent.incomplete),
         peers,
     }
     .into()
 }
+
+fn transition(event: Option<Event>, existing: Option<&common::tracker::Peer>) -> Transition {
+    match (event, existing) {
+        // A peer we don't know may have been expired, or may have skipped its "started" event.
+        // Either way it is in the swarm now, but we didn't see it download anything.
+        (Some(Event::Started) | Some(Event::Completed) | None, None) => Transition::Update,
+        (Some(Event::Started) | None, Some(_)) => Transition::Update,
+
+        // Don't count a completion twice, or count one from a peer that was already seeding.
+        (Some(Event::Completed), Some(existing)) if existing.left == Some(0) => Transition::Update,
+        (Some(Event::Completed), Some(_)) => Transition::Complete,
+
+        (Some(Event::Stopped), Some(_)) => Transition::Remove,
+        (Some(Event::Stopped), None) => Transition::Ignore,
+    }
+}
+
+/// Whether to check that `peer` accepts connections. Only the address that the announce came from
+/// is dialed, so that an `ip` parameter can't point the tracker at someone else's host.
+fn should_probe(
+    request: &common::tracker::Request,
+    peer: &common::tracker::Peer,
+    remote_ip: IpAddr,
+) -> bool {
+    request.ip.is_none_or(|ip| ip == remote_ip)
+        && peer.connectable.is_none()
+        && peer.addr.port() != 0
+        && request.event != Some(Event::Stopped)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Instant;
+
+    fn peer(left: u64) -> common::tracker::Peer {
+        common::tracker::Peer {
+            last_seen: Instant::now(),
+            peer_id: Some([0; 20].into()),
+            addr: "127.0.0.1:6881".parse().unwrap(),
+            uploaded: Some(0),
+            downloaded: Some(0),
+            left: Some(left),
+            key: None,
+            supportcrypto: None,
+            requirecrypto: None,
+            connectable: None,
+        }
+    }
+
+    #[test]
+    fn transition_test() {
+        let leecher = peer(100);
+        let seeder = peer(0);
+
+        assert_eq!(Transition::Update, transition(Some(Event::Started), None));
+        assert_eq!(Transition::Update, transition(Some(Event::Completed), None));
+        assert_eq!(Transition::Update, transition(None, None));
+        assert_eq!(Transition::Ignore, transition(Some(Event::Stopped), None));
+
+        assert_eq!(
+            Transition::Update,
+            transition(Some(Event::Started), Some(&leecher))
+        );
+        assert_eq!(
+            Transition::Complete,
+            transition(Some(Event::Completed), Some(&leecher))
+        );
+        assert_eq!(Transition::Update, transition(None, Some(&leecher)));
+        assert_eq!(
+            Transition::Remove,
+            transition(Some(Event::Stopped), Some(&leecher))
+        );
+
+        assert_eq!(
+            Transition::Update,
+            transition(Some(Event::Started), Some(&seeder))
+        );
+        assert_eq!(
+            Transition::Update,
+            transition(Some(Event::Completed), Some(&seeder))
+        );
+        assert_eq!(Transition::Update, transition(None, Some(&seeder)));
+        assert_eq!(
+            Transition::Remove,
+            transition(Some(Event::Stopped), Some(&seeder))
+        );
+    }
+
+    #[test]
+    fn should_probe_test() {
+        let remote_ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let mut request =
+            common::tracker::Request::new([0; 20].into(), [0; 20].into(), 6881, 0, 0, 100);
+        let peer = peer(100);
+
+        assert!(should_probe(&request, &peer, remote_ip));
+
+        request.ip = Some(remote_ip);
+        assert!(should_probe(&request, &peer, remote_ip));
+
+        request.ip = Some("192.0.2.1".parse().unwrap());
+        assert!(!should_probe(&request, &peer, remote_ip));
+
+        request.ip = None;
+        request.event = Some(Event::Stopped);
+        assert!(!should_probe(&request, &peer, remote_ip));
+    }
+}
@@ -0,0 +1,154 @@
+//! Liveness and readiness probes as JSON, for load balancers and orchestrators such as Kubernetes.
+//! `/healthz` fails only if a shard's worker has stopped taking jobs, leaving the announces for its
+//! torrents unanswered until the tracker is restarted. A panicking job doesn't stop the worker, so
+//! this takes the worker's task itself going away. `/readyz` fails in that case too, and until the
+//! tracker is listening on every address, so that no traffic is sent its way before then.
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+
+/// How far back the announce rate is averaged over.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+static STARTED: OnceLock<Instant> = OnceLock::new();
+static READY: AtomicBool = AtomicBool::new(false);
+static ANNOUNCES: Mutex<AnnounceRate> = Mutex::new(AnnounceRate::new());
+
+/// Announces received over the last [`RATE_WINDOW`], counted per second.
+#[derive(Debug)]
+struct AnnounceRate {
+    /// The start of each second that had announces, with their count, oldest first.
+    seconds: VecDeque<(Instant, u64)>,
+}
+
+/// Start the uptime clock.
+pub fn start(now: Instant) {
+    STARTED.get_or_init(|| now);
+}
+
+/// Report ready once the tracker is listening on every address.
+pub fn set_ready() {
+    READY.store(true, Ordering::Relaxed);
+}
+
+pub fn count_announce(now: Instant) {
+    ANNOUNCES.lock().unwrap().count(now);
+}
+
+pub async fn healthz_route() -> Response {
+    respond(false, Instant::now()).await
+}
+
+pub async fn readyz_route() -> Response {
+    respond(true, Instant::now()).await
+}
+
+async fn respond(readiness: bool, now: Instant) -> Response {
+    let started = *STARTED.get_or_init(|| now);
+
+    // A shard only fails to answer if its worker is gone, or if counting its swarms panicked.
+    let swarms = super::shard::each(|torrents| {
+        let peers = torrents
+            .iter()
+            .map(|torrent| torrent.peers.len())
+            .sum::<usize>();
+        (torrents.len(), peers)
+    })
+    .await
+    .map(|shards| {
+        shards.into_iter().fold((0, 0), |(torrents, peers), shard| {
+            (torrents + shard.0, peers + shard.1)
+        })
+    });
+
+    let status = match swarms {
+        None => "unavailable",
+        Some(_) if readiness && !READY.load(Ordering::Relaxed) => "starting",
+        Some(_) => "ok",
+    };
+
+    let mut body = String::new();
+    write!(
+        body,
+        "{{\"status\":\"{}\",\"uptime_seconds\":{},\"announces_per_second\":{:.3}",
+        status,
+        now.duration_since(started).as_secs(),
+        ANNOUNCES.lock().unwrap().per_second(now),
+    )
+    .unwrap();
+
+    if let Some((torrents, peers)) = swarms {
+        write!(body, ",\"torrents\":{},\"peers\":{}", torrents, peers).unwrap();
+    }
+
+    body.push_str("}\n");
+
+    let code = match status {
+        "ok" => StatusCode::OK,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    (code, [(header::CONTENT_TYPE, "application/json")], body).into_response()
+}
+
+impl AnnounceRate {
+    const fn new() -> Self {
+        Self {
+            seconds: VecDeque::new(),
+        }
+    }
+
+    fn count(&mut self, now: Instant) {
+        self.expire(now);
+
+        match self.seconds.back_mut() {
+            Some((second, count)) if now.duration_since(*second) < Duration::from_secs(1) => {
+                *count += 1;
+            }
+            _ => self.seconds.push_back((now, 1)),
+        }
+    }
+
+    fn per_second(&mut self, now: Instant) -> f64 {
+        self.expire(now);
+
+        let count: u64 = self.seconds.iter().map(|(_, count)| count).sum();
+        count as f64 / RATE_WINDOW.as_secs_f64()
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while self
+            .seconds
+            .front()
+            .is_some_and(|(second, _)| now.duration_since(*second) >= RATE_WINDOW)
+        {
+            self.seconds.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn announce_rate_test() {
+        let start = Instant::now();
+        let mut rate = AnnounceRate::new();
+
+        for millis in [0, 200, 900, 1000, 30_000] {
+            rate.count(start + Duration::from_millis(millis));
+        }
+
+        assert_eq!(3, rate.seconds.len());
+        assert_eq!(5.0 / 60.0, rate.per_second(start + Duration::from_secs(59)));
+        assert_eq!(2.0 / 60.0, rate.per_second(start + Duration::from_secs(60)));
+        assert_eq!(0.0, rate.per_second(start + Duration::from_secs(120)));
+    }
+}
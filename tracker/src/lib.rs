@@ -1,88 +1,373 @@
+mod admin;
 mod announce;
+mod health;
+mod probe;
+mod scrape;
+mod settings;
+mod shard;
+mod stats;
 mod torrent;
+mod users;
 
+use std::io;
 use std::net::{IpAddr, SocketAddr};
-use std::rc::Rc;
-use std::sync::{Mutex, MutexGuard};
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use axum::extract::connect_info::{ConnectInfo, Connected};
+use axum::extract::{Path, RawQuery, State};
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::serve::IncomingStream;
+use axum::Router;
 use clap::Parser;
+use tokio::net::TcpListener;
+use tokio::task::JoinSet;
 
 use toytorrent_common as common;
-
-use torrent::Torrents;
-
-static mut TORRENTS: Option<Rc<Mutex<Torrents>>> = None;
+use toytorrent_net as net;
 
 /// A barebones BitTorrent tracker
-#[derive(Debug, Parser)]
+#[derive(Clone, Debug, Parser)]
 pub struct Args {
-    /// The port to listen on
-    #[arg(short, long, default_value_t = 8080)]
-    port: u16,
+    /// The port to listen on. Repeat to listen on several ports, either pairwise with --bind or
+    /// on every bound address. Ignored if systemd passes in sockets to listen on.
+    #[arg(short, long, default_value = "8080")]
+    port: Vec<u16>,
 
-    /// The IP address to bind
+    /// The IP address to bind. Repeat to listen on several addresses, either pairwise with --port
+    /// or on every port. Ignored if systemd passes in sockets to listen on.
     #[arg(short, long, default_value = "0.0.0.0")]
-    bind: IpAddr,
+    bind: Vec<IpAddr>,
 
-    /// The interval to instruct clients to announce with
+    /// The interval to instruct clients to announce with. Can be changed in --config
     #[arg(short, long, default_value_t = 600)]
     interval: u32,
 
-    /// If set, the minimum interval to permit clients to announce
+    /// If set, the minimum interval to permit clients to announce. Can be changed in --config
     #[arg(long)]
     min_interval: Option<u32>,
 
-    /// The interval after which to consider a client dropped
-    #[arg(long, default_value_t = 900)]
+    /// The interval after which to consider a client dropped. Can be changed in --config
+    #[arg(long, default_value_t = 3600)]
     timeout_interval: u32,
 
-    /// The maximum number of peers to return
+    /// The maximum number of peers to return. Can be changed in --config
     #[arg(long, default_value_t = 30)]
     max_response_peers: u32,
+
+    /// Verify that announcing peers accept incoming connections, and prefer those that do
+    #[arg(long)]
+    probe_peers: bool,
+
+    /// Also report the number of peers that are downloading in announce responses, as BEP 48's
+    /// `downloaders`. Every peer without the complete torrent counts as downloading
+    #[arg(long)]
+    announce_downloaders: bool,
+
+    /// How often to record a snapshot of each swarm's size, in seconds
+    #[arg(long, default_value_t = 60)]
+    history_interval: u64,
+
+    /// The number of swarm size snapshots to keep per torrent
+    #[arg(long, default_value_t = 1440)]
+    history_length: usize,
+
+    /// The number of shards to split the swarms into, each handling the announces for its share
+    /// of torrents in parallel with the others. Defaults to the number of CPUs
+    #[arg(long)]
+    shards: Option<NonZeroUsize>,
+
+    /// A path to serve announces on. Repeat to serve aliases such as /announce.php.
+    #[arg(long = "announce-path", default_value = "/announce")]
+    announce_paths: Vec<String>,
+
+    /// A path to serve scrapes on. Repeat to serve aliases such as /scrape.php.
+    #[arg(long = "scrape-path", default_value = "/scrape")]
+    scrape_paths: Vec<String>,
+
+    /// Run a private tracker for the users in this file, one `<passkey> <name>` pair per line.
+    /// Users announce and scrape on `<path>/<passkey>`. Reread on SIGHUP.
+    #[arg(long)]
+    users: Option<PathBuf>,
+
+    /// With --users, refuse downloads to users whose upload/download ratio falls below this. Can
+    /// be changed in --config
+    #[arg(long, requires = "users")]
+    min_ratio: Option<f64>,
+
+    /// How much users may download before --min-ratio applies to them. Can be changed in --config
+    #[arg(long, default_value = "1GiB")]
+    ratio_grace: common::Bytes,
+
+    /// Override --interval, --min-interval, --timeout-interval, --max-response-peers, --min-ratio
+    /// and --ratio-grace with the `<option> = <value>` lines in this file, such as
+    /// `interval = 1800`. Reread on SIGHUP.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Enable the admin endpoints, such as POST /admin/torrents to register a torrent's name and
+    /// size, for requests bearing this token
+    #[arg(long)]
+    admin_token: Option<String>,
 }
 
-pub async fn run(args: Args) -> tide::Result<()> {
-    unsafe {
-        TORRENTS = Some(Rc::new(Mutex::new(Torrents::default())));
+impl Args {
+    /// The settings given on the command line that --config can override.
+    fn settings(&self) -> settings::Settings {
+        settings::Settings {
+            interval: self.interval,
+            min_interval: self.min_interval,
+            timeout_interval: self.timeout_interval,
+            max_response_peers: self.max_response_peers,
+            min_ratio: self.min_ratio,
+            ratio_grace: self.ratio_grace,
+        }
     }
 
-    let mut app = tide::new();
-    app.at("/announce").get(announce_route);
-    println!("Listening on {}:{}", args.bind, args.port);
-    app.listen(SocketAddr::from((args.bind, args.port))).await?;
+    /// Pair up the --bind and --port arguments. If only one of either is given, it is used with
+    /// every one of the other.
+    fn listen_addrs(&self) -> Result<Vec<SocketAddr>, String> {
+        match (&self.bind[..], &self.port[..]) {
+            ([bind], ports) => Ok(ports.iter().map(|&port| (*bind, port).into()).collect()),
+            (binds, [port]) => Ok(binds.iter().map(|&bind| (bind, *port).into()).collect()),
+            (binds, ports) if binds.len() == ports.len() => Ok(binds
+                .iter()
+                .zip(ports)
+                .map(|(&bind, &port)| (bind, port).into())
+                .collect()),
+            (binds, ports) => Err(format!(
+                "Got {} --bind and {} --port arguments; give either the same number of each or \
+                only one of either",
+                binds.len(),
+                ports.len(),
+            )),
+        }
+    }
+}
+
+/// Both ends of a connection to the tracker.
+#[derive(Clone, Copy, Debug)]
+struct Connection {
+    remote: SocketAddr,
+
+    /// The address the connection arrived on, which for listeners bound to an unspecified address
+    /// is that of the interface.
+    local: Option<SocketAddr>,
+}
+
+impl Connected<IncomingStream<'_>> for Connection {
+    fn connect_info(stream: IncomingStream<'_>) -> Self {
+        Self {
+            remote: stream.remote_addr(),
+            local: stream.local_addr().ok(),
+        }
+    }
+}
+
+pub async fn run(args: Args) -> io::Result<()> {
+    health::start(Instant::now());
+
+    let addrs = args.listen_addrs().map_err(io::Error::other)?;
+
+    if let Some(path) = &args.users {
+        users::load(path).map_err(io::Error::other)?;
+    }
+
+    settings::load(&args.settings(), args.config.as_deref()).map_err(io::Error::other)?;
+
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        tokio::spawn(reload_on_hangup(
+            signal(SignalKind::hangup())?,
+            args.clone(),
+        ));
+    }
+
+    shard::start(args.shards);
+    tokio::spawn(shard::compact());
+
+    tokio::spawn(stats::record(
+        Duration::from_secs(args.history_interval),
+        args.history_length,
+    ));
+
+    let mut app = Router::new();
+
+    for path in args.announce_paths.iter() {
+        println!("Serving announces on {}", path);
+        app = app.route(path, get(announce_route)).route(
+            &format!("{}/:passkey", path.trim_end_matches('/')),
+            get(announce_route),
+        );
+    }
+
+    for path in args.scrape_paths.iter() {
+        println!("Serving scrapes on {}", path);
+        app = app.route(path, get(scrape::scrape_route)).route(
+            &format!("{}/:passkey", path.trim_end_matches('/')),
+            get(scrape::scrape_route),
+        );
+    }
+
+    app = app
+        .route("/stats", get(stats::stats_route))
+        .route("/metrics", get(stats::metrics_route))
+        .route("/healthz", get(health::healthz_route))
+        .route("/readyz", get(health::readyz_route));
+
+    if args.admin_token.is_some() {
+        app = app.route("/admin/torrents", post(admin::register_torrent_route));
+    }
+
+    let app = app.with_state(Arc::new(args));
+    let mut servers = JoinSet::new();
+
+    let mut listeners = net::inherited_listeners()?
+        .into_iter()
+        .map(TcpListener::from_std)
+        .collect::<io::Result<Vec<_>>>()?;
+
+    if listeners.is_empty() {
+        for addr in addrs {
+            listeners.push(TcpListener::bind(addr).await?);
+        }
+    }
+
+    for listener in listeners {
+        println!("Listening on {}", listener.local_addr()?);
+
+        let service = app
+            .clone()
+            .into_make_service_with_connect_info::<Connection>();
+        servers.spawn(async move { axum::serve(listener, service).await });
+    }
+
+    health::set_ready();
+
+    while let Some(result) = servers.join_next().await {
+        result.map_err(io::Error::other)??;
+    }
 
     Ok(())
 }
 
-async fn announce_route(req: tide::Request<()>) -> tide::Result {
-    let Some(remote_socket) = req.remote().and_then(|s| s.parse::<SocketAddr>().ok()) else {
-        return into_result(common::tracker::FailureResponse {
-            failure_reason: "Missing remote address".to_string(),
+/// Read the --users and --config files again on every SIGHUP, which would otherwise terminate the
+/// tracker. The swarms, the connections and the stats of the users still listed are kept.
+#[cfg(unix)]
+async fn reload_on_hangup(mut hangups: tokio::signal::unix::Signal, args: Args) {
+    while hangups.recv().await.is_some() {
+        if args.users.is_none() && args.config.is_none() {
+            println!("Nothing to reload without --users or --config");
+            continue;
+        }
+
+        if let Some(path) = &args.users {
+            if let Err(e) = users::load(path) {
+                println!("Not reloading users: {}", e);
+            }
+        }
+
+        if let Some(path) = &args.config {
+            if let Err(e) = settings::load(&args.settings(), Some(path)) {
+                println!("Not reloading settings: {}", e);
+            }
+        }
+    }
+}
+
+async fn announce_route(
+    State(args): State<Arc<Args>>,
+    ConnectInfo(connection): ConnectInfo<Connection>,
+    passkey: Option<Path<String>>,
+    RawQuery(query): RawQuery,
+) -> Response {
+    let remote_socket = connection.remote;
+
+    if let Some(local_addr) = connection.local {
+        stats::count_request(local_addr);
+    }
+
+    health::count_announce(Instant::now());
+
+    let passkey = passkey.as_ref().map(|Path(passkey)| passkey.as_str());
+
+    if let Err(e) = users::authorize(passkey) {
+        // A missing or unknown passkey won't fix itself.
+        return into_response(common::tracker::FailureResponse {
+            failure_reason: e.to_string(),
+            retry_in: Some(common::tracker::RetryIn::Never),
+            ..Default::default()
         });
-    };
+    }
 
     println!(
-        "{:21} <# {}",
+        "{:21} <# {} (on {})",
         remote_socket,
-        req.url().query().unwrap_or("")
+        query.as_deref().unwrap_or(""),
+        connection
+            .local
+            .map_or("unknown listener".to_string(), |addr| addr.to_string()),
     );
-    let request = match req
-        .url()
-        .query()
+    let request = match query
+        .as_deref()
         .ok_or("Missing query")
         .and_then(|s| s.parse())
     {
         Ok(r) => r,
         Err(e) => {
-            return into_result(common::tracker::FailureResponse {
+            return into_response(common::tracker::FailureResponse {
                 failure_reason: e.to_string(),
+                ..Default::default()
             });
         }
     };
 
     println!("{:21} <- {:?}", remote_socket, request);
 
-    let response = announce::announce(request, remote_socket.ip()).await;
+    let Some(settings) = settings::current() else {
+        return into_response(common::tracker::FailureResponse {
+            failure_reason: "The tracker is unavailable".to_string(),
+            ..Default::default()
+        });
+    };
+
+    if let Err(e) =
+        users::record_announce(passkey, &request, settings.min_ratio, settings.ratio_grace)
+    {
+        println!("{:21} Refused: {}", remote_socket, e);
+        return into_response(common::tracker::FailureResponse {
+            failure_reason: e,
+            ..Default::default()
+        });
+    }
+
+    let info_hash = request.info_hash;
+    let announced = shard::with(&info_hash, move |torrents| {
+        let response = announce::announce(
+            torrents,
+            request,
+            remote_socket.ip(),
+            &common::SystemClock,
+            &args,
+            &settings,
+        );
+        let torrent = torrents.get(&info_hash).map(ToString::to_string);
+        (response, torrent)
+    });
+
+    let Some((response, torrent)) = announced.await else {
+        return into_response(common::tracker::FailureResponse {
+            failure_reason: "The tracker is unavailable".to_string(),
+            ..Default::default()
+        });
+    };
+
     println!("{:21} -> {:?}", remote_socket, response);
     println!(
         "{:21} #> {}\n",
@@ -98,23 +383,18 @@ async fn announce_route(req: tide::Request<()>) -> tide::Result {
             .collect::<String>(),
     );
 
-    println!("{}", torrents());
-
-    into_result(response)
-}
+    if let Some(torrent) = torrent {
+        println!("{}", torrent);
+    }
 
-fn torrents<'a>() -> MutexGuard<'a, Torrents> {
-    unsafe { TORRENTS.as_ref().unwrap() }.lock().unwrap()
+    into_response(response)
 }
 
-fn into_result<T: Into<common::tracker::Response>>(response: T) -> tide::Result {
+fn into_response<T: Into<common::tracker::Response>>(response: T) -> Response {
     let tracker_response: common::tracker::Response = response.into();
     let response_bytes: Vec<u8> = (&tracker_response).into();
 
-    Ok(tide::Response::builder(200)
-        .body(response_bytes)
-        .content_type("text/plain")
-        .build())
+    ([(header::CONTENT_TYPE, "text/plain")], response_bytes).into_response()
 }
 
 #[cfg(test)]
@@ -140,9 +420,10 @@ mod test {
                 event: Some(common::tracker::Event::Started),
 
                 numwant: Some(80),
-                key: Some("CE09B16B".as_bytes().to_vec()),
+                key: Some("CE09B16B".as_bytes().into()),
                 compact: Some(true),
                 supportcrypto: Some(true),
+                requirecrypto: None,
                 no_peer_id: None,
                 trackerid: None,
             }),
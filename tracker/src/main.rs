@@ -2,8 +2,8 @@ use clap::Parser;
 
 use toytorrent_tracker as tracker;
 
-#[async_std::main]
-async fn main() -> tide::Result<()> {
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
     let args = tracker::Args::parse();
 
     tracker::run(args).await
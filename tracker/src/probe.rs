@@ -0,0 +1,23 @@
+//! Background connectability checks. When enabled, each newly announced peer address is dialed
+//! once; peers that don't accept the connection are flagged and only handed out to other peers
+//! when there aren't enough connectable ones to fill the response.
+
+use std::time::Duration;
+
+use toytorrent_common as common;
+use toytorrent_net as net;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub async fn probe(info_hash: common::InfoHash, peer: common::tracker::Peer) {
+    let connectable = net::is_connectable(peer.addr, PROBE_TIMEOUT).await;
+
+    println!("{:21} ?> connectable: {}", peer.addr, connectable);
+
+    super::shard::with(&info_hash, move |torrents| {
+        if let Some(torrent) = torrents.get_mut(&info_hash) {
+            torrent.peers.set_connectable(&peer, connectable);
+        }
+    })
+    .await;
+}
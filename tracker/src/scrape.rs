@@ -0,0 +1,89 @@
+//! Scrape requests: swarm statistics for one or more torrents without announcing.
+
+use std::sync::Arc;
+
+use axum::extract::{ConnectInfo, Path, RawQuery};
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+
+use toytorrent_common as common;
+
+use super::torrent::{Torrent, Torrents};
+use super::Connection;
+
+pub async fn scrape_route(
+    ConnectInfo(connection): ConnectInfo<Connection>,
+    passkey: Option<Path<String>>,
+    RawQuery(query): RawQuery,
+) -> Response {
+    if let Some(local_addr) = connection.local {
+        super::stats::count_request(local_addr);
+    }
+
+    let passkey = passkey.as_ref().map(|Path(passkey)| passkey.as_str());
+
+    let request = super::users::authorize(passkey).and_then(|()| {
+        query
+            .as_deref()
+            .unwrap_or("")
+            .parse::<common::tracker::ScrapeRequest>()
+    });
+
+    let files = match request {
+        Ok(request) => {
+            let request = Arc::new(request);
+            let files = super::shard::each(move |torrents| scrape(&request, torrents).files).await;
+            files.ok_or("The tracker is unavailable")
+        }
+        Err(e) => Err(e),
+    };
+
+    // Each shard only knows its own torrents, so between them they have every one requested.
+    let response: common::tracker::ScrapeResponse = match files {
+        Ok(files) => common::tracker::SuccessScrapeResponse {
+            files: files.into_iter().flatten().collect(),
+        }
+        .into(),
+        Err(e) => common::tracker::FailureResponse {
+            failure_reason: e.to_string(),
+            ..Default::default()
+        }
+        .into(),
+    };
+
+    let response_bytes: Vec<u8> = (&response).into();
+
+    ([(header::CONTENT_TYPE, "text/plain")], response_bytes).into_response()
+}
+
+/// Stats for the requested torrents, or for every torrent if none were requested. Unknown torrents
+/// are left out of the response.
+fn scrape(
+    request: &common::tracker::ScrapeRequest,
+    torrents: &Torrents,
+) -> common::tracker::SuccessScrapeResponse {
+    let files = if request.info_hashes.is_empty() {
+        torrents.iter().map(scrape_file).collect()
+    } else {
+        request
+            .info_hashes
+            .iter()
+            .filter_map(|info_hash| torrents.get(info_hash))
+            .map(scrape_file)
+            .collect()
+    };
+
+    common::tracker::SuccessScrapeResponse { files }
+}
+
+fn scrape_file(torrent: &Torrent) -> (common::InfoHash, common::tracker::ScrapeFile) {
+    (
+        *torrent.info_hash(),
+        common::tracker::ScrapeFile {
+            complete: torrent.complete,
+            downloaded: torrent.downloaded,
+            incomplete: torrent.incomplete,
+            name: torrent.name.clone(),
+        },
+    )
+}
@@ -0,0 +1,133 @@
+//! The settings that can change while the tracker runs. They start out as given on the command
+//! line, with those in the --config file on top, and the file is read again on every SIGHUP.
+//! Announces read the settings afresh, so swarms and connections carry on as they were.
+
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use toytorrent_common as common;
+
+use super::torrent;
+
+/// The settings in effect, or `None` until they're loaded at startup.
+static SETTINGS: RwLock<Option<Arc<Settings>>> = RwLock::new(None);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    /// The interval to instruct clients to announce with.
+    pub interval: u32,
+
+    /// If set, the minimum interval to permit clients to announce.
+    pub min_interval: Option<u32>,
+
+    /// The interval after which to consider a client dropped.
+    pub timeout_interval: u32,
+
+    /// The maximum number of peers to return.
+    pub max_response_peers: u32,
+
+    /// With --users, refuse downloads to users whose ratio falls below this.
+    pub min_ratio: Option<f64>,
+
+    /// How much users may download before `min_ratio` applies to them.
+    pub ratio_grace: common::Bytes,
+}
+
+impl Settings {
+    /// Override these settings with the `<option> = <value>` lines in `s`, named as on the command
+    /// line without the dashes in front. Blank lines and lines starting with `#` are skipped.
+    fn apply(&mut self, s: &str) -> Result<(), String> {
+        for (number, line) in s.lines().enumerate().map(|(i, line)| (i + 1, line.trim())) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((name, value)) = line.split_once('=') else {
+                return Err(format!("Line {number}: expected `<option> = <value>`"));
+            };
+
+            let value = value.trim();
+            let invalid = |e: &dyn std::fmt::Display| format!("Line {number}: {value}: {e}");
+
+            match name.trim() {
+                "interval" => self.interval = value.parse().map_err(|e| invalid(&e))?,
+                "min-interval" => self.min_interval = Some(value.parse().map_err(|e| invalid(&e))?),
+                "timeout-interval" => {
+                    self.timeout_interval = value.parse().map_err(|e| invalid(&e))?
+                }
+                "max-response-peers" => {
+                    self.max_response_peers = value.parse().map_err(|e| invalid(&e))?
+                }
+                "min-ratio" => self.min_ratio = Some(value.parse().map_err(|e| invalid(&e))?),
+                "ratio-grace" => self.ratio_grace = value.parse().map_err(|e| invalid(&e))?,
+                name => return Err(format!("Line {number}: `{name}` can't be set here")),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Put `defaults` in effect, with the settings in the file at `path` on top if given. Nothing
+/// changes if the file can't be read.
+pub fn load(defaults: &Settings, path: Option<&Path>) -> Result<(), String> {
+    let mut settings = defaults.clone();
+
+    if let Some(path) = path {
+        let s = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        settings
+            .apply(&s)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        println!("Loaded settings from {}", path.display());
+    }
+
+    torrent::set_peer_expiry(Duration::from_secs(settings.timeout_interval.into()));
+    *SETTINGS.write().unwrap() = Some(Arc::new(settings));
+    Ok(())
+}
+
+/// The settings in effect, which stay as they are for as long as they're held.
+pub fn current() -> Option<Arc<Settings>> {
+    SETTINGS.read().unwrap().clone()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn apply_test() {
+        let defaults = Settings {
+            interval: 600,
+            min_interval: None,
+            timeout_interval: 3600,
+            max_response_peers: 30,
+            min_ratio: None,
+            ratio_grace: common::Bytes::from(1024),
+        };
+
+        let mut settings = defaults.clone();
+        settings
+            .apply("# Busy tonight\ninterval = 1800\n\nmin-ratio=0.5\ntimeout-interval = 2700\n")
+            .unwrap();
+
+        assert_eq!(
+            Settings {
+                interval: 1800,
+                timeout_interval: 2700,
+                min_ratio: Some(0.5),
+                ..defaults.clone()
+            },
+            settings,
+        );
+
+        let mut settings = defaults.clone();
+        assert!(settings.apply("interval = soon").is_err());
+        assert!(settings.apply("probe-peers = true").is_err());
+        assert!(settings.apply("interval").is_err());
+    }
+}
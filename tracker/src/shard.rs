@@ -0,0 +1,184 @@
+//! The swarms, split into shards by info hash. Each shard is owned by a worker task that runs the
+//! jobs sent to it one at a time, so announces for torrents on different shards are handled in
+//! parallel rather than queueing for a single lock, and the swarms need no lock at all.
+
+use std::num::NonZeroUsize;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tokio::sync::{mpsc, oneshot};
+use tokio::time;
+
+use toytorrent_common as common;
+
+use super::torrent::Torrents;
+
+/// The most jobs to queue for each shard. Beyond this, senders wait for room instead of piling up
+/// work that the shard can't get through.
+const QUEUE_LENGTH: usize = 1024;
+
+/// How often to drop expired peers from every swarm, including those that no announce has come
+/// across.
+const COMPACT_INTERVAL: Duration = Duration::from_secs(300);
+
+type Job = Box<dyn FnOnce(&mut Torrents) + Send>;
+
+static SHARDS: OnceLock<Vec<mpsc::Sender<Job>>> = OnceLock::new();
+
+/// Start a worker for each of `count` shards, or one per CPU if `None`. Only the first call has
+/// any effect.
+pub fn start(count: Option<NonZeroUsize>) {
+    SHARDS.get_or_init(|| {
+        let count = count
+            .or_else(|| thread::available_parallelism().ok())
+            .map_or(1, NonZeroUsize::get);
+
+        (0..count)
+            .map(|_| {
+                let (sender, receiver) = mpsc::channel(QUEUE_LENGTH);
+                tokio::spawn(work(receiver));
+                sender
+            })
+            .collect()
+    });
+}
+
+/// Run `job` on the shard that holds `info_hash`, or return `None` if the job panicked.
+pub async fn with<T, F>(info_hash: &common::InfoHash, job: F) -> Option<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut Torrents) -> T + Send + 'static,
+{
+    let shards = shards();
+
+    if shards.is_empty() {
+        return None;
+    }
+
+    send(&shards[index(info_hash, shards.len())], job)
+        .await?
+        .await
+        .ok()
+}
+
+/// Run `job` on every shard, returning the results in shard order, or `None` if it panicked on any
+/// of them. The shards run it in parallel, each seeing only its own torrents.
+pub async fn each<T, F>(job: F) -> Option<Vec<T>>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut Torrents) -> T + Clone + Send + 'static,
+{
+    let mut replies = Vec::new();
+
+    for shard in shards() {
+        replies.push(send(shard, job.clone()).await?);
+    }
+
+    let mut results = Vec::with_capacity(replies.len());
+
+    for reply in replies {
+        results.push(reply.await.ok()?);
+    }
+
+    Some(results)
+}
+
+/// Drop expired peers every [`COMPACT_INTERVAL`], so that the swarms only take up memory for live
+/// peers.
+pub async fn compact() {
+    loop {
+        time::sleep(COMPACT_INTERVAL).await;
+
+        let now = Instant::now();
+        each(move |torrents| torrents.expire_peers(now)).await;
+    }
+}
+
+fn shards() -> &'static [mpsc::Sender<Job>] {
+    SHARDS.get().map_or(&[], Vec::as_slice)
+}
+
+/// Info hashes are SHA-1 digests, so any of their bytes spread torrents evenly across shards.
+fn index(info_hash: &common::InfoHash, count: usize) -> usize {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&info_hash.as_slice()[..8]);
+    (u64::from_le_bytes(bytes) % count as u64) as usize
+}
+
+async fn send<T, F>(shard: &mpsc::Sender<Job>, job: F) -> Option<oneshot::Receiver<T>>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut Torrents) -> T + Send + 'static,
+{
+    let (sender, receiver) = oneshot::channel();
+
+    shard
+        .send(Box::new(move |torrents| {
+            // The requester may have gone away, such as a client that hung up.
+            sender.send(job(torrents)).ok();
+        }))
+        .await
+        .ok()?;
+
+    Some(receiver)
+}
+
+async fn work(mut receiver: mpsc::Receiver<Job>) {
+    let mut torrents = Torrents::default();
+
+    while let Some(job) = receiver.recv().await {
+        // A panicking job drops its reply, so its requester sees it fail, but the shard's other
+        // torrents carry on being served.
+        if panic::catch_unwind(AssertUnwindSafe(|| job(&mut torrents))).is_err() {
+            println!("A job panicked on a shard of {} torrents", torrents.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn index_test() {
+        let info_hash = common::InfoHash::from([7; 20]);
+
+        assert_eq!(0, index(&info_hash, 1));
+        assert_eq!(index(&info_hash, 4), index(&info_hash, 4));
+        assert!((0..20u8).all(|i| index(&[i; 20].into(), 3) < 3));
+    }
+
+    #[tokio::test]
+    async fn shard_test() {
+        start(NonZeroUsize::new(4));
+
+        for i in 0..20u8 {
+            let info_hash = common::InfoHash::from([i; 20]);
+            with(&info_hash, move |torrents| {
+                torrents.get_or_insert(info_hash);
+            })
+            .await
+            .unwrap();
+        }
+
+        let counts = each(|torrents| torrents.len()).await.unwrap();
+        assert_eq!(4, counts.len());
+        assert_eq!(20, counts.iter().sum::<usize>());
+
+        let info_hash = common::InfoHash::from([3; 20]);
+        let found = with(&info_hash, move |torrents| {
+            torrents.get(&info_hash).is_some()
+        });
+        assert_eq!(Some(true), found.await);
+
+        let panicked = with(&info_hash, |_| -> bool { panic!("Job panicked") });
+        assert_eq!(None, panicked.await);
+
+        let found = with(&info_hash, move |torrents| {
+            torrents.get(&info_hash).is_some()
+        });
+        assert_eq!(Some(true), found.await);
+    }
+}
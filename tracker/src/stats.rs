@@ -0,0 +1,247 @@
+//! Periodic snapshots of each swarm, exposed as a plain text page and as Prometheus metrics.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use tokio::time;
+
+use super::torrent::{Snapshot, Torrents};
+use super::users::Users;
+
+/// A per-torrent metric: its name, its help text, and how to read it from a snapshot.
+type Metric = (&'static str, &'static str, fn(&Snapshot) -> u64);
+
+/// The number of requests received on each local address. For listeners bound to an unspecified
+/// address, this is the address of the interface that the request arrived on.
+static REQUESTS: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+
+pub fn count_request(local_addr: SocketAddr) {
+    *REQUESTS
+        .lock()
+        .unwrap()
+        .entry(local_addr.to_string())
+        .or_default() += 1;
+}
+
+pub async fn record(interval: Duration, max_len: usize) {
+    loop {
+        time::sleep(interval).await;
+
+        let now = SystemTime::now();
+        super::shard::each(move |torrents| torrents.snapshot(now, max_len)).await;
+    }
+}
+
+pub async fn stats_route() -> Response {
+    let Some(torrents) = torrents().await else {
+        return unavailable();
+    };
+
+    let body = render_stats(
+        &torrents,
+        &REQUESTS.lock().unwrap(),
+        super::users::users().as_ref(),
+    );
+
+    ([(header::CONTENT_TYPE, "text/plain")], body).into_response()
+}
+
+pub async fn metrics_route() -> Response {
+    let Some(torrents) = torrents().await else {
+        return unavailable();
+    };
+
+    let body = render_metrics(
+        &torrents,
+        &REQUESTS.lock().unwrap(),
+        super::users::users().as_ref(),
+    );
+
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response()
+}
+
+/// Every shard's torrents, without the peers that the stats don't need.
+async fn torrents() -> Option<Torrents> {
+    let shards = super::shard::each(|torrents| torrents.without_peers()).await?;
+
+    Some(
+        shards
+            .into_iter()
+            .fold(Torrents::default(), |mut torrents, shard| {
+                torrents.merge(shard);
+                torrents
+            }),
+    )
+}
+
+fn unavailable() -> Response {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(header::CONTENT_TYPE, "text/plain")],
+        "The tracker is unavailable\n",
+    )
+        .into_response()
+}
+
+fn render_stats(
+    torrents: &Torrents,
+    requests: &BTreeMap<String, u64>,
+    users: Option<&Users>,
+) -> String {
+    let mut output = String::new();
+
+    for (local_addr, count) in requests {
+        writeln!(output, "listener {} {:>10} requests", local_addr, count).unwrap();
+    }
+
+    for user in users.iter().flat_map(|users| users.iter()) {
+        writeln!(
+            output,
+            "user {} {:>10} announces {:>6} completed",
+            user.name, user.announces, user.completed,
+        )
+        .unwrap();
+    }
+
+    for torrent in torrents.iter() {
+        writeln!(
+            output,
+            "{} {} {}",
+            torrent.info_hash(),
+            torrent.name.as_deref().unwrap_or(""),
+            torrent
+                .length
+                .map(|length| length.to_string())
+                .unwrap_or_default(),
+        )
+        .unwrap();
+
+        for snapshot in torrent.history.iter() {
+            writeln!(
+                output,
+                "  {:>10}  {:>6} complete  {:>6} incomplete  {:>6} downloaded",
+                unix_time(snapshot).as_secs(),
+                snapshot.complete,
+                snapshot.incomplete,
+                snapshot.downloaded,
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            output,
+            "  {:>10}  {:>6} complete  {:>6} incomplete  {:>6} downloaded",
+            "now", torrent.complete, torrent.incomplete, torrent.downloaded,
+        )
+        .unwrap();
+    }
+
+    output
+}
+
+fn render_metrics(
+    torrents: &Torrents,
+    requests: &BTreeMap<String, u64>,
+    users: Option<&Users>,
+) -> String {
+    let mut output = String::new();
+
+    writeln!(
+        output,
+        "# HELP toytorrent_requests Requests received on each listener"
+    )
+    .unwrap();
+    writeln!(output, "# TYPE toytorrent_requests counter").unwrap();
+
+    for (local_addr, count) in requests {
+        writeln!(
+            output,
+            "toytorrent_requests{{listener=\"{local_addr}\"}} {count}"
+        )
+        .unwrap();
+    }
+
+    if let Some(users) = users {
+        writeln!(
+            output,
+            "# HELP toytorrent_user_announces Announces from each user"
+        )
+        .unwrap();
+        writeln!(output, "# TYPE toytorrent_user_announces counter").unwrap();
+
+        for user in users.iter() {
+            writeln!(
+                output,
+                "toytorrent_user_announces{{user={:?}}} {}",
+                user.name, user.announces,
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            output,
+            "# HELP toytorrent_user_completed Completed downloads from each user"
+        )
+        .unwrap();
+        writeln!(output, "# TYPE toytorrent_user_completed counter").unwrap();
+
+        for user in users.iter() {
+            writeln!(
+                output,
+                "toytorrent_user_completed{{user={:?}}} {}",
+                user.name, user.completed,
+            )
+            .unwrap();
+        }
+    }
+
+    let metrics: [Metric; 3] = [
+        (
+            "toytorrent_seeders",
+            "Peers with the complete torrent",
+            |s| s.complete,
+        ),
+        (
+            "toytorrent_leechers",
+            "Peers without the complete torrent",
+            |s| s.incomplete,
+        ),
+        (
+            "toytorrent_downloads",
+            "Completed downloads reported to the tracker",
+            |s| s.downloaded,
+        ),
+    ];
+
+    for (name, help, value) in metrics {
+        writeln!(output, "# HELP {name} {help}").unwrap();
+        writeln!(output, "# TYPE {name} gauge").unwrap();
+
+        for torrent in torrents.iter() {
+            if let Some(snapshot) = torrent.history.latest() {
+                writeln!(
+                    output,
+                    "{name}{{info_hash=\"{}\"}} {} {}",
+                    torrent.info_hash(),
+                    value(snapshot),
+                    unix_time(snapshot).as_millis(),
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    output
+}
+
+fn unix_time(snapshot: &Snapshot) -> Duration {
+    snapshot
+        .time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+}
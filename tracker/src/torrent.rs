@@ -1,12 +1,25 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt;
 use std::hash::{Hash, Hasher};
-use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
-use rand::seq::{IteratorRandom, SliceRandom};
+use rand::seq::{index, IteratorRandom, SliceRandom};
+use rand::Rng;
 
 use toytorrent_common as common;
 
+/// Peers that haven't announced in this many seconds are dropped from the swarm, either when an
+/// announce comes across them or at the next compaction. Set from --timeout-interval.
+static PEER_EXPIRY: AtomicU64 = AtomicU64::new(3600);
+
+/// How many times as many peers as were asked for to sample, since some may turn out to be
+/// expired or otherwise unusable.
+const OVERSAMPLE: usize = 2;
+
+/// How many of the peers that announced most recently to remember, to give out first.
+const FRESH_PEERS: usize = 64;
+
 #[derive(Clone, Debug, Default, Eq, PartialEq)]
 pub struct Torrents(HashMap<common::InfoHash, Torrent>);
 
@@ -18,10 +31,39 @@ pub struct Torrent {
     pub incomplete: u64,
     pub downloaded: u64,
     pub name: Option<String>,
+    pub length: Option<common::Bytes>,
+    pub history: History,
 }
 
+/// A swarm's peers, indexed so that an announce can sample some of them, favouring the latest to
+/// announce, and count the seeders without visiting every one.
 #[derive(Clone, Debug, Default, Eq, PartialEq)]
-pub struct Peers(HashSet<common::tracker::Peer>);
+pub struct Peers {
+    /// Every peer, in no particular order, so that a random sample can be taken by position.
+    peers: Vec<common::tracker::Peer>,
+
+    /// The position of each peer in `peers`.
+    positions: HashMap<common::tracker::Peer, usize>,
+
+    /// The number of peers with the complete torrent.
+    seeders: u64,
+
+    /// The peers that announced most recently, oldest first, which are the likeliest to still be
+    /// around. Each is looked up in `positions` when used, so removed peers are skipped.
+    fresh: VecDeque<common::tracker::Peer>,
+}
+
+/// A bounded record of a torrent's swarm size over time, oldest first.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct History(VecDeque<Snapshot>);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Snapshot {
+    pub time: SystemTime,
+    pub complete: u64,
+    pub incomplete: u64,
+    pub downloaded: u64,
+}
 
 impl Torrents {
     pub fn get_or_insert(&mut self, info_hash: common::InfoHash) -> &mut Torrent {
@@ -29,6 +71,69 @@ impl Torrents {
             .entry(info_hash)
             .or_insert_with(|| Torrent::new(info_hash))
     }
+
+    pub fn get(&self, info_hash: &common::InfoHash) -> Option<&Torrent> {
+        self.0.get(info_hash)
+    }
+
+    pub fn get_mut(&mut self, info_hash: &common::InfoHash) -> Option<&mut Torrent> {
+        self.0.get_mut(info_hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// A copy of every torrent without its peers, for reports that only need the counts.
+    pub fn without_peers(&self) -> Self {
+        Self(
+            self.0
+                .iter()
+                .map(|(info_hash, torrent)| {
+                    let torrent = Torrent {
+                        peers: Peers::default(),
+                        name: torrent.name.clone(),
+                        history: torrent.history.clone(),
+                        ..*torrent
+                    };
+                    (*info_hash, torrent)
+                })
+                .collect(),
+        )
+    }
+
+    /// Add the torrents of another shard.
+    pub fn merge(&mut self, other: Self) {
+        self.0.extend(other.0);
+    }
+
+    /// All torrents, ordered by info hash.
+    pub fn iter(&self) -> impl Iterator<Item = &Torrent> {
+        let mut torrents: Vec<&Torrent> = self.0.values().collect();
+        torrents.sort_by_key(|torrent| torrent.info_hash);
+        torrents.into_iter()
+    }
+
+    /// Drop the expired peers of every torrent.
+    pub fn expire_peers(&mut self, now: Instant) {
+        for torrent in self.0.values_mut() {
+            torrent.peers.expire(now);
+            torrent.update_counts();
+        }
+    }
+
+    pub fn snapshot(&mut self, time: SystemTime, max_len: usize) {
+        for torrent in self.0.values_mut() {
+            let snapshot = Snapshot {
+                time,
+                complete: torrent.complete,
+                incomplete: torrent.incomplete,
+                downloaded: torrent.downloaded,
+            };
+
+            torrent.history.push(snapshot, max_len);
+        }
+    }
 }
 
 impl Torrent {
@@ -40,75 +145,252 @@ impl Torrent {
             incomplete: 0,
             downloaded: 0,
             name: None,
+            length: None,
+            history: History::default(),
         }
     }
 
+    pub fn info_hash(&self) -> &common::InfoHash {
+        &self.info_hash
+    }
+
+    /// Copy the seeder and leecher counts that the peers keep up to date.
     pub fn update_counts(&mut self) {
         (self.complete, self.incomplete) = self.peers.complete_incomplete();
     }
 }
 
 impl Peers {
+    pub fn get(&self, peer: &common::tracker::Peer) -> Option<&common::tracker::Peer> {
+        self.positions
+            .get(peer)
+            .map(|&position| &self.peers[position])
+    }
+
     pub fn remove(&mut self, peer: &common::tracker::Peer) {
-        self.0.remove(peer);
+        let Some(position) = self.positions.remove(peer) else {
+            return;
+        };
+
+        let removed = self.peers.swap_remove(position);
+        self.seeders -= u64::from(is_seeder(&removed));
+        self.fresh.retain(|fresh| fresh != peer);
+
+        // The last peer took the place of the removed one.
+        if let Some(moved) = self.peers.get(position) {
+            *self.positions.get_mut(moved).unwrap() = position;
+        }
     }
 
     pub fn replace(&mut self, peer: common::tracker::Peer) {
-        self.0.replace(peer);
+        self.seeders += u64::from(is_seeder(&peer));
+
+        self.fresh.retain(|fresh| fresh != &peer);
+        if self.fresh.len() >= FRESH_PEERS {
+            self.fresh.pop_front();
+        }
+        self.fresh.push_back(peer.clone());
+
+        match self.positions.remove(&peer) {
+            Some(position) => {
+                let replaced = std::mem::replace(&mut self.peers[position], peer.clone());
+                self.seeders -= u64::from(is_seeder(&replaced));
+                self.positions.insert(peer, position);
+            }
+            None => {
+                self.positions.insert(peer.clone(), self.peers.len());
+                self.peers.push(peer);
+            }
+        }
+    }
+
+    pub fn set_connectable(&mut self, peer: &common::tracker::Peer, connectable: bool) {
+        if let Some(&position) = self.positions.get(peer) {
+            let existing = &mut self.peers[position];
+
+            if existing.addr == peer.addr {
+                existing.connectable = Some(connectable);
+            }
+        }
+    }
+
+    /// Drop every expired peer, and the memory they took up.
+    pub fn expire(&mut self, now: Instant) {
+        let expired: Vec<common::tracker::Peer> = self
+            .peers
+            .iter()
+            .filter(|peer| is_expired(peer, now))
+            .cloned()
+            .collect();
+
+        for peer in expired.iter() {
+            self.remove(peer);
+        }
+
+        if self.peers.capacity() > 2 * self.peers.len() {
+            self.peers.shrink_to_fit();
+            self.positions.shrink_to_fit();
+        }
     }
 
     pub fn len(&self) -> usize {
-        self.0.len()
+        self.peers.len()
     }
 
     pub fn is_empty(&self) -> bool {
-        self.0.is_empty()
+        self.peers.is_empty()
     }
 
+    /// Choose up to `count` peers at random to give out, dropping any expired ones that turn up.
     pub fn get_multiple(
-        &self,
+        &mut self,
         count: usize,
         exclude: Option<&common::tracker::Peer>,
         requirecrypto: bool,
+        now: Instant,
     ) -> Vec<&common::tracker::Peer> {
         let mut rng = rand::thread_rng();
 
-        let expiry = Self::expiry();
+        // Expired peers are left for the next compaction unless an announce happens across them.
+        let amount = count.saturating_mul(OVERSAMPLE).min(self.peers.len());
+        let expired: Vec<common::tracker::Peer> = index::sample(&mut rng, self.peers.len(), amount)
+            .iter()
+            .map(|position| &self.peers[position])
+            .filter(|peer| is_expired(peer, now))
+            .cloned()
+            .collect();
 
-        let mut result = if requirecrypto {
-            self.0
-                .iter()
-                .filter(|&p| {
-                    Some(p) != exclude && p.last_seen > expiry && p.supportcrypto == Some(true)
-                })
-                .choose_multiple(&mut rng, count)
-        } else {
-            self.0
-                .iter()
-                .filter(|&p| Some(p) != exclude && p.last_seen > expiry)
-                .choose_multiple(&mut rng, count)
+        for peer in expired.iter() {
+            self.remove(peer);
+        }
+
+        // Peers that didn't give a port still count towards the stats, but can't be connected to.
+        let usable = |p: &&common::tracker::Peer| {
+            Some(*p) != exclude
+                && !is_expired(p, now)
+                && p.addr.port() != 0
+                && (!requirecrypto || p.supportcrypto == Some(true))
         };
 
+        // Up to half of the response is the peers that announced most recently, and the rest is
+        // random, so that peers don't all hear about the same few.
+        let fresh: HashSet<usize> = self
+            .fresh
+            .iter()
+            .rev()
+            .filter_map(|peer| self.positions.get(peer).copied())
+            .filter(|&position| {
+                let peer = &self.peers[position];
+                usable(&peer) && peer.connectable != Some(false)
+            })
+            .take(count / 2)
+            .collect();
+        let random = count - fresh.len();
+        let not_fresh = |p: &&common::tracker::Peer| !fresh.contains(&self.positions[*p]);
+
+        // A random sample usually has enough connectable peers, without visiting the whole swarm.
+        let amount = random.saturating_mul(OVERSAMPLE).min(self.peers.len());
+        let mut sampled: HashSet<usize> = index::sample(&mut rng, self.peers.len(), amount)
+            .into_iter()
+            .collect();
+
+        let (mut connectable, mut unconnectable) = partition(
+            sampled
+                .iter()
+                .map(|&position| &self.peers[position])
+                .filter(&usable)
+                .filter(&not_fresh),
+        );
+
+        // If it doesn't, draw more peers one at a time, up to as many draws as there are peers.
+        let mut draws = amount;
+        while connectable.len() < random && draws < self.peers.len() {
+            draws += 1;
+
+            let position = rng.gen_range(0..self.peers.len());
+            let peer = &self.peers[position];
+
+            if !sampled.insert(position) || !usable(&peer) || !not_fresh(&peer) {
+                continue;
+            }
+
+            if peer.connectable == Some(false) {
+                unconnectable.push(peer);
+            } else {
+                connectable.push(peer);
+            }
+        }
+
+        // Peers that failed a connectability probe are only used to fill out the response.
+        let mut result = connectable.into_iter().choose_multiple(&mut rng, random);
+        result.extend(fresh.iter().map(|&position| &self.peers[position]));
         result.shuffle(&mut rng);
 
+        if result.len() < count {
+            let remaining = count - result.len();
+            result.extend(
+                unconnectable
+                    .into_iter()
+                    .choose_multiple(&mut rng, remaining),
+            );
+        }
+
         result
     }
 
     fn complete_incomplete(&self) -> (u64, u64) {
-        self.0.iter().fold((0, 0), |(complete, incomplete), peer| {
-            if peer.left == Some(0) {
-                (complete + 1, incomplete)
-            } else {
-                (complete, incomplete + 1)
-            }
-        })
+        (self.seeders, self.peers.len() as u64 - self.seeders)
     }
+}
 
-    fn expiry() -> Instant {
-        Instant::now() - Duration::from_secs(3600)
+fn is_expired(peer: &common::tracker::Peer, now: Instant) -> bool {
+    now.duration_since(peer.last_seen) >= peer_expiry()
+}
+
+fn is_seeder(peer: &common::tracker::Peer) -> bool {
+    peer.left == Some(0)
+}
+
+/// Split peers into those that may be connectable and those that failed a probe.
+fn partition<'a>(
+    peers: impl Iterator<Item = &'a common::tracker::Peer>,
+) -> (
+    Vec<&'a common::tracker::Peer>,
+    Vec<&'a common::tracker::Peer>,
+) {
+    peers.partition(|p| p.connectable != Some(false))
+}
+
+impl History {
+    pub fn push(&mut self, snapshot: Snapshot, max_len: usize) {
+        if max_len == 0 {
+            return;
+        }
+
+        while self.0.len() >= max_len {
+            self.0.pop_front();
+        }
+
+        self.0.push_back(snapshot);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Snapshot> {
+        self.0.iter()
+    }
+
+    pub fn latest(&self) -> Option<&Snapshot> {
+        self.0.back()
     }
 }
 
+pub fn set_peer_expiry(expiry: Duration) {
+    PEER_EXPIRY.store(expiry.as_secs(), Ordering::Relaxed);
+}
+
+fn peer_expiry() -> Duration {
+    Duration::from_secs(PEER_EXPIRY.load(Ordering::Relaxed))
+}
+
 impl Hash for Torrent {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.info_hash.hash(state)
@@ -150,7 +432,7 @@ impl fmt::Display for Torrent {
 
 impl fmt::Display for Peers {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        let mut peer_vec: Vec<&common::tracker::Peer> = self.0.iter().collect();
+        let mut peer_vec: Vec<&common::tracker::Peer> = self.peers.iter().collect();
         peer_vec.sort();
 
         for (i, peer) in peer_vec.into_iter().enumerate() {
@@ -164,3 +446,220 @@ impl fmt::Display for Peers {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::{Clock, ManualClock};
+
+    #[test]
+    fn get_multiple_expiry_test() {
+        let clock = ManualClock::new();
+        let mut peers = Peers::default();
+
+        peers.replace(common::tracker::Peer {
+            last_seen: clock.now(),
+            peer_id: Some([0; 20].into()),
+            addr: "127.0.0.1:6881".parse().unwrap(),
+            uploaded: Some(0),
+            downloaded: Some(0),
+            left: Some(0),
+            key: None,
+            supportcrypto: None,
+            requirecrypto: None,
+            connectable: None,
+        });
+
+        clock.advance(peer_expiry() - Duration::from_secs(1));
+        assert_eq!(1, peers.get_multiple(10, None, false, clock.now()).len());
+
+        clock.advance(Duration::from_secs(1));
+        assert!(peers.get_multiple(10, None, false, clock.now()).is_empty());
+    }
+
+    #[test]
+    fn peers_test() {
+        let clock = ManualClock::new();
+        let mut peers = Peers::default();
+
+        let peer = |port: u16, left: u64| common::tracker::Peer {
+            last_seen: clock.now(),
+            peer_id: None,
+            addr: ([127, 0, 0, 1], port).into(),
+            uploaded: Some(0),
+            downloaded: Some(0),
+            left: Some(left),
+            key: None,
+            supportcrypto: None,
+            requirecrypto: None,
+            connectable: None,
+        };
+
+        for port in 1..=100 {
+            peers.replace(peer(port, u64::from(port % 4)));
+        }
+
+        assert_eq!(100, peers.len());
+        assert_eq!((25, 75), peers.complete_incomplete());
+
+        // Completing and removing keep the counts and positions up to date.
+        peers.replace(peer(1, 0));
+        peers.remove(&peer(4, 0));
+        peers.remove(&peer(4, 0));
+        peers.set_connectable(&peer(100, 0), false);
+
+        assert_eq!(99, peers.len());
+        assert_eq!((25, 74), peers.complete_incomplete());
+        assert!(peers.get(&peer(4, 0)).is_none());
+        assert_eq!(Some(false), peers.get(&peer(100, 0)).unwrap().connectable);
+
+        for (position, peer) in peers.peers.iter().enumerate() {
+            assert_eq!(position, peers.positions[peer]);
+        }
+
+        let sample = peers.get_multiple(10, Some(&peer(1, 0)), false, clock.now());
+        assert_eq!(10, sample.len());
+        assert!(sample
+            .iter()
+            .all(|p| p.addr.port() != 1 && p.connectable.is_none()));
+
+        // With too few connectable peers, the rest of the response is made up of the others.
+        assert_eq!(99, peers.get_multiple(200, None, false, clock.now()).len());
+
+        // Compaction drops every expired peer.
+        clock.advance(peer_expiry());
+        peers.replace(peer(8, 0));
+        peers.expire(clock.now());
+
+        assert_eq!(1, peers.len());
+        assert_eq!((1, 0), peers.complete_incomplete());
+        assert_eq!(Some(0), peers.positions.get(&peer(8, 0)).copied());
+    }
+
+    #[test]
+    fn fresh_test() {
+        let clock = ManualClock::new();
+        let mut peers = Peers::default();
+
+        let peer = |port: u16| common::tracker::Peer {
+            last_seen: clock.now(),
+            peer_id: None,
+            addr: ([127, 0, 0, 1], port).into(),
+            uploaded: Some(0),
+            downloaded: Some(0),
+            left: Some(1),
+            key: None,
+            supportcrypto: None,
+            requirecrypto: None,
+            connectable: None,
+        };
+
+        for port in 1..=1000 {
+            peers.replace(peer(port));
+        }
+
+        assert_eq!(FRESH_PEERS, peers.fresh.len());
+
+        // Half of the response is the latest announcers, newest first, less any that are gone.
+        peers.remove(&peer(1000));
+
+        for _ in 0..10 {
+            let sample = peers.get_multiple(10, None, false, clock.now());
+            assert_eq!(10, sample.len());
+            assert!(!sample.iter().any(|p| p.addr.port() == 1000));
+
+            for port in 995..1000 {
+                assert!(
+                    sample.iter().any(|p| p.addr.port() == port),
+                    "{port} missing"
+                );
+            }
+
+            let ports: HashSet<u16> = sample.iter().map(|p| p.addr.port()).collect();
+            assert_eq!(10, ports.len());
+        }
+
+        // Announcing again makes a peer fresh again.
+        peers.replace(peer(1));
+        assert_eq!(Some(&peer(1)), peers.fresh.back());
+        assert_eq!(1, peers.fresh.iter().filter(|p| **p == peer(1)).count());
+    }
+
+    #[test]
+    fn get_multiple_sparse_test() {
+        let clock = ManualClock::new();
+        let mut peers = Peers::default();
+
+        let peer = |port: u16| common::tracker::Peer {
+            last_seen: clock.now(),
+            peer_id: None,
+            addr: ([127, 0, 0, 1], port).into(),
+            uploaded: Some(0),
+            downloaded: Some(0),
+            left: Some(1),
+            key: None,
+            supportcrypto: None,
+            requirecrypto: None,
+            connectable: None,
+        };
+
+        // Only the first 50 peers are connectable, too few to turn up in the first sample.
+        for port in 1..=1000 {
+            peers.replace(peer(port));
+
+            if port > 50 {
+                peers.set_connectable(&peer(port), false);
+            }
+        }
+
+        for _ in 0..10 {
+            let sample = peers.get_multiple(4, None, false, clock.now());
+            assert_eq!(4, sample.len());
+            assert!(sample.iter().all(|p| p.connectable.is_none()), "{sample:?}");
+
+            let ports: HashSet<u16> = sample.iter().map(|p| p.addr.port()).collect();
+            assert_eq!(4, ports.len());
+        }
+    }
+
+    #[test]
+    fn complete_incomplete_test() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let clock = ManualClock::new();
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut peers = Peers::default();
+
+        // Leechers complete, seeders go back to leeching, and peers come and go at random.
+        for _ in 0..1000 {
+            let peer = common::tracker::Peer {
+                last_seen: clock.now(),
+                peer_id: Some([rng.gen_range(0..20); 20].into()),
+                addr: ([127, 0, 0, 1], 6881).into(),
+                uploaded: None,
+                downloaded: None,
+                left: Some(rng.gen_range(0..2)),
+                key: None,
+                supportcrypto: None,
+                requirecrypto: None,
+                connectable: None,
+            };
+
+            if rng.gen_bool(0.2) {
+                peers.remove(&peer);
+            } else {
+                peers.replace(peer);
+            }
+
+            let seeders = peers
+                .peers
+                .iter()
+                .filter(|peer| peer.left == Some(0))
+                .count() as u64;
+            let leechers = peers.peers.len() as u64 - seeders;
+            assert_eq!((seeders, leechers), peers.complete_incomplete());
+            assert_eq!(peers.peers.len(), peers.positions.len());
+        }
+    }
+}
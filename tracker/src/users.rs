@@ -0,0 +1,312 @@
+//! Passkeys for running a private tracker. Each user announces to `<announce path>/<passkey>`,
+//! which identifies them without a login.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Mutex, MutexGuard};
+
+use toytorrent_common as common;
+
+use common::tracker::Event;
+
+/// The users of a private tracker, or `None` for a public tracker.
+static USERS: Mutex<Option<Users>> = Mutex::new(None);
+
+/// Users by passkey.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Users(HashMap<String, User>);
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct User {
+    pub name: String,
+    pub announces: u64,
+    pub completed: u64,
+
+    /// The total transfer reported by the user's clients across all torrents.
+    pub uploaded: common::Bytes,
+    pub downloaded: common::Bytes,
+
+    /// The totals each client last reported for each torrent, to account for only what's new in
+    /// the next announce.
+    reported: HashMap<(common::InfoHash, common::PeerId), (u64, u64)>,
+}
+
+impl Users {
+    pub fn iter(&self) -> impl Iterator<Item = &User> {
+        self.0.values()
+    }
+
+    /// Carry over the stats of the users in `previous` that are still listed, under their names
+    /// from this list.
+    fn keep_stats(&mut self, previous: Users) {
+        for (passkey, stats) in previous.0 {
+            if let Some(user) = self.0.get_mut(&passkey) {
+                let name = std::mem::take(&mut user.name);
+                *user = User { name, ..stats };
+            }
+        }
+    }
+}
+
+impl User {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            announces: 0,
+            completed: 0,
+            uploaded: common::Bytes::default(),
+            downloaded: common::Bytes::default(),
+            reported: HashMap::new(),
+        }
+    }
+
+    /// Uploaded over downloaded, or `None` if the user hasn't downloaded anything.
+    pub fn ratio(&self) -> Option<f64> {
+        if self.downloaded == common::Bytes::default() {
+            None
+        } else {
+            Some(u64::from(self.uploaded) as f64 / u64::from(self.downloaded) as f64)
+        }
+    }
+
+    fn record_announce(&mut self, request: &common::tracker::Request) {
+        self.announces += 1;
+
+        if request.event == Some(Event::Completed) {
+            self.completed += 1;
+        }
+
+        let key = (request.info_hash, request.peer_id);
+        let (last_uploaded, last_downloaded) = self.reported.get(&key).copied().unwrap_or((0, 0));
+
+        // Clients count from zero again when restarted, in which case everything is new. Either
+        // total going backwards gives that away.
+        let (new_uploaded, new_downloaded) =
+            if request.uploaded < last_uploaded || request.downloaded < last_downloaded {
+                (request.uploaded, request.downloaded)
+            } else {
+                (
+                    request.uploaded - last_uploaded,
+                    request.downloaded - last_downloaded,
+                )
+            };
+
+        self.uploaded += common::Bytes::from(new_uploaded);
+        self.downloaded += common::Bytes::from(new_downloaded);
+
+        if request.event == Some(Event::Stopped) {
+            self.reported.remove(&key);
+        } else {
+            self.reported
+                .insert(key, (request.uploaded, request.downloaded));
+        }
+    }
+
+    /// Refuse users whose ratio has fallen below `min_ratio` once they've downloaded more than
+    /// `grace`. They may still seed, or stop, to recover.
+    fn check_ratio(
+        &self,
+        request: &common::tracker::Request,
+        min_ratio: f64,
+        grace: common::Bytes,
+    ) -> Result<(), String> {
+        if request.left == 0 || request.event == Some(Event::Stopped) || self.downloaded <= grace {
+            return Ok(());
+        }
+
+        match self.ratio() {
+            Some(ratio) if ratio < min_ratio => Err(format!(
+                "Your ratio of {:.2} is below the minimum of {:.2}; seed to raise it",
+                ratio, min_ratio,
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Parse one `<passkey> <name>` pair per line. Blank lines and lines starting with `#` are
+/// skipped.
+impl FromStr for Users {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut users = HashMap::new();
+
+        for (number, line) in s.lines().enumerate().map(|(i, line)| (i + 1, line.trim())) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((passkey, name)) = line.split_once(char::is_whitespace) else {
+                return Err(format!("Line {number}: expected a passkey and a name"));
+            };
+
+            if !passkey.bytes().all(|b| b.is_ascii_alphanumeric()) {
+                return Err(format!("Line {number}: passkeys must be alphanumeric"));
+            }
+
+            let user = User::new(name.trim());
+
+            if users.insert(passkey.to_string(), user).is_some() {
+                return Err(format!("Line {number}: duplicate passkey"));
+            }
+        }
+
+        Ok(Users(users))
+    }
+}
+
+/// Load the user database, making the tracker private. Loading it again keeps the stats of the
+/// users whose passkeys are still in it.
+pub fn load(path: &Path) -> Result<(), String> {
+    let mut users = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?
+        .parse::<Users>()
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    let mut current = USERS.lock().unwrap();
+
+    if let Some(previous) = current.take() {
+        users.keep_stats(previous);
+    }
+
+    println!("Private tracker with {} users", users.0.len());
+    *current = Some(users);
+
+    Ok(())
+}
+
+pub fn users<'a>() -> MutexGuard<'a, Option<Users>> {
+    USERS.lock().unwrap()
+}
+
+/// Check the passkey from a request's path. Any request is allowed on a public tracker.
+pub fn authorize(passkey: Option<&str>) -> Result<(), &'static str> {
+    match (users().as_ref(), passkey) {
+        (None, _) => Ok(()),
+        (Some(_), None) => Err("This tracker is private; announce with your passkey"),
+        (Some(users), Some(passkey)) if users.0.contains_key(passkey) => Ok(()),
+        (Some(_), Some(_)) => Err("Unknown passkey"),
+    }
+}
+
+/// Count an announce towards the stats of the user it came from, then, if `min_ratio` is set,
+/// check that the user may keep downloading.
+pub fn record_announce(
+    passkey: Option<&str>,
+    request: &common::tracker::Request,
+    min_ratio: Option<f64>,
+    grace: common::Bytes,
+) -> Result<(), String> {
+    let mut users = users();
+
+    let Some(user) = users
+        .as_mut()
+        .zip(passkey)
+        .and_then(|(users, passkey)| users.0.get_mut(passkey))
+    else {
+        return Ok(());
+    };
+
+    user.record_announce(request);
+
+    match min_ratio {
+        Some(min_ratio) => user.check_ratio(request, min_ratio, grace),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn from_str_test() {
+        let users: Users = "# passkey name\n\nabc123 Some User\nDEF456\talice\n"
+            .parse()
+            .unwrap();
+
+        assert_eq!(2, users.0.len());
+        assert_eq!("Some User", users.0["abc123"].name);
+        assert_eq!("alice", users.0["DEF456"].name);
+
+        assert!("abc123".parse::<Users>().is_err());
+        assert!("abc-123 name".parse::<Users>().is_err());
+        assert!("abc name\nabc other".parse::<Users>().is_err());
+    }
+
+    fn request(uploaded: u64, downloaded: u64, left: u64) -> common::tracker::Request {
+        common::tracker::Request {
+            info_hash: [1; 20].into(),
+            uploaded,
+            downloaded,
+            left,
+            event: None,
+            numwant: None,
+            peer_id: [2; 20].into(),
+            key: None,
+            ip: None,
+            port: 6881,
+            compact: None,
+            supportcrypto: None,
+            requirecrypto: None,
+            no_peer_id: None,
+            trackerid: None,
+        }
+    }
+
+    #[test]
+    fn ratio_test() {
+        let grace = common::Bytes::from(100);
+        let mut user = User::new("alice");
+
+        user.record_announce(&request(0, 100, 900));
+        assert_eq!(Some(0.0), user.ratio());
+        assert!(user.check_ratio(&request(0, 100, 900), 0.5, grace).is_ok());
+
+        // Only the transfer since the last announce counts.
+        user.record_announce(&request(20, 200, 800));
+        assert_eq!(common::Bytes::from(200), user.downloaded);
+        assert_eq!(Some(0.1), user.ratio());
+        assert!(user
+            .check_ratio(&request(20, 200, 800), 0.5, grace)
+            .is_err());
+
+        // Seeding is always allowed, to recover.
+        assert!(user.check_ratio(&request(20, 200, 0), 0.5, grace).is_ok());
+
+        // A restarted client counts from zero again.
+        user.record_announce(&request(180, 0, 0));
+        assert_eq!(common::Bytes::from(200), user.uploaded);
+        assert_eq!(Some(1.0), user.ratio());
+    }
+
+    #[test]
+    fn keep_stats_test() {
+        let mut previous: Users = "abc123 alice
+def456 bob
+"
+        .parse()
+        .unwrap();
+        previous
+            .0
+            .get_mut("abc123")
+            .unwrap()
+            .record_announce(&request(10, 20, 0));
+
+        let mut users: Users = "abc123 Alice
+ghi789 carol
+"
+        .parse()
+        .unwrap();
+        users.keep_stats(previous);
+
+        assert_eq!(2, users.0.len());
+        assert_eq!("Alice", users.0["abc123"].name);
+        assert_eq!(1, users.0["abc123"].announces);
+        assert_eq!(common::Bytes::from(10), users.0["abc123"].uploaded);
+        assert_eq!(0, users.0["ghi789"].announces);
+    }
+}